@@ -0,0 +1,567 @@
+//! A minimal parser of the ACPI tables: the RSDP given by the bootloader is validated, the root
+//! table (RSDT or XSDT) is walked to find the other tables, and the MADT is parsed to find the
+//! local APICs, the I/O APICs and the interrupt source overrides needed by [`crate::lapic`],
+//! [`crate::ioapic`] and [`crate::smp`]. The AML tables (DSDT and SSDTs) are not interpreted.
+//!
+//! The tables are read through a translation function giving the virtual address of a physical
+//! address, usually a direct mapping of the physical memory. All the tables are read as byte
+//! slices, since their fields are not aligned.
+use crate::{
+    address::{Physical, Virtual},
+    ioapic::{Polarity, TriggerMode},
+    smp::CpuSet,
+};
+
+/// The signature of the RSDP.
+pub const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The signature of the MADT.
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/// The size of the RSDP of ACPI 1.0, and of the extended RSDP of ACPI 2.0 and later.
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+/// The size of the header common to all the system description tables.
+pub const HEADER_SIZE: usize = 36;
+
+/// The size of the fixed fields of the MADT following the header.
+const MADT_FIXED_SIZE: usize = 8;
+
+/// The MADT flag set if the computer also has the two legacy 8259 PICs.
+const PCAT_COMPAT: u32 = 1 << 0;
+
+/// The flag of the local APIC records set if the processor is enabled, and the flag set if it can
+/// be enabled later.
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A function giving the virtual address of a physical address.
+pub type Translate = fn(Physical) -> Virtual;
+
+/// An error while parsing the ACPI tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The signature of the structure is not the expected one.
+    InvalidSignature,
+
+    /// The checksum of the structure is invalid.
+    InvalidChecksum,
+
+    /// The length of the structure is smaller than its fixed fields, or one of its records
+    /// extends past its end.
+    InvalidLength,
+
+    /// The requested table was not found.
+    NotFound,
+
+    /// An address read from the structure is not a valid physical address.
+    InvalidAddress,
+}
+
+/// Returns `true` if the sum of the given bytes is 0 modulo 256, as the checksums of all the ACPI
+/// structures require.
+#[must_use]
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Read a little-endian `u16` at the given offset of a table.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Read a little-endian `u32` at the given offset of a table.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+/// Read a little-endian `u64` at the given offset of a table.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// A system description table, with its header.
+#[derive(Debug, Clone, Copy)]
+pub struct Table<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Table<'a> {
+    /// Creates a table from its bytes, checking its length and its checksum. The bytes may extend
+    /// past the end of the table.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidLength`] if the length of the table is greater than the given
+    /// bytes or smaller than its header, or [`Error::InvalidChecksum`] if its checksum is invalid.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::InvalidLength);
+        }
+        let length = read_u32(bytes, 4) as usize;
+        if length < HEADER_SIZE || length > bytes.len() {
+            return Err(Error::InvalidLength);
+        }
+
+        let bytes = &bytes[..length];
+        if !checksum(bytes) {
+            return Err(Error::InvalidChecksum);
+        }
+        Ok(Self { bytes })
+    }
+
+    /// Map the table at the given physical address and parse it (see [`Table::parse`]).
+    ///
+    /// # Errors
+    /// See [`Table::parse`].
+    ///
+    /// # Safety
+    /// This function is unsafe because the given address must be the address of a system
+    /// description table, which must be mapped by the translation function and must not be
+    /// modified while the table is used.
+    pub unsafe fn map(address: Physical, translate: Translate) -> Result<Self, Error> {
+        let start = translate(address).as_ptr::<u8>();
+        let header = core::slice::from_raw_parts(start, HEADER_SIZE);
+        let length = (read_u32(header, 4) as usize).max(HEADER_SIZE);
+        Self::parse(core::slice::from_raw_parts(start, length))
+    }
+
+    /// Returns the signature of the table.
+    #[must_use]
+    pub fn signature(&self) -> [u8; 4] {
+        [self.bytes[0], self.bytes[1], self.bytes[2], self.bytes[3]]
+    }
+
+    /// Returns the revision of the table.
+    #[must_use]
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// Returns the identifier of the OEM.
+    #[must_use]
+    pub fn oem_id(&self) -> &'a [u8] {
+        &self.bytes[10..16]
+    }
+
+    /// Returns the bytes of the table, including its header.
+    #[must_use]
+    pub const fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Returns the bytes of the table following its header.
+    #[must_use]
+    pub fn data(&self) -> &'a [u8] {
+        &self.bytes[HEADER_SIZE..]
+    }
+}
+
+/// The root of the ACPI tables, found with the RSDP.
+#[derive(Debug, Clone, Copy)]
+pub struct Acpi {
+    root: Table<'static>,
+    xsdt: bool,
+    translate: Translate,
+}
+
+impl Acpi {
+    /// Validate the RSDP at the given physical address, and find the root table: the XSDT if the
+    /// RSDP is the extended RSDP of ACPI 2.0 or later, or the RSDT otherwise.
+    ///
+    /// # Errors
+    /// Returns an error if the RSDP or the root table is invalid.
+    ///
+    /// # Safety
+    /// This function is unsafe because the given address must be the address of the RSDP given by
+    /// the bootloader, and the ACPI tables must be mapped by the translation function and must
+    /// not be modified or unmapped afterwards.
+    pub unsafe fn from_rsdp(rsdp: Physical, translate: Translate) -> Result<Self, Error> {
+        let start = translate(rsdp).as_ptr::<u8>();
+        let rsdp = core::slice::from_raw_parts(start, RSDP_V1_SIZE);
+        if &rsdp[..8] != RSDP_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        if !checksum(rsdp) {
+            return Err(Error::InvalidChecksum);
+        }
+
+        let (address, xsdt) = if rsdp[15] >= 2 {
+            let rsdp = core::slice::from_raw_parts(start, RSDP_V2_SIZE);
+            if !checksum(rsdp) {
+                return Err(Error::InvalidChecksum);
+            }
+            (read_u64(rsdp, 24), true)
+        } else {
+            (u64::from(read_u32(rsdp, 16)), false)
+        };
+
+        let address = Physical::try_new(address).map_err(|_| Error::InvalidAddress)?;
+        let root = Table::map(address, translate)?;
+        let expected = if xsdt { b"XSDT" } else { b"RSDT" };
+        if &root.signature() != expected {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(Self {
+            root,
+            xsdt,
+            translate,
+        })
+    }
+
+    /// Returns the root table (the RSDT or the XSDT).
+    #[must_use]
+    pub const fn root(&self) -> Table<'static> {
+        self.root
+    }
+
+    /// Returns an iterator over the physical addresses of the tables listed by the root table.
+    /// The entries that are not valid physical addresses are skipped.
+    pub fn addresses(&self) -> impl Iterator<Item = Physical> + '_ {
+        let size = if self.xsdt { 8 } else { 4 };
+        let data = self.root.data();
+        (0..data.len() / size).filter_map(move |index| {
+            let address = if self.xsdt {
+                read_u64(data, index * 8)
+            } else {
+                u64::from(read_u32(data, index * 4))
+            };
+            Physical::try_new(address).ok()
+        })
+    }
+
+    /// Returns an iterator over the valid tables listed by the root table. The tables with an
+    /// invalid length or checksum are skipped.
+    pub fn tables(&self) -> impl Iterator<Item = Table<'static>> + '_ {
+        // SAFETY: The tables listed by the root table are mapped (see `Acpi::from_rsdp`)
+        self.addresses()
+            .filter_map(|address| unsafe { Table::map(address, self.translate) }.ok())
+    }
+
+    /// Find the first valid table with the given signature.
+    ///
+    /// # Errors
+    /// Returns [`Error::NotFound`] if there is no valid table with this signature.
+    pub fn find(&self, signature: &[u8; 4]) -> Result<Table<'static>, Error> {
+        self.tables()
+            .find(|table| &table.signature() == signature)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Find and parse the MADT.
+    ///
+    /// # Errors
+    /// Returns [`Error::NotFound`] if there is no MADT, or an error if it is invalid.
+    pub fn madt(&self) -> Result<Madt<'static>, Error> {
+        Madt::new(self.find(MADT_SIGNATURE)?)
+    }
+}
+
+/// The Multiple APIC Description Table (MADT), listing the interrupt controllers.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt<'a> {
+    table: Table<'a>,
+}
+
+impl<'a> Madt<'a> {
+    /// Creates a MADT from a parsed table.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidSignature`] if the table is not a MADT, or
+    /// [`Error::InvalidLength`] if it is too short or if one of its records is truncated.
+    pub fn new(table: Table<'a>) -> Result<Self, Error> {
+        if &table.signature() != MADT_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        if table.data().len() < MADT_FIXED_SIZE {
+            return Err(Error::InvalidLength);
+        }
+
+        let madt = Self { table };
+        let mut records = madt.records();
+        records.by_ref().for_each(drop);
+        if records.truncated {
+            return Err(Error::InvalidLength);
+        }
+        Ok(madt)
+    }
+
+    /// Returns the physical address of the local APICs, taking into account the address
+    /// override record if there is one.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidAddress`] if the address is not a valid physical address.
+    pub fn local_apic_address(&self) -> Result<Physical, Error> {
+        let address = self.records().find_map(|record| match record {
+            Record::LocalApicAddressOverride { address } => Some(address),
+            _ => None,
+        });
+        let address = address.unwrap_or_else(|| u64::from(read_u32(self.table.data(), 0)));
+        Physical::try_new(address).map_err(|_| Error::InvalidAddress)
+    }
+
+    /// Returns `true` if the computer also has the two legacy 8259 PICs, which must then be
+    /// disabled before using the I/O APICs.
+    #[must_use]
+    pub fn has_legacy_pics(&self) -> bool {
+        read_u32(self.table.data(), 4) & PCAT_COMPAT != 0
+    }
+
+    /// Returns an iterator over the records of the MADT.
+    #[must_use]
+    pub fn records(&self) -> Records<'a> {
+        Records {
+            bytes: &self.table.data()[MADT_FIXED_SIZE..],
+            truncated: false,
+        }
+    }
+
+    /// Returns the set of the local APIC IDs of the processors that are enabled or can be
+    /// enabled. The processors with an x2APIC ID greater than 255 are ignored: the processors
+    /// started with [`crate::smp`] must be found in the records, and registered with
+    /// [`crate::smp::register_cpu`].
+    #[must_use]
+    pub fn cpus(&self) -> CpuSet {
+        let mut cpus = CpuSet::new();
+        for record in self.records() {
+            let (apic_id, flags) = match record {
+                Record::LocalApic { apic_id, flags, .. } => (u32::from(apic_id), flags),
+                Record::LocalX2Apic { apic_id, flags, .. } => (apic_id, flags),
+                _ => continue,
+            };
+            if flags & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0 {
+                if let Ok(apic_id) = u8::try_from(apic_id) {
+                    cpus.insert(apic_id);
+                }
+            }
+        }
+        cpus
+    }
+
+    /// Returns the interrupt source override of the given ISA IRQ, or `None` if the IRQ is
+    /// identity-mapped to the GSI with the same number.
+    #[must_use]
+    pub fn isa_override(&self, irq: u8) -> Option<InterruptOverride> {
+        self.records().find_map(|record| match record {
+            Record::InterruptOverride(entry) if entry.bus == 0 && entry.source == irq => {
+                Some(entry)
+            }
+            _ => None,
+        })
+    }
+}
+
+/// An interrupt source override, giving the GSI and the configuration of an ISA IRQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    /// The bus of the interrupt, always 0 (ISA).
+    pub bus: u8,
+
+    /// The ISA IRQ.
+    pub source: u8,
+
+    /// The GSI delivering the interrupt.
+    pub gsi: u32,
+
+    /// The MPS INTI flags, giving the polarity and the trigger mode.
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// Returns the polarity of the interrupt. The ISA interrupts are active high, unless stated
+    /// otherwise.
+    #[must_use]
+    pub const fn polarity(&self) -> Polarity {
+        match self.flags & 0b11 {
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        }
+    }
+
+    /// Returns the trigger mode of the interrupt. The ISA interrupts are edge-triggered, unless
+    /// stated otherwise.
+    #[must_use]
+    pub const fn trigger_mode(&self) -> TriggerMode {
+        match (self.flags >> 2) & 0b11 {
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        }
+    }
+}
+
+/// A record of the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record {
+    /// A processor and its local APIC.
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+
+    /// An I/O APIC, with the first GSI it handles.
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+
+    /// An interrupt source override.
+    InterruptOverride(InterruptOverride),
+
+    /// A local APIC LINT input connected to the NMI. The processor ID 0xFF means all the
+    /// processors.
+    LocalApicNmi {
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+
+    /// The 64 bits address of the local APICs, replacing the 32 bits address of the MADT.
+    LocalApicAddressOverride { address: u64 },
+
+    /// A processor and its local x2APIC, for the APIC IDs greater than 254.
+    LocalX2Apic { apic_id: u32, flags: u32, uid: u32 },
+
+    /// A record of another type, with its type and its data (without its type and length).
+    Other { kind: u8, data: [u8; 14], len: u8 },
+}
+
+/// An iterator over the records of the MADT.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+    truncated: bool,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let [kind, length, ..] = *self.bytes else {
+            self.truncated = !self.bytes.is_empty();
+            return None;
+        };
+        let length = usize::from(length);
+        if length < 2 || length > self.bytes.len() {
+            self.truncated = true;
+            self.bytes = &[];
+            return None;
+        }
+
+        let (record, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(Record::decode(kind, record))
+    }
+}
+
+impl Record {
+    /// Decode a record from its type and its bytes (including the type and the length).
+    #[allow(clippy::cast_possible_truncation)]
+    fn decode(kind: u8, bytes: &[u8]) -> Self {
+        let len = bytes.len();
+        match kind {
+            0 if len >= 8 => Record::LocalApic {
+                processor_id: bytes[2],
+                apic_id: bytes[3],
+                flags: read_u32(bytes, 4),
+            },
+            1 if len >= 12 => Record::IoApic {
+                id: bytes[2],
+                address: read_u32(bytes, 4),
+                gsi_base: read_u32(bytes, 8),
+            },
+            2 if len >= 10 => Record::InterruptOverride(InterruptOverride {
+                bus: bytes[2],
+                source: bytes[3],
+                gsi: read_u32(bytes, 4),
+                flags: read_u16(bytes, 8),
+            }),
+            4 if len >= 6 => Record::LocalApicNmi {
+                processor_id: bytes[2],
+                flags: read_u16(bytes, 3),
+                lint: bytes[5],
+            },
+            5 if len >= 12 => Record::LocalApicAddressOverride {
+                address: read_u64(bytes, 4),
+            },
+            9 if len >= 16 => Record::LocalX2Apic {
+                apic_id: read_u32(bytes, 4),
+                flags: read_u32(bytes, 8),
+                uid: read_u32(bytes, 12),
+            },
+            _ => {
+                let mut data = [0; 14];
+                let size = (len - 2).min(data.len());
+                data[..size].copy_from_slice(&bytes[2..2 + size]);
+                Record::Other {
+                    kind,
+                    data,
+                    len: size as u8,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Madt, Record, Table, HEADER_SIZE};
+    use crate::{address::Physical, ioapic::TriggerMode};
+
+    /// Build a MADT with the given records, with a valid length and checksum.
+    fn build_madt(records: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(b"APIC");
+        bytes.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        for record in records {
+            bytes.extend_from_slice(record);
+        }
+
+        let length = u32::try_from(bytes.len()).unwrap();
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes[9] = sum.wrapping_neg();
+        bytes
+    }
+
+    #[test]
+    fn madt_parsing() {
+        let bytes = build_madt(&[
+            &[0, 8, 0, 0, 1, 0, 0, 0],
+            &[0, 8, 1, 2, 0, 0, 0, 0],
+            &[1, 12, 3, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0],
+            &[2, 10, 0, 9, 9, 0, 0, 0, 0x0D, 0],
+        ]);
+        let madt = Madt::new(Table::parse(&bytes).unwrap()).unwrap();
+        assert_eq!(madt.local_apic_address(), Ok(Physical::new(0xFEE0_0000)));
+        assert!(madt.has_legacy_pics());
+        assert_eq!(madt.cpus().count(), 1);
+        assert_eq!(madt.records().count(), 4);
+
+        let ioapic = Record::IoApic {
+            id: 3,
+            address: 0xFEC0_0000,
+            gsi_base: 0,
+        };
+        assert_eq!(madt.records().nth(2), Some(ioapic));
+
+        let sci = madt.isa_override(9).unwrap();
+        assert_eq!(sci.gsi, 9);
+        assert_eq!(sci.trigger_mode(), TriggerMode::Level);
+        assert!(madt.isa_override(0).is_none());
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_SIZE + 9] ^= 1;
+        assert_eq!(Table::parse(&corrupted).err(), Some(Error::InvalidChecksum));
+
+        let invalid = build_madt(&[&[5, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10]]);
+        let madt = Madt::new(Table::parse(&invalid).unwrap()).unwrap();
+        assert_eq!(madt.local_apic_address(), Err(Error::InvalidAddress));
+
+        let truncated = build_madt(&[&[0, 9, 0, 0, 1, 0, 0, 0]]);
+        let table = Table::parse(&truncated).unwrap();
+        assert_eq!(Madt::new(table).err(), Some(Error::InvalidLength));
+    }
+}
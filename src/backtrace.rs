@@ -0,0 +1,179 @@
+//! A backtrace walker following the chain of saved frame pointers. Each function compiled with
+//! frame pointers starts by pushing RBP and copying RSP to RBP, so RBP points to the saved RBP of
+//! the caller, followed by the return address into the caller. The kernel (and this crate) must
+//! be compiled with `-C force-frame-pointers=yes` for the backtraces to be complete.
+//!
+//! The chain is validated before each read, so that a corrupted stack ends the backtrace instead
+//! of causing a page fault: the frame pointers must be canonical, aligned on 8 bytes, strictly
+//! increasing and, if the bounds of the stack are known, inside the stack.
+use core::arch::asm;
+
+use crate::{
+    address::{Virtual, VirtualRange},
+    cpu::State,
+};
+
+/// The maximal number of frames reported by a backtrace, to stop on a looping chain.
+pub const MAX_DEPTH: usize = 64;
+
+/// The maximal distance between two consecutive frame pointers when the bounds of the stack are
+/// not known. A larger distance is considered as a corrupted frame pointer.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// A frame of a backtrace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The frame pointer (RBP) of the frame, or 0 for the frame of the interrupted instruction
+    /// reported by [`walk_state`].
+    pub frame_pointer: u64,
+
+    /// The address at which the execution continues in this frame.
+    pub return_address: u64,
+}
+
+/// Walk the frame pointer chain starting at the given RBP value, calling the given function for
+/// each frame, from the innermost to the outermost. The walk stops at a null frame pointer or
+/// return address, at an invalid frame pointer, or after [`MAX_DEPTH`] frames. Returns the number
+/// of frames reported.
+///
+/// Since the bounds of the stack are not known, two consecutive frames must not be farther than
+/// 1 MiB from each other and must be in the same half of the address space: [`walk_within`]
+/// should be preferred when the bounds are known.
+///
+/// # Safety
+/// This function is unsafe because the memory pointed to by the frame pointers is read: if the
+/// stack is corrupted, a frame pointer passing the validation could point to unmapped memory.
+pub unsafe fn walk<F: FnMut(Frame)>(rbp: u64, f: F) -> usize {
+    walk_inner(rbp, None, f)
+}
+
+/// Walk the frame pointer chain starting at the given RBP value, like [`walk`], but stop as soon
+/// as a frame is not inside the given stack.
+///
+/// # Safety
+/// This function is unsafe because the given stack must be mapped and readable.
+pub unsafe fn walk_within<F: FnMut(Frame)>(rbp: u64, stack: VirtualRange, f: F) -> usize {
+    walk_inner(rbp, Some((stack.start().as_u64(), stack.end().as_u64())), f)
+}
+
+/// Walk the stack of the given interrupted state: the instruction pointer of the state is
+/// reported first, followed by the frames of the frame pointer chain starting at the saved RBP
+/// (see [`walk`]). Returns the number of frames reported.
+///
+/// # Safety
+/// This function is unsafe for the same reasons as [`walk`]. The stack of the state must also be
+/// mapped in the current address space, which is not the case for the user stacks of other
+/// processes.
+pub unsafe fn walk_state<F: FnMut(Frame)>(state: &State, mut f: F) -> usize {
+    f(Frame {
+        frame_pointer: 0,
+        return_address: state.rip,
+    });
+    1 + walk_inner(state.rbp, None, f)
+}
+
+/// Walk the stack of the caller, starting at the current frame pointer (see [`walk`]).
+///
+/// # Safety
+/// This function is unsafe for the same reasons as [`walk`].
+#[inline(never)]
+pub unsafe fn walk_current<F: FnMut(Frame)>(f: F) -> usize {
+    let rbp: u64;
+    asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    walk_inner(rbp, None, f)
+}
+
+/// Implementation of the walkers. The bounds are the start and the end of the stack, if known.
+unsafe fn walk_inner<F: FnMut(Frame)>(mut rbp: u64, bounds: Option<(u64, u64)>, mut f: F) -> usize {
+    let mut depth = 0;
+    let mut previous: Option<u64> = None;
+
+    while depth < MAX_DEPTH && valid(rbp, previous, bounds) {
+        let frame = rbp as *const u64;
+        let return_address = frame.add(1).read();
+        if return_address == 0 || !Virtual::is_canonical(return_address) {
+            break;
+        }
+
+        f(Frame {
+            frame_pointer: rbp,
+            return_address,
+        });
+        depth += 1;
+        previous = Some(rbp);
+        rbp = frame.read();
+    }
+    depth
+}
+
+/// Check that the given frame pointer can be followed: it must be canonical, aligned and above the
+/// previous one, and its saved RBP and return address must be inside the stack.
+fn valid(rbp: u64, previous: Option<u64>, bounds: Option<(u64, u64)>) -> bool {
+    if rbp == 0 || rbp & 7 != 0 || !Virtual::is_canonical(rbp) {
+        return false;
+    }
+    let Some(end) = rbp.checked_add(16) else {
+        return false;
+    };
+    if !Virtual::is_canonical(end - 1) {
+        return false;
+    }
+
+    match (bounds, previous) {
+        (Some((start, stop)), _) if rbp < start || end > stop => false,
+        (_, Some(previous)) if rbp <= previous => false,
+        (None, Some(previous)) => {
+            rbp - previous <= MAX_FRAME_SIZE && (rbp >> 63) == (previous >> 63)
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Frame;
+    use crate::address::{Virtual, VirtualRange};
+
+    #[test]
+    fn frame_chain_walk() {
+        // Three frames, whose saved RBP points to the next frame, the last one ending the chain
+        let mut stack = [0u64; 8];
+        let base = stack.as_ptr() as u64;
+        stack[0] = base + 16;
+        stack[1] = 0x1000;
+        stack[2] = base + 40;
+        stack[3] = 0x2000;
+        stack[6] = 0x3000;
+
+        let mut frames = [Frame {
+            frame_pointer: 0,
+            return_address: 0,
+        }; 4];
+        let mut count = 0;
+        let depth = unsafe {
+            super::walk(stack.as_ptr() as u64, |frame| {
+                frames[count] = frame;
+                count += 1;
+            })
+        };
+        assert_eq!(depth, 3);
+        assert_eq!(frames[0].return_address, 0x1000);
+        assert_eq!(frames[1].frame_pointer, base + 16);
+        assert_eq!(frames[2].return_address, 0x3000);
+
+        // The third frame is outside of the given stack bounds
+        let bounds = VirtualRange::new(Virtual::new(base), Virtual::new(base + 40));
+        assert_eq!(unsafe { super::walk_within(base, bounds, |_| {}) }, 2);
+    }
+
+    #[test]
+    fn frame_pointer_validation() {
+        assert!(!super::valid(0, None, None));
+        assert!(!super::valid(0x1004, None, None));
+        assert!(!super::valid(0x0000_8000_0000_0000, None, None));
+        assert!(!super::valid(0x1000, Some(0x2000), None));
+        assert!(!super::valid(0x1000_0000, Some(0x1000), None));
+        assert!(super::valid(0x2000, Some(0x1000), None));
+        assert!(!super::valid(0x2000, None, Some((0x1000, 0x2008))));
+    }
+}
@@ -0,0 +1,127 @@
+//! Profiling of the early boot. [`mark`] records the TSC at a named point of the boot in a fixed
+//! static array, so it can be used before any allocator or clock is available, for example
+//! around the GDT, IDT and paging setup. Once the TSC is calibrated (see
+//! [`crate::tsc::calibrate`]), [`report`] prints the time elapsed between the marks, so that the
+//! regressions of the boot latency are visible.
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// The maximal number of marks. The marks recorded after this limit are dropped.
+pub const MAX_MARKS: usize = 64;
+
+/// The names of the marks, stored as the address and the length of a static string.
+static NAMES: [(AtomicUsize, AtomicUsize); MAX_MARKS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: (AtomicUsize, AtomicUsize) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    [EMPTY; MAX_MARKS]
+};
+
+/// The TSC value of the marks, or 0 if the mark is not recorded yet.
+static TIMESTAMPS: [AtomicU64; MAX_MARKS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_MARKS]
+};
+
+/// The number of slots taken by [`mark`], including the ones dropped after [`MAX_MARKS`].
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A recorded mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pub name: &'static str,
+
+    /// The value of the TSC when the mark was recorded.
+    pub tsc: u64,
+}
+
+/// Record a mark with the given name and the current value of the TSC. This can be called from
+/// any CPU, but the marks are only meaningful if the TSCs of the CPUs are synchronized.
+pub fn mark(name: &'static str) {
+    let tsc = crate::tsc::read();
+    let slot = COUNT.fetch_add(1, Ordering::Relaxed);
+    if let Some(timestamp) = TIMESTAMPS.get(slot) {
+        NAMES[slot]
+            .0
+            .store(name.as_ptr() as usize, Ordering::Relaxed);
+        NAMES[slot].1.store(name.len(), Ordering::Relaxed);
+        timestamp.store(tsc.max(1), Ordering::Release);
+    }
+}
+
+/// Returns an iterator over the recorded marks, in the order of their recording.
+pub fn marks() -> impl Iterator<Item = Mark> {
+    let count = COUNT.load(Ordering::Relaxed).min(MAX_MARKS);
+    (0..count).filter_map(|slot| {
+        let tsc = TIMESTAMPS[slot].load(Ordering::Acquire);
+        if tsc == 0 {
+            return None;
+        }
+
+        let address = NAMES[slot].0.load(Ordering::Relaxed) as *const u8;
+        let len = NAMES[slot].1.load(Ordering::Relaxed);
+        // SAFETY: The name was a static string, published before the timestamp
+        let name =
+            unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(address, len)) };
+        Some(Mark { name, tsc })
+    })
+}
+
+/// Returns the number of marks dropped because the array was full.
+#[must_use]
+pub fn dropped() -> usize {
+    COUNT.load(Ordering::Relaxed).saturating_sub(MAX_MARKS)
+}
+
+/// Print the recorded marks to the given writer, one per line, with the time elapsed since the
+/// previous mark and since the first one. The times are in microseconds if the TSC frequency is
+/// known (see [`crate::tsc::clock`]), and in TSC cycles otherwise.
+///
+/// # Errors
+/// Returns an error if the writer fails.
+pub fn report(writer: &mut impl fmt::Write) -> fmt::Result {
+    let clock = crate::tsc::clock();
+    let unit = if clock.is_some() { "us" } else { "cycles" };
+    let convert = |cycles: u64| clock.map_or(cycles, |clock| clock.cycles_to_ns(cycles) / 1000);
+
+    let mut first = None;
+    let mut previous = None;
+    for mark in marks() {
+        let first = *first.get_or_insert(mark.tsc);
+        let delta = mark.tsc.saturating_sub(previous.unwrap_or(mark.tsc));
+        let total = mark.tsc.saturating_sub(first);
+        writeln!(
+            writer,
+            "{:>10} {unit} {:>10} {unit}  {}",
+            convert(delta),
+            convert(total),
+            mark.name
+        )?;
+        previous = Some(mark.tsc);
+    }
+
+    match dropped() {
+        0 => Ok(()),
+        dropped => writeln!(writer, "{dropped} marks dropped"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn marks_and_report() {
+        super::mark("gdt");
+        super::mark("idt");
+        let names: Vec<_> = super::marks().map(|mark| mark.name).collect();
+        assert_eq!(names, ["gdt", "idt"]);
+
+        let mut output = String::new();
+        super::report(&mut output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("         0 "));
+        assert!(lines[1].ends_with("  idt"));
+    }
+}
@@ -0,0 +1,292 @@
+//! A monotonic clock, giving the time elapsed since its initialization in nanoseconds. The
+//! hardware source of the clock is selected once at initialization, so that the timekeeping code
+//! of the kernel does not depend on the available hardware:
+//! - the TSC, if it is invariant: it is the most precise and the cheapest to read.
+//! - an external counter, such as the HPET main counter, read with a function given by its driver.
+//! - the PIT as a last resort, whose IRQ 0 handler must call [`pit_tick`].
+//!
+//! This module also defines the [`ClockEvent`] trait, implemented by the timers that can fire a
+//! single interrupt after a given delay, for a tickless scheduler.
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    cpu::msr,
+    lapic::{LocalApic, LvtEntry, TicksPerMs, TimerMode},
+    pit::{self, Pit},
+    tsc::{self, TscClock},
+};
+
+/// The backend of the clock, encoded in [`BACKEND`].
+const NONE: u8 = 0;
+const TSC: u8 = 1;
+const COUNTER: u8 = 2;
+const PIT: u8 = 3;
+
+/// The selected backend.
+static BACKEND: AtomicU8 = AtomicU8::new(NONE);
+
+/// The frequency of the TSC, of the external counter, or of the PIT interrupts, in Hz.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The value of the counter when the clock was initialized.
+static ORIGIN: AtomicU64 = AtomicU64::new(0);
+
+/// The function reading the external counter.
+static COUNTER_READ: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of PIT interrupts since the clock was initialized.
+static PIT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The last time returned with the PIT backend, to keep the clock monotonic.
+static PIT_LAST: AtomicU64 = AtomicU64::new(0);
+
+/// An external free-running counter, such as the HPET main counter.
+#[derive(Debug, Clone, Copy)]
+pub struct Counter {
+    /// A function returning the current value of the counter.
+    pub read: fn() -> u64,
+
+    /// The frequency of the counter, in Hz.
+    pub hz: u64,
+}
+
+/// A hardware source for the clock.
+#[derive(Debug, Clone, Copy)]
+pub enum Backend {
+    /// The time stamp counter, with its calibrated frequency. It must be invariant and
+    /// synchronized between the CPUs.
+    Tsc(TscClock),
+
+    /// An external counter.
+    Counter(Counter),
+
+    /// The PIT, configured with the given frequency in periodic mode (see [`Pit::setup`]).
+    Pit(u64),
+}
+
+impl Backend {
+    /// Select the best available backend: the TSC if it is calibrated and invariant, then the
+    /// given external counter, then the given PIT.
+    #[must_use]
+    pub fn select(tsc: Option<TscClock>, counter: Option<Counter>, pit: &Pit) -> Self {
+        match (tsc, counter) {
+            (Some(clock), _) if tsc::is_invariant() => Backend::Tsc(clock),
+            (_, Some(counter)) => Backend::Counter(counter),
+            _ => Backend::Pit(pit.get_frequency()),
+        }
+    }
+}
+
+/// Initialize the clock with the given backend. The clock starts at 0.
+///
+/// # Panics
+/// Panics if the frequency of the backend is 0.
+///
+/// # Safety
+/// This function is unsafe because it must be called once, before any call to [`now`] on the
+/// other CPUs. With the PIT backend, the IRQ 0 handler must call [`pit_tick`].
+pub unsafe fn init(backend: Backend) {
+    let (kind, frequency, origin) = match backend {
+        Backend::Tsc(clock) => (TSC, clock.hz(), tsc::read()),
+        Backend::Counter(counter) => {
+            COUNTER_READ.store(counter.read as usize, Ordering::Relaxed);
+            (COUNTER, counter.hz, (counter.read)())
+        }
+        Backend::Pit(frequency) => {
+            PIT_TICKS.store(0, Ordering::Relaxed);
+            PIT_LAST.store(0, Ordering::Relaxed);
+            (PIT, frequency, 0)
+        }
+    };
+
+    assert!(frequency != 0, "Clock frequency cannot be 0");
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    ORIGIN.store(origin, Ordering::Relaxed);
+    BACKEND.store(kind, Ordering::Release);
+}
+
+/// Returns the number of nanoseconds elapsed since the clock was initialized, or 0 if it is not
+/// initialized yet.
+#[must_use]
+pub fn now() -> u64 {
+    // The backend must be loaded first: its acquire load synchronizes with the release store of
+    // `init`, so that the frequency cannot be read before it is initialized
+    let backend = BACKEND.load(Ordering::Acquire);
+    let frequency = FREQUENCY.load(Ordering::Relaxed);
+    match backend {
+        TSC => {
+            let cycles = tsc::read().wrapping_sub(ORIGIN.load(Ordering::Relaxed));
+            TscClock::new(frequency).cycles_to_ns(cycles)
+        }
+        COUNTER => {
+            // SAFETY: The function pointer was stored by `init` before the backend was published
+            let read: fn() -> u64 =
+                unsafe { core::mem::transmute(COUNTER_READ.load(Ordering::Relaxed)) };
+            let ticks = read().wrapping_sub(ORIGIN.load(Ordering::Relaxed));
+            TscClock::new(frequency).cycles_to_ns(ticks)
+        }
+        PIT => {
+            let period = 1_000_000_000 / frequency;
+            let ticks = PIT_TICKS.load(Ordering::Relaxed);
+            let offset = Pit::new(frequency).nano_offset().min(period);
+            let ns = ticks * period + offset;
+            PIT_LAST.fetch_max(ns, Ordering::Relaxed).max(ns)
+        }
+        _ => 0,
+    }
+}
+
+/// Signal a PIT interrupt to the clock. This must be called by the IRQ 0 handler when the clock
+/// uses the PIT backend, and does nothing otherwise.
+pub fn pit_tick() {
+    if BACKEND.load(Ordering::Relaxed) == PIT {
+        PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A timer that can fire a single interrupt after a given delay. The interrupt and its vector are
+/// configured when the timer is created, so the scheduler can program the next event without
+/// knowing which hardware timer is used. An HPET comparator driver can also implement this trait.
+pub trait ClockEvent {
+    /// Program the timer to fire a single interrupt after at least the given number of
+    /// nanoseconds, replacing any pending event. The delay is clamped to
+    /// [`ClockEvent::max_delta`], so the caller must reprogram the timer if the interrupt fires
+    /// before the wanted time.
+    ///
+    /// # Safety
+    /// This function is unsafe because it reprograms the hardware timer, and the interrupt
+    /// handler of the timer must be installed.
+    unsafe fn set_oneshot_after(&self, ns: u64);
+
+    /// Cancel the pending event, if any.
+    ///
+    /// # Safety
+    /// This function is unsafe because it reprograms the hardware timer.
+    unsafe fn stop(&self);
+
+    /// Returns the longest delay that can be programmed, in nanoseconds.
+    fn max_delta(&self) -> u64;
+}
+
+/// The local APIC timer, in one-shot mode.
+#[derive(Debug)]
+pub struct LapicTimer<'a> {
+    lapic: &'a LocalApic,
+    frequency: TicksPerMs,
+    vector: u8,
+}
+
+impl<'a> LapicTimer<'a> {
+    /// Creates a clock event device using the local APIC timer, with the given frequency (see
+    /// [`LocalApic::calibrate_timer`]) and the given interrupt vector. The divider of the timer
+    /// must not change after the calibration.
+    ///
+    /// # Panics
+    /// Panics if the frequency is 0.
+    #[must_use]
+    pub const fn new(lapic: &'a LocalApic, frequency: TicksPerMs, vector: u8) -> Self {
+        assert!(frequency.get() != 0, "Timer frequency cannot be 0");
+        Self {
+            lapic,
+            frequency,
+            vector,
+        }
+    }
+}
+
+impl ClockEvent for LapicTimer<'_> {
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe fn set_oneshot_after(&self, ns: u64) {
+        let ticks = (u128::from(ns) * u128::from(self.frequency.get())).div_ceil(1_000_000);
+        let count = ticks.clamp(1, u128::from(u32::MAX)) as u32;
+
+        self.lapic.set_lvt_timer(
+            LvtEntry::new(self.vector)
+                .set_timer_mode(TimerMode::OneShot)
+                .build(),
+        );
+        self.lapic.set_timer_initial_count(count);
+    }
+
+    unsafe fn stop(&self) {
+        self.lapic.set_timer_initial_count(0);
+    }
+
+    fn max_delta(&self) -> u64 {
+        u64::from(u32::MAX) * 1_000_000 / self.frequency.get()
+    }
+}
+
+/// The local APIC timer, in TSC deadline mode. The interrupt fires when the TSC reaches the
+/// programmed deadline, which is more precise than the one-shot mode and does not depend on the
+/// divider of the timer.
+#[derive(Debug)]
+pub struct TscDeadlineTimer<'a> {
+    lapic: &'a LocalApic,
+    clock: TscClock,
+    vector: u8,
+}
+
+impl<'a> TscDeadlineTimer<'a> {
+    /// Creates a clock event device using the local APIC timer in TSC deadline mode, with the
+    /// given TSC frequency (see [`tsc::calibrate`]) and the given interrupt vector.
+    ///
+    /// # Panics
+    /// Panics if the TSC deadline mode is not supported by the current CPU (see
+    /// [`crate::lapic::tsc_deadline_supported`]).
+    #[must_use]
+    pub fn new(lapic: &'a LocalApic, clock: TscClock, vector: u8) -> Self {
+        assert!(
+            crate::lapic::tsc_deadline_supported(),
+            "TSC deadline mode is not supported"
+        );
+        Self {
+            lapic,
+            clock,
+            vector,
+        }
+    }
+}
+
+impl ClockEvent for TscDeadlineTimer<'_> {
+    unsafe fn set_oneshot_after(&self, ns: u64) {
+        let cycles = self.clock.ns_to_cycles(ns.min(self.max_delta())).max(1);
+        self.lapic.set_lvt_timer(
+            LvtEntry::new(self.vector)
+                .set_timer_mode(TimerMode::TscDeadline)
+                .build(),
+        );
+
+        // The write to the LVT timer register (in xAPIC mode, a memory write) must be visible
+        // before the deadline is armed, otherwise the deadline could be ignored because the
+        // timer is not yet in TSC deadline mode
+        core::arch::x86_64::_mm_mfence();
+        msr::tsc_deadline::write(tsc::read().saturating_add(cycles));
+    }
+
+    unsafe fn stop(&self) {
+        msr::tsc_deadline::write(0);
+    }
+
+    fn max_delta(&self) -> u64 {
+        // Limit the delay so that the deadline cannot wrap around
+        self.clock.cycles_to_ns(u64::MAX / 2)
+    }
+}
+
+/// The channel 0 of the PIT, in mode 0 (interrupt on terminal count). The interrupt is the IRQ 0,
+/// whose vector depends on the interrupt controller.
+impl ClockEvent for Pit {
+    unsafe fn set_oneshot_after(&self, ns: u64) {
+        // The delay is rounded to the PIT ticks, which is precise enough for a clock event
+        let _ = self.one_shot_ns(ns.min(self.max_delta()));
+    }
+
+    unsafe fn stop(&self) {
+        Pit::stop(self);
+    }
+
+    fn max_delta(&self) -> u64 {
+        pit::MAX_COUNT * 1_000_000_000 / pit::PIT_FREQ
+    }
+}
@@ -0,0 +1,118 @@
+//! Conversions between the types of this crate and the corresponding types of the
+//! [`x86_64`](https://docs.rs/x86_64) crate, so that drivers written against that crate can be
+//! ported incrementally. The conversions are lossless: both crates enforce the same invariants
+//! on addresses, and the page table flags have the same bits.
+use x86_64::{
+    registers::segmentation::SegmentSelector, structures::paging::PageTableFlags, PhysAddr,
+    PrivilegeLevel, VirtAddr,
+};
+
+use crate::{
+    address::{Physical, Virtual},
+    cpu::Privilege,
+    paging::PageEntryFlags,
+    segment::Selector,
+};
+
+impl From<Virtual> for VirtAddr {
+    fn from(address: Virtual) -> Self {
+        VirtAddr::new_truncate(address.as_u64())
+    }
+}
+
+impl From<VirtAddr> for Virtual {
+    fn from(address: VirtAddr) -> Self {
+        // SAFETY: A `VirtAddr` is always canonical
+        unsafe { Virtual::new_unchecked(address.as_u64()) }
+    }
+}
+
+impl From<Physical> for PhysAddr {
+    fn from(address: Physical) -> Self {
+        PhysAddr::new_truncate(address.as_u64())
+    }
+}
+
+impl From<PhysAddr> for Physical {
+    fn from(address: PhysAddr) -> Self {
+        // SAFETY: A `PhysAddr` never has bits set above the bit 51
+        unsafe { Physical::new_unchecked(address.as_u64()) }
+    }
+}
+
+impl From<PageEntryFlags> for PageTableFlags {
+    fn from(flags: PageEntryFlags) -> Self {
+        PageTableFlags::from_bits_truncate(flags.bits())
+    }
+}
+
+impl From<PageTableFlags> for PageEntryFlags {
+    fn from(flags: PageTableFlags) -> Self {
+        PageEntryFlags::from_bits_truncate(flags.bits())
+    }
+}
+
+impl From<Privilege> for PrivilegeLevel {
+    fn from(privilege: Privilege) -> Self {
+        PrivilegeLevel::from_u16(privilege as u16)
+    }
+}
+
+impl From<PrivilegeLevel> for Privilege {
+    fn from(level: PrivilegeLevel) -> Self {
+        match level {
+            PrivilegeLevel::Ring0 => Privilege::Ring0,
+            PrivilegeLevel::Ring1 => Privilege::Ring1,
+            PrivilegeLevel::Ring2 => Privilege::Ring2,
+            PrivilegeLevel::Ring3 => Privilege::Ring3,
+        }
+    }
+}
+
+impl From<Selector> for SegmentSelector {
+    fn from(selector: Selector) -> Self {
+        SegmentSelector(selector.value())
+    }
+}
+
+impl From<SegmentSelector> for Selector {
+    fn from(selector: SegmentSelector) -> Self {
+        Selector::new(selector.index(), selector.rpl().into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use x86_64::{
+        registers::segmentation::SegmentSelector, structures::paging::PageTableFlags, PhysAddr,
+        VirtAddr,
+    };
+
+    use crate::{
+        address::{Physical, Virtual},
+        paging::PageEntryFlags,
+        segment::Selector,
+    };
+
+    #[test]
+    fn conversions() {
+        let virt = Virtual::new(0xFFFF_8000_0000_1000);
+        assert_eq!(VirtAddr::from(virt).as_u64(), virt.as_u64());
+        assert_eq!(Virtual::from(VirtAddr::from(virt)), virt);
+
+        let phys = Physical::new(0x1234_5000);
+        assert_eq!(Physical::from(PhysAddr::from(phys)), phys);
+
+        let flags = PageEntryFlags::PRESENT | PageEntryFlags::WRITABLE | PageEntryFlags::NO_EXECUTE;
+        let converted = PageTableFlags::from(flags);
+        assert!(converted.contains(PageTableFlags::NO_EXECUTE | PageTableFlags::WRITABLE));
+        assert_eq!(PageEntryFlags::from(converted), flags);
+
+        let selector = SegmentSelector::from(Selector::USER_DATA);
+        assert_eq!(selector.0, Selector::USER_DATA.value());
+        assert_eq!(
+            Selector::from(selector).value(),
+            Selector::USER_DATA.value()
+        );
+    }
+}
@@ -1,4 +1,10 @@
-use core::arch::asm;
+use core::{arch::asm, fmt};
+
+use crate::{
+    address::{Physical, Virtual},
+    paging::PageFaultErrorCode,
+    segment::{Selector, SelectorErrorCode},
+};
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -67,6 +73,109 @@ impl Default for State {
     }
 }
 
+impl State {
+    /// Returns `true` if the CPU pushed an error code on the stack for the interrupt number saved
+    /// in this state.
+    #[must_use]
+    pub const fn has_error_code(&self) -> bool {
+        matches!(self.number, 8 | 10..=14 | 17 | 21 | 29 | 30)
+    }
+
+    /// Returns `true` if this state was saved while running user code, i.e. if the requested
+    /// privilege level of the saved code segment is 3.
+    #[must_use]
+    pub const fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
+    /// Skip the instruction at the saved instruction pointer, which is `len` bytes long. This is
+    /// used when a faulting instruction is emulated by the handler (for example an invalid opcode
+    /// exception): the execution continues with the next instruction. The resume flag is cleared,
+    /// so that an instruction breakpoint on the next instruction is not ignored.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the length is not between 1 and 15 bytes (the maximum length of
+    /// an x86 instruction), or if the state is not consistent (see [`State::emulate_iret_to`]).
+    pub fn skip_instruction(&mut self, len: u64) {
+        debug_assert!((1..=15).contains(&len), "Invalid instruction length {len}");
+        self.emulate_iret_to(Virtual::new_truncate(self.rip.wrapping_add(len)));
+    }
+
+    /// Set the value returned to the interrupted code in the RAX register, for example the
+    /// result of a system call or of an emulated instruction.
+    pub fn set_return_value(&mut self, value: u64) {
+        self.rax = value;
+    }
+
+    /// Resume the execution of the interrupted code at the given address, with the same code and
+    /// stack segments, as if the `iretq` instruction had returned there. This is used to restart
+    /// a system call (by returning to the `syscall` instruction) or to redirect the execution to
+    /// a fixup or a signal handler. The resume flag is cleared.
+    ///
+    /// # Panics
+    /// In debug builds, panics if the state is not consistent: the code and the stack segments
+    /// must have the same privilege level, the reserved bit 1 of RFLAGS must be set, and a user
+    /// state must not return to a kernel address.
+    pub fn emulate_iret_to(&mut self, address: Virtual) {
+        debug_assert_eq!(self.cs & 3, self.ss & 3, "CS and SS privilege levels mismatch");
+        debug_assert!(
+            rflags::Flags::from_bits_truncate(self.rflags).contains(rflags::Flags::FIXED),
+            "Reserved bit 1 of RFLAGS is cleared"
+        );
+        debug_assert!(
+            !self.from_user() || address.is_user(),
+            "User state returning to kernel address {address:?}"
+        );
+
+        self.rip = address.as_u64();
+        self.rflags &= !rflags::Flags::RF.bits();
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        writeln!(
+            f,
+            "RIP={:016x} CS ={:016x} RFL={:016x} SS ={:016x}",
+            self.rip, self.cs, self.rflags, self.ss
+        )?;
+        write!(f, "INT={:016x} ERR={:016x}", self.number, self.code)?;
+
+        // Decode the error code, if the interrupt has one
+        if self.number == 14 {
+            write!(
+                f,
+                " {:?}",
+                PageFaultErrorCode::from_bits_truncate(self.code)
+            )?;
+        } else if self.has_error_code() && self.code != 0 {
+            write!(f, " ({})", SelectorErrorCode::new(self.code))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Privilege {
     Ring0 = 0,
     Ring1 = 1,
@@ -79,6 +188,41 @@ impl Privilege {
     pub const USER: Self = Self::Ring3;
 }
 
+/// Set the I/O privilege level (IOPL) of the given state, loaded in RFLAGS when returning to
+/// it. Code running in a ring numerically lower or equal to the IOPL can use the `in`, `out`,
+/// `ins` and `outs` instructions on all the ports, which allows a selected user driver to access
+/// its device directly, with an IOPL of [`Privilege::USER`].
+///
+/// Such code can also execute the `cli` and `sti` instructions, so a user driver with this
+/// privilege can prevent the preemption: it must be trusted. Otherwise, the processor checks
+/// the I/O permission bitmap of the TSS, but the TSS built by this crate has no bitmap (see
+/// [`crate::tss::TaskStateSegment::iomap_base`]), so all the ports are denied and the access is
+/// all or nothing. The IOPL cannot be changed by the user code itself: the `popf` and `iretq`
+/// instructions silently ignore it outside of ring 0.
+///
+/// # Panics
+/// In debug builds, panics if a privilege above [`Privilege::KERNEL`] is given to a kernel
+/// state, where it would have no effect.
+pub fn set_iopl(state: &mut State, privilege: Privilege) {
+    debug_assert!(
+        state.from_user() || privilege == Privilege::KERNEL,
+        "IOPL set on a kernel state"
+    );
+    state.rflags &= !rflags::Flags::IOPL.bits();
+    state.rflags |= (privilege as u64) << 12;
+}
+
+/// Returns the I/O privilege level of the given state (see [`set_iopl`]).
+#[must_use]
+pub const fn iopl(state: &State) -> Privilege {
+    match (state.rflags & rflags::Flags::IOPL.bits()) >> 12 {
+        0 => Privilege::Ring0,
+        1 => Privilege::Ring1,
+        2 => Privilege::Ring2,
+        _ => Privilege::Ring3,
+    }
+}
+
 /// Halts definitely the current CPU.
 ///
 /// # Warning
@@ -166,26 +310,196 @@ pub unsafe fn invlpg(address: u64) {
 }
 
 /// Save the current CPU state into `from` and load the state from `to`.
-/// 
+///
 /// When the saved state is restored, the CPU will return to the instruction after the call to
 /// `switch`, like if it had been a normal function call.
-/// 
+///
 /// # Safety
 /// This function is unsafe because it can cause undefined behavior if the given states are not
 /// properly initialized or saved. This function only touches general purpose registers, the stack
 /// pointer, the instruction pointer and the rflags. It does not touch any segment registers or
 /// any other registers.
 pub unsafe fn switch(from: &mut State, to: &State) {
+    switch_inner(from, to, 0);
+}
+
+/// Save the current CPU state into `from`, switch to the address space whose PML4 table is at the
+/// given physical address and load the state from `to`. The CR3 register is written after the
+/// current state has been saved and before the new state is restored, so there is no window where
+/// the current thread runs with the address space of the next one.
+///
+/// # Safety
+/// This function has the same safety requirements as [`switch`]. Furthermore, the given address
+/// must be the physical address of a valid PML4 table, aligned on a 4KiB boundary, and the `to`
+/// state as well as the code executing this function must be mapped in the new address space
+/// (this is usually the case for kernel memory, which is shared by all address spaces).
+pub unsafe fn switch_with_address_space(from: &mut State, to: &State, new_cr3: Physical) {
+    assert!(!new_cr3.is_null(), "Cannot switch to a null address space");
+    switch_inner(from, to, new_cr3.as_u64());
+}
+
+/// Load the given state into the CPU without saving the current one. The state is restored like
+/// at the end of an interrupt: the `swapgs` instruction is executed if the code segment of the
+/// state is not the kernel code segment, and the `iretq` instruction is used to load the
+/// instruction pointer, the code segment, the flags, the stack pointer and the stack segment.
+///
+/// This is useful to start the very first thread, or to enter user mode for the first time,
+/// without having to forge a dummy state to save with [`switch`].
+///
+/// # Safety
+/// This function is unsafe because it can cause undefined behavior if the given state is not
+/// valid. The memory containing the state is used as a stack during the restore, so it must be
+/// mapped and writable. If the state is a user mode state, the GS register must contain the
+/// kernel GS base before calling this function.
+pub unsafe fn restore_state(state: &State) -> ! {
+    asm!(
+        "cli",              // Avoid being interrupted while the stack is in a weird state
+        "mov rsp, {}",      // Use the state as a stack
+
+        /* Restore preserved registers */
+        "pop rbp",
+        "pop rbx",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        /* Restore scratch registers */
+        "pop rax",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+
+        "add rsp, 24",      // Skip error code, number and fake return address
+
+        /* Swapgs if the state is not a kernel state */
+        "cmp QWORD PTR [rsp + 8], 0x08",
+        "je 2f",
+
+        /* Switch to the user page tables if the page table isolation is enabled */
+        "cmp QWORD PTR gs:[{pti_cr3}], 0",
+        "je 3f",
+        "push rax",
+        "mov rax, cr3",
+        "or rax, gs:[{pti_cr3}]",
+        "mov cr3, rax",
+        "pop rax",
+        "3:",
+
+        /* Clear the CPU buffers if the MDS mitigation is enabled, after the last kernel access */
+        "cmp BYTE PTR [rip + {clear_cpu_buffers}], 0",
+        "je 4f",
+        "verw WORD PTR [rip + {verw_selector}]",
+        "4:",
+        "swapgs",
+        "2:",
+        "iretq",
+        in(reg) state,
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym mitigations::VERW_SELECTOR,
+        options(noreturn)
+    );
+}
+
+/// Enter user mode (ring 3) at the given entry point, with the given stack pointer and flags. An
+/// interrupt frame is built on the current stack with the user code and data segment selectors
+/// ([`Selector::USER_CODE64`] and [`Selector::USER_DATA`]), the `swapgs` instruction is executed
+/// to install the user GS base and the `iretq` instruction jumps to user mode. All general purpose
+/// registers are cleared before entering user mode, to avoid leaking kernel data.
+///
+/// This is the counterpart of the [`crate::syscall::entry`] path, and is typically used to start
+/// the first user process. The reserved bit 1 of the flags is always set, the I/O privilege level
+/// is always cleared, so that the user code cannot access the ports or disable the interrupts
+/// (see [`set_iopl`] to grant it to a trusted user driver), and the interrupt flag should usually
+/// be set in `rflags`, otherwise the user code will run with interrupts disabled.
+///
+/// # Panics
+/// This function panics if the entry point or the stack pointer are not user addresses.
+///
+/// # Safety
+/// This function is unsafe because the entry point and the stack must be mapped in the current
+/// address space with user permissions, the GDT must contain the user segments at the expected
+/// indexes, and the kernel GS must be active (the user GS base is loaded by `swapgs`). The
+/// current kernel stack is never freed by this function: the kernel must set the TSS and the
+/// per-CPU kernel stacks to be able to handle interrupts and system calls from user mode.
+pub unsafe fn enter_usermode(entry: Virtual, stack: Virtual, rflags: u64) -> ! {
+    assert!(entry.is_user(), "User entry point must be a user address");
+    assert!(stack.is_user(), "User stack must be a user address");
+
+    asm!(
+        "cli",              // Avoid being interrupted while switching the GS base
+
+        /* Build the interrupt frame used by iretq */
+        "push {ss}",
+        "push {stack}",
+        "push {rflags}",
+        "push {cs}",
+        "push {entry}",
+
+        /* Switch to the user page tables if the page table isolation is enabled */
+        "cmp QWORD PTR gs:[{pti_cr3}], 0",
+        "je 2f",
+        "mov rax, cr3",
+        "or rax, gs:[{pti_cr3}]",
+        "mov cr3, rax",
+        "2:",
+
+        /* Clear the CPU buffers if the MDS mitigation is enabled, after the last kernel access */
+        "cmp BYTE PTR [rip + {clear_cpu_buffers}], 0",
+        "je 3f",
+        "verw WORD PTR [rip + {verw_selector}]",
+        "3:",
+        "swapgs",
+
+        /* Clear all general purpose registers to avoid leaking kernel data */
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) u64::from(Selector::USER_DATA.value()),
+        stack = in(reg) stack.as_u64(),
+        rflags = in(reg) (rflags & !rflags::Flags::IOPL.bits()) | rflags::Flags::FIXED.bits(),
+        cs = in(reg) u64::from(Selector::USER_CODE64.value()),
+        entry = in(reg) entry.as_u64(),
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym mitigations::VERW_SELECTOR,
+        options(noreturn)
+    );
+}
+
+/// Implementation of [`switch`] and [`switch_with_address_space`]. If `cr3` is not 0, it is written
+/// to the CR3 register between the save of the current state and the restore of the new one.
+#[inline]
+unsafe fn switch_inner(from: &mut State, to: &State, cr3: u64) {
     asm!(
-        "mov rax, rsp",     // Save the current stack pointer
-        "mov rsp, [{}]",    // Load the new stack pointer (from)
+        "mov rax, rsp",                 // Save the current stack pointer
+        "lea rsp, [{from} + {size}]",   // Point the stack to the end of the `from` state
 
         /* Push the registers (usually pushed by the CPU during an interrupt) */
         "push 0",           // Null SS selector (because we are in kernel mode)
         "push rax",         // Push the saved stack pointer
         "pushfq",           // Push the current flags
         "push 0x08",        // Push the kernel code segment selector
-        "push 1f",          // Push the return address
+        "push 2f",          // Push the return address
 
         /* Push fake error code, interrupt number and skip address */
         "push 0",
@@ -211,7 +525,13 @@ pub unsafe fn switch(from: &mut State, to: &State) {
         "push rbx",
         "push rbp",
 
-        "mov rsp, [{}]",    // Load the new stack pointer (to)
+        /* Switch the address space if needed */
+        "test {cr3}, {cr3}",
+        "jz 3f",
+        "mov cr3, {cr3}",
+        "3:",
+
+        "mov rsp, {to}",    // Load the new stack pointer (to)
 
         /* Restore preserved registers */
         "pop rbp",
@@ -236,14 +556,245 @@ pub unsafe fn switch(from: &mut State, to: &State) {
         "iretq",            // Return to the new state
 
         /* When a state is restored, it return to this address */
-        "1:",
-        in(reg) from,
-        in(reg) to,
-        out("rax") _,   
+        "2:",
+        from = in(reg) from,
+        to = in(reg) to,
+        cr3 = in(reg) cr3,
+        size = const core::mem::size_of::<State>(),
+        out("rax") _,
         options(nostack, preserves_flags)
     );
 }
 
+/// The minimal context saved by [`switch_kernel`]. Only the stack pointer is stored here: the
+/// preserved registers, the flags and the return address are pushed onto the stack of the thread
+/// before switching.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct KernelContext {
+    rsp: u64,
+}
+
+impl KernelContext {
+    /// Create a new empty context. It must not be loaded with [`switch_kernel`] before being saved.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { rsp: 0 }
+    }
+
+    /// Prepare a context that will start executing the given entry point on the given stack when
+    /// loaded with [`switch_kernel`]. The entry point is called with the same flags as the thread
+    /// that switched to it (so usually with interrupts disabled), and must never return.
+    ///
+    /// # Safety
+    /// This function is unsafe because the given stack top must be a valid, writable and 16 bytes
+    /// aligned address, with enough space for the entry point to run.
+    #[must_use]
+    pub unsafe fn prepare(stack_top: Virtual, entry: extern "C" fn() -> !) -> Self {
+        assert!(
+            stack_top.is_aligned(16u64),
+            "Stack top is not aligned on a 16 bytes boundary"
+        );
+        let stack = stack_top.as_mut_ptr::<u64>();
+
+        // Fake return address of the entry point, followed by the address used by `ret` in the
+        // `switch_kernel` function.
+        stack.sub(1).write(0);
+        stack.sub(2).write(entry as usize as u64);
+        Self {
+            rsp: stack_top.as_u64() - 16,
+        }
+    }
+}
+
+/// Save the current kernel context into `from` and load the context from `to`. Unlike [`switch`],
+/// this function does not build an interrupt frame and does not use the `iretq` instruction: it
+/// only saves the preserved registers and the flags on the current stack, and jumps to the new
+/// context with a `ret` instruction. This is much faster, but can only be used to switch between
+/// two kernel threads that both gave up the CPU by calling this function (or that were created
+/// with [`KernelContext::prepare`]).
+///
+/// # Safety
+/// This function is unsafe because it can cause undefined behavior if the `to` context is not a
+/// context saved by this function or prepared with [`KernelContext::prepare`]. The states
+/// interrupted by an interrupt must still be restored with [`switch`].
+pub unsafe fn switch_kernel(from: &mut KernelContext, to: &KernelContext) {
+    asm!(
+        /* Save preserved registers and flags on the current stack */
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+
+        /* Push the return address and save the stack pointer */
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "mov [{from}], rsp",
+
+        /* Load the new stack pointer and jump to the new context */
+        "mov rsp, [{to}]",
+        "ret",
+
+        /* When a context is restored, it return to this address */
+        "2:",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        from = in(reg) core::ptr::addr_of_mut!(from.rsp),
+        to = in(reg) core::ptr::addr_of!(to.rsp),
+        out("rax") _,
+        clobber_abi("C"),
+    );
+}
+
+/// Switch to the given stack and call the given function with the given argument on it. The
+/// current stack is abandoned: this is typically used to leave the boot stack for a properly
+/// allocated kernel stack. The frame pointer is cleared, so that the backtraces end at the given
+/// function. If the function returns, an invalid opcode exception is raised.
+///
+/// # Panics
+/// This function panics if the stack top is not aligned on 16 bytes.
+///
+/// # Safety
+/// This function is unsafe because the given stack must be mapped, writable and large enough for
+/// the function to run. Since the current stack is abandoned, the destructors of the values
+/// living on it will never run.
+pub unsafe fn call_on_stack(stack_top: Virtual, f: extern "C" fn(usize), arg: usize) -> ! {
+    assert!(
+        stack_top.is_aligned(16u64),
+        "Stack top is not aligned on a 16 bytes boundary"
+    );
+    asm!(
+        "mov rsp, {stack}",
+        "xor ebp, ebp",
+        "call {f}",
+        "ud2",
+        stack = in(reg) stack_top.as_u64(),
+        f = in(reg) f,
+        in("rdi") arg,
+        options(noreturn)
+    );
+}
+
+/// Switch to the given stack, call the given function with the given argument on it and switch
+/// back to the current stack when it returns. A frame pointing to the current frame is pushed on
+/// the new stack, so that backtraces taken by the function continue on the current stack.
+///
+/// # Panics
+/// This function panics if the stack top is not aligned on 16 bytes.
+///
+/// # Safety
+/// This function is unsafe because the given stack must be mapped, writable and large enough for
+/// the function to run, and must not be used by anything else during the call.
+pub unsafe fn call_with_stack(stack_top: Virtual, f: extern "C" fn(usize), arg: usize) {
+    assert!(
+        stack_top.is_aligned(16u64),
+        "Stack top is not aligned on a 16 bytes boundary"
+    );
+    asm!(
+        /* Save the current stack pointer in a preserved register */
+        "mov r12, rsp",
+        "mov rsp, {stack}",
+
+        /* Push a frame linked to the current one: 16 bytes, so the stack stays aligned */
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "push rbp",
+        "mov rbp, rsp",
+        "call {f}",
+        "2:",
+        "pop rbp",
+        "mov rsp, r12",
+        stack = in(reg) stack_top.as_u64(),
+        f = in(reg) f,
+        in("rdi") arg,
+        out("rax") _,
+        out("r12") _,
+        clobber_abi("C"),
+    );
+}
+
+pub mod rflags {
+    use core::arch::asm;
+
+    use bitflags::bitflags;
+
+    bitflags! {
+        pub struct Flags: u64 {
+            /// Carry flag
+            const CF = 1 << 0;
+
+            /// Reserved, always set
+            const FIXED = 1 << 1;
+
+            /// Parity flag
+            const PF = 1 << 2;
+
+            /// Auxiliary carry flag
+            const AF = 1 << 4;
+
+            /// Zero flag
+            const ZF = 1 << 6;
+
+            /// Sign flag
+            const SF = 1 << 7;
+
+            /// Trap flag. If set, a debug exception is raised after each instruction
+            const TF = 1 << 8;
+
+            /// Interrupt flag. If set, the maskable interrupts are delivered
+            const IF = 1 << 9;
+
+            /// Direction flag
+            const DF = 1 << 10;
+
+            /// Overflow flag
+            const OF = 1 << 11;
+
+            /// I/O privilege level (2 bits)
+            const IOPL = 3 << 12;
+
+            /// Nested task flag
+            const NT = 1 << 14;
+
+            /// Resume flag. If set, the instruction breakpoints are ignored for one instruction
+            const RF = 1 << 16;
+
+            /// Virtual-8086 mode
+            const VM = 1 << 17;
+
+            /// Alignment check (or access control when SMAP is enabled)
+            const AC = 1 << 18;
+
+            /// Virtual interrupt flag
+            const VIF = 1 << 19;
+
+            /// Virtual interrupt pending
+            const VIP = 1 << 20;
+
+            /// Able to use the `cpuid` instruction
+            const ID = 1 << 21;
+        }
+    }
+
+    /// Read the current value of the RFLAGS register.
+    #[must_use]
+    pub fn read() -> Flags {
+        let value: u64;
+        unsafe {
+            asm!("pushfq; pop {}", out(reg) value, options(nomem, preserves_flags));
+        }
+        Flags::from_bits_truncate(value)
+    }
+}
+
 pub mod cr0 {
     use core::arch::asm;
 
@@ -305,13 +856,34 @@ pub mod cr0 {
         asm!("mov cr0, {}", in(reg) address, options(nostack, preserves_flags));
     }
 
+    /// Read the flags of the control register 0 (CR0). The bits that are not defined by
+    /// [`Flags`] are ignored.
+    #[must_use]
+    pub fn read_flags() -> Flags {
+        Flags::from_bits_truncate(read())
+    }
+
+    /// Update the flags of the control register 0 (CR0) with the given function, which
+    /// receives the current flags. The bits that are not defined by [`Flags`] are preserved.
+    ///
+    /// # Safety
+    /// This function is unsafe because it can cause undefined behavior (depending on the flags
+    /// changed). If a flag set is not supported by the CPU, it will cause a general protection
+    /// fault.
+    pub unsafe fn update(f: impl FnOnce(&mut Flags)) {
+        let value = read();
+        let mut flags = Flags::from_bits_truncate(value);
+        f(&mut flags);
+        write((value & !Flags::all().bits()) | flags.bits());
+    }
+
     /// Set the given flags in the control register 0 (CR0).
     ///
     /// # Safety
     /// This function is unsafe because it can cause undefined behavior (depending on the flags
     /// set). If a flag set is not supported by the CPU, it will cause a general protection fault.
     pub unsafe fn set(flags: Flags) {
-        write(read() | flags.bits());
+        update(|current| current.insert(flags));
     }
 
     /// Clear the given flags in the control register 0 (CR0).
@@ -320,12 +892,53 @@ pub mod cr0 {
     /// This function is unsafe because it can cause undefined behavior (depending on the flags
     /// cleared).
     pub unsafe fn clear(flags: Flags) {
-        write(read() & !flags.bits());
+        update(|current| current.remove(flags));
     }
 }
 
 pub mod cr2 {
-    use core::arch::asm;
+    use core::{arch::asm, fmt};
+
+    use crate::{address::Virtual, paging::PageFaultErrorCode};
+
+    /// The address and the cause of a page fault.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFaultInfo {
+        /// The address whose access caused the fault.
+        pub address: Virtual,
+
+        /// The cause of the fault, decoded from the error code pushed by the CPU.
+        pub cause: PageFaultErrorCode,
+    }
+
+    impl PageFaultInfo {
+        /// Returns `true` if the fault was caused by an access to a page that is not present,
+        /// and not by a protection violation.
+        #[must_use]
+        pub const fn not_present(&self) -> bool {
+            !self
+                .cause
+                .contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        }
+
+        /// Returns `true` if the fault was caused by a write access.
+        #[must_use]
+        pub const fn write(&self) -> bool {
+            self.cause.contains(PageFaultErrorCode::WRITE_ACCESS)
+        }
+
+        /// Returns `true` if the fault occurred while the CPU was running in user mode.
+        #[must_use]
+        pub const fn user(&self) -> bool {
+            self.cause.contains(PageFaultErrorCode::CPU_USER_MODE)
+        }
+    }
+
+    impl fmt::Display for PageFaultInfo {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "page fault at {:#x} ({:?})", self.address, self.cause)
+        }
+    }
 
     /// Read the current value of the control register 2 (CR0).
     #[must_use]
@@ -337,6 +950,31 @@ pub mod cr2 {
         value
     }
 
+    /// Read the current value of the control register 2, which contains the address that caused
+    /// the last page fault.
+    ///
+    /// # Panics
+    /// Panics if CR2 does not contain a canonical address, which cannot happen after a page fault
+    /// unless CR2 has been written with [`write`].
+    #[must_use]
+    pub fn read_virtual() -> Virtual {
+        Virtual::new(read())
+    }
+
+    /// Returns the address and the cause of the page fault being handled, from the error code
+    /// pushed by the CPU. This must be called by the page fault handler before enabling the
+    /// interrupts, since a nested page fault would overwrite CR2.
+    ///
+    /// # Panics
+    /// Panics if CR2 does not contain a canonical address (see [`read_virtual`]).
+    #[must_use]
+    pub fn page_fault_info(code: u64) -> PageFaultInfo {
+        PageFaultInfo {
+            address: read_virtual(),
+            cause: PageFaultErrorCode::from_bits_truncate(code),
+        }
+    }
+
     /// Write the given value to the control register 2 (CR0).
     ///
     /// # Safety
@@ -349,6 +987,8 @@ pub mod cr2 {
 pub mod cr3 {
     use core::arch::asm;
 
+    use crate::address::Physical;
+
     /// Read the current value of the control register 3 (CR0).
     #[must_use]
     pub fn read() -> u64 {
@@ -374,6 +1014,20 @@ pub mod cr3 {
     pub unsafe fn reload() {
         write(read());
     }
+
+    /// Returns the physical address of the current PML4 table, without the PCID and the flags.
+    #[must_use]
+    pub fn pml4() -> Physical {
+        Physical::new(read() & 0x000F_FFFF_FFFF_F000)
+    }
+
+    /// Returns the current PCID (the bits 0 to 11 of CR3), which is 0 if PCIDs are not enabled
+    /// (see [`crate::pcid`]).
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn pcid() -> u16 {
+        (read() & 0xFFF) as u16
+    }
 }
 
 pub mod cr4 {
@@ -471,13 +1125,34 @@ pub mod cr4 {
         asm!("mov cr4, {}", in(reg) address, options(nostack, preserves_flags));
     }
 
+    /// Read the flags of the control register 4 (CR4). The bits that are not defined by
+    /// [`Flags`] are ignored.
+    #[must_use]
+    pub fn read_flags() -> Flags {
+        Flags::from_bits_truncate(read())
+    }
+
+    /// Update the flags of the control register 4 (CR4) with the given function, which
+    /// receives the current flags. The bits that are not defined by [`Flags`] are preserved.
+    ///
+    /// # Safety
+    /// This function is unsafe because it can cause undefined behavior (depending on the flags
+    /// changed). If a flag set is not supported by the CPU, it will cause a general protection
+    /// fault.
+    pub unsafe fn update(f: impl FnOnce(&mut Flags)) {
+        let value = read();
+        let mut flags = Flags::from_bits_truncate(value);
+        f(&mut flags);
+        write((value & !Flags::all().bits()) | flags.bits());
+    }
+
     /// Set the given flags in the control register 4 (CR4).
     ///
     /// # Safety
     /// This function is unsafe because it can cause undefined behavior (depending on the flags
     /// set). If a flag set is not supported by the CPU, it will cause a general protection fault.
     pub unsafe fn set(flags: Flags) {
-        write(read() | flags.bits());
+        update(|current| current.insert(flags));
     }
 
     /// Clear the given flags in the control register 4 (CR4).
@@ -486,32 +1161,1591 @@ pub mod cr4 {
     /// This function is unsafe because it can cause undefined behavior (depending on the flags
     /// cleared).
     pub unsafe fn clear(flags: Flags) {
-        write(read() & !flags.bits());
+        update(|current| current.remove(flags));
     }
 }
 
-pub mod msr {
+pub mod cr8 {
+    //! The control register 8 (CR8), an alias of the priority class of the task priority register
+    //! of the local APIC (see [`crate::irq_priority`]). Accessing it is faster than accessing the
+    //! local APIC, and does not require it to be mapped.
     use core::arch::asm;
 
-    pub enum Register {
-        Efer = 0xC0000080,
-        Star = 0xC0000081,
-        Lstar = 0xC0000082,
-        Cstar = 0xC0000083,
-        Fmask = 0xC0000084,
-        FsBase = 0xC0000100,
-        GsBase = 0xC0000101,
-        KernelGsBase = 0xC0000102,
+    /// Read the current value of the control register 8 (CR8). Only the low 4 bits are used.
+    #[must_use]
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe {
+            asm!("mov {}, cr8", out(reg) value, options(nomem, nostack, preserves_flags));
+        }
+        value
     }
 
-    pub unsafe fn write(msr: Register, value: u64) {
-        asm!("wrmsr", in("ecx") msr as u32, in("eax") (value as u32), in("edx") (value >> 32));
+    /// Write the given value to the control register 8 (CR8).
+    ///
+    /// # Safety
+    /// This function is unsafe because a high value can prevent important interrupts (like the
+    /// timer) from being delivered. Setting a reserved bit causes a general protection fault.
+    pub unsafe fn write(value: u64) {
+        asm!("mov cr8, {}", in(reg) value, options(nostack, preserves_flags));
     }
+}
+
+pub mod msr {
+    //! Model specific registers (MSR). Each known register has its own submodule with typed
+    //! accessors, and the raw [`read`] and [`write`] functions can be used for the others.
+    use core::arch::asm;
 
-    pub unsafe fn read(msr: Register) -> u64 {
+    /// Read the model specific register at the given address.
+    ///
+    /// # Safety
+    /// This function is unsafe because reading an MSR that is not supported by the CPU causes a
+    /// general protection fault, and reading some MSRs may have side effects.
+    #[must_use]
+    pub unsafe fn read(msr: u32) -> u64 {
         let low: u32;
         let high: u32;
-        asm!("rdmsr", in("ecx") msr as u32, out("eax") low, out("edx") high);
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high);
         (high as u64) << 32 | (low as u64)
     }
+
+    /// Write the given value to the model specific register at the given address.
+    ///
+    /// # Safety
+    /// This function is unsafe because writing an MSR that is not supported by the CPU, or
+    /// writing a reserved bit, causes a general protection fault. Furthermore, many MSRs control
+    /// critical features of the CPU and can break memory safety if incorrectly written.
+    pub unsafe fn write(msr: u32, value: u64) {
+        asm!("wrmsr", in("ecx") msr, in("eax") (value as u32), in("edx") (value >> 32));
+    }
+
+    pub mod efer {
+        use bitflags::bitflags;
+
+        /// The address of the extended feature enable register (EFER).
+        pub const ADDRESS: u32 = 0xC000_0080;
+
+        bitflags! {
+            pub struct Flags: u64 {
+                /// System call extensions (`syscall` and `sysret` instructions)
+                const SCE = 1 << 0;
+
+                /// Long mode enable
+                const LME = 1 << 8;
+
+                /// Long mode active
+                const LMA = 1 << 10;
+
+                /// No-execute enable
+                const NXE = 1 << 11;
+
+                /// Secure virtual machine enable (AMD only)
+                const SVME = 1 << 12;
+
+                /// Long mode segment limit enable (AMD only)
+                const LMSLE = 1 << 13;
+
+                /// Fast `fxsave` and `fxrstor` (AMD only)
+                const FFXSR = 1 << 14;
+
+                /// Translation cache extension (AMD only)
+                const TCE = 1 << 15;
+            }
+        }
+
+        /// Read the current value of the EFER register.
+        #[must_use]
+        pub fn read() -> Flags {
+            Flags::from_bits_truncate(read_raw())
+        }
+
+        /// Read the raw value of the EFER register, including the bits unknown to [`Flags`].
+        #[must_use]
+        pub fn read_raw() -> u64 {
+            // SAFETY: The EFER register is supported by all x86_64 CPUs
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given flags to the EFER register.
+        ///
+        /// # Safety
+        /// This function is unsafe because clearing some flags (like [`Flags::LME`]) will crash
+        /// the kernel, and setting an unsupported flag causes a general protection fault.
+        pub unsafe fn write(flags: Flags) {
+            super::write(ADDRESS, flags.bits());
+        }
+
+        /// Set the given flags in the EFER register, preserving the other bits.
+        ///
+        /// # Safety
+        /// This function is unsafe because setting an unsupported flag causes a general
+        /// protection fault.
+        pub unsafe fn set(flags: Flags) {
+            super::write(ADDRESS, read_raw() | flags.bits());
+        }
+
+        /// Clear the given flags in the EFER register, preserving the other bits.
+        ///
+        /// # Safety
+        /// This function is unsafe because clearing some flags (like [`Flags::LME`]) will crash
+        /// the kernel.
+        pub unsafe fn clear(flags: Flags) {
+            super::write(ADDRESS, read_raw() & !flags.bits());
+        }
+    }
+
+    pub mod star {
+        use crate::syscall::Star;
+
+        /// The address of the STAR register, containing the segment selectors used by the
+        /// `syscall` and `sysret` instructions.
+        pub const ADDRESS: u32 = 0xC000_0081;
+
+        /// Read the current value of the STAR register.
+        #[must_use]
+        pub fn read() -> Star {
+            // SAFETY: The STAR register is supported by all x86_64 CPUs
+            Star::from_value(unsafe { super::read(ADDRESS) })
+        }
+
+        /// Write the given value to the STAR register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the segment selectors in the given value must be
+        /// valid descriptors in the GDT, otherwise the next system call will crash the kernel.
+        pub unsafe fn write(star: Star) {
+            super::write(ADDRESS, star.value());
+        }
+    }
+
+    pub mod lstar {
+        /// The address of the LSTAR register, containing the entry point of the `syscall`
+        /// instruction in 64 bits mode.
+        pub const ADDRESS: u32 = 0xC000_0082;
+
+        /// Read the current value of the LSTAR register.
+        #[must_use]
+        pub fn read() -> u64 {
+            // SAFETY: The LSTAR register is supported by all x86_64 CPUs
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given address to the LSTAR register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the given address must be a valid system call entry
+        /// point, otherwise the next system call will crash the kernel or worse.
+        pub unsafe fn write(address: u64) {
+            super::write(ADDRESS, address);
+        }
+    }
+
+    pub mod cstar {
+        /// The address of the CSTAR register, containing the entry point of the `syscall`
+        /// instruction in compatibility mode (AMD only).
+        pub const ADDRESS: u32 = 0xC000_0083;
+
+        /// Read the current value of the CSTAR register.
+        #[must_use]
+        pub fn read() -> u64 {
+            // SAFETY: The CSTAR register is supported by all x86_64 CPUs (but ignored by Intel)
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given address to the CSTAR register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the given address must be a valid system call entry
+        /// point, otherwise the next system call from compatibility mode will crash the kernel.
+        pub unsafe fn write(address: u64) {
+            super::write(ADDRESS, address);
+        }
+    }
+
+    pub mod fmask {
+        /// The address of the FMASK register, containing the RFLAGS bits cleared by the
+        /// `syscall` instruction.
+        pub const ADDRESS: u32 = 0xC000_0084;
+
+        /// Read the current value of the FMASK register.
+        #[must_use]
+        pub fn read() -> u64 {
+            // SAFETY: The FMASK register is supported by all x86_64 CPUs
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given mask to the FMASK register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the system call entry point usually relies on some
+        /// flags being cleared (for example, the interrupt flag).
+        pub unsafe fn write(mask: u64) {
+            super::write(ADDRESS, mask);
+        }
+    }
+
+    pub mod fs_base {
+        /// The address of the FS base register.
+        pub const ADDRESS: u32 = 0xC000_0100;
+
+        /// Read the current FS base.
+        #[must_use]
+        pub fn read() -> u64 {
+            // SAFETY: The FS base register is supported by all x86_64 CPUs
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given base to the FS base register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the code using the FS segment (for example, thread
+        /// local storage) expects it to point to valid memory. Writing a non-canonical address
+        /// causes a general protection fault.
+        pub unsafe fn write(base: u64) {
+            super::write(ADDRESS, base);
+        }
+    }
+
+    pub mod gs_base {
+        /// The address of the GS base register.
+        pub const ADDRESS: u32 = 0xC000_0101;
+
+        /// Read the current GS base.
+        #[must_use]
+        pub fn read() -> u64 {
+            // SAFETY: The GS base register is supported by all x86_64 CPUs
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given base to the GS base register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the code using the GS segment (for example, the
+        /// per-CPU area) expects it to point to valid memory. Writing a non-canonical address
+        /// causes a general protection fault.
+        pub unsafe fn write(base: u64) {
+            super::write(ADDRESS, base);
+        }
+    }
+
+    pub mod kernel_gs_base {
+        /// The address of the kernel GS base register, swapped with the GS base by the `swapgs`
+        /// instruction.
+        pub const ADDRESS: u32 = 0xC000_0102;
+
+        /// Read the current kernel GS base.
+        #[must_use]
+        pub fn read() -> u64 {
+            // SAFETY: The kernel GS base register is supported by all x86_64 CPUs
+            unsafe { super::read(ADDRESS) }
+        }
+
+        /// Write the given base to the kernel GS base register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the base will be loaded into the GS base by the next
+        /// `swapgs` instruction. Writing a non-canonical address causes a general protection
+        /// fault.
+        pub unsafe fn write(base: u64) {
+            super::write(ADDRESS, base);
+        }
+    }
+
+    pub mod tsc_aux {
+        /// The address of the TSC auxiliary register, returned by the `rdtscp` and `rdpid`
+        /// instructions.
+        pub const ADDRESS: u32 = 0xC000_0103;
+
+        /// Read the current value of the TSC auxiliary register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the register is only supported if the CPU supports
+        /// the `rdtscp` or the `rdpid` instruction.
+        #[must_use]
+        #[allow(clippy::cast_possible_truncation)]
+        pub unsafe fn read() -> u32 {
+            super::read(ADDRESS) as u32
+        }
+
+        /// Write the given value to the TSC auxiliary register.
+        ///
+        /// # Safety
+        /// This function is unsafe because the register is only supported if the CPU supports
+        /// the `rdtscp` or the `rdpid` instruction.
+        pub unsafe fn write(value: u32) {
+            super::write(ADDRESS, u64::from(value));
+        }
+    }
+
+    pub mod apic_base {
+        use bitflags::bitflags;
+
+        use crate::address::Physical;
+
+        /// The address of the APIC base register.
+        pub const ADDRESS: u32 = 0x1B;
+
+        /// The bits of the APIC base register containing the physical base of the local APIC.
+        const BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+        bitflags! {
+            pub struct Flags: u64 {
+                /// Set if the current CPU is the bootstrap processor (read-only)
+                const BSP = 1 << 8;
+
+                /// The x2APIC mode is enabled
+                const X2APIC = 1 << 10;
+
+                /// The local APIC is enabled
+                const ENABLE = 1 << 11;
+            }
+        }
+
+        /// Read the flags of the APIC base register.
+        #[must_use]
+        pub fn read() -> Flags {
+            // SAFETY: The APIC base register is supported by all x86_64 CPUs
+            Flags::from_bits_truncate(unsafe { super::read(ADDRESS) })
+        }
+
+        /// Read the physical base address of the local APIC registers.
+        #[must_use]
+        pub fn base() -> Physical {
+            // SAFETY: The APIC base register is supported by all x86_64 CPUs
+            Physical::new(unsafe { super::read(ADDRESS) } & BASE_MASK)
+        }
+
+        /// Write the given physical base and flags to the APIC base register.
+        ///
+        /// # Safety
+        /// This function is unsafe because relocating or disabling the local APIC breaks the
+        /// code using it. The base must be aligned on a 4 KiB boundary.
+        pub unsafe fn write(base: Physical, flags: Flags) {
+            super::write(ADDRESS, (base.as_u64() & BASE_MASK) | flags.bits());
+        }
+
+        /// Set the given flags in the APIC base register, preserving the base address.
+        ///
+        /// # Safety
+        /// This function is unsafe because enabling the x2APIC mode changes the way the local
+        /// APIC must be accessed.
+        pub unsafe fn set(flags: Flags) {
+            super::write(ADDRESS, super::read(ADDRESS) | flags.bits());
+        }
+
+        /// Clear the given flags in the APIC base register, preserving the base address.
+        ///
+        /// # Safety
+        /// This function is unsafe because disabling the local APIC breaks the code using it.
+        pub unsafe fn clear(flags: Flags) {
+            super::write(ADDRESS, super::read(ADDRESS) & !flags.bits());
+        }
+    }
+
+    pub mod pat {
+        /// The address of the page attribute table (PAT) register.
+        pub const ADDRESS: u32 = 0x277;
+
+        /// A memory type that can be stored in an entry of the page attribute table.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum MemoryType {
+            Uncacheable = 0,
+            WriteCombining = 1,
+            WriteThrough = 4,
+            WriteProtected = 5,
+            WriteBack = 6,
+            UncachedMinus = 7,
+        }
+
+        impl MemoryType {
+            /// Returns the memory type encoded by the given value, or `None` if the value is
+            /// reserved.
+            #[must_use]
+            pub const fn from_bits(bits: u8) -> Option<Self> {
+                match bits {
+                    0 => Some(Self::Uncacheable),
+                    1 => Some(Self::WriteCombining),
+                    4 => Some(Self::WriteThrough),
+                    5 => Some(Self::WriteProtected),
+                    6 => Some(Self::WriteBack),
+                    7 => Some(Self::UncachedMinus),
+                    _ => None,
+                }
+            }
+        }
+
+        /// The value of the page attribute table, made of 8 entries of one byte.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(transparent)]
+        pub struct Pat(u64);
+
+        impl Pat {
+            /// The value of the page attribute table after a reset.
+            pub const DEFAULT: Self = Self(0x0007_0406_0007_0406);
+
+            /// Returns the memory type of the given entry, or `None` if the entry contains a
+            /// reserved value.
+            ///
+            /// # Panics
+            /// This function panics if the index is greater than 7.
+            #[must_use]
+            #[allow(clippy::cast_possible_truncation)]
+            pub const fn get(&self, index: usize) -> Option<MemoryType> {
+                assert!(index < 8, "PAT index out of bounds");
+                MemoryType::from_bits((self.0 >> (index * 8)) as u8 & 0x07)
+            }
+
+            /// Returns a copy of this table with the given entry set to the given memory type.
+            ///
+            /// # Panics
+            /// This function panics if the index is greater than 7.
+            #[must_use]
+            pub const fn with(self, index: usize, kind: MemoryType) -> Self {
+                assert!(index < 8, "PAT index out of bounds");
+                let shift = index * 8;
+                Self(self.0 & !(0xFF << shift) | (kind as u64) << shift)
+            }
+
+            /// Returns the raw value of the page attribute table.
+            #[must_use]
+            pub const fn value(&self) -> u64 {
+                self.0
+            }
+        }
+
+        impl Default for Pat {
+            fn default() -> Self {
+                Self::DEFAULT
+            }
+        }
+
+        /// Read the current page attribute table.
+        #[must_use]
+        pub fn read() -> Pat {
+            // SAFETY: The PAT register is supported by all x86_64 CPUs
+            Pat(unsafe { super::read(ADDRESS) })
+        }
+
+        /// Write the given page attribute table.
+        ///
+        /// # Safety
+        /// This function is unsafe because changing the memory type of the pages already mapped
+        /// can break the kernel (for example, mapping MMIO registers as write-back). The TLB and
+        /// the caches must be flushed after changing the PAT.
+        pub unsafe fn write(pat: Pat) {
+            super::write(ADDRESS, pat.value());
+        }
+    }
+
+    pub mod misc_enable {
+        use bitflags::bitflags;
+
+        /// The address of the miscellaneous feature enable register (Intel only).
+        pub const ADDRESS: u32 = 0x1A0;
+
+        bitflags! {
+            pub struct Flags: u64 {
+                /// Fast strings operations (`rep movs` and `rep stos`)
+                const FAST_STRINGS = 1 << 0;
+
+                /// Automatic thermal control circuit
+                const AUTOMATIC_THERMAL_CONTROL = 1 << 3;
+
+                /// Performance monitoring available (read-only)
+                const PERFORMANCE_MONITORING = 1 << 7;
+
+                /// Branch trace storage unavailable (read-only)
+                const BTS_UNAVAILABLE = 1 << 11;
+
+                /// Precise event based sampling unavailable (read-only)
+                const PEBS_UNAVAILABLE = 1 << 12;
+
+                /// Enhanced Intel SpeedStep technology
+                const ENHANCED_SPEEDSTEP = 1 << 16;
+
+                /// The `monitor` and `mwait` instructions are enabled
+                const MONITOR_FSM = 1 << 18;
+
+                /// Limit the maximum CPUID leaf to 2
+                const LIMIT_CPUID = 1 << 22;
+
+                /// xTPR messages disabled
+                const XTPR_MESSAGE_DISABLE = 1 << 23;
+
+                /// No-execute feature disabled
+                const XD_DISABLE = 1 << 34;
+            }
+        }
+
+        /// Read the current value of the miscellaneous feature enable register.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported by Intel CPUs.
+        #[must_use]
+        pub unsafe fn read() -> Flags {
+            Flags::from_bits_truncate(super::read(ADDRESS))
+        }
+
+        /// Set the given flags in the miscellaneous feature enable register, preserving the
+        /// other bits.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported by Intel CPUs, and
+        /// setting some flags (like [`Flags::XD_DISABLE`]) can break the kernel.
+        pub unsafe fn set(flags: Flags) {
+            super::write(ADDRESS, super::read(ADDRESS) | flags.bits());
+        }
+
+        /// Clear the given flags in the miscellaneous feature enable register, preserving the
+        /// other bits.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported by Intel CPUs, and
+        /// clearing some flags can break the kernel.
+        pub unsafe fn clear(flags: Flags) {
+            super::write(ADDRESS, super::read(ADDRESS) & !flags.bits());
+        }
+    }
+
+    pub mod tsc_deadline {
+        /// The address of the TSC deadline register, used by the local APIC timer in TSC
+        /// deadline mode.
+        pub const ADDRESS: u32 = 0x6E0;
+
+        /// Read the current TSC deadline, or 0 if the deadline is disarmed.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported if the CPU supports
+        /// the TSC deadline mode of the local APIC timer.
+        #[must_use]
+        pub unsafe fn read() -> u64 {
+            super::read(ADDRESS)
+        }
+
+        /// Arm the local APIC timer to fire when the TSC reaches the given value. Writing 0
+        /// disarms the timer.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported if the CPU supports
+        /// the TSC deadline mode of the local APIC timer.
+        pub unsafe fn write(deadline: u64) {
+            super::write(ADDRESS, deadline);
+        }
+    }
+
+    pub mod spec_ctrl {
+        use bitflags::bitflags;
+
+        /// The address of the speculation control register.
+        pub const ADDRESS: u32 = 0x48;
+
+        bitflags! {
+            pub struct Flags: u64 {
+                /// Indirect branch restricted speculation
+                const IBRS = 1 << 0;
+
+                /// Single thread indirect branch predictors
+                const STIBP = 1 << 1;
+
+                /// Speculative store bypass disable
+                const SSBD = 1 << 2;
+            }
+        }
+
+        /// Read the current value of the speculation control register.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported by CPUs with the
+        /// speculation control features.
+        #[must_use]
+        pub unsafe fn read() -> Flags {
+            Flags::from_bits_truncate(super::read(ADDRESS))
+        }
+
+        /// Write the given flags to the speculation control register.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported by CPUs with the
+        /// speculation control features, and setting an unsupported flag causes a general
+        /// protection fault.
+        pub unsafe fn write(flags: Flags) {
+            super::write(ADDRESS, flags.bits());
+        }
+    }
+
+    pub mod pred_cmd {
+        /// The address of the prediction command register (write-only).
+        pub const ADDRESS: u32 = 0x49;
+
+        /// Indirect branch prediction barrier: prevents the indirect branch predictions made
+        /// before the barrier from influencing the ones made after it.
+        ///
+        /// # Safety
+        /// This function is unsafe because this register is only supported by CPUs with the
+        /// speculation control features.
+        pub unsafe fn ibpb() {
+            super::write(ADDRESS, 1);
+        }
+    }
+
+    pub mod arch_capabilities {
+        use bitflags::bitflags;
+
+        use crate::cpu::feature::Feature;
+
+        /// The address of the architecture capabilities register (read-only).
+        pub const ADDRESS: u32 = 0x10A;
+
+        bitflags! {
+            pub struct Flags: u64 {
+                /// Not vulnerable to rogue data cache load (Meltdown)
+                const RDCL_NO = 1 << 0;
+
+                /// Enhanced IBRS is supported
+                const IBRS_ALL = 1 << 1;
+
+                /// The return stack buffer may fall back to the branch predictor
+                const RSBA = 1 << 2;
+
+                /// The L1 data cache does not need to be flushed on VM entry
+                const SKIP_L1DFL_VMENTRY = 1 << 3;
+
+                /// Not vulnerable to speculative store bypass
+                const SSB_NO = 1 << 4;
+
+                /// Not vulnerable to microarchitectural data sampling
+                const MDS_NO = 1 << 5;
+
+                /// Not vulnerable to machine check errors on page size changes
+                const IF_PSCHANGE_MC_NO = 1 << 6;
+
+                /// The TSX control register is supported
+                const TSX_CTRL = 1 << 7;
+
+                /// Not vulnerable to TSX asynchronous abort
+                const TAA_NO = 1 << 8;
+            }
+        }
+
+        /// Returns `true` if the architecture capabilities register is supported by the
+        /// current CPU.
+        #[must_use]
+        pub fn supported() -> bool {
+            crate::cpu::features().has(Feature::ArchCapabilities)
+        }
+
+        /// Read the architecture capabilities register. If the register is not supported, no
+        /// flag is returned.
+        #[must_use]
+        pub fn read() -> Flags {
+            if supported() {
+                // SAFETY: The register is supported by the current CPU
+                Flags::from_bits_truncate(unsafe { super::read(ADDRESS) })
+            } else {
+                Flags::empty()
+            }
+        }
+    }
+}
+
+/// Mitigations of the speculative execution vulnerabilities of the CPU.
+pub mod mitigations {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{feature::Feature, msr::arch_capabilities, quirks::Vendor};
+    use crate::segment::Selector;
+
+    /// Set if the CPU buffers must be cleared when returning to user mode. This is read by the
+    /// interrupt and system call exit stubs, after the switch to the user page tables.
+    #[export_name = "silicium_clear_cpu_buffers"]
+    pub(crate) static CLEAR_CPU_BUFFERS: AtomicBool = AtomicBool::new(false);
+
+    /// The selector used as the memory operand of the `verw` instruction. It must be a valid
+    /// writable data segment selector.
+    #[export_name = "silicium_verw_selector"]
+    pub(crate) static VERW_SELECTOR: u16 = Selector::KERNEL_DATA.value();
+
+    /// Set if an `lfence` instruction must follow the conditional `swapgs` of the interrupt
+    /// entry stubs. This is read by the interrupt entry stubs, before the switch to the kernel
+    /// page tables.
+    #[export_name = "silicium_swapgs_fence"]
+    pub(crate) static SWAPGS_FENCE: AtomicBool = AtomicBool::new(true);
+
+    /// Returns `true` if the current CPU is vulnerable to microarchitectural data sampling
+    /// (MDS) or to TSX asynchronous abort (TAA), according to the architecture capabilities
+    /// register. Only Intel processors are affected.
+    #[must_use]
+    pub fn mds_vulnerable() -> bool {
+        let intel = super::vendor() == Vendor::Intel;
+        intel && !arch_capabilities::read().contains(arch_capabilities::Flags::MDS_NO)
+    }
+
+    /// Returns `true` if the microcode of the current CPU supports clearing the CPU buffers
+    /// with the `verw` instruction (`MD_CLEAR`).
+    #[must_use]
+    pub fn md_clear_supported() -> bool {
+        super::features().has(Feature::MdClear)
+    }
+
+    /// Enable or disable the clearing of the CPU buffers when returning to user mode from the
+    /// interrupt and system call exit stubs, [`super::enter_usermode`] and
+    /// [`super::restore_state`]. The buffers are cleared just before `swapgs`, after the last
+    /// access to the kernel data. The clearing is only enabled if the CPU is vulnerable and
+    /// supports it. Returns `true` if it is enabled.
+    ///
+    /// All CPUs of the system should have the same vulnerabilities, so this should only be called
+    /// once, on the BSP.
+    pub fn set_clear_on_user_return(enable: bool) -> bool {
+        let enable = enable && mds_vulnerable() && md_clear_supported();
+        CLEAR_CPU_BUFFERS.store(enable, Ordering::Relaxed);
+        enable
+    }
+
+    /// Returns `true` if the CPU buffers are cleared when returning to user mode.
+    #[must_use]
+    pub fn clear_on_user_return() -> bool {
+        CLEAR_CPU_BUFFERS.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable the `lfence` instruction placed after the conditional `swapgs` of the
+    /// interrupt entry stubs, on both the user and the kernel paths. Without it, the CPU can
+    /// speculatively execute the GS-relative loads of the entry code with the wrong GS base
+    /// (SWAPGS variant of Spectre v1). This is enabled by default, and should only be disabled
+    /// on processors that are not affected, or when the other mitigations are disabled.
+    ///
+    /// The stubs check this switch with a forward branch skipping the fence when it is disabled,
+    /// so the fence is on the statically predicted path. The `syscall` entry stub always
+    /// executes `swapgs`, so it does not need the fence.
+    pub fn set_swapgs_fence(enable: bool) {
+        SWAPGS_FENCE.store(enable, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the conditional `swapgs` of the interrupt entry stubs is followed by an
+    /// `lfence` instruction.
+    #[must_use]
+    pub fn swapgs_fence() -> bool {
+        SWAPGS_FENCE.load(Ordering::Relaxed)
+    }
+
+    /// Clear the CPU buffers (store buffers, fill buffers and load ports) with the documented
+    /// `verw` sequence, so that stale kernel data cannot be sampled by user code. This must be
+    /// called as late as possible on the return to user mode. This does nothing if the clearing
+    /// is not enabled with [`set_clear_on_user_return`].
+    #[inline]
+    pub fn clear_cpu_buffers() {
+        if CLEAR_CPU_BUFFERS.load(Ordering::Relaxed) {
+            // SAFETY: The memory operand is a valid data segment selector, and `verw` only
+            // modifies the ZF flag
+            unsafe {
+                core::arch::asm!(
+                    "verw WORD PTR [{}]",
+                    in(reg) core::ptr::addr_of!(VERW_SELECTOR),
+                    options(nostack, readonly)
+                );
+            }
+        }
+    }
+}
+
+/// Returns the features of the CPU, detected with `cpuid` on the first call (or by
+/// [`feature::init`]) and cached afterwards. All the CPUs are assumed to have the same features.
+#[inline]
+#[must_use]
+pub fn features() -> feature::Features {
+    feature::get()
+}
+
+/// The CPU features enumerated by `cpuid`, detected once and cached in a packed bitset so that
+/// hot paths do not need to execute `cpuid`, which is serializing and traps under virtualization.
+pub mod feature {
+    use core::{
+        arch::x86_64::{__cpuid, __cpuid_count, CpuidResult},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use super::quirks::Vendor;
+
+    /// A feature of the CPU. The discriminant is the index of the feature in [`Features`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u8)]
+    pub enum Feature {
+        /// Page size extension (4 MiB pages in 32 bits mode).
+        Pse,
+        /// Time stamp counter.
+        Tsc,
+        /// Local APIC.
+        Apic,
+        /// Global pages.
+        Pge,
+        /// Page attribute table.
+        Pat,
+        /// The `clflush` instruction.
+        Clflush,
+        /// The `monitor` and `mwait` instructions.
+        Monitor,
+        /// Process-context identifiers.
+        Pcid,
+        /// The x2APIC mode of the local APIC.
+        X2apic,
+        /// The TSC deadline mode of the local APIC timer.
+        TscDeadline,
+        /// The `xsave` family of instructions.
+        Xsave,
+        /// Advanced vector extensions.
+        Avx,
+        /// The `rdrand` instruction.
+        Rdrand,
+        /// Running under a hypervisor.
+        Hypervisor,
+        /// The `rdfsbase` family of instructions.
+        Fsgsbase,
+        /// Supervisor mode execution prevention.
+        Smep,
+        /// The `invpcid` instruction.
+        Invpcid,
+        /// The `rdseed` instruction.
+        Rdseed,
+        /// Supervisor mode access prevention.
+        Smap,
+        /// The `clflushopt` instruction.
+        Clflushopt,
+        /// The `clwb` instruction.
+        Clwb,
+        /// User mode instruction prevention.
+        Umip,
+        /// Protection keys for user pages.
+        Pku,
+        /// 5-level paging.
+        La57,
+        /// The `rdpid` instruction.
+        Rdpid,
+        /// Clearing the CPU buffers with `verw`.
+        MdClear,
+        /// The architecture capabilities MSR.
+        ArchCapabilities,
+        /// The execute disable bit of the page table entries.
+        Nx,
+        /// 1 GiB pages.
+        Page1G,
+        /// The `rdtscp` instruction.
+        Rdtscp,
+        /// The TSC is invariant.
+        InvariantTsc,
+    }
+
+    /// The register of a `cpuid` leaf enumerating a feature.
+    #[derive(Debug, Clone, Copy)]
+    enum Word {
+        Leaf1Ecx,
+        Leaf1Edx,
+        Leaf7Ebx,
+        Leaf7Ecx,
+        Leaf7Edx,
+        Extended1Edx,
+        Extended7Edx,
+    }
+
+    /// The register and the bit enumerating each feature.
+    const DEFINITIONS: [(Feature, Word, u32); 31] = [
+        (Feature::Pse, Word::Leaf1Edx, 3),
+        (Feature::Tsc, Word::Leaf1Edx, 4),
+        (Feature::Apic, Word::Leaf1Edx, 9),
+        (Feature::Pge, Word::Leaf1Edx, 13),
+        (Feature::Pat, Word::Leaf1Edx, 16),
+        (Feature::Clflush, Word::Leaf1Edx, 19),
+        (Feature::Monitor, Word::Leaf1Ecx, 3),
+        (Feature::Pcid, Word::Leaf1Ecx, 17),
+        (Feature::X2apic, Word::Leaf1Ecx, 21),
+        (Feature::TscDeadline, Word::Leaf1Ecx, 24),
+        (Feature::Xsave, Word::Leaf1Ecx, 26),
+        (Feature::Avx, Word::Leaf1Ecx, 28),
+        (Feature::Rdrand, Word::Leaf1Ecx, 30),
+        (Feature::Hypervisor, Word::Leaf1Ecx, 31),
+        (Feature::Fsgsbase, Word::Leaf7Ebx, 0),
+        (Feature::Smep, Word::Leaf7Ebx, 7),
+        (Feature::Invpcid, Word::Leaf7Ebx, 10),
+        (Feature::Rdseed, Word::Leaf7Ebx, 18),
+        (Feature::Smap, Word::Leaf7Ebx, 20),
+        (Feature::Clflushopt, Word::Leaf7Ebx, 23),
+        (Feature::Clwb, Word::Leaf7Ebx, 24),
+        (Feature::Umip, Word::Leaf7Ecx, 2),
+        (Feature::Pku, Word::Leaf7Ecx, 3),
+        (Feature::La57, Word::Leaf7Ecx, 16),
+        (Feature::Rdpid, Word::Leaf7Ecx, 22),
+        (Feature::MdClear, Word::Leaf7Edx, 10),
+        (Feature::ArchCapabilities, Word::Leaf7Edx, 29),
+        (Feature::Nx, Word::Extended1Edx, 20),
+        (Feature::Page1G, Word::Extended1Edx, 26),
+        (Feature::Rdtscp, Word::Extended1Edx, 27),
+        (Feature::InvariantTsc, Word::Extended7Edx, 8),
+    ];
+
+    /// The layout of [`Features`]: the features are in the low 32 bits, followed by the family,
+    /// the model and the vendor of the CPU. The last bit is set once the features have been
+    /// detected.
+    const FEATURES_MASK: u64 = 0xFFFF_FFFF;
+    const FAMILY_SHIFT: u64 = 32;
+    const MODEL_SHIFT: u64 = 40;
+    const VENDOR_SHIFT: u64 = 48;
+    const DETECTED: u64 = 1 << 63;
+
+    /// The encoding of the vendors in [`Features`].
+    const VENDOR_OTHER: u64 = 0;
+    const VENDOR_INTEL: u64 = 1;
+    const VENDOR_AMD: u64 = 2;
+
+    /// The cached features, or 0 if they have not been detected yet.
+    static FEATURES: AtomicU64 = AtomicU64::new(0);
+
+    /// A set of CPU features, with the vendor, the family and the model of the CPU.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Features(u64);
+
+    impl Features {
+        /// Returns `true` if the given feature is supported.
+        #[inline]
+        #[must_use]
+        pub const fn has(self, feature: Feature) -> bool {
+            (self.0 >> feature as u8) & 1 != 0
+        }
+
+        /// Returns the raw bitset, where the bit `n` is set if the feature with the discriminant
+        /// `n` is supported.
+        #[must_use]
+        pub const fn bits(self) -> u64 {
+            self.0 & FEATURES_MASK
+        }
+
+        /// Returns the vendor of the CPU.
+        #[must_use]
+        pub const fn vendor(self) -> Vendor {
+            match (self.0 >> VENDOR_SHIFT) & 0b11 {
+                VENDOR_INTEL => Vendor::Intel,
+                VENDOR_AMD => Vendor::Amd,
+                _ => Vendor::Other,
+            }
+        }
+
+        /// Returns the family of the CPU, including the extended family.
+        #[must_use]
+        #[allow(clippy::cast_possible_truncation)]
+        pub const fn family(self) -> u8 {
+            (self.0 >> FAMILY_SHIFT) as u8
+        }
+
+        /// Returns the model of the CPU, including the extended model.
+        #[must_use]
+        #[allow(clippy::cast_possible_truncation)]
+        pub const fn model(self) -> u8 {
+            (self.0 >> MODEL_SHIFT) as u8
+        }
+    }
+
+    /// Detect the features of the current CPU, and cache them. This is done on the first call
+    /// to [`super::features`] otherwise, but calling this early during the boot avoids a `cpuid`
+    /// in the first hot path that checks a feature.
+    pub fn init() {
+        FEATURES.store(detect().0, Ordering::Relaxed);
+    }
+
+    /// Returns the cached features, detecting them if needed. Detecting them concurrently on
+    /// several CPUs is harmless, since they all get the same result.
+    #[inline]
+    pub(super) fn get() -> Features {
+        match FEATURES.load(Ordering::Relaxed) {
+            0 => {
+                let features = detect();
+                FEATURES.store(features.0, Ordering::Relaxed);
+                features
+            }
+            bits => Features(bits),
+        }
+    }
+
+    /// Execute `cpuid` for the given leaf, or return zeros if the leaf is not supported.
+    fn leaf(leaf: u32, max: u32) -> CpuidResult {
+        if leaf <= max {
+            // SAFETY: `cpuid` is available on all x86_64 processors, and the leaf is supported
+            unsafe { __cpuid_count(leaf, 0) }
+        } else {
+            CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }
+        }
+    }
+
+    /// Detect the features of the current CPU with `cpuid`.
+    fn detect() -> Features {
+        // SAFETY: `cpuid` is available on all x86_64 processors
+        let (leaf0, max_extended) = unsafe { (__cpuid(0), __cpuid(0x8000_0000).eax) };
+        let max = leaf0.eax;
+        let leaf1 = leaf(1, max);
+        let leaf7 = leaf(7, max);
+        let extended1 = leaf(0x8000_0001, max_extended);
+        let extended7 = leaf(0x8000_0007, max_extended);
+
+        let bits = DEFINITIONS
+            .iter()
+            .fold(DETECTED, |bits, &(feature, word, bit)| {
+                let register = match word {
+                    Word::Leaf1Ecx => leaf1.ecx,
+                    Word::Leaf1Edx => leaf1.edx,
+                    Word::Leaf7Ebx => leaf7.ebx,
+                    Word::Leaf7Ecx => leaf7.ecx,
+                    Word::Leaf7Edx => leaf7.edx,
+                    Word::Extended1Edx => extended1.edx,
+                    Word::Extended7Edx => extended7.edx,
+                };
+                bits | u64::from((register >> bit) & 1) << feature as u8
+            });
+
+        let vendor = match (leaf0.ebx, leaf0.edx, leaf0.ecx) {
+            // "GenuineIntel"
+            (0x756E_6547, 0x4965_6E69, 0x6C65_746E) => VENDOR_INTEL,
+            // "AuthenticAMD" and "HygonGenuine", which is derived from the AMD Zen processors
+            (0x6874_7541, 0x6974_6E65, 0x444D_4163) | (0x6F67_7948, 0x6E65_476E, 0x656E_6975) => {
+                VENDOR_AMD
+            }
+            _ => VENDOR_OTHER,
+        };
+        let (family, model) = signature(leaf1.eax);
+        Features(
+            bits | u64::from(family) << FAMILY_SHIFT
+                | u64::from(model) << MODEL_SHIFT
+                | vendor << VENDOR_SHIFT,
+        )
+    }
+
+    /// Decode the family and the model of the processor signature (the register EAX of the
+    /// leaf 1 of `cpuid`). The extended family is only added to the family 0x0F, and the extended
+    /// model only to the families 0x06 and 0x0F.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) const fn signature(eax: u32) -> (u8, u8) {
+        let base_family = ((eax >> 8) & 0x0F) as u8;
+        let mut family = base_family;
+        let mut model = ((eax >> 4) & 0x0F) as u8;
+        if base_family == 0x0F {
+            family = family.saturating_add((eax >> 20) as u8);
+        }
+        if base_family == 0x06 || base_family == 0x0F {
+            model |= (((eax >> 16) & 0x0F) as u8) << 4;
+        }
+        (family, model)
+    }
+}
+
+/// Returns the vendor of the CPU (see [`features`]).
+#[inline]
+#[must_use]
+pub fn vendor() -> quirks::Vendor {
+    features().vendor()
+}
+
+/// Returns the vendor-specific behaviors of the CPU (see [`quirks::Quirks`]).
+#[inline]
+#[must_use]
+pub fn quirks() -> quirks::Quirks {
+    let features = features();
+    quirks::Quirks::new(features.vendor(), features.family(), features.model())
+}
+
+/// The behaviors that differ between the CPU vendors, gathered in one place so that the modules
+/// that depend on them (system calls, machine checks, performance counters) do not test the
+/// vendor themselves.
+pub mod quirks {
+    /// The vendor of a CPU.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Vendor {
+        Intel,
+
+        /// AMD, or a vendor of processors derived from AMD processors (Hygon).
+        Amd,
+
+        Other,
+    }
+
+    /// The performance monitoring counters of a CPU, used for cycle counting.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Pmu {
+        /// The architectural performance monitoring of Intel processors. Its version and number
+        /// of counters are enumerated by the leaf 0x0A of `cpuid`.
+        Architectural,
+
+        /// The legacy performance counters of AMD processors, which are always present.
+        Amd,
+
+        /// No known performance counters.
+        None,
+    }
+
+    impl Pmu {
+        /// Returns the event selection register of the first general purpose counter.
+        #[must_use]
+        pub const fn event_select(self) -> Option<u32> {
+            match self {
+                Pmu::Architectural => Some(0x186),
+                Pmu::Amd => Some(0xC001_0000),
+                Pmu::None => None,
+            }
+        }
+
+        /// Returns the register of the first general purpose counter.
+        #[must_use]
+        pub const fn counter(self) -> Option<u32> {
+            match self {
+                Pmu::Architectural => Some(0xC1),
+                Pmu::Amd => Some(0xC001_0004),
+                Pmu::None => None,
+            }
+        }
+
+        /// Returns the event number and the unit mask of the event counting the unhalted core
+        /// cycles, in the format of the event selection registers.
+        #[must_use]
+        pub const fn unhalted_cycles(self) -> Option<u64> {
+            match self {
+                Pmu::Architectural => Some(0x3C),
+                Pmu::Amd => Some(0x76),
+                Pmu::None => None,
+            }
+        }
+    }
+
+    /// The vendor-specific behaviors of a CPU.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[allow(clippy::struct_excessive_bools)]
+    pub struct Quirks {
+        pub vendor: Vendor,
+
+        /// `sysret` raises the general protection fault of a non-canonical return address in
+        /// kernel mode, with the user stack (Intel). The system call exit path must return
+        /// with `iretq` in this case, and only checks the return address when this is set (see
+        /// [`crate::syscall::setup`]).
+        pub sysret_gp_in_kernel: bool,
+
+        /// The `syscall` instruction is available in compatibility mode, and jumps to the entry
+        /// point in the CSTAR register (AMD). On Intel processors, it raises an invalid opcode
+        /// exception instead.
+        pub compat_syscall: bool,
+
+        /// The machine check bank 0 must not be enabled, because it reports spurious errors
+        /// (Intel processors of the family 6 before Nehalem, and AMD K7 processors). See
+        /// [`Quirks::mce_first_bank`].
+        pub mce_skip_bank0: bool,
+
+        /// The machine check banks may have the `MCi_CTL2` registers of the corrected machine
+        /// check interrupt (Intel). The `MCG_CAP` register tells whether they are present.
+        pub mce_cmci: bool,
+
+        /// The GART table walk errors of the bank 4 must not be reported, because they are
+        /// raised by the normal operation of the GART driver (AMD K8 processors). See
+        /// [`Quirks::mce_bank_ctl`].
+        pub mce_mask_gart_walk: bool,
+
+        /// The performance counters of the CPU.
+        pub pmu: Pmu,
+    }
+
+    impl Quirks {
+        /// Returns the behaviors of a CPU with the given vendor, family and model.
+        #[must_use]
+        pub const fn new(vendor: Vendor, family: u8, model: u8) -> Self {
+            let intel = matches!(vendor, Vendor::Intel);
+            let amd = matches!(vendor, Vendor::Amd);
+            Self {
+                vendor,
+                sysret_gp_in_kernel: !amd,
+                compat_syscall: amd,
+                mce_skip_bank0: (intel && family == 0x06 && model < 0x1A)
+                    || (amd && family == 0x06),
+                mce_cmci: intel,
+                mce_mask_gart_walk: amd && family == 0x0F,
+                pmu: match vendor {
+                    Vendor::Intel => Pmu::Architectural,
+                    Vendor::Amd => Pmu::Amd,
+                    Vendor::Other => Pmu::None,
+                },
+            }
+        }
+
+        /// Returns the first machine check bank that must be enabled. The banks below it must
+        /// be left disabled, and their errors ignored.
+        #[must_use]
+        pub const fn mce_first_bank(&self) -> u8 {
+            if self.mce_skip_bank0 {
+                1
+            } else {
+                0
+            }
+        }
+
+        /// Returns the value to write in the `MCi_CTL` register of the given machine check
+        /// bank to enable the reporting of its errors. On AMD processors, each bit of this
+        /// register enables an error type of the bank, and some of them must stay cleared.
+        #[must_use]
+        pub const fn mce_bank_ctl(&self, bank: u8) -> u64 {
+            if self.mce_mask_gart_walk && bank == 4 {
+                !(1 << 10)
+            } else {
+                !0
+            }
+        }
+    }
+}
+
+/// Helpers for debugging and for testing the exception paths, by deliberately raising specific
+/// exceptions.
+pub mod debug {
+    use core::arch::asm;
+
+    use bitflags::bitflags;
+
+    use super::{rflags, State};
+    use crate::address::Virtual;
+
+    /// The value of the debug status register (DR6) after a reset, with no exception reported.
+    const STATUS_RESET: u64 = 0xFFFF_0FF0;
+
+    bitflags! {
+        /// The cause of the last debug exception, reported in the debug status register (DR6).
+        pub struct Status: u64 {
+            /// The condition of the hardware breakpoint 0 was met
+            const B0 = 1 << 0;
+
+            /// The condition of the hardware breakpoint 1 was met
+            const B1 = 1 << 1;
+
+            /// The condition of the hardware breakpoint 2 was met
+            const B2 = 1 << 2;
+
+            /// The condition of the hardware breakpoint 3 was met
+            const B3 = 1 << 3;
+
+            /// An access to a debug register was detected
+            const BD = 1 << 13;
+
+            /// The exception was raised by the single-step execution (see [`single_step`])
+            const BS = 1 << 14;
+
+            /// The exception was raised by a task switch
+            const BT = 1 << 15;
+        }
+    }
+
+    /// Read the debug status register (DR6). The bits are sticky: they are not cleared by the
+    /// processor, so the debug exception handler must call [`clear_status`].
+    #[must_use]
+    pub fn status() -> Status {
+        let value: u64;
+        unsafe {
+            asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags));
+        }
+        Status::from_bits_truncate(value)
+    }
+
+    /// Clear the debug status register (DR6).
+    ///
+    /// # Safety
+    /// This function is unsafe because the causes of the current debug exception are lost: it
+    /// must only be called once they have been handled.
+    pub unsafe fn clear_status() {
+        write_status(Status::empty());
+    }
+
+    /// Write the given causes to the debug status register (DR6), the other bits being set to
+    /// their reset value.
+    unsafe fn write_status(status: Status) {
+        let value = STATUS_RESET | status.bits();
+        asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags));
+    }
+
+    /// Enable the single-step execution of the given state: a debug exception (#DB, vector 1) is
+    /// raised after the next instruction executed when returning to this state, and after each
+    /// following instruction until [`handle_single_step`] or [`stop_single_step`] is called.
+    ///
+    /// The `syscall` instruction clears the trap flag when entering the kernel (see
+    /// [`crate::syscall::FLAGS_MASK`]) and `sysretq` restores it, so the system calls made by a
+    /// traced user thread are stepped over.
+    pub fn single_step(state: &mut State) {
+        state.rflags |= rflags::Flags::TF.bits();
+    }
+
+    /// Disable the single-step execution of the given state (see [`single_step`]).
+    pub fn stop_single_step(state: &mut State) {
+        state.rflags &= !rflags::Flags::TF.bits();
+    }
+
+    /// Returns `true` if the single-step execution of the given state is enabled.
+    #[must_use]
+    pub const fn is_single_stepping(state: &State) -> bool {
+        state.rflags & rflags::Flags::TF.bits() != 0
+    }
+
+    /// Handle a debug exception raised by the single-step execution of the given state (see
+    /// [`single_step`]). If the exception is a single-step trap, the trap flag is cleared in the
+    /// state, the single-step bit is cleared in the debug status and the address of the next
+    /// instruction to execute is returned. Otherwise, `None` is returned and nothing is changed.
+    /// In both cases, the other causes of the exception (like a hardware breakpoint) are left
+    /// in the debug status, to be handled by the caller.
+    ///
+    /// A debug exception handler tracing the execution instruction by instruction is typically
+    /// written as follows:
+    /// ```ignore
+    /// fn debug_exception(state: &mut State) {
+    ///     if let Some(address) = debug::handle_single_step(state) {
+    ///         log::trace!("Stepped to {address:?}");
+    ///         if tracing {
+    ///             debug::single_step(state);
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn handle_single_step(state: &mut State) -> Option<Virtual> {
+        if !status().contains(Status::BS) {
+            return None;
+        }
+
+        stop_single_step(state);
+        // SAFETY: Only the single-step cause, which is handled here, is cleared
+        unsafe { write_status(status() - Status::BS) };
+        Some(Virtual::new_truncate(state.rip))
+    }
+
+    /// Raise a breakpoint exception (#BP, vector 3) with the `int3` instruction. The exception is
+    /// a trap: if the handler returns, the execution continues after the instruction.
+    #[inline]
+    pub fn int3() {
+        // SAFETY: The breakpoint exception is resumable, and a missing handler is a bug of the
+        // kernel that would fault anyway
+        unsafe {
+            asm!("int3", options(nomem, nostack));
+        }
+    }
+
+    /// Raise an invalid opcode exception (#UD, vector 6) with the `ud2` instruction.
+    ///
+    /// # Safety
+    /// This function is unsafe because the exception is a fault: the saved instruction pointer
+    /// points to the `ud2` instruction, so the handler must not return without skipping it (it
+    /// is 2 bytes long), otherwise the exception is raised again.
+    #[inline]
+    pub unsafe fn ud2() {
+        asm!("ud2", options(nomem, nostack));
+    }
+
+    /// Execute the magic breakpoint of the Bochs emulator (`xchg bx, bx`), which stops Bochs in
+    /// its debugger if `magic_break: enabled=1` is set in its configuration. This is a no-op on
+    /// other emulators and on real hardware.
+    #[inline]
+    pub fn bochs_magic_break() {
+        // SAFETY: Exchanging a register with itself does nothing
+        unsafe {
+            asm!("xchg bx, bx", options(nomem, nostack, preserves_flags));
+        }
+    }
+
+    /// Raise a page fault (#PF, vector 14) by reading a byte at the given address, which must not
+    /// be mapped (or not readable). This is meant for testing the page fault handler: CR2 will
+    /// contain the given address. If the handler maps the page and returns, the read is retried
+    /// and this function returns normally.
+    ///
+    /// # Safety
+    /// This function is unsafe because the page fault handler must be installed, and if the
+    /// address is mapped, it must be readable without side effects (it must not be an MMIO
+    /// register, for example).
+    #[inline]
+    pub unsafe fn trigger_page_fault(address: Virtual) {
+        let _ = core::ptr::read_volatile(address.as_ptr::<u8>());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::address::Virtual;
+    use core::mem::size_of;
+
+    #[test]
+    fn struct_size_checks() {
+        assert_eq!(size_of::<super::State>(), 23 * 8);
+    }
+
+    #[test]
+    fn state_mutation() {
+        use super::{rflags::Flags, State};
+
+        let mut state = State {
+            rip: 0x40_1000,
+            cs: 0x23,
+            ss: 0x1B,
+            rflags: (Flags::FIXED | Flags::IF | Flags::RF).bits(),
+            ..State::default()
+        };
+        assert!(state.from_user());
+
+        state.skip_instruction(2);
+        assert_eq!(state.rip, 0x40_1002);
+        assert_eq!(state.rflags, (Flags::FIXED | Flags::IF).bits());
+
+        state.set_return_value(42);
+        state.emulate_iret_to(Virtual::new(0x40_2000));
+        assert_eq!((state.rax, state.rip), (42, 0x40_2000));
+        assert_eq!(super::rflags::read() & Flags::FIXED, Flags::FIXED);
+    }
+
+    #[test]
+    fn single_step() {
+        use super::{debug, State};
+
+        let mut state = State {
+            rflags: 0x202,
+            ..State::default()
+        };
+        debug::single_step(&mut state);
+        assert_eq!(state.rflags, 0x302);
+        assert!(debug::is_single_stepping(&state));
+        debug::stop_single_step(&mut state);
+        assert_eq!(state.rflags, 0x202);
+    }
+
+    #[test]
+    fn iopl() {
+        use super::{Privilege, State};
+
+        let mut state = State {
+            cs: 0x23,
+            ss: 0x1B,
+            rflags: 0x202,
+            ..State::default()
+        };
+        assert_eq!(super::iopl(&state), Privilege::KERNEL);
+        super::set_iopl(&mut state, Privilege::USER);
+        assert_eq!(state.rflags, 0x3202);
+        assert_eq!(super::iopl(&state), Privilege::USER);
+        super::set_iopl(&mut state, Privilege::Ring1);
+        assert_eq!(state.rflags, 0x1202);
+    }
+
+    #[test]
+    fn cached_features() {
+        use super::feature::Feature;
+
+        let leaf1 = unsafe { core::arch::x86_64::__cpuid(1) };
+        let features = super::features();
+        assert_eq!(features.has(Feature::Tsc), leaf1.edx & (1 << 4) != 0);
+        assert_eq!(features.has(Feature::Pcid), leaf1.ecx & (1 << 17) != 0);
+        assert_eq!(super::features(), features);
+        assert_eq!(features.bits() >> 31, 0);
+    }
+
+    #[test]
+    fn vendor_quirks() {
+        use super::quirks::{Pmu, Quirks, Vendor};
+
+        // Family 6, model 0x9E (Kaby Lake), and family 0x17 (Zen 2)
+        assert_eq!(super::feature::signature(0x0009_06EA), (0x06, 0x9E));
+        assert_eq!(super::feature::signature(0x0083_0F10), (0x17, 0x31));
+
+        let intel = Quirks::new(Vendor::Intel, 0x06, 0x9E);
+        assert!(intel.sysret_gp_in_kernel && !intel.compat_syscall && !intel.mce_skip_bank0);
+        assert_eq!(intel.pmu.unhalted_cycles(), Some(0x3C));
+        assert_eq!(intel.mce_first_bank(), 0);
+        assert_eq!(Quirks::new(Vendor::Intel, 0x06, 0x17).mce_first_bank(), 1);
+
+        let amd = Quirks::new(Vendor::Amd, 0x17, 0x31);
+        assert!(!amd.sysret_gp_in_kernel && amd.compat_syscall && !amd.mce_cmci);
+        assert_eq!(amd.pmu.counter(), Some(0xC001_0004));
+        assert_eq!(amd.mce_bank_ctl(4), !0);
+        assert_eq!(Quirks::new(Vendor::Amd, 0x06, 0x08).mce_first_bank(), 1);
+
+        let k8 = Quirks::new(Vendor::Amd, 0x0F, 0x41);
+        assert_eq!(k8.mce_bank_ctl(4), !(1 << 10));
+        assert_eq!(k8.mce_bank_ctl(3), !0);
+        assert_eq!(Quirks::new(Vendor::Other, 0, 0).pmu, Pmu::None);
+    }
+
+    #[test]
+    fn kernel_context_prepare() {
+        #[repr(align(16))]
+        struct Stack([u64; 4]);
+
+        extern "C" fn entry() -> ! {
+            unreachable!()
+        }
+
+        let entry: extern "C" fn() -> ! = entry;
+        let mut stack = Stack([0; 4]);
+        let top = stack.0.as_mut_ptr() as u64 + 32;
+        let context = unsafe { super::KernelContext::prepare(Virtual::new(top), entry) };
+        assert_eq!(context.rsp, top - 16);
+        assert_eq!(stack.0[2], entry as usize as u64);
+        assert_eq!(stack.0[3], 0);
+    }
+
+    #[test]
+    fn call_with_stack() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static STACK_POINTER: AtomicUsize = AtomicUsize::new(0);
+        static ARGUMENT: AtomicUsize = AtomicUsize::new(0);
+
+        extern "C" fn function(arg: usize) {
+            let local = 0u8;
+            STACK_POINTER.store(core::ptr::addr_of!(local) as usize, Ordering::Relaxed);
+            ARGUMENT.store(arg, Ordering::Relaxed);
+        }
+
+        let mut stack = vec![0u8; 64 * 1024];
+        let bottom = stack.as_mut_ptr() as usize;
+        let top = (bottom + stack.len()) & !15;
+        unsafe { super::call_with_stack(Virtual::new(top as u64), function, 42) };
+        assert_eq!(ARGUMENT.load(Ordering::Relaxed), 42);
+        assert!((bottom..top).contains(&STACK_POINTER.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn state_display() {
+        let state = super::State {
+            rip: 0xFFFF_8000_0000_1000,
+            number: 13,
+            code: 0x10,
+            ..Default::default()
+        };
+        let dump = format!("{state}");
+        assert_eq!(dump.lines().count(), 6);
+        assert!(dump.contains("RIP=ffff800000001000"));
+        assert!(dump.contains("(index=0x2, table=GDT, external=false)"));
+    }
+
+    #[test]
+    fn page_fault_info() {
+        use super::cr2::PageFaultInfo;
+        use crate::paging::PageFaultErrorCode;
+
+        let info = PageFaultInfo {
+            address: Virtual::new(0x1000),
+            cause: PageFaultErrorCode::from_bits_truncate(0b110),
+        };
+        assert!(info.not_present() && info.write() && info.user());
+        assert_eq!(
+            info.to_string(),
+            "page fault at 0x1000 (WRITE_ACCESS | CPU_USER_MODE)"
+        );
+    }
+
+    #[test]
+    fn pat_entries() {
+        use super::msr::pat::{MemoryType, Pat};
+
+        let pat = Pat::DEFAULT;
+        assert_eq!(pat.get(0), Some(MemoryType::WriteBack));
+        assert_eq!(pat.get(3), Some(MemoryType::Uncacheable));
+
+        let pat = pat.with(1, MemoryType::WriteCombining);
+        assert_eq!(pat.get(1), Some(MemoryType::WriteCombining));
+        assert_eq!(pat.value(), 0x0007_0406_0007_0106);
+    }
 }
@@ -0,0 +1,134 @@
+//! Deferred work, the mechanism behind softirqs and tasklets. An interrupt handler that has heavy
+//! work to do (for example, processing the packets received by a network card) can mark it as
+//! pending with [`raise`] and return quickly. The work is then done by the handler of
+//! [`VECTOR`], triggered by a self IPI, with interrupts enabled: since [`VECTOR`] has a lower
+//! priority class than all the device interrupts (see [`crate::irq_routing::FIRST_VECTOR`]),
+//! it only runs once the current interrupt handlers have finished, and can be interrupted by them.
+//!
+//! Up to 64 kinds of work are identified by their bit in a per-CPU pending mask. The handler of
+//! [`VECTOR`] installed by the kernel must:
+//! - send an end-of-interrupt to the local APIC, so that the other interrupts are not blocked,
+//! - enable the interrupts,
+//! - repeatedly call [`take_pending`] and do the pending work, until no work is pending,
+//! - disable the interrupts before returning.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    irq_priority::Class,
+    lapic::LocalApic,
+    smp::{Error, MAX_CPUS},
+};
+
+/// The interrupt vector of the deferred work. Its priority class ([`Class::DEFERRED`]) is lower
+/// than the one of all the device interrupts, and higher than the one of the legacy PIC vectors,
+/// which are not used when the local APIC is.
+pub const VECTOR: u8 = Class::DEFERRED.first_vector();
+
+/// The work pending on each CPU, indexed by CPU index (see [`crate::smp::register_cpu`]).
+static PENDING: [AtomicU64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// Mark the given kind of work as pending on the current CPU, and trigger [`VECTOR`] with a self
+/// IPI if no work was pending. This is intended to be called from an interrupt handler, with
+/// interrupts disabled.
+///
+/// # Errors
+/// Returns [`Error::UnknownCpu`] if the identifier of the current CPU is not a valid CPU index,
+/// or [`Error::Ipi`] if the self IPI cannot be sent (see [`LocalApic::self_ipi`]). In the latter
+/// case, the work stays pending, and will be done the next time [`VECTOR`] is triggered.
+///
+/// # Panics
+/// Panics if the kind of work is greater than 63.
+///
+/// # Safety
+/// This function is unsafe because the kernel must have installed a handler for [`VECTOR`] (see
+/// the module documentation), and the per-CPU area must have been initialized on the current CPU
+/// with its CPU index as identifier (see [`crate::percpu::cpu_id_fast`]).
+pub unsafe fn raise(lapic: &LocalApic, work: u8) -> Result<(), Error> {
+    raise_on(lapic, current_cpu(), work)
+}
+
+/// Returns the work pending on the current CPU, and clear it. This is called by the handler of
+/// [`VECTOR`]. No work is ever pending if the current CPU has no valid CPU index.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized on the current
+/// CPU (see [`raise`]).
+#[must_use]
+pub unsafe fn take_pending() -> u64 {
+    pending_mask(current_cpu()).map_or(0, |mask| mask.swap(0, Ordering::AcqRel))
+}
+
+/// Returns the work pending on the current CPU, without clearing it.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized on the current
+/// CPU (see [`raise`]).
+#[must_use]
+pub unsafe fn pending() -> u64 {
+    pending_mask(current_cpu()).map_or(0, |mask| mask.load(Ordering::Acquire))
+}
+
+/// Implementation of [`raise`], for the CPU with the given index.
+unsafe fn raise_on(lapic: &LocalApic, cpu: u64, work: u8) -> Result<(), Error> {
+    assert!(work < 64, "Invalid deferred work {work}");
+    let mask = pending_mask(cpu).ok_or(Error::UnknownCpu)?;
+    if mask.fetch_or(1 << work, Ordering::AcqRel) == 0 {
+        lapic.self_ipi(VECTOR).map_err(Error::Ipi)?;
+    }
+    Ok(())
+}
+
+/// Returns the CPU index of the current CPU, without accessing the memory if possible.
+unsafe fn current_cpu() -> u64 {
+    crate::percpu::cpu_id_fast()
+}
+
+/// Returns the pending mask of the CPU with the given index, or `None` if the index is invalid.
+fn pending_mask(cpu: u64) -> Option<&'static AtomicU64> {
+    usize::try_from(cpu).ok().and_then(|cpu| PENDING.get(cpu))
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::Ordering;
+
+    use crate::{
+        address::Virtual,
+        lapic::LocalApic,
+        mock::{self, Target},
+    };
+
+    #[repr(C, align(4096))]
+    struct Page([u32; 1024]);
+
+    #[test]
+    fn raise_and_take() {
+        let mut page = Box::new(Page([0; 1024]));
+        let base = Virtual::from_ptr(page.0.as_mut_ptr());
+        let lapic = unsafe { LocalApic::new_xapic(base) };
+        let icr = Target::Mmio(base.as_u64() + 0x300);
+        mock::reset();
+
+        // Only the first raise sends the self IPI. An explicit CPU index is used, so that the
+        // test does not depend on the per-CPU area of the host
+        unsafe { super::raise_on(&lapic, 3, 3) }.unwrap();
+        unsafe { super::raise_on(&lapic, 3, 5) }.unwrap();
+        let ipis: Vec<_> = mock::writes()
+            .into_iter()
+            .filter(|write| write.target == icr)
+            .map(|write| write.value)
+            .collect();
+        assert_eq!(ipis, [0x0004_4030]);
+
+        let mask = super::pending_mask(3).unwrap();
+        assert_eq!(mask.swap(0, Ordering::AcqRel), 0b10_1000);
+        assert_eq!(mask.load(Ordering::Acquire), 0);
+
+        let error = unsafe { super::raise_on(&lapic, super::MAX_CPUS as u64, 3) };
+        assert_eq!(error, Err(super::Error::UnknownCpu));
+    }
+}
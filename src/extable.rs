@@ -0,0 +1,171 @@
+//! The exception table, which allows an instruction that may fault (for example an access to a
+//! user pointer) to resume at a fixup address instead of crashing the kernel. Each entry of the
+//! table gives a range of instructions and the address where the execution continues if one of
+//! them faults. The entries are emitted from inline assembly with the [`crate::extable`] macro in
+//! the `silicium_extable` section, and the page fault and general protection fault handlers of
+//! the kernel must call [`fixup`] before treating a kernel fault as fatal.
+//!
+//! The linker collects the entries of all the object files, and defines the
+//! `__start_silicium_extable` and `__stop_silicium_extable` symbols around the section. With a
+//! custom linker script, the section must be kept in an output section of the same name:
+//! ```text
+//! silicium_extable : { KEEP(*(silicium_extable)) }
+//! ```
+use crate::cpu::State;
+
+/// An entry of the exception table. The addresses are stored as signed 32-bit offsets from the
+/// field containing them, so that the table does not need to be relocated when the kernel is
+/// position independent.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Entry {
+    start: i32,
+    end: i32,
+    fixup: i32,
+}
+
+impl Entry {
+    /// Returns the address of the first instruction of the range.
+    #[must_use]
+    pub fn start(&self) -> u64 {
+        resolve(&self.start)
+    }
+
+    /// Returns the address following the last instruction of the range.
+    #[must_use]
+    pub fn end(&self) -> u64 {
+        resolve(&self.end)
+    }
+
+    /// Returns the address where the execution continues if an instruction of the range faults.
+    #[must_use]
+    pub fn fixup(&self) -> u64 {
+        resolve(&self.fixup)
+    }
+
+    /// Returns `true` if the given instruction pointer is inside the range of this entry.
+    #[must_use]
+    pub fn contains(&self, rip: u64) -> bool {
+        rip >= self.start() && rip < self.end()
+    }
+}
+
+extern "C" {
+    #[linkage = "extern_weak"]
+    static __start_silicium_extable: *const Entry;
+    #[linkage = "extern_weak"]
+    static __stop_silicium_extable: *const Entry;
+}
+
+/// Emit an entry of the exception table from inline assembly. The arguments are the labels of the
+/// start and the end of the instruction range, and the label of the fixup code, as string
+/// literals. The macro expands to a string literal to put in the `asm!` template:
+/// ```ignore
+/// asm!(
+///     "2: mov {value}, [{ptr}]",
+///     "3:",
+///     silicium_x86_64::extable!("2b", "3b", "4f"),
+///     "jmp 5f",
+///     "4: mov {value}, 0",
+///     "5:",
+///     ptr = in(reg) ptr,
+///     value = out(reg) value,
+/// );
+/// ```
+#[macro_export]
+macro_rules! extable {
+    ($start:literal, $end:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection silicium_extable, \"aR\"\n",
+            ".balign 4\n",
+            ".long ",
+            $start,
+            " - .\n",
+            ".long ",
+            $end,
+            " - .\n",
+            ".long ",
+            $fixup,
+            " - .\n",
+            ".popsection\n"
+        )
+    };
+}
+
+/// Returns the entries of the exception table, collected by the linker.
+#[must_use]
+pub fn entries() -> &'static [Entry] {
+    // SAFETY: The symbols are either both defined by the linker around the section, which only
+    // contains entries, or both undefined (null) if the section is empty
+    unsafe {
+        let start = __start_silicium_extable;
+        let stop = __stop_silicium_extable;
+        if start.is_null() || stop <= start {
+            return &[];
+        }
+        let count = (stop as usize - start as usize) / core::mem::size_of::<Entry>();
+        core::slice::from_raw_parts(start, count)
+    }
+}
+
+/// Returns the fixup address of the instruction at the given address, or `None` if the
+/// instruction is not in the exception table.
+#[must_use]
+pub fn lookup(rip: u64) -> Option<u64> {
+    search(entries(), rip)
+}
+
+/// Resume the execution of the given faulting state at the fixup address of its instruction, if
+/// any. Returns `true` if the instruction pointer of the state has been changed, in which case
+/// the exception handler must return to the state instead of treating the fault as fatal.
+///
+/// Only the kernel instructions should be fixed up: the handler must check that the fault comes
+/// from kernel mode before calling this function.
+pub fn fixup(state: &mut State) -> bool {
+    match lookup(state.rip) {
+        Some(fixup) => {
+            state.rip = fixup;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Find the fixup address of the given instruction in the given entries.
+fn search(entries: &[Entry], rip: u64) -> Option<u64> {
+    entries
+        .iter()
+        .find(|entry| entry.contains(rip))
+        .map(Entry::fixup)
+}
+
+/// Returns the address stored as an offset from the given field of an entry.
+fn resolve(field: &i32) -> u64 {
+    (field as *const i32 as u64).wrapping_add_signed(i64::from(*field))
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn linked_entries() {
+        let (start, end, fixup): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "lea {start}, [rip + 2f]",
+                "lea {end}, [rip + 3f]",
+                "lea {fixup}, [rip + 4f]",
+                "2: nop",
+                "3:",
+                crate::extable!("2b", "3b", "4f"),
+                "4:",
+                start = out(reg) start,
+                end = out(reg) end,
+                fixup = out(reg) fixup,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        assert_eq!(super::lookup(start), Some(fixup));
+        assert_eq!(super::lookup(end), None);
+        assert!(super::entries().iter().any(|entry| entry.end() == end));
+    }
+}
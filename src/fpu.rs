@@ -0,0 +1,439 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cpu::cr0;
+
+/// Callback registered with [`set_restore_callback`]. It is called by
+/// [`handle_device_not_available`] to load the extended state of the current thread.
+static RESTORE_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// The legacy FXSAVE area, used to save the x87, MMX and SSE state of a thread.
+#[derive(Debug, Clone)]
+#[repr(C, align(16))]
+pub struct State([u8; 512]);
+
+impl State {
+    /// Creates a new zeroed FPU state. This state should not be restored directly because a
+    /// zeroed MXCSR register masks no exceptions: the state should be initialized by saving a
+    /// clean state (for example, just after a `fninit` instruction)
+    #[must_use]
+    pub const fn new() -> Self {
+        Self([0; 512])
+    }
+
+    /// Save the current x87, MMX and SSE state into this structure.
+    ///
+    /// # Safety
+    /// This function is unsafe because the TS flag must be cleared in the CR0 register (see
+    /// [`clts`]), otherwise the `fxsave64` instruction raises a device not available exception
+    /// (`#NM`). The state loaded on the CPU may also belong to another thread if its restore was
+    /// deferred.
+    pub unsafe fn save(&mut self) {
+        asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags));
+    }
+
+    /// Restore the x87, MMX and SSE state from this structure.
+    ///
+    /// # Safety
+    /// This function is unsafe because restoring a state with reserved bits set in the MXCSR
+    /// register will cause a general protection fault.
+    pub unsafe fn restore(&self) {
+        asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags));
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marker type returned by [`handle_device_not_available`] when the `#NM` exception was caused by
+/// a deferred FPU switch and the extended state of the current thread has been restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Restored;
+
+/// Set the task switched flag (TS) in the CR0 register. Once set, the first x87, MMX or SSE
+/// instruction executed will raise a device not available exception (`#NM`), allowing the kernel
+/// to restore the extended state only when it is really needed. This function should be called
+/// by the scheduler during a context switch, instead of restoring the extended state of the next
+/// thread.
+///
+/// # Safety
+/// This function is unsafe because the `#NM` exception must be correctly handled, otherwise any
+/// floating point instruction will crash the kernel: a restore callback must have been set with
+/// [`set_restore_callback`] and the `#NM` handler must call [`handle_device_not_available`].
+#[inline]
+pub unsafe fn set_ts() {
+    cr0::set(cr0::Flags::TS);
+}
+
+/// Clear the task switched flag (TS) in the CR0 register.
+///
+/// # Safety
+/// This function is unsafe because once the TS flag is cleared, the current thread can use the
+/// extended state currently loaded on the CPU, which may belong to another thread.
+#[inline]
+pub unsafe fn clts() {
+    asm!("clts", options(nomem, nostack, preserves_flags));
+}
+
+/// Returns `true` if the task switched flag (TS) is set in the CR0 register, meaning that the
+/// extended state restore of the current thread has been deferred.
+#[must_use]
+pub fn deferred() -> bool {
+    cr0::read_flags().contains(cr0::Flags::TS)
+}
+
+/// Set the callback used to restore the extended state of the current thread when a `#NM`
+/// exception is raised. The callback is usually a scheduler function that restores the FPU state
+/// of the current thread with [`State::restore`].
+pub fn set_restore_callback(callback: fn()) {
+    RESTORE_CALLBACK.store(callback as usize, Ordering::Release);
+}
+
+/// Handle a device not available exception (`#NM`). If the exception was caused by a deferred
+/// FPU switch, the TS flag is cleared and the restore callback is called to load the extended
+/// state of the current thread, and [`Restored`] is returned. Otherwise, `None` is returned and the
+/// exception must be handled by the caller.
+///
+/// # Panics
+/// This function panics if the TS flag is set but no restore callback has been set with
+/// [`set_restore_callback`].
+///
+/// # Safety
+/// This function is unsafe because it must only be called from the `#NM` exception handler.
+pub unsafe fn handle_device_not_available() -> Option<Restored> {
+    if !deferred() {
+        return None;
+    }
+
+    let callback = RESTORE_CALLBACK.load(Ordering::Acquire);
+    assert!(callback != 0, "No FPU restore callback set");
+
+    clts();
+    let callback: fn() = core::mem::transmute(callback);
+    callback();
+    Some(Restored)
+}
+
+/// Initialize the x87 FPU and the SSE unit to a defined state: all floating point exceptions are
+/// masked, the rounding mode is set to round-to-nearest and the x87 precision is set to extended
+/// precision.
+///
+/// # Safety
+/// This function is unsafe because it requires the OSFXSR flag to be set in the CR4 register,
+/// otherwise the `ldmxcsr` instruction will raise an invalid opcode exception.
+pub unsafe fn init() {
+    asm!("fninit", options(nomem, nostack, preserves_flags));
+    control_word::write(control_word::Flags::DEFAULT);
+    mxcsr::write(mxcsr::Flags::DEFAULT);
+}
+
+/// Rounding modes supported by the x87 FPU and the SSE unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to the nearest value (default)
+    Nearest = 0,
+
+    /// Round toward negative infinity
+    Down = 1,
+
+    /// Round toward positive infinity
+    Up = 2,
+
+    /// Round toward zero (truncate)
+    TowardZero = 3,
+}
+
+impl RoundingMode {
+    /// Create a rounding mode from the 2 bits value used by the MXCSR register and the x87
+    /// control word. Only the 2 lowest bits are used.
+    #[must_use]
+    pub const fn from_bits(bits: u16) -> Self {
+        match bits & 0b11 {
+            0 => Self::Nearest,
+            1 => Self::Down,
+            2 => Self::Up,
+            _ => Self::TowardZero,
+        }
+    }
+}
+
+pub mod mxcsr {
+    use core::arch::asm;
+
+    use bitflags::bitflags;
+
+    use super::RoundingMode;
+
+    bitflags! {
+        pub struct Flags: u32 {
+            /// Invalid operation flag
+            const IE = 1 << 0;
+
+            /// Denormal flag
+            const DE = 1 << 1;
+
+            /// Divide-by-zero flag
+            const ZE = 1 << 2;
+
+            /// Overflow flag
+            const OE = 1 << 3;
+
+            /// Underflow flag
+            const UE = 1 << 4;
+
+            /// Precision flag
+            const PE = 1 << 5;
+
+            /// Denormals are zeros
+            const DAZ = 1 << 6;
+
+            /// Invalid operation mask
+            const IM = 1 << 7;
+
+            /// Denormal operation mask
+            const DM = 1 << 8;
+
+            /// Divide-by-zero mask
+            const ZM = 1 << 9;
+
+            /// Overflow mask
+            const OM = 1 << 10;
+
+            /// Underflow mask
+            const UM = 1 << 11;
+
+            /// Precision mask
+            const PM = 1 << 12;
+
+            /// Rounding control, low bit
+            const RC_LOW = 1 << 13;
+
+            /// Rounding control, high bit
+            const RC_HIGH = 1 << 14;
+
+            /// Flush to zero
+            const FZ = 1 << 15;
+
+            /// All exception status flags
+            const EXCEPTION_FLAGS = Self::IE.bits | Self::DE.bits | Self::ZE.bits
+                | Self::OE.bits | Self::UE.bits | Self::PE.bits;
+
+            /// All exception masks
+            const EXCEPTION_MASKS = Self::IM.bits | Self::DM.bits | Self::ZM.bits
+                | Self::OM.bits | Self::UM.bits | Self::PM.bits;
+
+            /// Value of the MXCSR register after a reset: all exceptions are masked and the
+            /// rounding mode is set to round-to-nearest.
+            const DEFAULT = Self::EXCEPTION_MASKS.bits;
+        }
+    }
+
+    impl Flags {
+        /// Returns the rounding mode encoded in these flags.
+        #[must_use]
+        #[allow(clippy::cast_possible_truncation)]
+        pub const fn rounding_mode(&self) -> RoundingMode {
+            RoundingMode::from_bits((self.bits >> 13) as u16)
+        }
+
+        /// Returns these flags with the rounding mode set to the given mode.
+        #[must_use]
+        pub const fn with_rounding_mode(self, mode: RoundingMode) -> Self {
+            let bits = self.bits & !(Self::RC_LOW.bits | Self::RC_HIGH.bits);
+            Self::from_bits_truncate(bits | (mode as u32) << 13)
+        }
+    }
+
+    /// Read the current value of the MXCSR register.
+    #[must_use]
+    pub fn read() -> Flags {
+        let mut value: u32 = 0;
+        unsafe {
+            asm!(
+                "stmxcsr [{}]",
+                in(reg) core::ptr::addr_of_mut!(value),
+                options(nostack, preserves_flags),
+            );
+        }
+        Flags::from_bits_truncate(value)
+    }
+
+    /// Write the given flags to the MXCSR register.
+    ///
+    /// # Safety
+    /// This function is unsafe because unmasking an exception can cause unexpected SIMD
+    /// floating-point exceptions, which must be handled by the kernel.
+    pub unsafe fn write(flags: Flags) {
+        let value = flags.bits();
+        asm!("ldmxcsr [{}]", in(reg) core::ptr::addr_of!(value), options(nostack, preserves_flags));
+    }
+
+    /// Clear all exception status flags in the MXCSR register.
+    pub fn clear_exceptions() {
+        unsafe {
+            write(read() - Flags::EXCEPTION_FLAGS);
+        }
+    }
+}
+
+pub mod control_word {
+    use core::arch::asm;
+
+    use bitflags::bitflags;
+
+    use super::RoundingMode;
+
+    bitflags! {
+        pub struct Flags: u16 {
+            /// Invalid operation mask
+            const IM = 1 << 0;
+
+            /// Denormal operand mask
+            const DM = 1 << 1;
+
+            /// Zero divide mask
+            const ZM = 1 << 2;
+
+            /// Overflow mask
+            const OM = 1 << 3;
+
+            /// Underflow mask
+            const UM = 1 << 4;
+
+            /// Precision mask
+            const PM = 1 << 5;
+
+            /// Reserved, always set to 1 by `fninit`
+            const RESERVED = 1 << 6;
+
+            /// Precision control, low bit
+            const PC_LOW = 1 << 8;
+
+            /// Precision control, high bit
+            const PC_HIGH = 1 << 9;
+
+            /// Rounding control, low bit
+            const RC_LOW = 1 << 10;
+
+            /// Rounding control, high bit
+            const RC_HIGH = 1 << 11;
+
+            /// Infinity control (ignored on modern processors)
+            const X = 1 << 12;
+
+            /// All exception masks
+            const EXCEPTION_MASKS = Self::IM.bits | Self::DM.bits | Self::ZM.bits
+                | Self::OM.bits | Self::UM.bits | Self::PM.bits;
+
+            /// Value of the control word after a `fninit` instruction: all exceptions are masked,
+            /// the rounding mode is set to round-to-nearest and the precision is set to extended
+            /// precision.
+            const DEFAULT = Self::EXCEPTION_MASKS.bits | Self::RESERVED.bits
+                | Self::PC_LOW.bits | Self::PC_HIGH.bits;
+        }
+    }
+
+    /// Precision used by the x87 FPU for its computations.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Precision {
+        /// Single precision (24 bits mantissa)
+        Single = 0,
+
+        /// Double precision (53 bits mantissa)
+        Double = 2,
+
+        /// Double extended precision (64 bits mantissa, default)
+        Extended = 3,
+    }
+
+    impl Flags {
+        /// Returns the rounding mode encoded in these flags.
+        #[must_use]
+        pub const fn rounding_mode(&self) -> RoundingMode {
+            RoundingMode::from_bits(self.bits >> 10)
+        }
+
+        /// Returns these flags with the rounding mode set to the given mode.
+        #[must_use]
+        pub const fn with_rounding_mode(self, mode: RoundingMode) -> Self {
+            let bits = self.bits & !(Self::RC_LOW.bits | Self::RC_HIGH.bits);
+            Self::from_bits_truncate(bits | (mode as u16) << 10)
+        }
+
+        /// Returns the precision encoded in these flags. The reserved value (1) is reported
+        /// as extended precision.
+        #[must_use]
+        pub const fn precision(&self) -> Precision {
+            match (self.bits >> 8) & 0b11 {
+                0 => Precision::Single,
+                2 => Precision::Double,
+                _ => Precision::Extended,
+            }
+        }
+
+        /// Returns these flags with the precision set to the given precision.
+        #[must_use]
+        pub const fn with_precision(self, precision: Precision) -> Self {
+            let bits = self.bits & !(Self::PC_LOW.bits | Self::PC_HIGH.bits);
+            Self::from_bits_truncate(bits | (precision as u16) << 8)
+        }
+    }
+
+    /// Read the current value of the x87 FPU control word.
+    #[must_use]
+    pub fn read() -> Flags {
+        let mut value: u16 = 0;
+        unsafe {
+            asm!(
+                "fnstcw [{}]",
+                in(reg) core::ptr::addr_of_mut!(value),
+                options(nostack, preserves_flags),
+            );
+        }
+        Flags::from_bits_truncate(value)
+    }
+
+    /// Write the given flags to the x87 FPU control word.
+    ///
+    /// # Safety
+    /// This function is unsafe because unmasking an exception can cause unexpected x87
+    /// floating-point exceptions, which must be handled by the kernel.
+    pub unsafe fn write(flags: Flags) {
+        let value = flags.bits();
+        asm!("fldcw [{}]", in(reg) core::ptr::addr_of!(value), options(nostack, preserves_flags));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::mem::{align_of, size_of};
+
+    #[test]
+    fn struct_size_checks() {
+        assert_eq!(size_of::<super::State>(), 512);
+        assert_eq!(align_of::<super::State>(), 16);
+    }
+
+    #[test]
+    fn default_control_values() {
+        assert_eq!(super::mxcsr::Flags::DEFAULT.bits(), 0x1F80);
+        assert_eq!(super::control_word::Flags::DEFAULT.bits(), 0x037F);
+    }
+
+    #[test]
+    fn rounding_mode_encoding() {
+        let flags = super::mxcsr::Flags::DEFAULT.with_rounding_mode(super::RoundingMode::Up);
+        assert_eq!(flags.bits(), 0x5F80);
+        assert_eq!(flags.rounding_mode(), super::RoundingMode::Up);
+
+        let flags = super::control_word::Flags::DEFAULT
+            .with_rounding_mode(super::RoundingMode::TowardZero)
+            .with_precision(super::control_word::Precision::Double);
+        assert_eq!(flags.bits(), 0x0E7F);
+        assert_eq!(flags.rounding_mode(), super::RoundingMode::TowardZero);
+        assert_eq!(flags.precision(), super::control_word::Precision::Double);
+    }
+}
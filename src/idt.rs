@@ -216,6 +216,100 @@ impl Default for DescriptorFlags {
     }
 }
 
+/// A consuming builder of IDT descriptors. Unlike the `&mut self` builders of [`Descriptor`] and
+/// [`DescriptorFlags`], the handler must be given to create the builder, the built descriptor is
+/// always present, and the builder is consumed by [`DescriptorBuilder::build`].
+///
+/// By default, the descriptor is an interrupt gate (the IF flag is cleared when the handler is
+/// invoked), with a DPL of 0, no IST and the kernel code segment as selector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct DescriptorBuilder {
+    handler: u64,
+    selector: u16,
+    flags: u16,
+}
+
+impl DescriptorBuilder {
+    /// The present bit of the descriptor flags.
+    const PRESENT: u16 = 1 << 15;
+
+    /// The type of an interrupt gate and of a trap gate, in the descriptor flags.
+    const INTERRUPT_GATE: u16 = 0xE << 8;
+    const TRAP_GATE: u16 = 0xF << 8;
+
+    /// The masks of the DPL and of the IST fields of the descriptor flags.
+    const DPL_MASK: u16 = 0b11 << 13;
+    const IST_MASK: u16 = 0b111;
+
+    /// Creates a new builder for a descriptor invoking the given handler, which should be a
+    /// function generated by the [`interrupt_handler`] macro.
+    pub fn new(handler: unsafe extern "C" fn()) -> Self {
+        Self {
+            handler: handler as usize as u64,
+            selector: Selector::KERNEL_CODE64.value(),
+            flags: Self::INTERRUPT_GATE,
+        }
+    }
+
+    /// Use the given interrupt stack table entry, from 1 to 7, when the handler is invoked. The
+    /// entry 1 is the first stack of the `interrupt_stack_table` of the TSS.
+    ///
+    /// # Panics
+    /// Panics if the entry is not between 1 and 7.
+    pub const fn ist(self, entry: u8) -> Self {
+        assert!(
+            entry >= 1 && entry <= 7,
+            "IST entry must be between 1 and 7"
+        );
+        Self {
+            flags: (self.flags & !Self::IST_MASK) | entry as u16,
+            ..self
+        }
+    }
+
+    /// Set the privilege level required to invoke the handler with the `int` instruction. It is
+    /// ignored for hardware interrupts and exceptions.
+    pub const fn dpl(self, dpl: Privilege) -> Self {
+        Self {
+            flags: (self.flags & !Self::DPL_MASK) | (dpl as u16) << 13,
+            ..self
+        }
+    }
+
+    /// Use a trap gate instead of an interrupt gate: the IF flag is not cleared when the handler
+    /// is invoked. This must not be used with the handlers generated by [`interrupt_handler`],
+    /// which require the interrupts to be disabled.
+    pub const fn trap(self) -> Self {
+        Self {
+            flags: self.flags | Self::TRAP_GATE,
+            ..self
+        }
+    }
+
+    /// Set the segment selector loaded into the CS register when the handler is invoked.
+    pub const fn selector(self, selector: Selector) -> Self {
+        Self {
+            selector: selector.value(),
+            ..self
+        }
+    }
+
+    /// Build the descriptor, marked as present.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn build(self) -> Descriptor {
+        Descriptor {
+            offset_low: self.handler as u16,
+            selector: self.selector,
+            flags: DescriptorFlags(self.flags | Self::PRESENT),
+            offset_middle: (self.handler >> 16) as u16,
+            offset_high: (self.handler >> 32) as u32,
+            zero: 0,
+        }
+    }
+}
+
 #[repr(C, packed)]
 pub struct Register {
     limit: u16,
@@ -249,6 +343,26 @@ impl Register {
     }
 }
 
+/// The interrupt stack frame pushed by the CPU when an interrupt is triggered, given to the
+/// handlers generated by the [`light_interrupt_handler`] macro.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl InterruptFrame {
+    /// Returns `true` if the interrupt was triggered while running in user mode.
+    #[must_use]
+    pub const fn from_user(&self) -> bool {
+        self.cs & 0b11 != 0
+    }
+}
+
 /// This macro generates an interrupt handler.
 ///
 /// The handler is a naked function that pushes the interrupt ID and error code (if any) on the
@@ -271,6 +385,12 @@ impl Register {
 /// When your handler is invoked, your are free to re-enable interrupts if you want to, as their
 /// previous state will be restored when the interrupt is finished.
 ///
+/// The saved CS register is trusted to tell whether the GS register and the page tables must be
+/// switched, which is only true for the interrupts that cannot be triggered in the entry and exit
+/// windows of the kernel. The handlers of the vectors using an IST stack (the NMI, the debug
+/// exception, the machine check and the double fault) must be generated with the
+/// [`paranoid_interrupt_handler`] macro instead.
+///
 /// Failure to follow these rules will result in a undefined behavior, likely a crash.
 #[macro_export]
 #[cfg(feature = "int_handler")]
@@ -313,13 +433,419 @@ macro_rules! interrupt_handler {
     };
 }
 
+/// This macro generates an interrupt handler like the [`interrupt_handler`] macro, for the vectors
+/// using an IST stack (the NMI, the debug exception, the machine check and the double fault).
+/// These interrupts can be triggered between the `swapgs` instruction and the switch of the page
+/// tables of the kernel entry and exit code, where the saved CS register is the kernel one but
+/// the GS register or the page tables are still the user ones. The generated handler calls
+/// [`paranoid_interrupt_enter`] and [`paranoid_interrupt_exit`], which check the GS base and CR3
+/// registers themselves.
+///
+/// # Warning
+/// The rules of the [`interrupt_handler`] macro apply. In addition, the handler must not switch
+/// to another thread, and must not modify the interrupt number of the state, which is used by
+/// [`paranoid_interrupt_exit`].
+#[macro_export]
+#[cfg(feature = "int_handler")]
+macro_rules! paranoid_interrupt_handler {
+    ($id:expr, $name:ident, $handler:ident) => {
+        #[naked]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            core::arch::asm!("
+                push {id}
+                call paranoid_interrupt_enter
+                call {handler}
+                jmp paranoid_interrupt_exit
+                ",
+                id = const $id,
+                handler = sym $handler,
+                options(noreturn));
+        }
+    };
+    ($id:expr, $name:ident, $handler:ident, $err:expr) => {
+        #[naked]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            core::arch::asm!("
+                push {err}
+                push {id}
+                call paranoid_interrupt_enter
+                call {handler}
+                jmp paranoid_interrupt_exit
+                ",
+                err = const $err,
+                id = const $id,
+                handler = sym $handler,
+                options(noreturn));
+        }
+    };
+}
+
+/// This macro generates a lightweight interrupt handler, for the interrupts that do not push an
+/// error code and whose handler only needs the interrupt stack frame pushed by the CPU, for example
+/// the spurious interrupt and the local APIC timer handlers.
+///
+/// Unlike the [`interrupt_handler`] macro, the handler only saves the scratch registers (the
+/// preserved registers are saved by the handler itself if it uses them, as required by the
+/// system V ABI), and it does not save and switch the FS register, which requires four slow MSR
+/// accesses. The GS register, the page table isolation and the MDS mitigation are handled as
+/// usual (see [`light_interrupt_enter`] and [`light_interrupt_exit`]).
+///
+/// # Warning
+/// The handler must have the following signature:
+/// ``` extern "C" fn handler(_: &silicium_x86_86::idt::InterruptFrame) ```
+///
+/// Since the FS register is not switched, the handler must not access thread-local data. It also
+/// cannot switch to another thread, since the state of the interrupted code is not saved in a
+/// [`crate::cpu::State`]. The rules of the [`interrupt_handler`] macro about the interrupts also
+/// apply, and the handlers of the vectors using an IST stack must be generated with the
+/// [`paranoid_light_interrupt_handler`] macro instead.
+#[macro_export]
+#[cfg(feature = "light_int_handler")]
+macro_rules! light_interrupt_handler {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            core::arch::asm!("
+                call light_interrupt_enter
+                call {handler}
+                jmp light_interrupt_exit
+                ",
+                handler = sym $handler,
+                options(noreturn));
+        }
+    };
+}
+
+/// This macro generates a lightweight interrupt handler like the [`light_interrupt_handler`]
+/// macro, for the vectors using an IST stack (for example, a NMI handler calling
+/// [`crate::watchdog::check`]). The generated handler calls [`paranoid_light_interrupt_enter`] and
+/// [`paranoid_light_interrupt_exit`], which do not trust the saved CS register (see the
+/// [`paranoid_interrupt_handler`] macro).
+///
+/// # Warning
+/// The rules of the [`light_interrupt_handler`] macro apply.
+#[macro_export]
+#[cfg(feature = "light_int_handler")]
+macro_rules! paranoid_light_interrupt_handler {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            core::arch::asm!("
+                call paranoid_light_interrupt_enter
+                call {handler}
+                jmp paranoid_light_interrupt_exit
+                ",
+                handler = sym $handler,
+                options(noreturn));
+        }
+    };
+}
+
+/// Prepare a handler generated by the [`light_interrupt_handler`] macro to be called:
+///  - Clear the direction flag (DF) in the EFLAGS register, swap the GS register, fence it and
+///    switch to the kernel page tables if needed, like the [`interrupt_enter`] function.
+///
+///  - Save the scratch registers (RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11) on the stack, and
+///    align the stack on a 16 bytes boundary.
+///
+///  - Prepare the argument for the handler, a pointer to the interrupt stack frame.
+#[naked]
+#[no_mangle]
+#[linkage = "weak"]
+#[cfg(feature = "light_int_handler")]
+pub unsafe extern "C" fn light_interrupt_enter() {
+    asm!(
+        "
+        # Needed by the system V ABI
+        cld
+
+        # Swap gs if needed. The interrupt stack frame directly follows the return address.
+        cmp QWORD PTR [rsp + 8 * 2], 0x08    # 0x08 is the selector for the CS kernel selector
+        je 2f
+        swapgs
+
+        # Prevent the GS-relative loads below from being speculatively executed with the user
+        # GS (SWAPGS variant of Spectre v1), unless disabled. The fence is on the fall-through
+        # path of the check, which is the static prediction of a forward branch
+        cmp BYTE PTR [rip + {swapgs_fence}], 0
+        je 4f
+        lfence
+       4:
+
+        # Switch to the kernel page tables if the page table isolation is enabled
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 1f
+        push rax
+        mov rax, cr3
+        or rax, gs:[{pti_cr3}]
+        btr rax, 12
+        btr rax, 11
+        mov cr3, rax
+        pop rax
+        jmp 1f
+
+        # The kernel path must also be fenced, since the branch above can be mispredicted
+       2:
+        cmp BYTE PTR [rip + {swapgs_fence}], 0
+        je 1f
+        lfence
+       1:
+
+        # Save scratch registers
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+
+        # Stack should be aligned on a 16 bytes boundary: the CPU pushed 5 registers and we
+        # pushed the return address and 9 registers
+        sub rsp, 8
+
+        # Prepare the argument for the handler, skipping the padding, the scratch registers and
+        # the return address
+        lea rdi, [rsp + 8 * 11]
+
+        # Return to the handler stub
+        jmp QWORD PTR [rsp + 8 * 10]
+        ",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        swapgs_fence = sym crate::cpu::mitigations::SWAPGS_FENCE,
+        options(noreturn)
+    );
+}
+
+/// Restore the context after a handler generated by the [`light_interrupt_handler`] macro (the
+/// opposite of the [`light_interrupt_enter`] function): restore the scratch registers, skip the
+/// return address used by [`light_interrupt_enter`], handle the GS register, the page table
+/// isolation and the MDS mitigation like the [`interrupt_exit`] function, and perform an `iretq`
+/// instruction.
+#[naked]
+#[no_mangle]
+#[linkage = "weak"]
+#[cfg(feature = "light_int_handler")]
+pub unsafe extern "C" fn light_interrupt_exit() {
+    asm!(
+        "
+        # Skip the padding
+        add rsp, 8
+
+        # Restore scratch registers
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+
+        # Skip the return address
+        add rsp, 8
+
+        # Swapgs if necessary
+        cli                              # To avoid race condition
+        cmp QWORD PTR [rsp + 8], 0x08    # 0x08 is the selector for the CS kernel selector
+        je 1f
+
+        # Switch to the user page tables if the page table isolation is enabled
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 3f
+        push rax
+        mov rax, cr3
+        or rax, gs:[{pti_cr3}]
+        mov cr3, rax
+        pop rax
+       3:
+
+        # Clear the CPU buffers if the MDS mitigation is enabled. This must be done after the
+        # last access to the kernel data, otherwise it would be left in the buffers
+        cmp BYTE PTR [rip + {clear_cpu_buffers}], 0
+        je 2f
+        verw WORD PTR [rip + {verw_selector}]
+       2:
+        swapgs
+       1:
+        iretq",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym crate::cpu::mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym crate::cpu::mitigations::VERW_SELECTOR,
+        options(noreturn)
+    );
+}
+
+/// Prepare a handler generated by the [`paranoid_light_interrupt_handler`] macro to be called, like
+/// the [`light_interrupt_enter`] function, but without trusting the saved CS register:
+///  - Save the scratch registers on the stack. The IST stacks are mapped by the user page tables
+///    (see [`crate::pti`]), so this can be done before switching them.
+///
+///  - Swap the GS register if the GS base is not a kernel address, which is read from the MSR.
+///    Both paths are followed by an `lfence` instruction, like in [`interrupt_enter`].
+///
+///  - Switch to the kernel page tables if the page table isolation is enabled and CR3 selects the
+///    user PML4 (see [`crate::pti::USER_PML4_BIT`]).
+///
+///  - Save whether the GS register was swapped and the previous CR3 (or 0 if it was not switched)
+///    on the stack, for [`paranoid_light_interrupt_exit`].
+///
+/// The user GS base must never be a kernel address, otherwise the GS register would not be
+/// swapped.
+#[naked]
+#[no_mangle]
+#[linkage = "weak"]
+#[cfg(feature = "light_int_handler")]
+pub unsafe extern "C" fn paranoid_light_interrupt_enter() {
+    asm!(
+        "
+        # Needed by the system V ABI
+        cld
+
+        # Reserve the slots of the GS and CR3 state, between the return address and the scratch
+        # registers
+        sub rsp, 8 * 2
+
+        # Save scratch registers
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+
+        # Swap gs if the GS base is not a kernel address. The saved CS cannot be trusted, since
+        # the interrupt may have been triggered just before or after a swapgs in kernel mode
+        mov ecx, 0xC0000101
+        rdmsr
+        xor esi, esi
+        test edx, edx
+        js 2f
+        swapgs
+        mov esi, 1
+       2:
+
+        # Prevent the GS-relative loads below from being speculatively executed with the wrong
+        # GS (SWAPGS variant of Spectre v1), unless disabled
+        cmp BYTE PTR [rip + {swapgs_fence}], 0
+        je 4f
+        lfence
+       4:
+
+        # Switch to the kernel page tables if the page table isolation is enabled and the user
+        # PML4 is active, whatever the privilege level of the interrupted code
+        xor edi, edi
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 3f
+        mov rax, cr3
+        bt rax, 12
+        jnc 3f
+        mov rdi, rax
+        or rax, gs:[{pti_cr3}]
+        btr rax, 12
+        btr rax, 11
+        mov cr3, rax
+       3:
+        mov [rsp + 8 * 9], rsi
+        mov [rsp + 8 * 10], rdi
+
+        # Stack should be aligned on a 16 bytes boundary: the CPU pushed 5 registers and we
+        # pushed the return address, the 2 slots and 9 registers
+        sub rsp, 8
+
+        # Prepare the argument for the handler, skipping the padding, the scratch registers, the
+        # slots and the return address
+        lea rdi, [rsp + 8 * 13]
+
+        # Return to the handler stub
+        jmp QWORD PTR [rsp + 8 * 12]
+        ",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        swapgs_fence = sym crate::cpu::mitigations::SWAPGS_FENCE,
+        options(noreturn)
+    );
+}
+
+/// Restore the context after a handler generated by the [`paranoid_light_interrupt_handler`] macro
+/// (the opposite of the [`paranoid_light_interrupt_enter`] function): restore the scratch
+/// registers, restore the previous CR3 if it was switched, clear the CPU buffers if returning to
+/// user mode, swap the GS register back if it was swapped, and perform an `iretq` instruction.
+#[naked]
+#[no_mangle]
+#[linkage = "weak"]
+#[cfg(feature = "light_int_handler")]
+pub unsafe extern "C" fn paranoid_light_interrupt_exit() {
+    asm!(
+        "
+        # Skip the padding
+        add rsp, 8
+
+        # Restore scratch registers
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+
+        # The stack contains the GS slot, the CR3 slot, the return address and the interrupt
+        # stack frame
+        cli
+        cmp QWORD PTR [rsp + 8], 0
+        je 3f
+        push rax
+        mov rax, [rsp + 8 * 2]
+        or rax, gs:[{pti_cr3}]
+        mov cr3, rax
+        pop rax
+       3:
+
+        # Clear the CPU buffers if the MDS mitigation is enabled and we return to user mode,
+        # after the last access to the kernel data
+        cmp QWORD PTR [rsp + 8 * 4], 0x08    # 0x08 is the selector for the CS kernel selector
+        je 2f
+        cmp BYTE PTR [rip + {clear_cpu_buffers}], 0
+        je 2f
+        verw WORD PTR [rip + {verw_selector}]
+       2:
+
+        # Swap gs back if it was swapped, and skip the slots and the return address without
+        # changing the flags
+        cmp QWORD PTR [rsp], 0
+        lea rsp, [rsp + 8 * 3]
+        je 1f
+        swapgs
+       1:
+        iretq",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym crate::cpu::mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym crate::cpu::mitigations::VERW_SELECTOR,
+        options(noreturn)
+    );
+}
+
 /// This macro prepare a rust interrupt handler to be called. It is used by the [`interrupt_handler`]
 /// macro, and performs the following actions:
 ///  - Clear the direction flag (DF) in the EFLAGS register. This is required by the system V ABI.
 ///
 ///  - Swap the GS register if needed with the `swapgs` instruction. The GS register is swapped if
 ///    the interrupt was triggered from user mode. This is required because the GS register could be
-///    used by the user code, andthe kernel use it to store TLS data.
+///    used by the user code, andthe kernel use it to store TLS data. Both paths are followed by an
+///    `lfence` instruction, so that the GS-relative loads cannot be speculatively executed with
+///    the wrong GS base (see [`crate::cpu::mitigations::set_swapgs_fence`]).
 ///
 ///  - Save the scratch registers (RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11) on the stack.
 ///
@@ -342,10 +868,37 @@ pub unsafe extern "C" fn interrupt_enter() {
         # Needed by the system V ABI
         cld
 
-        # Swap gs if needed
-        cmp QWORD PTR [rsp + 8 * 2], 0x08    # 0x08 is the selector for the CS kernel selector
-        je 1f
+        # Swap gs if needed. The interrupt stack frame follows the return address, the interrupt
+        # number and the error code
+        cmp QWORD PTR [rsp + 8 * 4], 0x08    # 0x08 is the selector for the CS kernel selector
+        je 2f
         swapgs
+
+        # Prevent the GS-relative loads below from being speculatively executed with the user
+        # GS (SWAPGS variant of Spectre v1), unless disabled. The fence is on the fall-through
+        # path of the check, which is the static prediction of a forward branch
+        cmp BYTE PTR [rip + {swapgs_fence}], 0
+        je 4f
+        lfence
+       4:
+
+        # Switch to the kernel page tables if the page table isolation is enabled
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 1f
+        push rax
+        mov rax, cr3
+        or rax, gs:[{pti_cr3}]
+        btr rax, 12
+        btr rax, 11
+        mov cr3, rax
+        pop rax
+        jmp 1f
+
+        # The kernel path must also be fenced, since the branch above can be mispredicted
+       2:
+        cmp BYTE PTR [rip + {swapgs_fence}], 0
+        je 1f
+        lfence
        1:
         
         # Save scratch registers
@@ -390,6 +943,8 @@ pub unsafe extern "C" fn interrupt_enter() {
         mov rax, [rsp + 16 * 8]
         jmp rax
         ",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        swapgs_fence = sym crate::cpu::mitigations::SWAPGS_FENCE,
         options(noreturn)
     );
 }
@@ -442,9 +997,212 @@ pub unsafe extern "C" fn interrupt_exit() {
         cli                              # To avoid race condition
         cmp QWORD PTR [rsp + 8], 0x08    # 0x08 is the selector for the CS kernel selector
         je 1f
+
+        # Switch to the user page tables if the page table isolation is enabled
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 3f
+        push rax
+        mov rax, cr3
+        or rax, gs:[{pti_cr3}]
+        mov cr3, rax
+        pop rax
+       3:
+
+        # Clear the CPU buffers if the MDS mitigation is enabled. This must be done after the
+        # last access to the kernel data, otherwise it would be left in the buffers
+        cmp BYTE PTR [rip + {clear_cpu_buffers}], 0
+        je 2f
+        verw WORD PTR [rip + {verw_selector}]
+       2:
+        swapgs
+       1:
+        iretq",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym crate::cpu::mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym crate::cpu::mitigations::VERW_SELECTOR,
+        options(noreturn)
+    );
+}
+
+/// Prepare a handler generated by the [`paranoid_interrupt_handler`] macro to be called, like the
+/// [`interrupt_enter`] function, but without trusting the saved CS register: the GS register is
+/// swapped if the GS base is not a kernel address, and the kernel page tables are loaded if the
+/// page table isolation is enabled and CR3 selects the user PML4, as done by
+/// [`paranoid_light_interrupt_enter`]. Whether the GS register was swapped and the previous CR3
+/// (or 0 if it was not switched) are pushed on the stack below the saved FS register, for
+/// [`paranoid_interrupt_exit`].
+///
+/// The user GS base must never be a kernel address, otherwise the GS register would not be
+/// swapped.
+#[naked]
+#[no_mangle]
+#[linkage = "weak"]
+#[cfg(feature = "int_handler")]
+pub unsafe extern "C" fn paranoid_interrupt_enter() {
+    asm!(
+        "
+        # Needed by the system V ABI
+        cld
+
+        # Save scratch registers. The IST stacks are mapped by the user page tables, so this can
+        # be done before switching them
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+
+        # Swap gs if the GS base is not a kernel address. The saved CS cannot be trusted, since
+        # the interrupt may have been triggered just before or after a swapgs in kernel mode
+        mov ecx, 0xC0000101
+        rdmsr
+        xor esi, esi
+        test edx, edx
+        js 2f
+        swapgs
+        mov esi, 1
+       2:
+
+        # Prevent the GS-relative loads below from being speculatively executed with the wrong
+        # GS (SWAPGS variant of Spectre v1), unless disabled
+        cmp BYTE PTR [rip + {swapgs_fence}], 0
+        je 4f
+        lfence
+       4:
+
+        # Switch to the kernel page tables if the page table isolation is enabled and the user
+        # PML4 is active, whatever the privilege level of the interrupted code
+        xor edi, edi
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 3f
+        mov rax, cr3
+        bt rax, 12
+        jnc 3f
+        mov rdi, rax
+        or rax, gs:[{pti_cr3}]
+        btr rax, 12
+        btr rax, 11
+        mov cr3, rax
+       3:
+
+        # Save preserved registers
+        push r15
+        push r14
+        push r13
+        push r12
+        push rbx
+        push rbp
+
+        # Save the FS base, and set it to the kernel GS base
+        mov ecx, 0xC0000100
+        rdmsr
+        shl rdx, 32
+        or rdx, rax
+        push rdx
+        mov ecx, 0xC0000101
+        rdmsr
+        mov ecx, 0xC0000100
+        wrmsr
+
+        # Save the GS and CR3 state. The stack stays aligned on a 16 bytes boundary
+        push rdi
+        push rsi
+
+        # Prepare the argument for the handler, skipping the slots and the saved FS register
+        lea rdi, [rsp + 8 * 3]
+
+        # We pushed 18 registers, so the return address is at rsp + 18 * 8
+        mov rax, [rsp + 18 * 8]
+        jmp rax
+        ",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        swapgs_fence = sym crate::cpu::mitigations::SWAPGS_FENCE,
+        options(noreturn)
+    );
+}
+
+/// Restore the context after a handler generated by the [`paranoid_interrupt_handler`] macro (the
+/// opposite of the [`paranoid_interrupt_enter`] function). The GS and CR3 slots are first moved to
+/// the interrupt number and return address slots of the state, which are not used anymore. The FS
+/// register and the general purpose registers are then restored, the previous CR3 is restored if
+/// it was switched, the CPU buffers are cleared if returning to user mode, and the GS register is
+/// swapped back if it was swapped before the `iretq` instruction.
+#[naked]
+#[no_mangle]
+#[linkage = "weak"]
+#[cfg(feature = "int_handler")]
+pub unsafe extern "C" fn paranoid_interrupt_exit() {
+    asm!(
+        "
+        # Move the GS slot to the interrupt number slot, and the CR3 slot to the return address
+        # slot (after the slots, the saved FS register and 15 general purpose registers)
+        pop rax
+        mov [rsp + 8 * 18], rax
+        pop rax
+        mov [rsp + 8 * 16], rax
+
+        # Restore FS
+        pop rdx
+        mov eax, edx
+        shr rdx, 32
+        mov ecx, 0xC0000100
+        wrmsr
+
+        # Restore preserved registers
+        pop rbp
+        pop rbx
+        pop r12
+        pop r13
+        pop r14
+        pop r15
+
+        # Restore scratch registers
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+
+        # The stack contains the CR3 slot, the GS slot, the error code and the interrupt stack
+        # frame
+        cli
+        cmp QWORD PTR [rsp], 0
+        je 3f
+        push rax
+        mov rax, [rsp + 8]
+        or rax, gs:[{pti_cr3}]
+        mov cr3, rax
+        pop rax
+       3:
+
+        # Clear the CPU buffers if the MDS mitigation is enabled and we return to user mode,
+        # after the last access to the kernel data
+        cmp QWORD PTR [rsp + 8 * 4], 0x08    # 0x08 is the selector for the CS kernel selector
+        je 2f
+        cmp BYTE PTR [rip + {clear_cpu_buffers}], 0
+        je 2f
+        verw WORD PTR [rip + {verw_selector}]
+       2:
+
+        # Swap gs back if it was swapped, and skip the slots and the error code without changing
+        # the flags
+        cmp QWORD PTR [rsp + 8], 0
+        lea rsp, [rsp + 8 * 3]
+        je 1f
         swapgs
        1:
         iretq",
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym crate::cpu::mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym crate::cpu::mitigations::VERW_SELECTOR,
         options(noreturn)
     );
 }
@@ -453,9 +1211,36 @@ pub unsafe extern "C" fn interrupt_exit() {
 mod test {
     use core::mem::size_of;
 
+    use crate::{cpu::Privilege, segment::Selector};
+
     #[test]
     fn struct_size_checks() {
         assert_eq!(size_of::<super::Descriptor>(), 16);
         assert_eq!(size_of::<super::Register>(), 10);
+        assert_eq!(size_of::<super::InterruptFrame>(), 5 * 8);
+    }
+
+    #[test]
+    fn descriptor_builder() {
+        unsafe extern "C" fn handler() {}
+
+        let address = handler as unsafe extern "C" fn() as usize as u64;
+        let descriptor = super::DescriptorBuilder::new(handler)
+            .ist(1)
+            .dpl(Privilege::Ring3)
+            .build();
+        let low = u64::from(descriptor.offset_low);
+        let middle = u64::from(descriptor.offset_middle);
+        let high = u64::from(descriptor.offset_high);
+        assert_eq!(low | middle << 16 | high << 32, address);
+        assert_eq!({ descriptor.flags }.0, 0xEE01);
+        assert_eq!({ descriptor.selector }, Selector::KERNEL_CODE64.value());
+
+        let descriptor = super::DescriptorBuilder::new(handler)
+            .ist(3)
+            .ist(2)
+            .trap()
+            .build();
+        assert_eq!({ descriptor.flags }.0, 0x8F02);
     }
 }
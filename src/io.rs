@@ -1,5 +1,6 @@
-use core::arch::asm;
 use core::marker::PhantomData;
+use core::ops::{BitAnd, BitOr, Not, Range};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 pub trait IO {
     /// Write a value to a port.
@@ -92,6 +93,34 @@ impl<T: IO> Port<T> {
     pub fn read(&self) -> T {
         unsafe { T::read(self.port) }
     }
+
+    /// Read the port, and write back the value returned by the given function. The read and the
+    /// write are not atomic: the caller must make sure that the port is not accessed concurrently.
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// Same as [`Port::update`], but pause for a short time after the write (see
+    /// [`IO::write_and_pause`]).
+    pub fn update_and_pause(&self, f: impl FnOnce(T) -> T) {
+        self.write_and_pause(f(self.read()));
+    }
+
+    /// Set the given bits of the port, keeping the other bits (see [`Port::update`]).
+    pub fn set_bits(&self, mask: T)
+    where
+        T: BitOr<Output = T>,
+    {
+        self.update(|value| value | mask);
+    }
+
+    /// Clear the given bits of the port, keeping the other bits (see [`Port::update`]).
+    pub fn clear_bits(&self, mask: T)
+    where
+        T: BitAnd<Output = T> + Not<Output = T>,
+    {
+        self.update(|value| value & !mask);
+    }
 }
 
 pub struct UnsafePort<T> {
@@ -120,41 +149,376 @@ impl<T: IO> UnsafePort<T> {
     pub unsafe fn read(&self) -> T {
         T::read(self.port)
     }
+
+    /// Read the port, and write back the value returned by the given function. The read and the
+    /// write are not atomic: the caller must make sure that the port is not accessed concurrently.
+    ///
+    /// # Safety
+    /// This function is unsafe for the same reasons as [`UnsafePort::write`].
+    pub unsafe fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    /// Same as [`UnsafePort::update`], but pause for a short time after the write (see
+    /// [`IO::write_and_pause`]).
+    ///
+    /// # Safety
+    /// This function is unsafe for the same reasons as [`UnsafePort::write`].
+    pub unsafe fn update_and_pause(&self, f: impl FnOnce(T) -> T) {
+        self.write_and_pause(f(self.read()));
+    }
+
+    /// Set the given bits of the port, keeping the other bits (see [`UnsafePort::update`]).
+    ///
+    /// # Safety
+    /// This function is unsafe for the same reasons as [`UnsafePort::write`].
+    pub unsafe fn set_bits(&self, mask: T)
+    where
+        T: BitOr<Output = T>,
+    {
+        self.update(|value| value | mask);
+    }
+
+    /// Clear the given bits of the port, keeping the other bits (see [`UnsafePort::update`]).
+    ///
+    /// # Safety
+    /// This function is unsafe for the same reasons as [`UnsafePort::write`].
+    pub unsafe fn clear_bits(&self, mask: T)
+    where
+        T: BitAnd<Output = T> + Not<Output = T>,
+    {
+        self.update(|value| value & !mask);
+    }
 }
 
+/// The maximal number of port regions that can be claimed at the same time.
+pub const MAX_REGIONS: usize = 64;
+
+/// The claimed port regions, encoded as `start | len << 16`, or 0 for a free slot. The slots are
+/// only modified with [`REGIONS_LOCK`] held.
+static REGIONS: [AtomicU32; MAX_REGIONS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: AtomicU32 = AtomicU32::new(0);
+    [FREE; MAX_REGIONS]
+};
+
+/// The lock protecting the updates of [`REGIONS`]. It is only held with the interrupts disabled
+/// (see [`lock_regions`]).
+static REGIONS_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// An error returned when claiming a port region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The range of ports is empty.
+    Empty,
+
+    /// The range overlaps the region already claimed from `start` (inclusive) to `end`
+    /// (exclusive).
+    Overlapping { start: u16, end: u16 },
+
+    /// [`MAX_REGIONS`] regions are already claimed.
+    RegistryFull,
+}
+
+/// An exclusive claim on a contiguous range of ports, registered in a global registry so that two
+/// drivers cannot drive the same device by mistake. The region is released when dropped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PortRegion {
+    start: u16,
+    len: u16,
+}
+
+impl PortRegion {
+    /// Claim the given range of ports, for example `0x3F8..0x400` for the first serial port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Empty`] if the range is empty, [`Error::Overlapping`] if a port of the
+    /// range is already claimed, and [`Error::RegistryFull`] if too many regions are claimed.
+    ///
+    /// # Safety
+    /// This function is unsafe because the ports of the range can then be accessed safely with
+    /// [`PortRegion::port`]: the range must be the ports of a device owned by the caller.
+    pub unsafe fn claim(ports: Range<u16>) -> Result<Self, Error> {
+        if ports.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        let region = Self {
+            start: ports.start,
+            len: ports.end - ports.start,
+        };
+
+        let guard = lock_regions();
+        let result = region.register();
+        drop(guard);
+        result.map(|()| region)
+    }
+
+    /// Returns the first port of the region.
+    #[must_use]
+    pub const fn start(&self) -> u16 {
+        self.start
+    }
+
+    /// Returns the port following the last port of the region.
+    #[must_use]
+    pub const fn end(&self) -> u16 {
+        self.start + self.len
+    }
+
+    /// Returns the number of ports of the region.
+    #[must_use]
+    pub const fn len(&self) -> u16 {
+        self.len
+    }
+
+    /// Returns `false`, since a region cannot be empty.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if the given port belongs to the region.
+    #[must_use]
+    pub const fn contains(&self, port: u16) -> bool {
+        port >= self.start && port - self.start < self.len
+    }
+
+    /// Returns the port at the given offset from the start of the region.
+    ///
+    /// # Panics
+    /// Panics if the port, with the size of `T`, does not fit in the region.
+    #[must_use]
+    pub fn port<T: IO>(&self, offset: u16) -> Port<T> {
+        let size = core::mem::size_of::<T>();
+        assert!(
+            usize::from(offset) + size <= usize::from(self.len),
+            "Port offset {offset:#x} is outside of the region"
+        );
+        // SAFETY: The port belongs to the region, which is owned by the caller of `claim`
+        unsafe { Port::new(self.start + offset) }
+    }
+
+    /// Add the region to the registry, if it does not overlap a claimed region. The registry
+    /// lock must be held.
+    fn register(&self) -> Result<(), Error> {
+        let mut free = None;
+        for (index, slot) in REGIONS.iter().enumerate() {
+            match decode(slot.load(Ordering::Relaxed)) {
+                None => {
+                    free.get_or_insert(index);
+                }
+                Some((start, len)) => {
+                    let end = u32::from(start) + u32::from(len);
+                    if u32::from(self.start) < end && start < self.end() {
+                        return Err(Error::Overlapping {
+                            start,
+                            end: start + len,
+                        });
+                    }
+                }
+            }
+        }
+
+        let index = free.ok_or(Error::RegistryFull)?;
+        REGIONS[index].store(encode(self.start, self.len), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for PortRegion {
+    fn drop(&mut self) {
+        let value = encode(self.start, self.len);
+        let _guard = lock_regions();
+        if let Some(slot) = REGIONS
+            .iter()
+            .find(|slot| slot.load(Ordering::Relaxed) == value)
+        {
+            slot.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns `true` if the given port belongs to a claimed region.
+#[must_use]
+pub fn is_claimed(port: u16) -> bool {
+    REGIONS.iter().any(|slot| {
+        decode(slot.load(Ordering::Relaxed))
+            .is_some_and(|(start, len)| port >= start && port - start < len)
+    })
+}
+
+/// A guard releasing the lock of the region registry when dropped, and restoring the interrupt
+/// state saved by [`lock_regions`].
+struct RegionsGuard {
+    interrupts: bool,
+}
+
+impl Drop for RegionsGuard {
+    fn drop(&mut self) {
+        REGIONS_LOCK.store(false, Ordering::Release);
+        if self.interrupts {
+            crate::irq::enable();
+        }
+    }
+}
+
+/// Disable interrupts on the current CPU and acquire the lock of the region registry, spinning
+/// until it is available. A region can be dropped by an interrupt handler, which would spin
+/// forever if it interrupted the lock owner on the same CPU.
+fn lock_regions() -> RegionsGuard {
+    let interrupts = disable_interrupts();
+    while REGIONS_LOCK
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    RegionsGuard { interrupts }
+}
+
+/// Disable interrupts on the current CPU, and returns `true` if they were enabled.
+#[cfg(not(any(test, feature = "mock_io")))]
+fn disable_interrupts() -> bool {
+    let interrupts = crate::irq::enabled();
+    crate::irq::disable();
+    interrupts
+}
+
+/// The interrupts cannot be disabled on the build host, where there are no interrupt handlers.
+#[cfg(any(test, feature = "mock_io"))]
+fn disable_interrupts() -> bool {
+    false
+}
+
+/// Encode a region in a slot of the registry.
+const fn encode(start: u16, len: u16) -> u32 {
+    start as u32 | (len as u32) << 16
+}
+
+/// Decode a slot of the registry, returning the start and the length of the region, or `None`
+/// if the slot is free.
+#[allow(clippy::cast_possible_truncation)]
+const fn decode(slot: u32) -> Option<(u16, u16)> {
+    match (slot as u16, (slot >> 16) as u16) {
+        (_, 0) => None,
+        region => Some(region),
+    }
+}
+
+#[cfg(not(any(test, feature = "mock_io")))]
 pub unsafe fn outb(port: u16, value: u8) {
-    asm!("out dx, al", in("dx") port, in("al") value);
+    core::arch::asm!("out dx, al", in("dx") port, in("al") value);
 }
 
+#[cfg(not(any(test, feature = "mock_io")))]
 pub unsafe fn outw(port: u16, value: u16) {
-    asm!("out dx, ax", in("dx") port, in("ax") value);
+    core::arch::asm!("out dx, ax", in("dx") port, in("ax") value);
 }
 
+#[cfg(not(any(test, feature = "mock_io")))]
 pub unsafe fn outd(port: u16, value: u32) {
-    asm!("out dx, eax", in("dx") port, in("eax") value);
+    core::arch::asm!("out dx, eax", in("dx") port, in("eax") value);
 }
 
 #[must_use]
+#[cfg(not(any(test, feature = "mock_io")))]
 pub unsafe fn inb(port: u16) -> u8 {
     let mut value: u8;
-    asm!("in al, dx", in("dx") port, out("al") value);
+    core::arch::asm!("in al, dx", in("dx") port, out("al") value);
     value
 }
 
 #[must_use]
+#[cfg(not(any(test, feature = "mock_io")))]
 pub unsafe fn inw(port: u16) -> u16 {
     let mut value: u16;
-    asm!("in ax, dx", in("dx") port, out("ax") value);
+    core::arch::asm!("in ax, dx", in("dx") port, out("ax") value);
     value
 }
 
 #[must_use]
+#[cfg(not(any(test, feature = "mock_io")))]
 pub unsafe fn ind(port: u16) -> u32 {
     let mut value: u32;
-    asm!("in eax, dx", in("dx") port, out("eax") value);
+    core::arch::asm!("in eax, dx", in("dx") port, out("eax") value);
     value
 }
 
+#[cfg(any(test, feature = "mock_io"))]
+pub unsafe fn outb(port: u16, value: u8) {
+    crate::mock::port_write(port, 1, u64::from(value));
+}
+
+#[cfg(any(test, feature = "mock_io"))]
+pub unsafe fn outw(port: u16, value: u16) {
+    crate::mock::port_write(port, 2, u64::from(value));
+}
+
+#[cfg(any(test, feature = "mock_io"))]
+pub unsafe fn outd(port: u16, value: u32) {
+    crate::mock::port_write(port, 4, u64::from(value));
+}
+
+#[must_use]
+#[cfg(any(test, feature = "mock_io"))]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe fn inb(port: u16) -> u8 {
+    crate::mock::port_read(port, 1) as u8
+}
+
+#[must_use]
+#[cfg(any(test, feature = "mock_io"))]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe fn inw(port: u16) -> u16 {
+    crate::mock::port_read(port, 2) as u16
+}
+
+#[must_use]
+#[cfg(any(test, feature = "mock_io"))]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe fn ind(port: u16) -> u32 {
+    crate::mock::port_read(port, 4) as u32
+}
+
 pub unsafe fn pause() {
     outb(0x80, 0); // Used by linux, may be fragile
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Error, PortRegion};
+    use crate::mock;
+
+    #[test]
+    fn port_regions() {
+        mock::reset();
+        let serial = unsafe { PortRegion::claim(0x3F8..0x400) }.unwrap();
+        assert_eq!(
+            (serial.start(), serial.end(), serial.len()),
+            (0x3F8, 0x400, 8)
+        );
+        assert!(super::is_claimed(0x3FF) && !super::is_claimed(0x400));
+
+        assert_eq!(
+            unsafe { PortRegion::claim(0x3FC..0x404) },
+            Err(Error::Overlapping {
+                start: 0x3F8,
+                end: 0x400
+            })
+        );
+        assert_eq!(
+            unsafe { PortRegion::claim(0x3F8..0x3F8) },
+            Err(Error::Empty)
+        );
+
+        let next = unsafe { PortRegion::claim(0x400..0x408) }.unwrap();
+        serial.port::<u8>(3).write(0x80);
+        assert_eq!(mock::port_writes(0x3FB), [0x80]);
+
+        drop(serial);
+        assert!(!super::is_claimed(0x3F8));
+        let serial = unsafe { PortRegion::claim(0x3F8..0x400) }.unwrap();
+        assert!(serial.contains(0x3F8) && !next.contains(0x3F8));
+    }
+}
@@ -0,0 +1,497 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitfield::{Bit, BitMut, BitRange, BitRangeMut};
+
+use crate::{address::Virtual, mmio::Volatile, register_block};
+
+register_block! {
+    /// The memory-mapped registers of the I/O APIC.
+    struct Registers {
+        /// The register selection register.
+        0x00 => select: Volatile<u32>,
+
+        /// The register window, giving access to the selected register.
+        0x10 => window: Volatile<u32>,
+    }
+}
+
+/// Represents the I/O APIC registers, accessed through the register selection register and the
+/// register window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Id = 0x00,
+    Version = 0x01,
+    Arbitration = 0x02,
+}
+
+/// The lock serializing the accesses to the register selection register and the register window
+/// of the I/O APICs. It is shared by all handles, since a handle can be cloned.
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+/// The index of the first redirection entry register. Each entry is made of two 32 bits registers.
+const REDIRECTION_TABLE: u32 = 0x10;
+
+/// The delivery mode of an interrupt redirected by the I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed = 0,
+    LowestPriority = 1,
+    Smi = 2,
+    Nmi = 4,
+    Init = 5,
+    ExtInt = 7,
+}
+
+/// The polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh = 0,
+    ActiveLow = 1,
+}
+
+/// The trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge = 0,
+    Level = 1,
+}
+
+/// An entry of the redirection table of an I/O APIC, which configures how the interrupt of a
+/// global system interrupt (GSI) line is delivered to the local APICs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    /// A masked entry.
+    pub const MASKED: Self = Self(1 << 16);
+
+    /// Create a new unmasked entry delivering a fixed interrupt with the given vector to the
+    /// local APIC with the given ID, in physical destination mode. The entry is active high and
+    /// edge-triggered.
+    #[must_use]
+    pub const fn new(vector: u8, destination: u8) -> Self {
+        Self(vector as u64 | (destination as u64) << 56)
+    }
+
+    /// Create an entry from its raw value.
+    #[must_use]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Set the vector of the interrupt.
+    #[must_use]
+    pub fn set_vector(&mut self, vector: u8) -> &mut Self {
+        self.0.set_bit_range(7, 0, u64::from(vector));
+        self
+    }
+
+    /// Set the delivery mode of the interrupt. The default is [`DeliveryMode::Fixed`].
+    #[must_use]
+    pub fn set_delivery(&mut self, delivery: DeliveryMode) -> &mut Self {
+        self.0.set_bit_range(10, 8, delivery as u64);
+        self
+    }
+
+    /// Set the polarity of the line. The default is [`Polarity::ActiveHigh`].
+    #[must_use]
+    pub fn set_polarity(&mut self, polarity: Polarity) -> &mut Self {
+        self.0.set_bit(13, polarity == Polarity::ActiveLow);
+        self
+    }
+
+    /// Set the trigger mode of the line. The default is [`TriggerMode::Edge`].
+    #[must_use]
+    pub fn set_trigger(&mut self, trigger: TriggerMode) -> &mut Self {
+        self.0.set_bit(15, trigger == TriggerMode::Level);
+        self
+    }
+
+    /// Mask or unmask the entry. A masked entry does not deliver any interrupt.
+    #[must_use]
+    pub fn set_masked(&mut self, masked: bool) -> &mut Self {
+        self.0.set_bit(16, masked);
+        self
+    }
+
+    /// Set the local APIC ID of the destination, in physical destination mode.
+    #[must_use]
+    pub fn set_destination(&mut self, destination: u8) -> &mut Self {
+        self.0.set_bit_range(63, 56, u64::from(destination));
+        self
+    }
+
+    /// Build the entry from the current state.
+    #[must_use]
+    pub fn build(&mut self) -> Self {
+        *self
+    }
+
+    /// Returns the vector of the interrupt.
+    #[must_use]
+    pub fn vector(&self) -> u8 {
+        self.0.bit_range(7, 0)
+    }
+
+    /// Returns the local APIC ID of the destination.
+    #[must_use]
+    pub fn destination(&self) -> u8 {
+        self.0.bit_range(63, 56)
+    }
+
+    /// Returns `true` if the entry is masked.
+    #[must_use]
+    pub fn masked(&self) -> bool {
+        self.0.bit(16)
+    }
+
+    /// Returns the raw value of the entry.
+    #[must_use]
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A handle to the registers of an I/O APIC, mapped in the virtual address space. Each I/O APIC
+/// handles a contiguous range of global system interrupts (GSI), starting at its GSI base.
+#[derive(Debug, Clone)]
+pub struct IoApic {
+    registers: Registers,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    /// Creates a new handle to the I/O APIC registers mapped at the given virtual address, which
+    /// handles the GSIs starting at the given base.
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must ensure that the given base address is a
+    /// valid virtual address that points to the I/O APIC registers, mapped with caching disabled.
+    /// The mapping must live as long as the returned handle.
+    #[must_use]
+    pub const unsafe fn new(base: Virtual, gsi_base: u32) -> Self {
+        Self {
+            registers: Registers::new(base),
+            gsi_base,
+        }
+    }
+
+    /// Returns the ID of the I/O APIC.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn id(&self) -> u8 {
+        ((self.read(Register::Id as u32) >> 24) & 0x0F) as u8
+    }
+
+    /// Returns the version of the I/O APIC.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn version(&self) -> u8 {
+        self.read(Register::Version as u32) as u8
+    }
+
+    /// Returns the number of redirection entries of the I/O APIC.
+    #[must_use]
+    pub fn redirection_entries(&self) -> u32 {
+        ((self.read(Register::Version as u32) >> 16) & 0xFF) + 1
+    }
+
+    /// Returns the first GSI handled by this I/O APIC.
+    #[must_use]
+    pub const fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    /// Returns `true` if the given GSI is handled by this I/O APIC.
+    #[must_use]
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.redirection_entries()
+    }
+
+    /// Returns the redirection entry of the given GSI.
+    ///
+    /// # Panics
+    /// This function panics if the given GSI is not handled by this I/O APIC.
+    #[must_use]
+    pub fn redirection(&self, gsi: u32) -> RedirectionEntry {
+        let index = self.entry_register(gsi);
+        let low = u64::from(self.read(index));
+        let high = u64::from(self.read(index + 1));
+        RedirectionEntry::from_bits(high << 32 | low)
+    }
+
+    /// Set the redirection entry of the given GSI. The entry is masked while it is updated, so
+    /// that no interrupt is delivered with a partially written entry.
+    ///
+    /// # Panics
+    /// This function panics if the given GSI is not handled by this I/O APIC.
+    ///
+    /// # Safety
+    /// This function is unsafe because an interrupt can be delivered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    #[allow(clippy::cast_possible_truncation)]
+    pub unsafe fn set_redirection(&self, gsi: u32, entry: RedirectionEntry) {
+        let index = self.entry_register(gsi);
+        self.write(index, RedirectionEntry::MASKED.bits() as u32);
+        self.write(index + 1, (entry.bits() >> 32) as u32);
+        self.write(index, entry.bits() as u32);
+    }
+
+    /// Mask the given GSI.
+    ///
+    /// # Panics
+    /// This function panics if the given GSI is not handled by this I/O APIC.
+    ///
+    /// # Safety
+    /// This function is unsafe because the interrupts of the given GSI will be lost.
+    pub unsafe fn mask(&self, gsi: u32) {
+        self.set_redirection(gsi, self.redirection(gsi).set_masked(true).build());
+    }
+
+    /// Unmask the given GSI.
+    ///
+    /// # Panics
+    /// This function panics if the given GSI is not handled by this I/O APIC.
+    ///
+    /// # Safety
+    /// This function is unsafe because an interrupt can be delivered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn unmask(&self, gsi: u32) {
+        self.set_redirection(gsi, self.redirection(gsi).set_masked(false).build());
+    }
+
+    /// Read the register with the given index. The register selection and the access to the
+    /// register window are done with interrupts disabled and under a lock, so that another CPU
+    /// or an interrupt handler cannot select another register in between.
+    #[must_use]
+    pub fn read(&self, register: u32) -> u32 {
+        // Reading a register does not have any side effects, except changing the selected one
+        locked(|| {
+            self.registers.select().write(register);
+            self.registers.window().read()
+        })
+    }
+
+    /// Write the given value to the register with the given index. Like [`IoApic::read`], the
+    /// access is serialized with the other accesses to the I/O APIC registers.
+    ///
+    /// # Safety
+    /// This function is unsafe because writing to some registers can trigger interrupts.
+    pub unsafe fn write(&self, register: u32, value: u32) {
+        locked(|| {
+            self.registers.select().write(register);
+            self.registers.window().write(value);
+        });
+    }
+
+    /// Returns the index of the low register of the redirection entry of the given GSI.
+    fn entry_register(&self, gsi: u32) -> u32 {
+        assert!(self.handles(gsi), "GSI {gsi} is not handled by this I/O APIC");
+        REDIRECTION_TABLE + (gsi - self.gsi_base) * 2
+    }
+}
+
+/// Execute the given function with interrupts disabled and the [`LOCK`] held, spinning until
+/// it is available.
+fn locked<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    crate::irq::without(|| {
+        while LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let ret = f();
+        LOCK.store(false, Ordering::Release);
+        ret
+    })
+}
+
+/// An interrupt source override, as described by the ACPI MADT. It indicates that an ISA IRQ is
+/// not identity mapped to a GSI, or that it does not use the default ISA polarity and trigger
+/// mode (active high and edge-triggered).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+impl SourceOverride {
+    /// Creates a source override from the fields of a MADT entry. The flags use the MPS INTI
+    /// encoding: the bits 0-1 are the polarity and the bits 2-3 the trigger mode, where 0 means
+    /// the default of the bus (active high and edge-triggered for ISA).
+    #[must_use]
+    pub const fn from_madt(isa_irq: u8, gsi: u32, flags: u16) -> Self {
+        Self {
+            isa_irq,
+            gsi,
+            polarity: match flags & 0b11 {
+                0b11 => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            },
+            trigger: match (flags >> 2) & 0b11 {
+                0b11 => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            },
+        }
+    }
+}
+
+/// The route of an ISA IRQ to a global system interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// The routing table of the 16 legacy ISA IRQs to global system interrupts. By default, each
+/// ISA IRQ is identity mapped to the GSI with the same number, active high and edge-triggered.
+/// The interrupt source overrides of the ACPI MADT must be applied with
+/// [`IsaRouting::apply`] (on most machines, the PIT IRQ 0 is routed to the GSI 2, and the IRQ 2
+/// of the cascaded PIC is then not routed at all).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsaRouting {
+    routes: [Option<Route>; 16],
+}
+
+impl IsaRouting {
+    /// Creates a new routing table where each ISA IRQ is identity mapped.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn new() -> Self {
+        let mut routes = [None; 16];
+        let mut irq = 0;
+        while irq < 16 {
+            routes[irq] = Some(Route {
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            });
+            irq += 1;
+        }
+        Self { routes }
+    }
+
+    /// Apply the given interrupt source override. The other ISA IRQ routed to the same GSI, if
+    /// any, is not routed anymore: its redirection entry is the one of the overridden IRQ.
+    ///
+    /// # Panics
+    /// This function panics if the ISA IRQ of the override is greater than 15.
+    pub fn apply(&mut self, source: SourceOverride) {
+        assert!(source.isa_irq < 16, "Invalid ISA IRQ {}", source.isa_irq);
+        for route in &mut self.routes {
+            if route.is_some_and(|route| route.gsi == source.gsi) {
+                *route = None;
+            }
+        }
+        self.routes[usize::from(source.isa_irq)] = Some(Route {
+            gsi: source.gsi,
+            polarity: source.polarity,
+            trigger: source.trigger,
+        });
+    }
+
+    /// Returns the route of the given ISA IRQ, or `None` if its GSI is used by another ISA IRQ
+    /// because of an interrupt source override.
+    ///
+    /// # Panics
+    /// This function panics if the given ISA IRQ is greater than 15.
+    #[must_use]
+    pub fn route(&self, irq: u8) -> Option<Route> {
+        assert!(irq < 16, "Invalid ISA IRQ {irq}");
+        self.routes[usize::from(irq)]
+    }
+
+    /// Returns the ISA IRQ routed to the given GSI, if any.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn isa_irq(&self, gsi: u32) -> Option<u8> {
+        self.routes
+            .iter()
+            .position(|route| route.is_some_and(|route| route.gsi == gsi))
+            .map(|irq| irq as u8)
+    }
+
+    /// Program the redirection entry of the given ISA IRQ in the I/O APIC handling its GSI, to
+    /// deliver the given vector to the local APIC with the given ID. The entry is left masked if
+    /// `masked` is `true`. Returns `false` if the ISA IRQ is not routed (see [`Self::route`]), or
+    /// if none of the given I/O APICs handles its GSI.
+    ///
+    /// # Safety
+    /// This function is unsafe because an interrupt can be delivered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    #[must_use]
+    pub unsafe fn program(
+        &self,
+        ioapics: &[IoApic],
+        irq: u8,
+        vector: u8,
+        destination: u8,
+        masked: bool,
+    ) -> bool {
+        let Some(route) = self.route(irq) else {
+            return false;
+        };
+        let Some(ioapic) = ioapics.iter().find(|ioapic| ioapic.handles(route.gsi)) else {
+            return false;
+        };
+
+        let entry = RedirectionEntry::new(vector, destination)
+            .set_polarity(route.polarity)
+            .set_trigger(route.trigger)
+            .set_masked(masked)
+            .build();
+        ioapic.set_redirection(route.gsi, entry);
+        true
+    }
+}
+
+impl Default for IsaRouting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IsaRouting, Polarity, RedirectionEntry, SourceOverride, TriggerMode};
+
+    #[test]
+    fn redirection_entry() {
+        let entry = RedirectionEntry::new(0x21, 3)
+            .set_polarity(Polarity::ActiveLow)
+            .set_trigger(TriggerMode::Level)
+            .set_masked(true)
+            .build();
+        assert_eq!(entry.bits(), 0x0300_0000_0001_A021);
+        assert_eq!(entry.vector(), 0x21);
+        assert_eq!(entry.destination(), 3);
+    }
+
+    #[test]
+    fn isa_routing() {
+        let mut routing = IsaRouting::new();
+        assert_eq!(routing.route(4).map(|route| route.gsi), Some(4));
+
+        routing.apply(SourceOverride::from_madt(0, 2, 0));
+        routing.apply(SourceOverride::from_madt(9, 9, 0b1111));
+        assert_eq!(routing.route(0).map(|route| route.gsi), Some(2));
+        assert_eq!(routing.route(2), None);
+        let route = routing.route(9).unwrap();
+        assert_eq!(route.polarity, Polarity::ActiveLow);
+        assert_eq!(route.trigger, TriggerMode::Level);
+        assert_eq!(routing.isa_irq(2), Some(0));
+        assert_eq!(routing.isa_irq(20), None);
+
+        // The displaced IRQ is never programmed over the entry of the overridden one
+        let ioapics = [unsafe { super::IoApic::new(crate::address::Virtual::new(0x1000), 0) }];
+        assert!(!unsafe { routing.program(&ioapics, 2, 0x22, 0, true) });
+    }
+}
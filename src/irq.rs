@@ -1,7 +1,26 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    cpu::feature::Feature,
+    percpu::{GsAccess, IRQ_DEPTH_OFFSET, IRQ_STATE_OFFSET},
+};
+
+/// The number of wakeups from [`idle`] of each CPU, indexed by local APIC ID.
+static WAKEUPS: [AtomicU64; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+/// Set if [`idle`] should use the `monitor` and `mwait` instructions instead of `hlt`.
+static IDLE_MWAIT: AtomicBool = AtomicBool::new(false);
 
 /// Waits for an interrupt. If interrupts are disabled, this function will never return, so be
-/// careful when using it.
+/// careful when using it. Prefer [`idle`] in idle loops, which is free of race conditions.
 #[inline]
 pub fn enable_and_wait() {
     unsafe {
@@ -10,6 +29,63 @@ pub fn enable_and_wait() {
     }
 }
 
+/// Enables interrupts and waits for the next interrupt. The `sti` instruction delays the
+/// recognition of interrupts until the end of the next instruction, so no interrupt can be
+/// handled between `sti` and `hlt` (or `mwait`): if the caller checked that there is nothing to
+/// do with interrupts disabled, a wakeup interrupt cannot be lost. Interrupts are left enabled
+/// when this function returns.
+///
+/// If enabled with [`set_idle_mwait`], the `mwait` instruction is used instead of `hlt`. The CPU
+/// is then also woken up when the wakeup counter of the current CPU is written, and may enter a
+/// deeper sleep state depending on the processor.
+///
+/// The wakeups of the CPUs with a local APIC ID greater than 255 are not counted, and these CPUs
+/// always use the `hlt` instruction.
+pub fn idle() {
+    let counter = usize::try_from(crate::lapic::initial_apic_id())
+        .ok()
+        .and_then(|cpu| WAKEUPS.get(cpu));
+
+    unsafe {
+        if let (true, Some(counter)) = (IDLE_MWAIT.load(Ordering::Relaxed), counter) {
+            asm!(
+                "monitor",
+                in("rax") addr_of!(*counter),
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack, preserves_flags)
+            );
+            asm!("sti", "mwait", in("eax") 0, in("ecx") 0, options(nomem, nostack));
+        } else {
+            asm!("sti", "hlt", options(nomem, nostack));
+        }
+    }
+    if let Some(counter) = counter {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` if the `monitor` and `mwait` instructions are supported by the current CPU.
+#[must_use]
+pub fn mwait_supported() -> bool {
+    crate::cpu::features().has(Feature::Monitor)
+}
+
+/// Select whether [`idle`] should prefer the `mwait` instruction over `hlt`. The `mwait`
+/// instruction is only used if it is supported by the CPU (see [`mwait_supported`]). Returns
+/// `true` if `mwait` will be used.
+pub fn set_idle_mwait(prefer: bool) -> bool {
+    let mwait = prefer && mwait_supported();
+    IDLE_MWAIT.store(mwait, Ordering::Relaxed);
+    mwait
+}
+
+/// Returns the number of times the CPU with the given local APIC ID was woken up from [`idle`].
+#[must_use]
+pub fn wakeups(apic_id: u8) -> u64 {
+    WAKEUPS[usize::from(apic_id)].load(Ordering::Relaxed)
+}
+
 /// Disables interrupts.
 #[inline]
 pub fn disable() {
@@ -46,6 +122,62 @@ pub fn restore(state: bool) {
     }
 }
 
+/// Disables interrupts and increments the interrupt-disable depth of the current CPU. The
+/// interrupt state is saved by the outermost call, and restored by the matching outermost call to
+/// [`enable_nested`]. This allows independent subsystems to nest critical sections without
+/// re-enabling interrupts too early, which is not possible with [`disable`] and [`enable`].
+///
+/// # Safety
+/// This function is unsafe because the depth counter is stored in the per-CPU area: it must have
+/// been initialized with [`crate::percpu::init`] on the current CPU, and the kernel GS must be
+/// active. Each call must be balanced by a call to [`enable_nested`] on the same CPU.
+#[inline]
+pub unsafe fn disable_nested() {
+    let state = enabled();
+    disable();
+
+    let depth = u64::read(IRQ_DEPTH_OFFSET);
+    if depth == 0 {
+        u64::write(IRQ_STATE_OFFSET, u64::from(state));
+    }
+    u64::write(IRQ_DEPTH_OFFSET, depth + 1);
+}
+
+/// Decrements the interrupt-disable depth of the current CPU. If the depth reaches zero,
+/// interrupts are re-enabled if they were enabled before the outermost call to
+/// [`disable_nested`], otherwise interrupts stay disabled.
+///
+/// # Panics
+/// This function panics if it is called more times than [`disable_nested`].
+///
+/// # Safety
+/// This function is unsafe because the depth counter is stored in the per-CPU area: it must have
+/// been initialized with [`crate::percpu::init`] on the current CPU, and the kernel GS must be
+/// active.
+#[inline]
+pub unsafe fn enable_nested() {
+    let depth = u64::read(IRQ_DEPTH_OFFSET);
+    assert!(depth > 0, "Unbalanced call to enable_nested");
+    u64::write(IRQ_DEPTH_OFFSET, depth - 1);
+
+    if depth == 1 && u64::read(IRQ_STATE_OFFSET) != 0 {
+        enable();
+    }
+}
+
+/// Returns the current interrupt-disable depth of the current CPU, i.e the number of calls to
+/// [`disable_nested`] not yet balanced by a call to [`enable_nested`].
+///
+/// # Safety
+/// This function is unsafe because the depth counter is stored in the per-CPU area: it must have
+/// been initialized with [`crate::percpu::init`] on the current CPU, and the kernel GS must be
+/// active.
+#[inline]
+#[must_use]
+pub unsafe fn nested_depth() -> u64 {
+    u64::read(IRQ_DEPTH_OFFSET)
+}
+
 /// Raises an interrupt with the given ID.
 ///
 /// # Safety
@@ -58,12 +190,13 @@ pub unsafe fn raise<const T: u8>() {
 
 /// Executes the given function with interrupts disabled. The previous interrupt state is restored
 /// after the function returns, so interrupts will not be re-enabled if they were disabled before
-/// calling this function.
+/// calling this function. In tests and with the `mock_io` feature, the interrupt flag is not
+/// modelled and the function is simply called.
 pub fn without<F, R>(f: F) -> R
 where
     F: FnOnce() -> R,
 {
-    let irq = enabled();
+    let irq = cfg!(not(any(test, feature = "mock_io"))) && enabled();
     if irq {
         disable();
     }
@@ -73,3 +206,132 @@ where
     }
     ret
 }
+
+/// Control of the non-maskable interrupts (NMI) with the bit 7 of the CMOS index port. When this
+/// bit is set, the NMI line of the chipset is masked. The index port is write-only, so the mask
+/// state is tracked in software, and the CMOS driver must use [`nmi::index`] to compute the value
+/// written to the index port, so that selecting a CMOS register does not clobber the NMI mask.
+pub mod nmi {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::io::{inb, outb};
+
+    /// The CMOS index port. The bit 7 of the written value is the NMI mask.
+    pub const INDEX_PORT: u16 = 0x70;
+
+    /// The CMOS data port.
+    pub const DATA_PORT: u16 = 0x71;
+
+    /// The NMI mask bit of the CMOS index port.
+    pub const MASK_BIT: u8 = 1 << 7;
+
+    /// The CMOS register selected when the NMI mask is changed. The status register D is selected
+    /// because it is read-only, so leaving it selected is harmless.
+    const DEFAULT_INDEX: u8 = 0x0D;
+
+    /// The current NMI mask state.
+    static MASKED: AtomicBool = AtomicBool::new(false);
+
+    /// Mask the NMIs at the chipset level. This does not mask the NMIs sent by the local APIC,
+    /// for example with [`crate::lapic::IpiPriority::Nmi`].
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the CMOS index port: the caller must ensure
+    /// that no other CPU is accessing the CMOS at the same time.
+    pub unsafe fn mask() {
+        MASKED.store(true, Ordering::SeqCst);
+        select(DEFAULT_INDEX);
+    }
+
+    /// Unmask the NMIs at the chipset level.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the CMOS index port: the caller must ensure
+    /// that no other CPU is accessing the CMOS at the same time. The NMI handler must also be
+    /// installed, otherwise a pending NMI would triple fault the CPU.
+    pub unsafe fn unmask() {
+        MASKED.store(false, Ordering::SeqCst);
+        select(DEFAULT_INDEX);
+    }
+
+    /// Returns `true` if the NMIs are masked with [`mask`].
+    #[must_use]
+    pub fn is_masked() -> bool {
+        MASKED.load(Ordering::SeqCst)
+    }
+
+    /// Returns the value to write to the CMOS index port to select the given register without
+    /// changing the NMI mask.
+    #[must_use]
+    pub fn index(register: u8) -> u8 {
+        if is_masked() {
+            (register & !MASK_BIT) | MASK_BIT
+        } else {
+            register & !MASK_BIT
+        }
+    }
+
+    /// Select the given CMOS register, preserving the NMI mask. The data port is read after, as
+    /// recommended, because some chipsets are left in an undefined state if the index port is
+    /// written without accessing the data port.
+    unsafe fn select(register: u8) {
+        outb(INDEX_PORT, index(register));
+        let _ = inb(DATA_PORT);
+    }
+}
+
+/// Implementation of the [`critical_section`] crate, enabled with the `critical_section` feature.
+/// Interrupts are disabled on the current CPU, and a global spin lock is taken so that the
+/// critical section is also exclusive with the other CPUs. Nested critical sections on the same
+/// CPU are supported: only the outermost release unlocks the spin lock and restores the previous
+/// interrupt state.
+///
+/// The local APIC ID is used to identify the current CPU, so that this implementation can be used
+/// before the per-CPU area and the local APIC are initialized.
+#[cfg(feature = "critical_section")]
+mod critical_section_impl {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    /// The CPU owning the critical section, or [`NO_OWNER`] if it is free.
+    static OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+    const NO_OWNER: u32 = u32::MAX;
+
+    /// Restore states returned by `acquire`.
+    const NESTED: u8 = 0;
+    const DISABLED: u8 = 1;
+    const ENABLED: u8 = 2;
+
+    struct CriticalSection;
+    critical_section::set_impl!(CriticalSection);
+
+    unsafe impl critical_section::Impl for CriticalSection {
+        unsafe fn acquire() -> critical_section::RawRestoreState {
+            let state = super::enabled();
+            super::disable();
+
+            let cpu = crate::lapic::initial_apic_id();
+            if OWNER.load(Ordering::Relaxed) == cpu {
+                return NESTED;
+            }
+            while OWNER
+                .compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+
+            if state {
+                ENABLED
+            } else {
+                DISABLED
+            }
+        }
+
+        unsafe fn release(state: critical_section::RawRestoreState) {
+            if state != NESTED {
+                OWNER.store(NO_OWNER, Ordering::Release);
+                super::restore(state == ENABLED);
+            }
+        }
+    }
+}
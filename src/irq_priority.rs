@@ -0,0 +1,152 @@
+//! Interrupt priority classes. The local APIC delivers the interrupts by priority class, the upper
+//! 4 bits of the vector: an interrupt is only delivered if its class is higher than the processor
+//! priority, which is the maximum of the task priority set by the kernel (see
+//! [`set_priority_floor`]) and of the class of the interrupt being serviced. The vectors are
+//! therefore allocated per class, so that the important interrupts are never blocked behind the
+//! less important ones:
+//!
+//! - 0x00 to 0x1F: the exceptions, which are never blocked by the priority.
+//! - [`Class::LEGACY`] (0x20 to 0x2F): the legacy PIC, when the local APIC is not used.
+//! - [`Class::DEFERRED`] (0x30 to 0x3F): the deferred work (see [`crate::deferred::VECTOR`]).
+//! - [`Class::DEVICE`] to [`Class::DEVICE_HIGH`] (0x40 to 0xEF): the device interrupts (see
+//!   [`crate::irq_routing`]).
+//! - [`Class::IPI`] (0xF0 to 0xFF): the inter-processor interrupts, the local APIC interrupts and
+//!   the spurious interrupt.
+//!
+//! The layout is checked at compile time for the vectors defined by this crate. Since a device
+//! interrupt can never raise the processor priority to [`Class::IPI`], and [`set_priority_floor`]
+//! refuses to set it, the IPIs (like [`crate::smp::CALL_FUNCTION_VECTOR`]) are always delivered
+//! when the interrupts are enabled.
+use crate::cpu;
+
+/// The priority class of an interrupt vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Class(u8);
+
+impl Class {
+    /// The lowest class. Used as a priority floor, all the interrupts are delivered.
+    pub const PASSIVE: Self = Self(0);
+
+    /// The class of the legacy PIC vectors.
+    pub const LEGACY: Self = Self(2);
+
+    /// The class of the deferred work vector.
+    pub const DEFERRED: Self = Self(3);
+
+    /// The lowest class of the device interrupts.
+    pub const DEVICE: Self = Self(4);
+
+    /// The highest class of the device interrupts.
+    pub const DEVICE_HIGH: Self = Self(0xE);
+
+    /// The class of the inter-processor interrupts, of the local APIC interrupts (timer, thermal,
+    /// errors...) and of the spurious interrupt.
+    pub const IPI: Self = Self(0xF);
+
+    /// Creates a new priority class.
+    ///
+    /// # Panics
+    /// Panics if the class is greater than 15.
+    #[must_use]
+    pub const fn new(class: u8) -> Self {
+        assert!(class < 16, "Invalid priority class");
+        Self(class)
+    }
+
+    /// Returns the priority class of the given vector.
+    #[must_use]
+    pub const fn of(vector: u8) -> Self {
+        Self(vector >> 4)
+    }
+
+    /// Returns the first vector of this class.
+    #[must_use]
+    pub const fn first_vector(self) -> u8 {
+        self.0 << 4
+    }
+
+    /// Returns the last vector of this class.
+    #[must_use]
+    pub const fn last_vector(self) -> u8 {
+        self.0 << 4 | 0xF
+    }
+
+    /// Returns true if the given vector belongs to this class.
+    #[must_use]
+    pub const fn contains(self, vector: u8) -> bool {
+        vector >> 4 == self.0
+    }
+
+    /// Returns the value of the task priority register which blocks the interrupts of this class
+    /// and of all the lower classes.
+    #[must_use]
+    pub const fn task_priority(self) -> u8 {
+        self.0 << 4
+    }
+
+    /// Returns the class, between 0 and 15.
+    #[must_use]
+    pub const fn value(self) -> u8 {
+        self.0
+    }
+}
+
+// Check the layout of the vectors defined by this crate
+const _: () = {
+    assert!(Class::DEFERRED.contains(crate::deferred::VECTOR));
+    assert!(Class::of(crate::irq_routing::FIRST_VECTOR).value() == Class::DEVICE.value());
+    assert!(Class::of(crate::irq_routing::LAST_VECTOR).value() == Class::DEVICE_HIGH.value());
+    assert!(Class::IPI.contains(crate::smp::CALL_FUNCTION_VECTOR));
+};
+
+/// Returns the priority floor of the current CPU: the interrupts of this class and of the lower
+/// classes are not delivered.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn priority_floor() -> Class {
+    Class(cpu::cr8::read() as u8 & 0xF)
+}
+
+/// Set the priority floor of the current CPU with the control register 8, an alias of the task
+/// priority register of the local APIC (see [`crate::lapic::LocalApic::set_task_priority`]): the
+/// interrupts of the given class and of the lower classes are not delivered until the floor is
+/// lowered. The previous floor is returned, so that it can be restored.
+///
+/// For example, a floor of [`Class::DEFERRED`] prevents the deferred work from running while
+/// still delivering the device interrupts, and a floor of [`Class::DEVICE_HIGH`] blocks all the
+/// device interrupts but not the IPIs.
+///
+/// # Panics
+/// Panics if the class is [`Class::IPI`]: the IPIs must never be blocked, otherwise the other
+/// CPUs could wait forever for the current one (for example in [`crate::smp::call_on`]).
+///
+/// # Safety
+/// This function is unsafe because raising the floor delays the interrupts below it, which can
+/// break the code waiting for them (for example a timer interrupt).
+#[must_use]
+pub unsafe fn set_priority_floor(class: Class) -> Class {
+    assert!(
+        class < Class::IPI,
+        "The IPIs cannot be blocked by the priority floor"
+    );
+    let previous = priority_floor();
+    cpu::cr8::write(u64::from(class.value()));
+    previous
+}
+
+#[cfg(test)]
+mod test {
+    use super::Class;
+
+    #[test]
+    fn classes() {
+        assert_eq!(Class::of(0x41), Class::DEVICE);
+        assert_eq!(Class::of(0xFD), Class::IPI);
+        assert_eq!(Class::DEFERRED.first_vector(), 0x30);
+        assert_eq!(Class::DEFERRED.last_vector(), 0x3F);
+        assert_eq!(Class::DEVICE_HIGH.task_priority(), 0xE0);
+        assert!(Class::LEGACY.contains(0x2F));
+        assert!(!Class::LEGACY.contains(0x30));
+        assert!(Class::DEVICE < Class::IPI);
+    }
+}
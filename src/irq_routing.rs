@@ -0,0 +1,507 @@
+//! Routing of the device interrupts to the CPUs. A [`Router`] allocates the interrupt vectors of
+//! each CPU, programs the redirection entries of the I/O APICs for the global system interrupts
+//! (GSI), and encodes the messages of the message signaled interrupts (MSI), so that the kernel
+//! only has to say where an interrupt must be delivered. When a CPU goes offline, its interrupts
+//! can be moved to the remaining CPUs with [`Router::rebalance`].
+//!
+//! The vectors are allocated per CPU: the same vector can be used by different interrupts on
+//! different CPUs, so an interrupt handler must identify its interrupt from both the current CPU
+//! and the vector (see [`Router::source`]).
+use crate::{
+    ioapic::{IoApic, Route},
+    irq_priority::Class,
+    smp::CpuSet,
+};
+
+/// The first vector that can be allocated. The vectors below are reserved for the exceptions,
+/// the legacy PIC and the deferred work (see [`crate::deferred::VECTOR`]), which must have a
+/// lower priority class than all the device interrupts (see [`crate::irq_priority`]).
+pub const FIRST_VECTOR: u8 = Class::DEVICE.first_vector();
+
+/// The last vector that can be allocated. The vectors above are reserved for the inter-processor
+/// interrupts, the local APIC interrupts and the spurious interrupt.
+pub const LAST_VECTOR: u8 = Class::DEVICE_HIGH.last_vector();
+
+/// The maximal number of interrupts that can be routed.
+pub const MAX_ROUTES: usize = 64;
+
+/// The base address of the MSI messages, which targets the local APICs.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/// An error reported by a [`Router`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The vector is outside of the allocatable range.
+    InvalidVector(u8),
+
+    /// The vector is already allocated on the CPU.
+    VectorInUse { cpu: u8, vector: u8 },
+
+    /// All the allocatable vectors of the CPU are in use.
+    NoFreeVector(u8),
+
+    /// The interrupt source is already routed.
+    AlreadyRouted,
+
+    /// The interrupt source is not routed.
+    NotRouted,
+
+    /// The routing table is full (see [`MAX_ROUTES`]).
+    TableFull,
+
+    /// None of the I/O APICs handles the GSI.
+    NoIoApic(u32),
+
+    /// No online CPU can receive the interrupts of the offline CPU.
+    NoOnlineCpu,
+}
+
+/// A source of interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// A global system interrupt, delivered by an I/O APIC.
+    Gsi(u32),
+
+    /// A message signaled interrupt, identified by an arbitrary number chosen by the kernel (for
+    /// example the PCI address of the function and the index of the MSI-X table entry).
+    Msi(u32),
+}
+
+/// The address and the data of a message signaled interrupt, to be written in the MSI capability
+/// or the MSI-X table entry of a device. The message delivers a fixed, edge-triggered interrupt
+/// to a single local APIC, in physical destination mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// Creates the message delivering the given vector to the local APIC with the given ID.
+    #[must_use]
+    pub const fn new(destination: u8, vector: u8) -> Self {
+        Self {
+            address: MSI_ADDRESS_BASE | (destination as u64) << 12,
+            data: vector as u32,
+        }
+    }
+
+    /// Returns the local APIC ID of the destination.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn destination(&self) -> u8 {
+        (self.address >> 12) as u8
+    }
+
+    /// Returns the vector of the interrupt.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn vector(&self) -> u8 {
+        self.data as u8
+    }
+}
+
+/// The CPU and the vector an interrupt source is delivered to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binding {
+    pub source: Source,
+    pub cpu: u8,
+    pub vector: u8,
+}
+
+impl Binding {
+    /// Returns the MSI message delivering the interrupt to its CPU and vector.
+    #[must_use]
+    pub const fn message(&self) -> MsiMessage {
+        MsiMessage::new(self.cpu, self.vector)
+    }
+}
+
+/// The interrupt router of the system, combining a per-CPU vector allocator, the I/O APICs and
+/// the encoding of MSI messages. The CPUs are identified by their local APIC ID.
+#[derive(Debug, Clone)]
+pub struct Router<'a> {
+    ioapics: &'a [IoApic],
+    vectors: [[u64; 4]; 256],
+    bindings: [Option<Binding>; MAX_ROUTES],
+}
+
+impl<'a> Router<'a> {
+    /// Creates a new router using the given I/O APICs, where no vector is allocated.
+    #[must_use]
+    pub const fn new(ioapics: &'a [IoApic]) -> Self {
+        Self {
+            ioapics,
+            vectors: [[0; 4]; 256],
+            bindings: [None; MAX_ROUTES],
+        }
+    }
+
+    /// Returns `true` if the given vector is allocated on the given CPU.
+    #[must_use]
+    pub const fn is_allocated(&self, cpu: u8, vector: u8) -> bool {
+        self.vectors[cpu as usize][(vector / 64) as usize] & (1 << (vector % 64)) != 0
+    }
+
+    /// Allocate the lowest free vector of the given CPU.
+    ///
+    /// # Errors
+    /// Returns [`Error::NoFreeVector`] if all the allocatable vectors of the CPU are in use.
+    pub fn allocate_vector(&mut self, cpu: u8) -> Result<u8, Error> {
+        let vector = (FIRST_VECTOR..=LAST_VECTOR)
+            .find(|&vector| !self.is_allocated(cpu, vector))
+            .ok_or(Error::NoFreeVector(cpu))?;
+        self.toggle(cpu, vector);
+        Ok(vector)
+    }
+
+    /// Allocate the given vector on the given CPU, for example for an interrupt whose vector is
+    /// fixed by the kernel.
+    ///
+    /// # Errors
+    /// Returns an error if the vector is outside of the allocatable range, or if it is already
+    /// allocated on the CPU.
+    pub fn reserve_vector(&mut self, cpu: u8, vector: u8) -> Result<(), Error> {
+        if !(FIRST_VECTOR..=LAST_VECTOR).contains(&vector) {
+            return Err(Error::InvalidVector(vector));
+        }
+        if self.is_allocated(cpu, vector) {
+            return Err(Error::VectorInUse { cpu, vector });
+        }
+        self.toggle(cpu, vector);
+        Ok(())
+    }
+
+    /// Free the given vector of the given CPU. This does nothing if the vector is not allocated.
+    pub fn free_vector(&mut self, cpu: u8, vector: u8) {
+        if self.is_allocated(cpu, vector) {
+            self.toggle(cpu, vector);
+        }
+    }
+
+    /// Deliver the given GSI to the given CPU, with the given vector or with a newly allocated
+    /// one if `vector` is `None`. The redirection entry is programmed with the polarity and the
+    /// trigger mode of the route, and unmasked.
+    ///
+    /// # Errors
+    /// Returns an error if the GSI is already routed, if none of the I/O APICs handles it, if the
+    /// routing table is full, or if the vector cannot be allocated.
+    ///
+    /// # Safety
+    /// This function is unsafe because the interrupt can be delivered as soon as this function
+    /// returns: the IDT of the CPU must have a handler for the vector.
+    pub unsafe fn route_gsi(
+        &mut self,
+        route: Route,
+        cpu: u8,
+        vector: Option<u8>,
+    ) -> Result<Binding, Error> {
+        let ioapic = self.ioapic(route.gsi)?;
+        let binding = self.bind(Source::Gsi(route.gsi), cpu, vector)?;
+        let entry = ioapic
+            .redirection(route.gsi)
+            .set_vector(binding.vector)
+            .set_destination(cpu)
+            .set_polarity(route.polarity)
+            .set_trigger(route.trigger)
+            .set_masked(false)
+            .build();
+        ioapic.set_redirection(route.gsi, entry);
+        Ok(binding)
+    }
+
+    /// Deliver the MSI with the given identifier to the given CPU, with the given vector or
+    /// with a newly allocated one if `vector` is `None`. The kernel must write the message of
+    /// the returned binding (see [`Binding::message`]) to the device.
+    ///
+    /// # Errors
+    /// Returns an error if the MSI is already routed, if the routing table is full, or if the
+    /// vector cannot be allocated.
+    pub fn route_msi(&mut self, id: u32, cpu: u8, vector: Option<u8>) -> Result<Binding, Error> {
+        self.bind(Source::Msi(id), cpu, vector)
+    }
+
+    /// Remove the route of the given source and free its vector. The redirection entry of a GSI
+    /// is masked, but the kernel must disable the MSI of a device itself, before calling this
+    /// function.
+    ///
+    /// # Errors
+    /// Returns [`Error::NotRouted`] if the source is not routed.
+    ///
+    /// # Safety
+    /// This function is unsafe because the interrupts of the source will be lost.
+    pub unsafe fn unroute(&mut self, source: Source) -> Result<Binding, Error> {
+        let slot = self.slot(source).ok_or(Error::NotRouted)?;
+        let binding = self.bindings[slot].take().ok_or(Error::NotRouted)?;
+        if let Source::Gsi(gsi) = source {
+            self.ioapic(gsi)?.mask(gsi);
+        }
+        self.free_vector(binding.cpu, binding.vector);
+        Ok(binding)
+    }
+
+    /// Move the given source to the given CPU. The current vector is kept if it is free on the
+    /// new CPU and `vector` is `None`, so that the same IDT handler can be used. The redirection
+    /// entry of a GSI is updated, but the kernel must write the new message of a MSI (see
+    /// [`Binding::message`]) to the device.
+    ///
+    /// # Errors
+    /// Returns an error if the source is not routed, or if no vector can be allocated on the new
+    /// CPU. The route is left unchanged in this case.
+    ///
+    /// # Safety
+    /// This function is unsafe because the interrupt can be delivered to the new CPU as soon as
+    /// this function returns: its IDT must have a handler for the vector. An interrupt sent
+    /// before the move can still be delivered to the old CPU.
+    pub unsafe fn set_affinity(
+        &mut self,
+        source: Source,
+        cpu: u8,
+        vector: Option<u8>,
+    ) -> Result<Binding, Error> {
+        let slot = self.slot(source).ok_or(Error::NotRouted)?;
+        let old = self.bindings[slot].ok_or(Error::NotRouted)?;
+        if old.cpu == cpu && vector.is_none_or(|vector| vector == old.vector) {
+            return Ok(old);
+        }
+
+        let vector = match vector {
+            Some(vector) => self.reserve_vector(cpu, vector).map(|()| vector)?,
+            None => match self.reserve_vector(cpu, old.vector) {
+                Ok(()) => old.vector,
+                Err(_) => self.allocate_vector(cpu)?,
+            },
+        };
+
+        if let Source::Gsi(gsi) = source {
+            let ioapic = match self.ioapic(gsi) {
+                Ok(ioapic) => ioapic,
+                Err(error) => {
+                    self.free_vector(cpu, vector);
+                    return Err(error);
+                }
+            };
+            let entry = ioapic
+                .redirection(gsi)
+                .set_vector(vector)
+                .set_destination(cpu)
+                .build();
+            ioapic.set_redirection(gsi, entry);
+        }
+
+        let binding = Binding {
+            source,
+            cpu,
+            vector,
+        };
+        self.bindings[slot] = Some(binding);
+        self.free_vector(old.cpu, old.vector);
+        Ok(binding)
+    }
+
+    /// Move all the interrupts delivered to the given CPU, which is going offline, to the online
+    /// CPUs receiving the fewest interrupts (see [`Router::set_affinity`]). The CPUs are
+    /// identified by their local APIC ID, not by their [`crate::smp`] index. The given function
+    /// is called with the old and the new binding of each moved source, so that the kernel can
+    /// reprogram the MSIs and update its interrupt handlers. Returns the number of moved
+    /// sources.
+    ///
+    /// # Errors
+    /// Returns [`Error::NoOnlineCpu`] if no other CPU is online, or the error of
+    /// [`Router::set_affinity`] if a source cannot be moved. The sources moved before the error
+    /// are reported to the given function.
+    ///
+    /// # Safety
+    /// This function is unsafe for the same reasons as [`Router::set_affinity`].
+    pub unsafe fn rebalance(
+        &mut self,
+        offline: u8,
+        online: &CpuSet,
+        mut moved: impl FnMut(Binding, Binding),
+    ) -> Result<usize, Error> {
+        let mut count = 0;
+        for slot in 0..MAX_ROUTES {
+            let Some(old) = self.bindings[slot].filter(|binding| binding.cpu == offline) else {
+                continue;
+            };
+
+            let target = online
+                .iter()
+                .filter(|&cpu| cpu != offline)
+                .min_by_key(|&cpu| self.load(cpu))
+                .ok_or(Error::NoOnlineCpu)?;
+            let new = self.set_affinity(old.source, target, None)?;
+            moved(old, new);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns the binding of the given source, if it is routed.
+    #[must_use]
+    pub fn binding(&self, source: Source) -> Option<Binding> {
+        self.slot(source).and_then(|slot| self.bindings[slot])
+    }
+
+    /// Returns the source delivered to the given CPU with the given vector, if any.
+    #[must_use]
+    pub fn source(&self, cpu: u8, vector: u8) -> Option<Source> {
+        self.bindings()
+            .find(|binding| binding.cpu == cpu && binding.vector == vector)
+            .map(|binding| binding.source)
+    }
+
+    /// Returns an iterator over the bindings of the routed sources.
+    pub fn bindings(&self) -> impl Iterator<Item = Binding> + '_ {
+        self.bindings.iter().filter_map(|binding| *binding)
+    }
+
+    /// Returns the number of sources delivered to the given CPU.
+    #[must_use]
+    pub fn load(&self, cpu: u8) -> usize {
+        self.bindings().filter(|binding| binding.cpu == cpu).count()
+    }
+
+    /// Allocate the vector and a slot of the routing table for the given source.
+    fn bind(&mut self, source: Source, cpu: u8, vector: Option<u8>) -> Result<Binding, Error> {
+        if self.slot(source).is_some() {
+            return Err(Error::AlreadyRouted);
+        }
+        let slot = self
+            .bindings
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::TableFull)?;
+
+        let vector = match vector {
+            Some(vector) => self.reserve_vector(cpu, vector).map(|()| vector)?,
+            None => self.allocate_vector(cpu)?,
+        };
+        let binding = Binding {
+            source,
+            cpu,
+            vector,
+        };
+        self.bindings[slot] = Some(binding);
+        Ok(binding)
+    }
+
+    /// Returns the slot of the routing table of the given source, if it is routed.
+    fn slot(&self, source: Source) -> Option<usize> {
+        self.bindings
+            .iter()
+            .position(|binding| binding.is_some_and(|binding| binding.source == source))
+    }
+
+    /// Returns the I/O APIC handling the given GSI.
+    fn ioapic(&self, gsi: u32) -> Result<&'a IoApic, Error> {
+        self.ioapics
+            .iter()
+            .find(|ioapic| ioapic.handles(gsi))
+            .ok_or(Error::NoIoApic(gsi))
+    }
+
+    /// Toggle the allocation of the given vector on the given CPU.
+    fn toggle(&mut self, cpu: u8, vector: u8) {
+        self.vectors[usize::from(cpu)][usize::from(vector / 64)] ^= 1 << (vector % 64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Binding, Error, MsiMessage, Router, Source, FIRST_VECTOR};
+    use crate::{
+        ioapic::{Polarity, Route, TriggerMode},
+        smp::CpuSet,
+    };
+
+    #[test]
+    fn msi_message() {
+        let message = MsiMessage::new(3, 0x41);
+        assert_eq!(message.address, 0xFEE0_3000);
+        assert_eq!(message.data, 0x41);
+        assert_eq!(message.destination(), 3);
+        assert_eq!(message.vector(), 0x41);
+    }
+
+    #[test]
+    fn vector_allocation() {
+        let mut router = Router::new(&[]);
+        assert_eq!(router.allocate_vector(0), Ok(FIRST_VECTOR));
+        assert_eq!(router.allocate_vector(0), Ok(FIRST_VECTOR + 1));
+        assert_eq!(router.allocate_vector(1), Ok(FIRST_VECTOR));
+        assert_eq!(
+            router.reserve_vector(0, 0x20),
+            Err(Error::InvalidVector(0x20))
+        );
+        assert_eq!(
+            router.reserve_vector(0, FIRST_VECTOR),
+            Err(Error::VectorInUse {
+                cpu: 0,
+                vector: FIRST_VECTOR
+            })
+        );
+
+        router.free_vector(0, FIRST_VECTOR);
+        assert_eq!(router.allocate_vector(0), Ok(FIRST_VECTOR));
+
+        let route = Route {
+            gsi: 19,
+            polarity: Polarity::ActiveLow,
+            trigger: TriggerMode::Level,
+        };
+        let result = unsafe { router.route_gsi(route, 0, None) };
+        assert_eq!(result, Err(Error::NoIoApic(19)));
+    }
+
+    #[test]
+    fn msi_routing() {
+        let mut router = Router::new(&[]);
+        let binding = router.route_msi(7, 3, Some(0x50)).unwrap();
+        assert_eq!(binding.message(), MsiMessage::new(3, 0x50));
+        assert_eq!(router.route_msi(7, 1, None), Err(Error::AlreadyRouted));
+        assert_eq!(router.source(3, 0x50), Some(Source::Msi(7)));
+
+        // The vector is kept when moving the MSI, if it is free on the new CPU
+        router.reserve_vector(1, 0x50).unwrap();
+        let binding = unsafe { router.set_affinity(Source::Msi(7), 2, None) }.unwrap();
+        assert_eq!((binding.cpu, binding.vector), (2, 0x50));
+        let binding = unsafe { router.set_affinity(Source::Msi(7), 1, None) }.unwrap();
+        assert_eq!((binding.cpu, binding.vector), (1, FIRST_VECTOR));
+        assert!(!router.is_allocated(2, 0x50));
+
+        let binding = unsafe { router.unroute(Source::Msi(7)) }.unwrap();
+        assert!(!router.is_allocated(1, binding.vector));
+        assert_eq!(router.binding(Source::Msi(7)), None);
+    }
+
+    #[test]
+    fn rebalance() {
+        let mut router = Router::new(&[]);
+        for id in 0..4 {
+            router.route_msi(id, 3, None).unwrap();
+        }
+        router.route_msi(4, 1, None).unwrap();
+
+        let mut online = CpuSet::new();
+        online.insert(1);
+        online.insert(2);
+        online.insert(3);
+
+        let mut moves: Vec<(Binding, Binding)> = Vec::new();
+        let count = unsafe { router.rebalance(3, &online, |old, new| moves.push((old, new))) };
+        assert_eq!(count, Ok(4));
+        assert_eq!(router.load(3), 0);
+        assert_eq!(router.load(1), 3);
+        assert_eq!(router.load(2), 2);
+        assert!(moves
+            .iter()
+            .all(|(old, new)| old.source == new.source && new.cpu != 3));
+
+        router.route_msi(5, 3, None).unwrap();
+        let mut alone = CpuSet::new();
+        alone.insert(3);
+        let result = unsafe { router.rebalance(3, &alone, |_, _| ()) };
+        assert_eq!(result, Err(Error::NoOnlineCpu));
+    }
+}
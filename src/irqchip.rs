@@ -0,0 +1,132 @@
+//! A common interface over the interrupt controllers, so that the kernel can select the
+//! controller at runtime (the 8259 PICs during early boot, then the I/O APICs and the local APIC
+//! once they are discovered) and use it without knowing which one is active.
+use crate::{ioapic::IoApic, lapic::LocalApic, pic::ChainedPics};
+
+/// An interrupt controller. The IRQ numbers taken by the methods of this trait are the input
+/// lines of the controller, and not the interrupt vectors: the vector of an IRQ is given by
+/// [`IrqChip::irq_to_vector`].
+pub trait IrqChip {
+    /// Mask the given IRQ. A masked IRQ is not delivered to the CPUs.
+    ///
+    /// # Panics
+    /// Panics if the given IRQ is not handled by the controller.
+    ///
+    /// # Safety
+    /// This function is unsafe because it reconfigures the interrupt controller, and the
+    /// interrupts of the given IRQ will be lost.
+    unsafe fn mask(&self, irq: u32);
+
+    /// Unmask the given IRQ.
+    ///
+    /// # Panics
+    /// Panics if the given IRQ is not handled by the controller.
+    ///
+    /// # Safety
+    /// This function is unsafe because an interrupt can be delivered as soon as the IRQ is
+    /// unmasked: the IDT entry of its vector must be properly configured.
+    unsafe fn unmask(&self, irq: u32);
+
+    /// Signal the end of the given IRQ. This must be called at the end of the interrupt handler.
+    /// Returns `false` if the IRQ was a spurious interrupt, in which case the kernel must ignore
+    /// it.
+    ///
+    /// # Safety
+    /// This function is unsafe because it must only be called at the end of the handler of the
+    /// given IRQ, otherwise an interrupt could be acknowledged while still being serviced.
+    unsafe fn eoi(&self, irq: u32) -> bool;
+
+    /// Returns the interrupt vector of the given IRQ, or `None` if the IRQ is not handled by
+    /// the controller.
+    fn irq_to_vector(&self, irq: u32) -> Option<u8>;
+}
+
+impl IrqChip for ChainedPics {
+    unsafe fn mask(&self, irq: u32) {
+        let vector = self.irq_to_vector(irq).expect("IRQ not handled by the PICs");
+        ChainedPics::mask(self, vector);
+    }
+
+    unsafe fn unmask(&self, irq: u32) {
+        let vector = self.irq_to_vector(irq).expect("IRQ not handled by the PICs");
+        ChainedPics::unmask(self, vector);
+    }
+
+    unsafe fn eoi(&self, irq: u32) -> bool {
+        match self.irq_to_vector(irq) {
+            Some(vector) => self.send_eoi(vector) == crate::pic::Eoi::Sent,
+            None => false,
+        }
+    }
+
+    fn irq_to_vector(&self, irq: u32) -> Option<u8> {
+        u8::try_from(irq)
+            .ok()
+            .filter(|&irq| irq < 16)
+            .map(|irq| self.base() + irq)
+    }
+}
+
+/// The I/O APICs and the local APIC of the current CPU, used together as an interrupt
+/// controller. The IRQs are the global system interrupts (GSI) of the I/O APICs, and the vector
+/// of a GSI is the vector base plus the GSI: an ISA IRQ must be translated to its GSI with
+/// [`crate::ioapic::IsaRouting`], and its redirection entry programmed with the vector given by
+/// [`IrqChip::irq_to_vector`].
+#[derive(Debug, Clone)]
+pub struct Apic<'a> {
+    lapic: LocalApic,
+    ioapics: &'a [IoApic],
+    vector_base: u8,
+}
+
+impl<'a> Apic<'a> {
+    /// Creates a new APIC interrupt controller using the given local APIC and I/O APICs. The GSI
+    /// `n` will use the interrupt vector `vector_base + n`.
+    #[must_use]
+    pub const fn new(lapic: LocalApic, ioapics: &'a [IoApic], vector_base: u8) -> Self {
+        Self {
+            lapic,
+            ioapics,
+            vector_base,
+        }
+    }
+
+    /// Returns the local APIC used to signal the end of interrupts.
+    #[must_use]
+    pub const fn lapic(&self) -> &LocalApic {
+        &self.lapic
+    }
+
+    /// Returns the I/O APIC handling the given GSI.
+    ///
+    /// # Panics
+    /// Panics if none of the I/O APICs handles the given GSI.
+    fn ioapic(&self, gsi: u32) -> &IoApic {
+        self.ioapics
+            .iter()
+            .find(|ioapic| ioapic.handles(gsi))
+            .expect("GSI not handled by any I/O APIC")
+    }
+}
+
+impl IrqChip for Apic<'_> {
+    unsafe fn mask(&self, irq: u32) {
+        self.ioapic(irq).mask(irq);
+    }
+
+    unsafe fn unmask(&self, irq: u32) {
+        self.ioapic(irq).unmask(irq);
+    }
+
+    unsafe fn eoi(&self, _irq: u32) -> bool {
+        self.lapic.eoi();
+        true
+    }
+
+    fn irq_to_vector(&self, irq: u32) -> Option<u8> {
+        self.ioapics.iter().find(|ioapic| ioapic.handles(irq))?;
+        u8::try_from(irq)
+            .ok()
+            .and_then(|irq| self.vector_base.checked_add(irq))
+    }
+}
@@ -1,8 +1,28 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use bitfield::{Bit, BitMut, BitRange, BitRangeMut};
+use bitflags::bitflags;
 
-use crate::address::Virtual;
+use crate::{
+    address::Virtual,
+    cpu::{feature::Feature, msr, quirks::Vendor},
+    mmio::Volatile,
+    pit::Pit,
+};
 
-static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// The MSR of the first local APIC register in x2APIC mode. The MSR of each register is this base
+/// plus the offset of the register in xAPIC mode divided by 16.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// The MSR used to send a self IPI in x2APIC mode.
+const X2APIC_SELF_IPI: u32 = 0x83F;
+
+/// The software enable bit of the spurious interrupt vector register.
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+
+/// The EOI broadcast suppression bit of the spurious interrupt vector register.
+const SPURIOUS_SUPPRESS_EOI: u32 = 1 << 12;
+
+/// The number of times the delivery status of an IPI is polled before giving up.
+const IPI_TIMEOUT_SPINS: u32 = 100_000;
 
 /// Represents the local APIC registers. The values are the offsets from the
 /// base address of the local APIC.
@@ -63,9 +83,11 @@ pub enum Register {
 }
 
 /// Represents the destination of an IPI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IpiDestination {
-    /// Send the IPI to the given core.
-    Core(u8),
+    /// Send the IPI to the core with the given local APIC ID. In xAPIC mode, only IDs lower than
+    /// 256 can be targeted.
+    Core(u32),
 
     /// Send the IPI to the current core.
     SelfOnly,
@@ -77,6 +99,7 @@ pub enum IpiDestination {
     OtherCores,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IpiPriority {
     /// Normal priority.
     Normal = 0,
@@ -89,89 +112,1128 @@ pub enum IpiPriority {
 
     /// NMI priority. Send an NMI instead of an IPI, the interrupt vector is ignored.
     Nmi = 4,
-    // ...
-}
-
-/// Setup the local APIC. This function must be called before any other function in this module.
-/// The parameter is the base virtual address of the local APIC.
-///
-/// # Safety
-/// This function is unsafe because the caller must ensure that the given base address is valid,
-/// and is a virtual address that points to the local APIC (and not a physical address !). When
-/// remapping the physical memory, caching should be disabled for the local APIC memory region.
-pub unsafe fn setup(base: Virtual) {
-    assert!(base.is_page_aligned());
-    LAPIC_BASE.store(base.as_u64(), Ordering::Relaxed);
-}
-
-/// Enable the local APIC by setting the spurious interrupt vector register. This function must be
-/// called after the `setup` function, and for each core in the system.
-pub unsafe fn enable() {
-    let spurious = read(Register::SpuriousInterruptVector);
-    write(Register::SpuriousInterruptVector, spurious | 1 << 8);
-}
-
-/// Check if the local APIC has been initialized. This is useful to check if we can*
-/// use the local APIC, especially in the early boot process.
-pub fn initialized() -> bool {
-    LAPIC_BASE.load(Ordering::Relaxed) != 0
-}
-
-/// Send an IPI to the given destination with the given priorit to trigger the
-/// given interrupt vector.
-///
-/// # Safety
-/// This function is unsafe because the caller must ensure that the given
-/// interrupt vector is valid and can be triggered by an IPI. Furthermore, the caller needs to
-/// ensure that the `setup` function has been called before, in order to set the base address of
-/// the local APIC.
-pub unsafe fn send_ipi(destination: IpiDestination, priority: IpiPriority, vector: u8) {
-    let cmd = match destination {
-        IpiDestination::Core(core) => (
-            u32::from(core) << 24,
-            u32::from(vector) | (priority as u32) << 8,
-        ),
-        IpiDestination::SelfOnly => (0, u32::from(vector) | ((priority as u32) << 8) | 1 << 18),
-        IpiDestination::AllCores => (0, u32::from(vector) | ((priority as u32) << 8) | 2 << 18),
-        IpiDestination::OtherCores => (0, u32::from(vector) | ((priority as u32) << 8) | 3 << 18),
-    };
-
-    write(Register::InterruptCommand1, cmd.0);
-    write(Register::InterruptCommand0, cmd.1);
-
-    // Wait for the IPI to be sent
-    while read(Register::InterruptCommand0) & (1 << 12) != 0 {
-        core::hint::spin_loop();
-    }
-}
-
-/// Send an end-of-interrupt signal to the local APIC. This function must be called after an
-/// interrupt has been handled. Otherwise, no local APIC interrupts will be triggered until this
-/// function is called.
-/// 
-/// # Safety
-/// This function is safe because sending an end-of-interrupt signal should not have any direct
-/// side effects that could lead to memory unsafety or undefined behavior. 
-pub fn send_eoi() {
-    unsafe {
-        write(Register::EndOfInterrupt, 0);
+
+    /// Send an INIT request to the target core, which resets it into the wait-for-SIPI state. The
+    /// interrupt vector is ignored.
+    Init = 5,
+
+    /// Send a startup IPI (SIPI) to the target core. The interrupt vector is the physical page
+    /// number where the target core starts executing in real mode.
+    Startup = 6,
+}
+
+bitflags! {
+    /// The errors reported by the error status register of the local APIC.
+    pub struct ErrorStatus: u32 {
+        /// A checksum error was detected in a sent message (P6 and Pentium only)
+        const SEND_CHECKSUM = 1 << 0;
+
+        /// A checksum error was detected in a received message (P6 and Pentium only)
+        const RECEIVE_CHECKSUM = 1 << 1;
+
+        /// A sent message was not accepted by any APIC (P6 and Pentium only)
+        const SEND_ACCEPT = 1 << 2;
+
+        /// A received message was not accepted by any APIC (P6 and Pentium only)
+        const RECEIVE_ACCEPT = 1 << 3;
+
+        /// A lowest priority IPI was sent but this mode is not supported
+        const REDIRECTABLE_IPI = 1 << 4;
+
+        /// An IPI with an illegal vector (0 to 15) was sent
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+
+        /// An interrupt with an illegal vector (0 to 15) was received
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+
+        /// An access to an unimplemented register was made
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
+}
+
+/// The content of the version register of the local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    /// The version of the local APIC (0x1X for integrated APICs)
+    pub version: u8,
+
+    /// The number of LVT entries minus one
+    pub max_lvt: u8,
+
+    /// Set if the EOI broadcast to the I/O APICs can be suppressed
+    pub eoi_broadcast_suppression: bool,
+}
+
+impl Version {
+    /// Decode the given value of the version register.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self {
+            version: bits as u8,
+            max_lvt: (bits >> 16) as u8,
+            eoi_broadcast_suppression: bits & (1 << 24) != 0,
+        }
+    }
+}
+
+/// The divider applied to the bus clock to obtain the frequency of the local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+impl TimerDivide {
+    /// Returns the divisor applied to the bus clock.
+    #[must_use]
+    pub const fn divisor(self) -> u32 {
+        match self {
+            Self::By1 => 1,
+            Self::By2 => 2,
+            Self::By4 => 4,
+            Self::By8 => 8,
+            Self::By16 => 16,
+            Self::By32 => 32,
+            Self::By64 => 64,
+            Self::By128 => 128,
+        }
+    }
+
+    /// Decode the given value of the divide configuration register.
+    #[must_use]
+    pub const fn from_bits(bits: u32) -> Self {
+        match bits & 0b1011 {
+            0b0000 => Self::By2,
+            0b0001 => Self::By4,
+            0b0010 => Self::By8,
+            0b0011 => Self::By16,
+            0b1000 => Self::By32,
+            0b1001 => Self::By64,
+            0b1010 => Self::By128,
+            _ => Self::By1,
+        }
+    }
+}
+
+/// The frequency of the local APIC timer, in ticks per millisecond, as measured by
+/// [`LocalApic::calibrate_timer`]. It depends on the divider set with
+/// [`LocalApic::set_timer_divide`] at the time of the calibration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicksPerMs(u64);
+
+impl TicksPerMs {
+    /// Creates a new timer frequency from the given number of ticks per millisecond.
+    #[must_use]
+    pub const fn new(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// Returns the number of ticks per millisecond.
+    #[must_use]
+    pub const fn get(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the initial count to use to generate timer interrupts at the given frequency, in
+    /// Hz, saturated to the maximal count of the timer.
+    ///
+    /// # Panics
+    /// Panics if the frequency is 0.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn initial_count(&self, frequency: u64) -> u32 {
+        assert!(frequency != 0, "Timer frequency cannot be 0");
+        let count = self.0 * 1000 / frequency;
+        if count > u32::MAX as u64 {
+            u32::MAX
+        } else {
+            count as u32
+        }
+    }
+}
+
+/// The level of an IPI. All IPIs must be sent with the assert level, except the INIT level
+/// de-assert IPI, which is only needed by old processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiLevel {
+    Deassert = 0,
+    Assert = 1,
+}
+
+/// The trigger mode of an IPI. It is only used by the INIT level de-assert IPI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiTrigger {
+    Edge = 0,
+    Level = 1,
+}
+
+/// An error that occurred while sending an IPI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiError {
+    /// The local APIC did not send the IPI in time.
+    Timeout,
+
+    /// The local APIC reported errors while sending the IPI.
+    Rejected(ErrorStatus),
+}
+
+/// An inter-processor interrupt, that can be sent with [`LocalApic::send`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipi {
+    destination: IpiDestination,
+    priority: IpiPriority,
+    vector: u8,
+    level: IpiLevel,
+    trigger: IpiTrigger,
+}
+
+impl Ipi {
+    /// Create a new IPI to the given destination, with the given delivery mode and vector. The
+    /// IPI is edge-triggered, with the assert level.
+    #[must_use]
+    pub const fn new(destination: IpiDestination, priority: IpiPriority, vector: u8) -> Self {
+        Self {
+            destination,
+            priority,
+            vector,
+            level: IpiLevel::Assert,
+            trigger: IpiTrigger::Edge,
+        }
+    }
+
+    /// Create an INIT IPI to the given destination, level-triggered with the assert level.
+    #[must_use]
+    pub const fn init(destination: IpiDestination) -> Self {
+        Self {
+            destination,
+            priority: IpiPriority::Init,
+            vector: 0,
+            level: IpiLevel::Assert,
+            trigger: IpiTrigger::Level,
+        }
+    }
+
+    /// Create an INIT level de-assert IPI to the given destination. This is only needed by
+    /// processors before the Pentium 4 and Xeon, and is ignored by the others.
+    #[must_use]
+    pub const fn init_deassert(destination: IpiDestination) -> Self {
+        Self {
+            destination,
+            priority: IpiPriority::Init,
+            vector: 0,
+            level: IpiLevel::Deassert,
+            trigger: IpiTrigger::Level,
+        }
+    }
+
+    /// Create a startup IPI to the given destination. The target cores will start executing in
+    /// real mode at the beginning of the given physical page.
+    #[must_use]
+    pub const fn startup(destination: IpiDestination, page: u8) -> Self {
+        Self::new(destination, IpiPriority::Startup, page)
+    }
+
+    /// Set the level of the IPI. The default is [`IpiLevel::Assert`].
+    #[must_use]
+    pub fn set_level(&mut self, level: IpiLevel) -> &mut Self {
+        self.level = level;
+        self
+    }
+
+    /// Set the trigger mode of the IPI. The default is [`IpiTrigger::Edge`].
+    #[must_use]
+    pub fn set_trigger(&mut self, trigger: IpiTrigger) -> &mut Self {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Build the IPI from the current state.
+    #[must_use]
+    pub fn build(&mut self) -> Self {
+        *self
+    }
+
+    /// Returns the destination of the IPI.
+    #[must_use]
+    pub const fn destination(&self) -> IpiDestination {
+        self.destination
+    }
+
+    /// Returns the low 32 bits of the interrupt command register for this IPI, without the
+    /// destination field.
+    #[must_use]
+    pub const fn command(&self) -> u32 {
+        let shorthand = match self.destination {
+            IpiDestination::Core(_) => 0,
+            IpiDestination::SelfOnly => 1,
+            IpiDestination::AllCores => 2,
+            IpiDestination::OtherCores => 3,
+        };
+        self.vector as u32
+            | (self.priority as u32) << 8
+            | (self.level as u32) << 14
+            | (self.trigger as u32) << 15
+            | shorthand << 18
+    }
+}
+
+/// The delivery mode of an interrupt generated by a local vector table (LVT) entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LvtDelivery {
+    /// Deliver the interrupt with the vector of the entry.
+    Fixed = 0,
+
+    /// Deliver a system management interrupt. The vector must be 0.
+    Smi = 2,
+
+    /// Deliver a non-maskable interrupt. The vector is ignored.
+    Nmi = 4,
+
+    /// Deliver an INIT request. Not supported by the timer, thermal and CMCI entries.
+    Init = 5,
+
+    /// Act as if the interrupt came from an external controller (like the 8259 PIC). Only
+    /// supported by the LINT0 entry.
+    ExtInt = 7,
+}
+
+/// The polarity of the LINT0 and LINT1 pins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LvtPolarity {
+    ActiveHigh = 0,
+    ActiveLow = 1,
+}
+
+/// The mode of the local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// The timer fires once when the count reaches 0.
+    OneShot = 0,
+
+    /// The timer fires each time the count reaches 0, and is reloaded with the initial count.
+    Periodic = 1,
+
+    /// The timer fires when the TSC reaches the deadline written in the TSC deadline MSR.
+    TscDeadline = 2,
+}
+
+/// An entry of the local vector table (LVT), which configures how the local interrupt sources
+/// (the timer, the LINT0 and LINT1 pins, the performance counters, the thermal sensor, the
+/// corrected machine check errors and the internal errors) are delivered to the CPU. Some fields
+/// are only used by some entries: the polarity and the trigger mode are only used by the LINT0
+/// and LINT1 entries, and the timer mode is only used by the timer entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct LvtEntry(u32);
+
+impl LvtEntry {
+    /// A masked entry.
+    pub const MASKED: Self = Self(1 << 16);
+
+    /// Create a new unmasked entry delivering a fixed interrupt with the given vector. The entry
+    /// is active high and edge-triggered.
+    #[must_use]
+    pub const fn new(vector: u8) -> Self {
+        Self(vector as u32)
+    }
+
+    /// Create an entry from the raw value of a LVT register.
+    #[must_use]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Set the vector of the interrupt.
+    #[must_use]
+    pub fn set_vector(&mut self, vector: u8) -> &mut Self {
+        self.0.set_bit_range(7, 0, u32::from(vector));
+        self
+    }
+
+    /// Set the delivery mode of the interrupt. The default is [`LvtDelivery::Fixed`].
+    #[must_use]
+    pub fn set_delivery(&mut self, delivery: LvtDelivery) -> &mut Self {
+        self.0.set_bit_range(10, 8, delivery as u32);
+        self
+    }
+
+    /// Set the polarity of the pin. The default is [`LvtPolarity::ActiveHigh`].
+    #[must_use]
+    pub fn set_polarity(&mut self, polarity: LvtPolarity) -> &mut Self {
+        self.0.set_bit(13, polarity == LvtPolarity::ActiveLow);
+        self
+    }
+
+    /// Set the trigger mode of the pin. The default is [`IpiTrigger::Edge`]. The NMI, SMI and
+    /// INIT delivery modes are always edge-triggered.
+    #[must_use]
+    pub fn set_trigger(&mut self, trigger: IpiTrigger) -> &mut Self {
+        self.0.set_bit(15, trigger == IpiTrigger::Level);
+        self
+    }
+
+    /// Mask or unmask the entry. A masked entry does not deliver any interrupt.
+    #[must_use]
+    pub fn set_masked(&mut self, masked: bool) -> &mut Self {
+        self.0.set_bit(16, masked);
+        self
+    }
+
+    /// Set the mode of the timer. The default is [`TimerMode::OneShot`].
+    #[must_use]
+    pub fn set_timer_mode(&mut self, mode: TimerMode) -> &mut Self {
+        self.0.set_bit_range(18, 17, mode as u32);
+        self
+    }
+
+    /// Build the entry from the current state.
+    #[must_use]
+    pub fn build(&mut self) -> Self {
+        *self
+    }
+
+    /// Returns the vector of the interrupt.
+    #[must_use]
+    pub fn vector(&self) -> u8 {
+        self.0.bit_range(7, 0)
+    }
+
+    /// Returns `true` if the entry is masked.
+    #[must_use]
+    pub fn masked(&self) -> bool {
+        self.0.bit(16)
+    }
+
+    /// Returns `true` if an interrupt has been sent to the CPU but not yet accepted. This bit is
+    /// read-only.
+    #[must_use]
+    pub fn pending(&self) -> bool {
+        self.0.bit(12)
+    }
+
+    /// Returns the raw value of the entry.
+    #[must_use]
+    pub const fn bits(&self) -> u32 {
+        self.0
     }
 }
 
-/// Write the given value to the given register.
-pub unsafe fn write(register: Register, value: u32) {
-    let base = LAPIC_BASE.load(Ordering::Relaxed);
-    let addr = base + register as u64;
-    let ptr = addr as *mut u32;
+/// The way the local APIC registers are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The registers are memory mapped, and APIC IDs are 8 bits wide.
+    XApic,
+
+    /// The registers are accessed with MSRs, and APIC IDs are 32 bits wide.
+    X2Apic,
+}
+
+impl Mode {
+    /// Returns the mode enabled on the current CPU, read from the APIC base register.
+    #[must_use]
+    pub fn current() -> Self {
+        if msr::apic_base::read().contains(msr::apic_base::Flags::X2APIC) {
+            Self::X2Apic
+        } else {
+            Self::XApic
+        }
+    }
+
+    /// Returns the APIC ID contained in the given value of the ID register. In xAPIC mode, the ID
+    /// is in the upper 8 bits of the register, and in x2APIC mode it uses the whole register.
+    #[must_use]
+    pub const fn decode_id(self, register: u32) -> u32 {
+        match self {
+            Self::XApic => register >> 24,
+            Self::X2Apic => register,
+        }
+    }
+}
+
+/// A handle to the local APIC registers. In xAPIC mode, the registers are mapped in the virtual
+/// address space. The local APIC of each CPU is mapped at the same physical address, so a single
+/// handle can be shared by all CPUs: each CPU will access its own local APIC through it. In x2APIC
+/// mode, the registers are accessed with MSRs and the base address is not used.
+#[derive(Debug, Clone)]
+pub struct LocalApic {
+    base: Virtual,
+    mode: Mode,
+}
+
+impl LocalApic {
+    /// Creates a new handle to the local APIC. If the x2APIC mode is supported by the CPU (see
+    /// [`x2apic_supported`]), it is enabled on the current CPU and the given base address is not
+    /// used. Otherwise, the registers are accessed through the given virtual address.
+    ///
+    /// # Panics
+    /// This function panics if the given base address is not page aligned.
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must ensure that the given base address is
+    /// valid, and is a virtual address that points to the local APIC (and not a physical
+    /// address !). When remapping the physical memory, caching should be disabled for the local
+    /// APIC memory region. The mapping must live as long as the returned handle.
+    #[must_use]
+    pub unsafe fn new(base: Virtual) -> Self {
+        if x2apic_supported() {
+            Self::new_x2apic()
+        } else {
+            Self::new_xapic(base)
+        }
+    }
+
+    /// Creates a new handle to the local APIC in xAPIC mode, even if the x2APIC mode is
+    /// supported.
+    ///
+    /// # Panics
+    /// This function panics if the given base address is not page aligned.
+    ///
+    /// # Safety
+    /// See [`LocalApic::new`]. Furthermore, the x2APIC mode must not be enabled on the current
+    /// CPU, because it cannot be disabled without disabling the local APIC.
+    #[must_use]
+    pub const unsafe fn new_xapic(base: Virtual) -> Self {
+        assert!(base.is_page_aligned());
+        Self {
+            base,
+            mode: Mode::XApic,
+        }
+    }
+
+    /// Creates a new handle to the local APIC in x2APIC mode, and enable the x2APIC mode on the
+    /// current CPU.
+    ///
+    /// # Panics
+    /// This function panics if the x2APIC mode is not supported by the CPU.
+    ///
+    /// # Safety
+    /// This function is unsafe because the code using the local APIC in xAPIC mode will stop
+    /// working on the current CPU.
+    #[must_use]
+    pub unsafe fn new_x2apic() -> Self {
+        assert!(x2apic_supported(), "x2APIC mode is not supported");
+        msr::apic_base::set(msr::apic_base::Flags::ENABLE | msr::apic_base::Flags::X2APIC);
+        Self {
+            base: Virtual::new(0),
+            mode: Mode::X2Apic,
+        }
+    }
+
+    /// Returns the virtual base address of the local APIC registers. This is meaningless in
+    /// x2APIC mode.
+    #[must_use]
+    pub const fn base(&self) -> Virtual {
+        self.base
+    }
+
+    /// Returns the mode used to access the local APIC registers.
+    #[must_use]
+    pub const fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Enable the local APIC of the current CPU by setting the software enable bit in the
+    /// spurious interrupt vector register, with the given spurious interrupt vector. In x2APIC
+    /// mode, the x2APIC mode is also enabled on the current CPU. This function must be called on
+    /// each core in the system.
+    ///
+    /// The EOI broadcast suppression bit is left untouched: it is disabled after a reset, and can
+    /// be enabled with [`LocalApic::set_eoi_broadcast_suppression`].
+    ///
+    /// # Safety
+    /// This function is unsafe because the local APIC can trigger interrupts as soon as it is
+    /// enabled: the IDT must be properly configured, including an handler for the spurious
+    /// vector (which must not send an EOI).
+    pub unsafe fn enable(&self, spurious_vector: u8) {
+        if self.mode == Mode::X2Apic {
+            msr::apic_base::set(msr::apic_base::Flags::ENABLE | msr::apic_base::Flags::X2APIC);
+        }
+        let spurious = self.read(Register::SpuriousInterruptVector) & !0xFF;
+        self.write(
+            Register::SpuriousInterruptVector,
+            spurious | SPURIOUS_ENABLE | u32::from(spurious_vector),
+        );
+    }
+
+    /// Disable the local APIC of the current CPU by clearing the software enable bit in the
+    /// spurious interrupt vector register. All LVT entries are masked while the local APIC is
+    /// disabled.
+    ///
+    /// # Safety
+    /// This function is unsafe because the code relying on local APIC interrupts (the timer, the
+    /// IPIs...) will stop working on the current CPU.
+    pub unsafe fn disable(&self) {
+        let spurious = self.read(Register::SpuriousInterruptVector);
+        self.write(Register::SpuriousInterruptVector, spurious & !SPURIOUS_ENABLE);
+    }
+
+    /// Set the vector of the spurious interrupts generated by the local APIC. On P6 and Pentium
+    /// processors, the lowest 4 bits of the vector are hardwired to 1, so a vector ending with
+    /// 0xF (like 0xFF) should be used for compatibility.
+    ///
+    /// # Safety
+    /// This function is unsafe because an handler must be installed in the IDT for the given
+    /// vector.
+    pub unsafe fn set_spurious_vector(&self, vector: u8) {
+        let spurious = self.read(Register::SpuriousInterruptVector) & !0xFF;
+        self.write(Register::SpuriousInterruptVector, spurious | u32::from(vector));
+    }
+
+    /// Enable or disable the suppression of the EOI broadcast. When enabled, an EOI for a
+    /// level-triggered interrupt is not broadcasted to the I/O APICs, and the kernel must send
+    /// the EOI directly to the I/O APIC that delivered the interrupt. Returns `false` if this
+    /// feature is not supported by the local APIC (see [`Version::eoi_broadcast_suppression`]),
+    /// in which case nothing is changed.
+    ///
+    /// # Safety
+    /// This function is unsafe because level-triggered interrupts will not be delivered anymore if
+    /// the kernel does not send EOIs to the I/O APICs when the suppression is enabled.
+    #[must_use]
+    pub unsafe fn set_eoi_broadcast_suppression(&self, suppress: bool) -> bool {
+        if !self.version().eoi_broadcast_suppression {
+            return false;
+        }
+
+        let spurious = self.read(Register::SpuriousInterruptVector);
+        if suppress {
+            self.write(Register::SpuriousInterruptVector, spurious | SPURIOUS_SUPPRESS_EOI);
+        } else {
+            self.write(Register::SpuriousInterruptVector, spurious & !SPURIOUS_SUPPRESS_EOI);
+        }
+        true
+    }
+
+    /// Returns the local APIC ID of the current CPU, read from the ID register.
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        self.mode.decode_id(self.read(Register::Id))
+    }
+
+    /// Returns the content of the version register.
+    #[must_use]
+    pub fn version(&self) -> Version {
+        Version::from_bits(self.read(Register::Version))
+    }
+
+    /// Returns the current task priority. Interrupts with a priority class (the upper 4 bits of
+    /// the vector) lower or equal to the priority class of the task priority are not delivered.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn task_priority(&self) -> u8 {
+        self.read(Register::TaskPriority) as u8
+    }
+
+    /// Set the task priority of the current CPU (see [`LocalApic::task_priority`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because setting a high task priority can prevent important
+    /// interrupts (like the timer) from being delivered.
+    pub unsafe fn set_task_priority(&self, priority: u8) {
+        self.write(Register::TaskPriority, u32::from(priority));
+    }
+
+    /// Returns the current processor priority, computed by the local APIC from the task priority
+    /// and the highest priority interrupt being serviced.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn processor_priority(&self) -> u8 {
+        self.read(Register::ProcessorPriority) as u8
+    }
+
+    /// Returns the errors detected by the local APIC since the last call to this function. The
+    /// error status register is only updated when it is written, so it is written before being
+    /// read: this also clears the errors reported by the previous update.
+    #[must_use]
+    pub fn error_status(&self) -> ErrorStatus {
+        // SAFETY: Writing the error status register only updates its content
+        unsafe {
+            self.write(Register::ErrorStatus, 0);
+        }
+        ErrorStatus::from_bits_truncate(self.read(Register::ErrorStatus))
+    }
+
+    /// Returns the vector of the spurious interrupts generated by the local APIC.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn spurious_vector(&self) -> u8 {
+        self.read(Register::SpuriousInterruptVector) as u8
+    }
+
+    /// Returns `true` if the given vector is being serviced by the current CPU, i.e. it has been
+    /// delivered to the CPU but no EOI has been sent yet.
+    #[must_use]
+    pub fn in_service(&self, vector: u8) -> bool {
+        self.vector_bit(Register::InService0 as u64, vector)
+    }
+
+    /// Returns `true` if an interrupt with the given vector has been accepted by the local APIC
+    /// but not yet delivered to the CPU.
+    #[must_use]
+    pub fn requested(&self, vector: u8) -> bool {
+        self.vector_bit(Register::InterruptRequest0 as u64, vector)
+    }
+
+    /// Returns `true` if the given vector was accepted as level-triggered by the local APIC.
+    #[must_use]
+    pub fn level_triggered(&self, vector: u8) -> bool {
+        self.vector_bit(Register::TriggerMode0 as u64, vector)
+    }
+
+    /// Set the LVT entry of the local APIC timer.
+    ///
+    /// # Safety
+    /// This function is unsafe because the timer can trigger interrupts as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_timer(&self, entry: LvtEntry) {
+        self.write(Register::LvtTimer, entry.bits());
+    }
+
+    /// Set the LVT entry of the LINT0 pin, usually connected to the 8259 PIC (with the
+    /// [`LvtDelivery::ExtInt`] delivery mode), or masked when the I/O APIC is used.
+    ///
+    /// # Safety
+    /// This function is unsafe because interrupts can be triggered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_lint0(&self, entry: LvtEntry) {
+        self.write(Register::LvtLint0, entry.bits());
+    }
+
+    /// Set the LVT entry of the LINT1 pin, usually connected to the NMI line of the chipset (with
+    /// the [`LvtDelivery::Nmi`] delivery mode).
+    ///
+    /// # Safety
+    /// This function is unsafe because interrupts can be triggered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_lint1(&self, entry: LvtEntry) {
+        self.write(Register::LvtLint1, entry.bits());
+    }
+
+    /// Set the LVT entry of the thermal sensor. This entry is not present on all processors (see
+    /// [`Version::max_lvt`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because interrupts can be triggered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_thermal(&self, entry: LvtEntry) {
+        self.write(Register::LvtThermalSensor, entry.bits());
+    }
+
+    /// Set the LVT entry of the performance monitoring counters overflow. This entry is not
+    /// present on all processors (see [`Version::max_lvt`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because interrupts can be triggered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_performance(&self, entry: LvtEntry) {
+        self.write(Register::LvtPerformanceCounter, entry.bits());
+    }
+
+    /// Set the LVT entry of the internal errors of the local APIC (see
+    /// [`LocalApic::error_status`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because interrupts can be triggered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_error(&self, entry: LvtEntry) {
+        self.write(Register::LvtError, entry.bits());
+    }
+
+    /// Set the LVT entry of the corrected machine check error interrupts. This entry is not
+    /// present on all processors (see [`Version::max_lvt`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because interrupts can be triggered as soon as the entry is
+    /// unmasked: the IDT must be properly configured.
+    pub unsafe fn set_lvt_cmci(&self, entry: LvtEntry) {
+        self.write(Register::LvtCmci, entry.bits());
+    }
+
+    /// Returns the LVT entry of the local APIC timer.
+    #[must_use]
+    pub fn lvt_timer(&self) -> LvtEntry {
+        LvtEntry::from_bits(self.read(Register::LvtTimer))
+    }
+
+    /// Set the divider of the local APIC timer.
+    ///
+    /// # Safety
+    /// This function is unsafe because it changes the frequency of the timer, which may be used
+    /// by the kernel for scheduling or timekeeping.
+    pub unsafe fn set_timer_divide(&self, divide: TimerDivide) {
+        self.write(Register::DivideConfiguration, divide as u32);
+    }
+
+    /// Set the initial count of the local APIC timer, and restart the count down from it. Writing
+    /// 0 stops the timer.
+    ///
+    /// # Safety
+    /// This function is unsafe because the timer will trigger an interrupt when the count reaches
+    /// 0, if it is not masked in the LVT timer register.
+    pub unsafe fn set_timer_initial_count(&self, count: u32) {
+        self.write(Register::InitialCount, count);
+    }
+
+    /// Returns the current divider of the local APIC timer.
+    #[must_use]
+    pub fn timer_divide(&self) -> TimerDivide {
+        TimerDivide::from_bits(self.read(Register::DivideConfiguration))
+    }
+
+    /// Measure the frequency of the local APIC timer with the current divider. If the CPU
+    /// enumerates its core crystal clock frequency with the `cpuid` instruction (leaves 0x15 and
+    /// 0x16 on Intel processors), the frequency is computed from it. Otherwise, the timer is
+    /// measured during one period of the given PIT (see [`Pit::wait_period`]): a PIT with a
+    /// frequency of 100 Hz (a 10 ms period) gives a good trade-off between accuracy and boot time.
+    ///
+    /// The timer is stopped when this function returns, and the LVT timer register is restored.
+    ///
+    /// # Safety
+    /// This function is unsafe because it reprograms the local APIC timer and the channel 2 of
+    /// the PIT. Interrupts should be disabled during the calibration to keep it accurate.
+    #[must_use]
+    pub unsafe fn calibrate_timer(&self, pit: &Pit) -> TicksPerMs {
+        let divisor = u64::from(self.timer_divide().divisor());
+        if let Some(frequency) = crystal_frequency() {
+            return TicksPerMs::new(frequency / divisor / 1000);
+        }
+
+        // Mask the timer interrupt during the measure
+        let lvt = self.lvt_timer();
+        self.set_lvt_timer(LvtEntry::MASKED);
+
+        self.set_timer_initial_count(u32::MAX);
+        pit.wait_period();
+        let elapsed = u32::MAX - self.timer_current_count();
+        self.set_timer_initial_count(0);
+        self.set_lvt_timer(lvt);
+
+        TicksPerMs::new(u64::from(elapsed) * pit.get_frequency() / 1000)
+    }
+
+    /// Returns the current count of the local APIC timer.
+    #[must_use]
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(Register::CurrentCount)
+    }
+
+    /// Send an IPI to the given destination with the given priority to trigger the given
+    /// interrupt vector. See [`LocalApic::send`] for more details.
+    ///
+    /// # Errors
+    /// See [`LocalApic::send`].
+    ///
+    /// # Safety
+    /// See [`LocalApic::send`].
+    pub unsafe fn send_ipi(
+        &self,
+        destination: IpiDestination,
+        priority: IpiPriority,
+        vector: u8,
+    ) -> Result<(), IpiError> {
+        self.send(Ipi::new(destination, priority, vector))
+    }
+
+    /// Send an IPI with a normal priority to the current core, to trigger the given interrupt
+    /// vector. The interrupt is handled once the processor priority drops below the priority
+    /// class of the vector (the vector divided by 16) and the interrupts are enabled, which can be
+    /// used to defer work from an interrupt handler (see [`crate::deferred`]).
+    ///
+    /// # Errors
+    /// See [`LocalApic::send`].
+    ///
+    /// # Safety
+    /// See [`LocalApic::send`].
+    pub unsafe fn self_ipi(&self, vector: u8) -> Result<(), IpiError> {
+        self.send_ipi(IpiDestination::SelfOnly, IpiPriority::Normal, vector)
+    }
+
+    /// Send the given IPI, and wait until it has been sent. In x2APIC mode, an IPI with a normal
+    /// priority sent to the current core uses the dedicated self IPI register, and there is no
+    /// need to wait for the IPI to be sent. Interrupts are disabled while the IPI is sent, and
+    /// the previous interrupt state is restored before returning.
+    ///
+    /// # Errors
+    /// Returns [`IpiError::Timeout`] if the local APIC did not send the IPI in time, and
+    /// [`IpiError::Rejected`] if the local APIC reported a send error in the error status
+    /// register.
+    ///
+    /// # Panics
+    /// This function panics if the destination core ID is greater than 255 in xAPIC mode.
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must ensure that the given interrupt vector is
+    /// valid and can be triggered by an IPI. The error status register is cleared by this
+    /// function, so errors unrelated to the IPI will be lost.
+    pub unsafe fn send(&self, ipi: Ipi) -> Result<(), IpiError> {
+        let destination = match ipi.destination {
+            IpiDestination::Core(core) => core,
+            _ => 0,
+        };
+
+        // An interrupt handler sending an IPI between the writes to the two halves of the
+        // interrupt command register, or reading the error status register, would corrupt this
+        // IPI or steal its errors, so the whole sequence runs with interrupts disabled.
+        crate::irq::without(|| {
+            // Clear the errors unrelated to this IPI
+            let _ = self.error_status();
+            match self.mode {
+                Mode::XApic => {
+                    assert!(
+                        destination < 256,
+                        "Cannot send an IPI to a core ID > 255 in xAPIC mode"
+                    );
+                    self.write(Register::InterruptCommand1, destination << 24);
+                    self.write(Register::InterruptCommand0, ipi.command());
+
+                    // Wait for the IPI to be sent
+                    let mut spins = 0;
+                    while self.read(Register::InterruptCommand0) & (1 << 12) != 0 {
+                        if spins == IPI_TIMEOUT_SPINS {
+                            return Err(IpiError::Timeout);
+                        }
+                        core::hint::spin_loop();
+                        spins += 1;
+                    }
+                }
+                Mode::X2Apic => {
+                    if let (IpiDestination::SelfOnly, IpiPriority::Normal) =
+                        (ipi.destination, ipi.priority)
+                    {
+                        msr::write(X2APIC_SELF_IPI, u64::from(ipi.vector));
+                    } else {
+                        let msr = X2APIC_MSR_BASE + (Register::InterruptCommand0 as u32 >> 4);
+                        msr::write(msr, u64::from(destination) << 32 | u64::from(ipi.command()));
+                    }
+                }
+            }
+
+            let errors = self.error_status()
+                & (ErrorStatus::SEND_CHECKSUM
+                    | ErrorStatus::SEND_ACCEPT
+                    | ErrorStatus::REDIRECTABLE_IPI
+                    | ErrorStatus::SEND_ILLEGAL_VECTOR);
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(IpiError::Rejected(errors))
+            }
+        })
+    }
+
+    /// Send an end-of-interrupt signal to the local APIC. This function must be called after an
+    /// interrupt has been handled. Otherwise, no local APIC interrupts will be triggered until
+    /// this function is called.
+    pub fn eoi(&self) {
+        // SAFETY: Sending an end-of-interrupt signal should not have any direct side effects that
+        // could lead to memory unsafety or undefined behavior.
+        unsafe {
+            self.write(Register::EndOfInterrupt, 0);
+        }
+    }
+
+    /// Write the given value to the given register. The typed accessors should be preferred when
+    /// they exist.
+    ///
+    /// # Safety
+    /// This function is unsafe because writing to some registers can trigger interrupts or
+    /// disable the local APIC. In x2APIC mode, writing to a register that does not exist in this
+    /// mode (like [`Register::InterruptCommand1`]) causes a general protection fault.
+    pub unsafe fn write(&self, register: Register, value: u32) {
+        self.write_offset(register as u64, value);
+    }
+
+    /// Read the value of the given register. The typed accessors should be preferred when they
+    /// exist.
+    ///
+    /// # Panics
+    /// In x2APIC mode, reading a register that does not exist in this mode (like
+    /// [`Register::InterruptCommand1`]) causes a general protection fault.
+    #[must_use]
+    pub fn read(&self, register: Register) -> u32 {
+        self.read_offset(register as u64)
+    }
+
+    /// Returns the bit of the given vector in the 256 bits register starting at the given offset
+    /// (the in-service, interrupt request and trigger mode registers). Each 32 bits part of these
+    /// registers is aligned on a 16 bytes boundary.
+    fn vector_bit(&self, offset: u64, vector: u8) -> bool {
+        let bits = self.read_offset(offset + u64::from(vector / 32) * 16);
+        bits & (1 << (vector % 32)) != 0
+    }
+
+    /// Read the register at the given offset, in xAPIC or x2APIC mode.
+    #[allow(clippy::cast_possible_truncation)]
+    fn read_offset(&self, offset: u64) -> u32 {
+        // SAFETY: The base address is valid (see `LocalApic::new`), and reading a local APIC
+        // register does not have any side effects.
+        unsafe {
+            match self.mode {
+                Mode::XApic => Volatile::<u32>::from_address(self.base + offset).read(),
+                Mode::X2Apic => msr::read(X2APIC_MSR_BASE + (offset >> 4) as u32) as u32,
+            }
+        }
+    }
+
+    /// Write the register at the given offset, in xAPIC or x2APIC mode.
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe fn write_offset(&self, offset: u64, value: u32) {
+        match self.mode {
+            Mode::XApic => Volatile::<u32>::from_address(self.base + offset).write(value),
+            Mode::X2Apic => msr::write(X2APIC_MSR_BASE + (offset >> 4) as u32, u64::from(value)),
+        }
+    }
+}
+
+/// Returns the frequency of the core crystal clock, in Hz, if it is enumerated by the `cpuid`
+/// instruction. On Intel processors, the local APIC timer is clocked by the core crystal clock
+/// (leaf 0x15). If the crystal frequency is not enumerated, the bus frequency reported by leaf 0x16
+/// is used. This is not reliable on AMD processors, where `None` is always returned.
+fn crystal_frequency() -> Option<u64> {
+    if crate::cpu::vendor() != Vendor::Intel {
+        return None;
+    }
+
+    let max = unsafe { core::arch::x86_64::__cpuid(0).eax };
+    if max >= 0x15 {
+        let leaf = unsafe { core::arch::x86_64::__cpuid(0x15) };
+        if leaf.ecx != 0 {
+            return Some(u64::from(leaf.ecx));
+        }
+    }
+    if max >= 0x16 {
+        let leaf = unsafe { core::arch::x86_64::__cpuid(0x16) };
+        if leaf.ecx & 0xFFFF != 0 {
+            return Some(u64::from(leaf.ecx & 0xFFFF) * 1_000_000);
+        }
+    }
+    None
+}
+
+/// Returns `true` if the TSC deadline mode of the local APIC timer is supported by the current CPU.
+#[must_use]
+pub fn tsc_deadline_supported() -> bool {
+    crate::cpu::features().has(Feature::TscDeadline)
+}
+
+/// Returns `true` if the x2APIC mode is supported by the current CPU.
+#[must_use]
+pub fn x2apic_supported() -> bool {
+    crate::cpu::features().has(Feature::X2apic)
+}
+
+/// Returns the local APIC ID of the current CPU, without requiring a [`LocalApic`] handle. In
+/// x2APIC mode, the 32 bits ID register is read from its MSR. In xAPIC mode, the ID register is
+/// memory mapped and cannot be read without a mapping: the 8 bits ID reported by the `cpuid`
+/// instruction is returned instead, which is equal to the register unless the kernel changes it.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn apic_id() -> u32 {
+    match Mode::current() {
+        // SAFETY: The ID register is readable in x2APIC mode
+        Mode::X2Apic => unsafe {
+            let register = msr::read(X2APIC_MSR_BASE + (Register::Id as u32 >> 4)) as u32;
+            Mode::X2Apic.decode_id(register)
+        },
+        Mode::XApic => unsafe { core::arch::x86_64::__cpuid(1).ebx >> 24 },
+    }
+}
+
+/// Returns the initial local APIC ID of the current CPU, as reported by the `cpuid` instruction.
+/// This does not require the local APIC to be mapped or enabled, and can be used before the
+/// per-CPU area is set up (for example to index per-CPU tables). It is equal to the ID register
+/// of the local APIC unless the kernel changes it. The 32 bits x2APIC ID is returned if the CPU
+/// enumerates it (leaf 0x0B), otherwise the 8 bits xAPIC ID is returned (leaf 0x01).
+#[must_use]
+pub fn initial_apic_id() -> u32 {
     unsafe {
-        ptr.write_volatile(value);
+        if core::arch::x86_64::__cpuid(0).eax >= 0x0B {
+            let topology = core::arch::x86_64::__cpuid_count(0x0B, 0);
+            if topology.ebx != 0 {
+                return topology.edx;
+            }
+        }
+        core::arch::x86_64::__cpuid(1).ebx >> 24
     }
 }
 
-/// Read the value of the given register.
-pub unsafe fn read(register: Register) -> u32 {
-    let base = LAPIC_BASE.load(Ordering::Relaxed);
-    let addr = base + register as u64;
-    let ptr = addr as *const u32;
-    unsafe { ptr.read_volatile() }
+#[cfg(test)]
+mod test {
+    #[test]
+    fn id_decoding() {
+        use super::Mode;
+
+        assert_eq!(Mode::XApic.decode_id(0x0300_0000), 3);
+        assert_eq!(Mode::XApic.decode_id(0xFF00_0000), 0xFF);
+        assert_eq!(Mode::X2Apic.decode_id(0x0000_0103), 0x103);
+    }
+
+    #[test]
+    fn version_decoding() {
+        let version = super::Version::from_bits(0x0105_0014);
+        assert_eq!(version.version, 0x14);
+        assert_eq!(version.max_lvt, 5);
+        assert!(version.eoi_broadcast_suppression);
+    }
+
+    #[test]
+    fn ipi_command() {
+        use super::{Ipi, IpiDestination, IpiLevel, IpiPriority, IpiTrigger};
+
+        let ipi = Ipi::new(IpiDestination::Core(3), IpiPriority::Normal, 0xFD);
+        assert_eq!(ipi.command(), 0x0000_40FD);
+        assert_eq!(Ipi::init(IpiDestination::Core(1)).command(), 0x0000_C500);
+        assert_eq!(Ipi::init_deassert(IpiDestination::AllCores).command(), 0x0008_8500);
+        assert_eq!(Ipi::startup(IpiDestination::Core(1), 0x08).command(), 0x0000_4608);
+
+        let ipi = Ipi::new(IpiDestination::OtherCores, IpiPriority::Nmi, 0)
+            .set_level(IpiLevel::Deassert)
+            .set_trigger(IpiTrigger::Level)
+            .build();
+        assert_eq!(ipi.command(), 0x000C_8400);
+    }
+
+    #[test]
+    fn lvt_entry() {
+        use super::{IpiTrigger, LvtDelivery, LvtEntry, LvtPolarity, TimerMode};
+
+        let nmi = LvtEntry::new(0).set_delivery(LvtDelivery::Nmi).build();
+        assert_eq!(nmi.bits(), 0x0400);
+
+        let lint0 = LvtEntry::new(0x20)
+            .set_delivery(LvtDelivery::ExtInt)
+            .set_polarity(LvtPolarity::ActiveLow)
+            .set_trigger(IpiTrigger::Level)
+            .set_masked(true)
+            .build();
+        assert_eq!(lint0.bits(), 0x0001_A720);
+        assert!(lint0.masked());
+        assert_eq!(lint0.vector(), 0x20);
+
+        let timer = LvtEntry::new(0x30).set_timer_mode(TimerMode::Periodic).build();
+        assert_eq!(timer.bits(), 0x0002_0030);
+    }
+
+    #[test]
+    fn timer_divide_encoding() {
+        use super::TimerDivide;
+        for divide in [TimerDivide::By1, TimerDivide::By2, TimerDivide::By16, TimerDivide::By128] {
+            assert_eq!(TimerDivide::from_bits(divide as u32), divide);
+        }
+        assert_eq!(TimerDivide::By32.divisor(), 32);
+    }
+
+    #[test]
+    fn ticks_initial_count() {
+        let ticks = super::TicksPerMs::new(100_000);
+        assert_eq!(ticks.initial_count(1000), 100_000);
+        assert_eq!(ticks.initial_count(100), 1_000_000);
+        assert_eq!(super::TicksPerMs::new(u64::MAX / 1000).initial_count(1), u32::MAX);
+    }
 }
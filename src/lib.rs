@@ -3,7 +3,7 @@
 //! Silicium (for now), and may not be stable, safe or well-documented. Use at your own risk.
 //! The code is greatly inspired by [Phil Opp's blog](https://os.phil-opp.com/), and his [crate](
 //! https://github.com/rust-osdev/x86_64)
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "mock_io")), no_std)]
 #![feature(linkage)]
 #![feature(asm_const)]
 #![feature(step_trait)]
@@ -14,20 +14,55 @@
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_safety_doc)]
 
+pub mod acpi;
 pub mod address;
+pub mod backtrace;
+pub mod bootprof;
+pub mod clock;
+#[cfg(feature = "x86_64_compat")]
+pub mod compat;
 pub mod cpu;
+pub mod deferred;
+pub mod extable;
+pub mod fpu;
 pub mod gdt;
 pub mod idt;
 pub mod io;
+pub mod ioapic;
 pub mod irq;
+pub mod irq_priority;
+pub mod irq_routing;
+pub mod irqchip;
 pub mod lapic;
+pub mod memory;
+pub mod mmio;
+#[cfg(any(test, feature = "mock_io"))]
+pub mod mock;
+pub mod once;
 pub mod paging;
+pub mod panic_support;
+pub mod pci;
+pub mod pcid;
+pub mod percpu;
 pub mod pic;
 pub mod pit;
+pub mod power;
+pub mod ps2;
+pub mod pti;
+pub mod qemu;
+pub mod rtc;
 pub mod segment;
 pub mod serial;
+pub mod smp;
+pub mod stack;
+pub mod syscall;
+#[cfg(feature = "test_runner")]
+pub mod test_runner;
+pub mod thermal;
 pub mod tsc;
 pub mod tss;
+pub mod usercopy;
+pub mod watchdog;
 
 pub mod prelude {
     pub use crate::*;
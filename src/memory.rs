@@ -0,0 +1,506 @@
+//! The physical memory map given by the bootloader, in a form independent of the boot protocol.
+//! The map is stored in a fixed-size array, so that it can be built before any allocator is
+//! available. With the `limine` and `multiboot2` features, the memory map (and the higher-half
+//! direct mapping offset for Limine) can be converted from the responses of these protocols.
+use crate::address::Physical;
+
+/// The maximal number of regions of a [`MemoryMap`].
+pub const MAX_REGIONS: usize = 128;
+
+/// The highest valid physical address. The regions of a [`MemoryMap`] are clamped so that they
+/// end at most at this address.
+const LAST_ADDRESS: u64 = 0x000F_FFFF_FFFF_FFFF;
+
+/// An error while building a memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The memory map already contains [`MAX_REGIONS`] regions.
+    Full,
+
+    /// The structure given by the bootloader is truncated or malformed.
+    InvalidLength,
+
+    /// The bootloader did not give a memory map.
+    NotFound,
+}
+
+/// The usage of a region of the physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Free memory, that can be used by the kernel.
+    Usable,
+
+    /// Memory reserved by the firmware or the hardware.
+    Reserved,
+
+    /// Memory containing the ACPI tables, that can be used once the tables have been parsed.
+    AcpiReclaimable,
+
+    /// Memory reserved for the firmware, that must be preserved across sleep states.
+    AcpiNvs,
+
+    /// Memory reported as defective.
+    BadMemory,
+
+    /// Memory used by the bootloader, that can be used once its structures are no longer needed.
+    BootloaderReclaimable,
+
+    /// Memory containing the kernel and the modules loaded by the bootloader.
+    KernelAndModules,
+
+    /// The memory of the framebuffer.
+    Framebuffer,
+}
+
+/// A contiguous region of the physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: Physical,
+    pub len: u64,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    const EMPTY: Self = Self {
+        start: Physical::zero(),
+        len: 0,
+        kind: RegionKind::Reserved,
+    };
+
+    /// Returns the address following the last byte of the region, or `None` if it is not a valid
+    /// physical address. It is always valid for the regions of a [`MemoryMap`].
+    #[must_use]
+    pub const fn end(&self) -> Option<Physical> {
+        match self.start.as_u64().checked_add(self.len) {
+            Some(end) => match Physical::try_new(end) {
+                Ok(end) => Some(end),
+                Err(_) => None,
+            },
+            None => None,
+        }
+    }
+
+    /// Returns `true` if the given address belongs to the region.
+    #[must_use]
+    pub const fn contains(&self, address: Physical) -> bool {
+        address.as_u64() >= self.start.as_u64() && address.as_u64() - self.start.as_u64() < self.len
+    }
+}
+
+/// The memory map of the computer, as a list of regions. The regions are kept in the order given
+/// by the bootloader, which should be sorted by address, and contiguous regions of the same kind
+/// are merged.
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    count: usize,
+}
+
+impl MemoryMap {
+    /// Creates an empty memory map.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            regions: [MemoryRegion::EMPTY; MAX_REGIONS],
+            count: 0,
+        }
+    }
+
+    /// Add a region to the memory map. Empty regions are ignored, a region extending beyond the
+    /// highest valid physical address is clamped (it is ignored if it only contains the highest
+    /// address), and a region following a region of the same kind is merged with it.
+    ///
+    /// # Errors
+    /// Returns [`Error::Full`] if the memory map already contains [`MAX_REGIONS`] regions.
+    pub fn push(&mut self, mut region: MemoryRegion) -> Result<(), Error> {
+        region.len = region.len.min(LAST_ADDRESS - region.start.as_u64());
+        if region.len == 0 {
+            return Ok(());
+        }
+
+        if let Some(last) = self.regions[..self.count].last_mut() {
+            if last.kind == region.kind && last.end() == Some(region.start) {
+                if let Some(len) = last.len.checked_add(region.len) {
+                    last.len = len;
+                    return Ok(());
+                }
+            }
+        }
+
+        let slot = self.regions.get_mut(self.count).ok_or(Error::Full)?;
+        *slot = region;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Sort the regions by address, for bootloaders that do not give a sorted memory map.
+    /// Contiguous regions of the same kind are not merged.
+    pub fn sort(&mut self) {
+        self.regions[..self.count].sort_unstable_by_key(|region| region.start);
+    }
+
+    /// Returns the regions of the memory map.
+    #[must_use]
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.count]
+    }
+
+    /// Returns an iterator over the usable regions of the memory map.
+    pub fn usable(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions()
+            .iter()
+            .filter(|region| region.kind == RegionKind::Usable)
+    }
+
+    /// Returns the total size of the usable regions, in bytes.
+    #[must_use]
+    pub fn usable_size(&self) -> u64 {
+        self.usable().map(|region| region.len).sum()
+    }
+
+    /// Returns the region containing the given address, if any.
+    #[must_use]
+    pub fn find(&self, address: Physical) -> Option<&MemoryRegion> {
+        self.regions()
+            .iter()
+            .find(|region| region.contains(address))
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Conversions from the responses of the [Limine boot protocol](
+/// https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md). The structures have the
+/// layout defined by the protocol, so the responses given by the `limine` crate can be cast to
+/// them.
+#[cfg(feature = "limine")]
+pub mod limine {
+    use super::{Error, MemoryMap, MemoryRegion, RegionKind};
+    use crate::address::{InvalidPhysical, Physical, Virtual};
+
+    /// The types of the memory map entries.
+    pub const USABLE: u64 = 0;
+    pub const RESERVED: u64 = 1;
+    pub const ACPI_RECLAIMABLE: u64 = 2;
+    pub const ACPI_NVS: u64 = 3;
+    pub const BAD_MEMORY: u64 = 4;
+    pub const BOOTLOADER_RECLAIMABLE: u64 = 5;
+    pub const KERNEL_AND_MODULES: u64 = 6;
+    pub const FRAMEBUFFER: u64 = 7;
+
+    /// An entry of the memory map response.
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    pub struct MemmapEntry {
+        pub base: u64,
+        pub length: u64,
+        pub kind: u64,
+    }
+
+    impl TryFrom<&MemmapEntry> for MemoryRegion {
+        type Error = InvalidPhysical;
+
+        fn try_from(entry: &MemmapEntry) -> Result<Self, InvalidPhysical> {
+            let kind = match entry.kind {
+                USABLE => RegionKind::Usable,
+                ACPI_RECLAIMABLE => RegionKind::AcpiReclaimable,
+                ACPI_NVS => RegionKind::AcpiNvs,
+                BAD_MEMORY => RegionKind::BadMemory,
+                BOOTLOADER_RECLAIMABLE => RegionKind::BootloaderReclaimable,
+                KERNEL_AND_MODULES => RegionKind::KernelAndModules,
+                FRAMEBUFFER => RegionKind::Framebuffer,
+                _ => RegionKind::Reserved,
+            };
+            Ok(Self {
+                start: Physical::try_new(entry.base)?,
+                len: entry.length,
+                kind,
+            })
+        }
+    }
+
+    /// The memory map response.
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct MemmapResponse {
+        pub revision: u64,
+        pub entry_count: u64,
+        pub entries: *const *const MemmapEntry,
+    }
+
+    /// The higher-half direct mapping (HHDM) response.
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct HhdmResponse {
+        pub revision: u64,
+        pub offset: u64,
+    }
+
+    impl HhdmResponse {
+        /// Returns the virtual address where the physical memory is mapped.
+        #[must_use]
+        pub const fn offset(&self) -> Virtual {
+            Virtual::new(self.offset)
+        }
+    }
+
+    /// Build a memory map from the given entries. The entries starting at an invalid physical
+    /// address are ignored.
+    ///
+    /// # Errors
+    /// Returns [`Error::Full`] if there are too many regions.
+    pub fn memory_map<'a>(
+        entries: impl IntoIterator<Item = &'a MemmapEntry>,
+    ) -> Result<MemoryMap, Error> {
+        let mut map = MemoryMap::new();
+        for region in entries
+            .into_iter()
+            .filter_map(|entry| entry.try_into().ok())
+        {
+            map.push(region)?;
+        }
+        Ok(map)
+    }
+
+    /// Build a memory map from the memory map response of the bootloader.
+    ///
+    /// # Errors
+    /// Returns [`Error::Full`] if there are too many regions.
+    ///
+    /// # Safety
+    /// This function is unsafe because the response must be a valid memory map response given
+    /// by the bootloader, whose entries are still mapped.
+    pub unsafe fn from_response(response: &MemmapResponse) -> Result<MemoryMap, Error> {
+        let count = usize::try_from(response.entry_count).map_err(|_| Error::InvalidLength)?;
+        let entries = core::slice::from_raw_parts(response.entries, count);
+        memory_map(entries.iter().map(|&entry| &*entry))
+    }
+}
+
+/// Conversions from the boot information of the [Multiboot2 specification](
+/// https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html). Multiboot2 does not map
+/// the physical memory in the higher half, so there is no equivalent to the Limine HHDM.
+#[cfg(feature = "multiboot2")]
+pub mod multiboot2 {
+    use super::{Error, MemoryMap, MemoryRegion, RegionKind};
+    use crate::address::Physical;
+
+    /// The type of the memory map tag, and of the tag ending the boot information.
+    const MEMORY_MAP_TAG: u32 = 6;
+    const END_TAG: u32 = 0;
+
+    /// The size of the fixed fields of the boot information, of a tag, and of the memory map
+    /// tag.
+    const INFO_HEADER_SIZE: usize = 8;
+    const TAG_HEADER_SIZE: usize = 8;
+    const MEMORY_MAP_HEADER_SIZE: usize = 16;
+
+    /// The size of an entry of the memory map defined by the specification.
+    const ENTRY_SIZE: usize = 24;
+
+    /// The types of the memory map entries.
+    pub const AVAILABLE: u32 = 1;
+    pub const ACPI_RECLAIMABLE: u32 = 3;
+    pub const ACPI_NVS: u32 = 4;
+    pub const BAD_MEMORY: u32 = 5;
+
+    /// Read a little-endian `u32` at the given offset.
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        let mut value = [0; 4];
+        value.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(value)
+    }
+
+    /// Read a little-endian `u64` at the given offset.
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(value)
+    }
+
+    /// Build a memory map from the given boot information structure, whose address is given in
+    /// EBX by the bootloader. The slice must cover the whole structure, whose size is given by its
+    /// first field.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidLength`] if the structure or one of its tags is truncated,
+    /// [`Error::NotFound`] if there is no memory map tag, and [`Error::Full`] if there are too
+    /// many regions.
+    pub fn memory_map(info: &[u8]) -> Result<MemoryMap, Error> {
+        if info.len() < INFO_HEADER_SIZE {
+            return Err(Error::InvalidLength);
+        }
+        let total = read_u32(info, 0) as usize;
+        let info = info.get(..total).ok_or(Error::InvalidLength)?;
+
+        let mut offset = INFO_HEADER_SIZE;
+        while offset + TAG_HEADER_SIZE <= info.len() {
+            let kind = read_u32(info, offset);
+            let size = read_u32(info, offset + 4) as usize;
+            let tag = info
+                .get(offset..offset + size)
+                .filter(|_| size >= TAG_HEADER_SIZE)
+                .ok_or(Error::InvalidLength)?;
+
+            match kind {
+                MEMORY_MAP_TAG => return memory_map_tag(tag),
+                END_TAG => break,
+                _ => offset += size.next_multiple_of(8),
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    /// Build a memory map from the given memory map tag, including its header. The entries
+    /// starting at an invalid physical address are ignored.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidLength`] if the tag is truncated, and [`Error::Full`] if there
+    /// are too many regions.
+    pub fn memory_map_tag(tag: &[u8]) -> Result<MemoryMap, Error> {
+        if tag.len() < MEMORY_MAP_HEADER_SIZE {
+            return Err(Error::InvalidLength);
+        }
+        let entry_size = read_u32(tag, 8) as usize;
+        if entry_size < ENTRY_SIZE {
+            return Err(Error::InvalidLength);
+        }
+
+        let mut map = MemoryMap::new();
+        for entry in tag[MEMORY_MAP_HEADER_SIZE..].chunks_exact(entry_size) {
+            let Ok(start) = Physical::try_new(read_u64(entry, 0)) else {
+                continue;
+            };
+            let kind = match read_u32(entry, 16) {
+                AVAILABLE => RegionKind::Usable,
+                ACPI_RECLAIMABLE => RegionKind::AcpiReclaimable,
+                ACPI_NVS => RegionKind::AcpiNvs,
+                BAD_MEMORY => RegionKind::BadMemory,
+                _ => RegionKind::Reserved,
+            };
+            map.push(MemoryRegion {
+                start,
+                len: read_u64(entry, 8),
+                kind,
+            })?;
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, MemoryMap, MemoryRegion, RegionKind};
+    use crate::address::Physical;
+
+    fn region(start: u64, len: u64, kind: RegionKind) -> MemoryRegion {
+        MemoryRegion {
+            start: Physical::new(start),
+            len,
+            kind,
+        }
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut map = MemoryMap::new();
+        map.push(region(0x10_0000, 0x10_0000, RegionKind::Usable))
+            .unwrap();
+        map.push(region(0x20_0000, 0x10_0000, RegionKind::Usable))
+            .unwrap();
+        map.push(region(0, 0x9F000, RegionKind::Usable)).unwrap();
+        map.push(region(0xF_0000, 0, RegionKind::Reserved)).unwrap();
+        assert_eq!(map.regions().len(), 2);
+        assert_eq!(map.usable_size(), 0x20_0000 + 0x9F000);
+
+        map.sort();
+        assert_eq!(map.regions()[0].start, Physical::new(0));
+        assert_eq!(map.find(Physical::new(0x2F_FFFF)), Some(&map.regions()[1]));
+        assert_eq!(map.find(Physical::new(0x30_0000)), None);
+
+        let mut map = MemoryMap::new();
+        for index in 0..u64::try_from(super::MAX_REGIONS).unwrap() {
+            map.push(region(index * 0x2000, 0x1000, RegionKind::Reserved))
+                .unwrap();
+        }
+        let last = region(0x1000_0000, 0x1000, RegionKind::Usable);
+        assert_eq!(map.push(last), Err(Error::Full));
+
+        // A region given by the bootloader may extend beyond the physical address space
+        let mut map = MemoryMap::new();
+        map.push(region(0xF_FFFF_FFFF_0000, u64::MAX, RegionKind::Reserved))
+            .unwrap();
+        map.push(region(0xF_FFFF_FFFF_FFFF, 0x1000, RegionKind::Reserved))
+            .unwrap();
+        assert_eq!(map.regions().len(), 1);
+        assert_eq!(map.regions()[0].len, 0xFFFF);
+        assert_eq!(
+            map.regions()[0].end(),
+            Some(Physical::new(0xF_FFFF_FFFF_FFFF))
+        );
+        assert_eq!(region(0x1000, u64::MAX, RegionKind::Usable).end(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "limine")]
+    fn limine_memory_map() {
+        use super::limine::{self, MemmapEntry, MemmapResponse};
+
+        let entries = [
+            MemmapEntry {
+                base: 0,
+                length: 0x9F000,
+                kind: limine::USABLE,
+            },
+            MemmapEntry {
+                base: 0x10_0000,
+                length: 0x1000,
+                kind: limine::KERNEL_AND_MODULES,
+            },
+        ];
+        let pointers: Vec<_> = entries.iter().map(core::ptr::from_ref).collect();
+        let response = MemmapResponse {
+            revision: 0,
+            entry_count: 2,
+            entries: pointers.as_ptr(),
+        };
+
+        let map = unsafe { limine::from_response(&response) }.unwrap();
+        assert_eq!(map.regions()[0], region(0, 0x9F000, RegionKind::Usable));
+        assert_eq!(map.regions()[1].kind, RegionKind::KernelAndModules);
+    }
+
+    #[test]
+    #[cfg(feature = "multiboot2")]
+    fn multiboot2_memory_map() {
+        let mut info = vec![0u8; 8];
+        // A command line tag, padded to 8 bytes
+        info.extend_from_slice(&[1, 0, 0, 0, 10, 0, 0, 0, b'-', b'v', 0, 0, 0, 0, 0, 0]);
+        // The memory map tag, with two entries
+        info.extend_from_slice(&[6, 0, 0, 0, 64, 0, 0, 0, 24, 0, 0, 0, 0, 0, 0, 0]);
+        for (base, len, kind) in [(0u64, 0x9F000u64, 1u32), (0xF_0000, 0x1_0000, 2)] {
+            info.extend_from_slice(&base.to_le_bytes());
+            info.extend_from_slice(&len.to_le_bytes());
+            info.extend_from_slice(&kind.to_le_bytes());
+            info.extend_from_slice(&[0; 4]);
+        }
+        info.extend_from_slice(&[0, 0, 0, 0, 8, 0, 0, 0]);
+        let total = u32::try_from(info.len()).unwrap();
+        info[..4].copy_from_slice(&total.to_le_bytes());
+
+        let map = super::multiboot2::memory_map(&info).unwrap();
+        assert_eq!(map.regions()[0], region(0, 0x9F000, RegionKind::Usable));
+        assert_eq!(
+            map.regions()[1],
+            region(0xF_0000, 0x1_0000, RegionKind::Reserved)
+        );
+        assert_eq!(
+            super::multiboot2::memory_map(&info[..40]).err(),
+            Some(Error::InvalidLength)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Memory-mapped device registers. A register is accessed through a reference to a
+//! [`Volatile`], [`ReadOnly`] or [`WriteOnly`] placed at its address, so that every access is a
+//! single volatile access of the size of the register, which the compiler cannot elide, merge or
+//! reorder with the other register accesses. A compiler fence is also issued before each write
+//! and after each read, so that the accesses to normal memory (such as DMA buffers shared with
+//! the device) are not moved across the register accesses.
+//!
+//! The registers of a device are usually described with [`register_block!`](crate::register_block),
+//! which generates a handle giving access to each register at its offset from the base address
+//! of the device.
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use crate::address::Virtual;
+
+/// A readable and writable register.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+/// A read-only register.
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy>(Volatile<T>);
+
+/// A write-only register.
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy>(Volatile<T>);
+
+impl<T: Copy> Volatile<T> {
+    /// Returns a reference to the register at the given address.
+    ///
+    /// # Safety
+    /// This function is unsafe because the given address must be the address of a register of
+    /// type `T`, aligned and mapped as uncacheable memory for the lifetime of the reference.
+    #[must_use]
+    pub unsafe fn from_address<'a>(address: Virtual) -> &'a Self {
+        &*address.as_ptr::<Self>()
+    }
+
+    /// Read the register.
+    #[inline]
+    #[must_use]
+    pub fn read(&self) -> T {
+        #[cfg(any(test, feature = "mock_io"))]
+        if let Some(value) = mock_read(self.0.get()) {
+            compiler_fence(Ordering::SeqCst);
+            return value;
+        }
+
+        // SAFETY: The register is valid (see `Volatile::from_address`)
+        let value = unsafe { self.0.get().read_volatile() };
+        compiler_fence(Ordering::SeqCst);
+        value
+    }
+
+    /// Write the register.
+    #[inline]
+    pub fn write(&self, value: T) {
+        compiler_fence(Ordering::SeqCst);
+        #[cfg(any(test, feature = "mock_io"))]
+        mock_write(self.0.get(), value);
+
+        // SAFETY: The register is valid (see `Volatile::from_address`)
+        unsafe { self.0.get().write_volatile(value) };
+    }
+
+    /// Read the register, and write back the value returned by the given function. The read and
+    /// the write are two separate accesses, which are not atomic.
+    #[inline]
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+impl<T: Copy> ReadOnly<T> {
+    /// Returns a reference to the register at the given address.
+    ///
+    /// # Safety
+    /// See [`Volatile::from_address`].
+    #[must_use]
+    pub unsafe fn from_address<'a>(address: Virtual) -> &'a Self {
+        &*address.as_ptr::<Self>()
+    }
+
+    /// Read the register.
+    #[inline]
+    #[must_use]
+    pub fn read(&self) -> T {
+        self.0.read()
+    }
+}
+
+impl<T: Copy> WriteOnly<T> {
+    /// Returns a reference to the register at the given address.
+    ///
+    /// # Safety
+    /// See [`Volatile::from_address`].
+    #[must_use]
+    pub unsafe fn from_address<'a>(address: Virtual) -> &'a Self {
+        &*address.as_ptr::<Self>()
+    }
+
+    /// Write the register.
+    #[inline]
+    pub fn write(&self, value: T) {
+        self.0.write(value);
+    }
+}
+
+/// Returns the value queued in the device model for the given register, if any (see
+/// [`crate::mock`]).
+#[cfg(any(test, feature = "mock_io"))]
+#[allow(clippy::cast_possible_truncation)]
+fn mock_read<T: Copy>(register: *const T) -> Option<T> {
+    let size = core::mem::size_of::<T>();
+    if size > 8 {
+        return None;
+    }
+    let value = crate::mock::mmio_read(register as u64, size as u8)?;
+    // SAFETY: The value has at least as many bytes as `T`, and the registers are plain integers
+    Some(unsafe { value.to_le_bytes().as_ptr().cast::<T>().read_unaligned() })
+}
+
+/// Log a write of the given register in the device model (see [`crate::mock`]).
+#[cfg(any(test, feature = "mock_io"))]
+#[allow(clippy::cast_possible_truncation)]
+fn mock_write<T: Copy>(register: *mut T, value: T) {
+    let size = core::mem::size_of::<T>();
+    if size <= 8 {
+        let mut bytes = [0u8; 8];
+        // SAFETY: The buffer has at least as many bytes as `T`
+        unsafe { bytes.as_mut_ptr().cast::<T>().write_unaligned(value) };
+        crate::mock::mmio_write(register as u64, size as u8, u64::from_le_bytes(bytes));
+    }
+}
+
+impl<T: Copy + core::fmt::Debug> core::fmt::Debug for ReadOnly<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("ReadOnly").field(&self.read()).finish()
+    }
+}
+
+impl<T: Copy> core::fmt::Debug for WriteOnly<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("WriteOnly")
+    }
+}
+
+/// Declare a handle to a block of memory-mapped registers, giving access to each register at its
+/// offset from the base address of the block. The handle is created with an unsafe `new`
+/// function taking the base address, which must be mapped as uncacheable memory while the handle
+/// is used.
+///
+/// # Example
+/// ```ignore
+/// register_block! {
+///     /// The registers of the I/O APIC.
+///     pub struct IoApicRegisters {
+///         /// The register selection register.
+///         0x00 => select: Volatile<u32>,
+///         /// The register window.
+///         0x10 => window: Volatile<u32>,
+///     }
+/// }
+///
+/// let registers = unsafe { IoApicRegisters::new(base) };
+/// registers.select().write(0x01);
+/// let version = registers.window().read();
+/// ```
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $offset:literal => $field:ident : $register:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name {
+            base: $crate::address::Virtual,
+        }
+
+        impl $name {
+            /// Creates a handle to the registers mapped at the given base address.
+            ///
+            /// # Safety
+            /// This function is unsafe because the registers must be mapped at the given address
+            /// as uncacheable memory, for as long as the handle is used.
+            #[must_use]
+            $vis const unsafe fn new(base: $crate::address::Virtual) -> Self {
+                Self { base }
+            }
+
+            /// Returns the base address of the registers.
+            #[must_use]
+            $vis const fn base(&self) -> $crate::address::Virtual {
+                self.base
+            }
+
+            $(
+                $(#[$field_meta])*
+                #[must_use]
+                $vis fn $field(&self) -> &$register {
+                    // SAFETY: The registers are mapped at the base address (see `new`)
+                    unsafe { <$register>::from_address(self.base + ($offset as u64)) }
+                }
+            )*
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReadOnly, Volatile, WriteOnly};
+    use crate::address::Virtual;
+
+    crate::register_block! {
+        struct Device {
+            0x00 => control: Volatile<u32>,
+            0x04 => status: ReadOnly<u16>,
+            0x08 => doorbell: WriteOnly<u64>,
+        }
+    }
+
+    #[test]
+    fn register_block() {
+        crate::mock::reset();
+        let mut memory = [0u64; 2];
+        let base = Virtual::from_ptr(memory.as_mut_ptr());
+        let device = unsafe { Device::new(base) };
+
+        device.control().write(0xDEAD_0000);
+        device.control().update(|value| value | 0xBEEF);
+        device.doorbell().write(u64::MAX);
+        assert_eq!(device.control().read(), 0xDEAD_BEEF);
+        assert_eq!(device.status().read(), 0);
+        assert_eq!(
+            unsafe { base.as_ptr::<u64>().add(1).read_volatile() },
+            u64::MAX
+        );
+    }
+}
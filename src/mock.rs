@@ -0,0 +1,187 @@
+//! An in-memory device model replacing the I/O ports and the memory-mapped registers, so that
+//! the drivers can be unit-tested on the build host. It is enabled for the tests of this crate,
+//! and for the other crates with the `mock_io` feature (which links the standard library).
+//!
+//! When the model is enabled, the port accesses of [`crate::io`] never reach the hardware: the
+//! reads return the values programmed with [`push_read`] or [`set_read`] (all ones otherwise, as
+//! on a floating bus), and the writes are logged. The accesses to the registers of
+//! [`crate::mmio`] are also logged, and the reads return the values programmed with
+//! [`push_mmio_read`] if any, or the value in memory otherwise, so that a register block can be
+//! backed by a plain buffer.
+//!
+//! The model is local to each thread, so that the tests running in parallel do not interfere.
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+
+use crate::address::Virtual;
+
+/// The target of an access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Target {
+    /// An I/O port.
+    Port(u16),
+
+    /// A memory-mapped register, at the given virtual address.
+    Mmio(u64),
+}
+
+/// A logged write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Write {
+    pub target: Target,
+
+    /// The size of the access, in bytes.
+    pub size: u8,
+    pub value: u64,
+}
+
+/// The state of the device model of a thread.
+#[derive(Debug, Default)]
+struct Device {
+    reads: BTreeMap<Target, VecDeque<u64>>,
+    defaults: BTreeMap<Target, u64>,
+    writes: Vec<Write>,
+}
+
+std::thread_local! {
+    static DEVICE: RefCell<Device> = RefCell::new(Device::default());
+}
+
+/// Returns the mask of the bits of an access of the given size.
+fn mask(size: u8) -> u64 {
+    u64::MAX >> (64 - u32::from(size) * 8)
+}
+
+/// Clear the programmed reads and the logged writes of the current thread.
+pub fn reset() {
+    DEVICE.with(|device| *device.borrow_mut() = Device::default());
+}
+
+/// Queue a value returned by a read of the given port. The queued values are returned in order,
+/// before the value set with [`set_read`].
+pub fn push_read(port: u16, value: u32) {
+    DEVICE.with(|device| {
+        let mut device = device.borrow_mut();
+        let queue = device.reads.entry(Target::Port(port)).or_default();
+        queue.push_back(u64::from(value));
+    });
+}
+
+/// Set the value returned by the reads of the given port when no value is queued.
+pub fn set_read(port: u16, value: u32) {
+    DEVICE.with(|device| {
+        let mut device = device.borrow_mut();
+        device.defaults.insert(Target::Port(port), u64::from(value));
+    });
+}
+
+/// Queue a value returned by a read of the register at the given address, instead of the value
+/// in memory.
+pub fn push_mmio_read(address: Virtual, value: u64) {
+    DEVICE.with(|device| {
+        let mut device = device.borrow_mut();
+        let queue = device
+            .reads
+            .entry(Target::Mmio(address.as_u64()))
+            .or_default();
+        queue.push_back(value);
+    });
+}
+
+/// Returns the writes logged on the current thread, in order.
+#[must_use]
+pub fn writes() -> Vec<Write> {
+    DEVICE.with(|device| device.borrow().writes.clone())
+}
+
+/// Returns the writes logged on the current thread, in order, and clear the log.
+#[must_use]
+pub fn take_writes() -> Vec<Write> {
+    DEVICE.with(|device| core::mem::take(&mut device.borrow_mut().writes))
+}
+
+/// Returns the values written to the given port, in order.
+#[must_use]
+pub fn port_writes(port: u16) -> Vec<u64> {
+    DEVICE.with(|device| {
+        let device = device.borrow();
+        let writes = device.writes.iter();
+        writes
+            .filter(|write| write.target == Target::Port(port))
+            .map(|write| write.value)
+            .collect()
+    })
+}
+
+/// Read a port of the given size.
+pub(crate) fn port_read(port: u16, size: u8) -> u64 {
+    let target = Target::Port(port);
+    let value = DEVICE.with(|device| {
+        let mut device = device.borrow_mut();
+        let queued = device.reads.get_mut(&target).and_then(VecDeque::pop_front);
+        queued.or_else(|| device.defaults.get(&target).copied())
+    });
+    value.unwrap_or(u64::MAX) & mask(size)
+}
+
+/// Write a port of the given size.
+pub(crate) fn port_write(port: u16, size: u8, value: u64) {
+    log(Target::Port(port), size, value);
+}
+
+/// Returns the value queued for the register at the given address, if any.
+pub(crate) fn mmio_read(address: u64, size: u8) -> Option<u64> {
+    DEVICE.with(|device| {
+        let mut device = device.borrow_mut();
+        let queue = device.reads.get_mut(&Target::Mmio(address));
+        queue
+            .and_then(VecDeque::pop_front)
+            .map(|value| value & mask(size))
+    })
+}
+
+/// Log a write to the register at the given address.
+pub(crate) fn mmio_write(address: u64, size: u8, value: u64) {
+    log(Target::Mmio(address), size, value);
+}
+
+/// Log a write.
+fn log(target: Target, size: u8, value: u64) {
+    DEVICE.with(|device| {
+        device.borrow_mut().writes.push(Write {
+            target,
+            size,
+            value: value & mask(size),
+        });
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Target, Write};
+    use crate::io::{inb, ind, outw};
+
+    #[test]
+    fn port_model() {
+        super::reset();
+        super::push_read(0x60, 0x1C);
+        super::set_read(0x60, 0xAA);
+        unsafe {
+            assert_eq!(inb(0x60), 0x1C);
+            assert_eq!(inb(0x60), 0xAA);
+            assert_eq!(ind(0xCFC), u32::MAX);
+            outw(0x604, 0x2000);
+        }
+
+        let write = Write {
+            target: Target::Port(0x604),
+            size: 2,
+            value: 0x2000,
+        };
+        assert_eq!(super::take_writes(), [write]);
+        assert!(super::writes().is_empty());
+    }
+}
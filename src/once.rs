@@ -0,0 +1,156 @@
+//! A cell that can be written only once, to store the IDT, the GDT and the other tables needed
+//! early during the boot as statics, without `static mut`. Once initialized, the value can only
+//! be accessed through shared references, so the tables must be completely built by the
+//! initialization function before being loaded (for example with [`crate::idt::Register`]).
+//!
+//! The initialization is expected to happen during the early boot, on a single CPU. Instead of
+//! spinning, which would deadlock if an interrupt handler tried to initialize the cell while the
+//! interrupted code was already doing it, a concurrent or reentrant initialization panics.
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// The states of an [`InitOnce`].
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// A cell initialized once, then only shared.
+pub struct InitOnce<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: The value is written once before being published with a release store, and is only
+// accessed through shared references afterwards
+unsafe impl<T: Send + Sync> Sync for InitOnce<T> {}
+unsafe impl<T: Send> Send for InitOnce<T> {}
+
+impl<T> InitOnce<T> {
+    /// Creates a new uninitialized cell.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns `true` if the cell has been initialized.
+    #[must_use]
+    pub fn is_initialized(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    /// Returns a reference to the value, or `None` if the cell is not initialized yet.
+    #[must_use]
+    pub fn get(&self) -> Option<&T> {
+        if self.is_initialized() {
+            // SAFETY: The value has been written before the state was set to `READY`
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Initialize the cell with the given value.
+    ///
+    /// # Errors
+    /// Returns the value if the cell is already initialized, or is being initialized.
+    pub fn set(&self, value: T) -> Result<&T, T> {
+        if self.begin_init() {
+            Ok(self.finish_init(value))
+        } else {
+            Err(value)
+        }
+    }
+
+    /// Returns a reference to the value, initializing the cell with the given function if it is
+    /// not initialized yet.
+    ///
+    /// # Panics
+    /// Panics if the cell is being initialized, i.e. if this function is called from the
+    /// initialization function, from an interrupt handler interrupting the initialization, or
+    /// concurrently on another CPU. The initialization is intended for the early boot, when a
+    /// single CPU is running.
+    #[track_caller]
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+
+        assert!(
+            self.begin_init(),
+            "InitOnce initialized concurrently or reentrantly"
+        );
+        self.finish_init(f())
+    }
+
+    /// Try to move the cell from the uninitialized state to the initializing state. Returns
+    /// `false` if the cell is already initialized or being initialized.
+    fn begin_init(&self) -> bool {
+        self.state
+            .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Write the value and publish it. The cell must be in the initializing state.
+    fn finish_init(&self, value: T) -> &T {
+        // SAFETY: The cell is in the initializing state, so no reference to the value exists and
+        // no other writer can exist
+        let value = unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        value
+    }
+}
+
+impl<T> Default for InitOnce<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for InitOnce<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            // SAFETY: The value is initialized, and is not used anymore
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for InitOnce<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("InitOnce").field(value).finish(),
+            None => f.write_str("InitOnce(<uninit>)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InitOnce;
+
+    #[test]
+    fn init_once() {
+        static TABLE: InitOnce<[u64; 4]> = InitOnce::new();
+        assert!(TABLE.get().is_none());
+        assert_eq!(TABLE.get_or_init(|| [1, 2, 3, 4])[2], 3);
+        assert_eq!(TABLE.get_or_init(|| unreachable!()), &[1, 2, 3, 4]);
+        assert_eq!(TABLE.set([0; 4]), Err([0; 4]));
+
+        let cell = InitOnce::new();
+        assert_eq!(cell.set(5), Ok(&5));
+        assert_eq!(cell.get(), Some(&5));
+    }
+
+    #[test]
+    #[should_panic(expected = "InitOnce initialized concurrently or reentrantly")]
+    fn reentrant_init() {
+        let cell = InitOnce::new();
+        cell.get_or_init(|| *cell.get_or_init(|| 1) + 1);
+    }
+}
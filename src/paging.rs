@@ -3,9 +3,20 @@ pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_MASK: usize = !(PAGE_SIZE - 1);
 pub const PAGE_OFFSET_MASK: usize = PAGE_SIZE - 1;
 
-use crate::address::Physical;
+/// The value of the entry of a guard page (see [`guard_page`]). The entry is not present, so the
+/// other bits are ignored by the processor: the bit 9 ([`PageEntryFlags::BIT_9`]) is set, and
+/// "GUARD" is written in the address bits, so that it cannot be confused with a swapped out page.
+const GUARD_ENTRY: u64 = 0x0047_5541_5244_0200;
+
+use crate::{
+    address::{Physical, Virtual, VirtualRange},
+    cpu::cr2::PageFaultInfo,
+};
 use bitflags::bitflags;
-use core::ops::{Index, IndexMut};
+use core::{
+    fmt,
+    ops::{Index, IndexMut},
+};
 
 #[derive(Debug)]
 #[repr(C, align(8))]
@@ -66,6 +77,12 @@ impl PageEntry {
         self.flags().contains(PageEntryFlags::USER)
     }
 
+    /// Returns `true` if this entry is the marker of a guard page (see [`guard_page`]).
+    #[must_use]
+    pub const fn is_guard(&self) -> bool {
+        self.0 == GUARD_ENTRY
+    }
+
     /// Set the entry to 0, indicating that the page is not present in memory.
     pub fn clear(&mut self) {
         self.0 = 0;
@@ -238,6 +255,416 @@ impl Level {
             Self::PageMapLevel4 => Some(Self::PageTableDirectoryPointer),
         }
     }
+
+    /// Returns the size of the memory mapped by an entry of a table of this level: 4 KiB for a
+    /// page table, 2 MiB for a page directory, 1 GiB for a page directory pointer table and 512
+    /// GiB for a PML4.
+    #[must_use]
+    pub const fn entry_size(&self) -> u64 {
+        (PAGE_SIZE as u64) << (9 * (*self as u64 - 1))
+    }
+}
+
+/// Call the given function for each present leaf entry mapping a part of the given range, with
+/// the virtual address of the start of the page it maps and the level of its table. A leaf entry
+/// maps a 4 KiB page in a page table, or a huge page (2 MiB or 1 GiB) in a page directory or a
+/// page directory pointer table. The page tables are found with the `translate` function, which
+/// returns the virtual address where the page table at the given physical address is accessible
+/// (for example, through the higher half direct mapping).
+///
+/// The TLB is not flushed: if the function changes the entries, the caller must flush them.
+///
+/// # Safety
+/// This function is unsafe because `translate` must return the virtual address of valid page
+/// tables, which must not be accessed concurrently.
+pub unsafe fn for_each_leaf(
+    pml4: &mut PageTable,
+    range: VirtualRange,
+    translate: &impl Fn(Physical) -> Virtual,
+    f: &mut impl FnMut(Virtual, Level, &mut PageEntry),
+) {
+    walk(pml4, Level::PageMapLevel4, 0, range, translate, f);
+}
+
+/// Implementation of [`for_each_leaf`] for a table of the given level, mapping the memory
+/// starting at `base`.
+unsafe fn walk(
+    table: &mut PageTable,
+    level: Level,
+    base: u64,
+    range: VirtualRange,
+    translate: &impl Fn(Physical) -> Virtual,
+    f: &mut impl FnMut(Virtual, Level, &mut PageEntry),
+) {
+    let size = level.entry_size();
+    for (index, entry) in table.iter_mut().enumerate() {
+        // The last address is used instead of the end, which overflows for the last entry
+        let start = Virtual::new_truncate(base + index as u64 * size);
+        let last = start.as_u64() + (size - 1);
+        let intersects = start < range.end() && range.start().as_u64() <= last;
+        let Some(address) = entry.address().filter(|_| intersects) else {
+            continue;
+        };
+
+        let huge = entry.flags().contains(PageEntryFlags::HUGE_PAGE);
+        match level.next() {
+            Some(next) if !huge || level == Level::PageMapLevel4 => {
+                let table = &mut *translate(address).as_mut_ptr::<PageTable>();
+                walk(table, next, start.as_u64(), range, translate, f);
+            }
+            _ => f(start, level, entry),
+        }
+    }
+}
+
+/// Returns the leaf entry used to translate the given address, and the level of its table: the
+/// entry of the page table, or the entry of a huge page in a page directory or a page directory
+/// pointer table. The entry is returned even if it is not present. `None` is returned if a
+/// table of the walk is missing. See [`for_each_leaf`] for the `translate` function.
+///
+/// # Safety
+/// This function is unsafe because `translate` must return the virtual address of valid page
+/// tables, which must not be accessed concurrently.
+pub unsafe fn entry_mut<'a>(
+    pml4: &'a mut PageTable,
+    address: Virtual,
+    translate: &impl Fn(Physical) -> Virtual,
+) -> Option<(&'a mut PageEntry, Level)> {
+    let mut table = pml4;
+    let mut level = Level::PageMapLevel4;
+    loop {
+        let entry = &mut table[address.page_index(level as u64)];
+        let huge =
+            level != Level::PageMapLevel4 && entry.flags().contains(PageEntryFlags::HUGE_PAGE);
+        match level.next() {
+            Some(_) if huge && entry.is_present() => return Some((entry, level)),
+            Some(next) => {
+                table = &mut *translate(entry.address()?).as_mut_ptr::<PageTable>();
+                level = next;
+            }
+            None => return Some((entry, level)),
+        }
+    }
+}
+
+/// An error returned by [`guard_page`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardError {
+    /// The page below the stack is not covered by a page table.
+    NoPageTable,
+
+    /// The page below the stack is part of a huge page.
+    HugePage,
+}
+
+/// Turn the page below the stack starting at the given address (its lowest address) into a
+/// guard page: its entry is replaced by a non-present marker, so that a stack overflow raises a
+/// page fault recognized by [`check_stack_overflow`] instead of silently corrupting the memory
+/// below. The physical page previously mapped there is returned, to be freed by the caller. See
+/// [`for_each_leaf`] for the `translate` function.
+///
+/// The TLB entry of the guard page is not flushed: it must be flushed on all the CPUs (for
+/// example with [`crate::cpu::invlpg`]) if the page was mapped.
+///
+/// # Errors
+/// Returns an error if the page below the stack is not mapped by a page table, since there is no
+/// entry where the marker can be written, or if it is part of a huge page.
+///
+/// # Panics
+/// Panics if the address is not aligned on a page boundary.
+///
+/// # Safety
+/// This function is unsafe because `translate` must return the virtual address of valid page
+/// tables, which must not be accessed concurrently. The page below the stack must not be used.
+pub unsafe fn guard_page(
+    pml4: &mut PageTable,
+    stack_bottom: Virtual,
+    translate: &impl Fn(Physical) -> Virtual,
+) -> Result<Option<Physical>, GuardError> {
+    assert!(stack_bottom.is_page_aligned(), "Stack bottom is not page aligned");
+    let guard = Virtual::new_truncate(stack_bottom.as_u64() - PAGE_SIZE as u64);
+    match entry_mut(pml4, guard, translate) {
+        Some((entry, Level::PageTable)) => {
+            let previous = entry.address();
+            entry.0 = GUARD_ENTRY;
+            Ok(previous)
+        }
+        Some(_) => Err(GuardError::HugePage),
+        None => Err(GuardError::NoPageTable),
+    }
+}
+
+/// A stack overflow detected by [`check_stack_overflow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackOverflow {
+    /// The local APIC ID of the CPU where the overflow happened.
+    pub cpu: u32,
+
+    /// The identifier of the task given by the kernel.
+    pub task: u64,
+
+    /// The faulting address, in the guard page.
+    pub address: Virtual,
+}
+
+impl fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stack overflow on CPU {} task {} (guard page hit at {:#x})",
+            self.cpu,
+            self.task,
+            self.address.as_u64()
+        )
+    }
+}
+
+/// Check if the given page fault was raised by an access to a guard page (see [`guard_page`]),
+/// which means that the stack of the current task overflowed. This is intended to be called by
+/// the page fault handler, with the current PML4, before reporting a generic page fault. The
+/// task identifier is only used in the report. See [`for_each_leaf`] for the `translate`
+/// function.
+///
+/// The page fault handler must run on an interrupt stack (see
+/// [`crate::idt::DescriptorFlags::set_stack_index`]): otherwise the processor cannot push the
+/// interrupt frame on the overflowed stack, and raises a double fault instead.
+///
+/// # Safety
+/// This function is unsafe because `translate` must return the virtual address of valid page
+/// tables, which must not be accessed concurrently.
+pub unsafe fn check_stack_overflow(
+    pml4: &mut PageTable,
+    fault: &PageFaultInfo,
+    task: u64,
+    translate: &impl Fn(Physical) -> Virtual,
+) -> Option<StackOverflow> {
+    if !fault.not_present() {
+        return None;
+    }
+
+    let (entry, _) = entry_mut(pml4, fault.address, translate)?;
+    entry.is_guard().then(|| StackOverflow {
+        cpu: crate::lapic::initial_apic_id(),
+        task,
+        address: fault.address,
+    })
+}
+
+/// The policy applied by [`enforce_wx`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WxPolicy {
+    /// If set, the kernel pages that are both writable and executable are made non-executable,
+    /// except the ones that overlap the code section. Otherwise, they are only reported.
+    pub repair: bool,
+
+    /// The code section of the kernel (`.text`), remapped read-only and executable.
+    pub text: Option<VirtualRange>,
+
+    /// The read-only data section of the kernel (`.rodata`), remapped read-only and
+    /// non-executable.
+    pub rodata: Option<VirtualRange>,
+}
+
+/// The result of [`enforce_wx`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WxReport {
+    /// The number of pages that were both writable and executable.
+    pub violations: usize,
+
+    /// The number of violations repaired (see [`WxPolicy::repair`]).
+    pub repaired: usize,
+
+    /// The number of pages of the code and the read-only data sections whose permissions were
+    /// changed.
+    pub remapped: usize,
+}
+
+/// Enforce the W^X policy on the kernel half of the given PML4: no kernel page should be both
+/// writable and executable. The pages of the sections given in the policy are first remapped
+/// with their expected permissions, then the whole kernel half is checked: each page that is
+/// still writable and executable is reported to `report` with its address and the level of its
+/// entry, and made non-executable if [`WxPolicy::repair`] is set. See [`for_each_leaf`] for the
+/// `translate` function.
+///
+/// Only the pages entirely contained in a section are remapped: a huge page that also maps
+/// something else is left untouched, and reported if it is writable and executable. Such a page
+/// is never repaired if it overlaps the code section, since the kernel would fault the next time
+/// it executes the code it maps. The intermediate entries are not changed, since the permissions of a page are the most
+/// restrictive of all the entries along its walk.
+///
+/// The TLB is not flushed: the changed kernel entries must be flushed on all the CPUs, for
+/// example with [`global_pages::flush_global`] if they are global.
+///
+/// # Safety
+/// This function is unsafe because `translate` must return the virtual address of valid page
+/// tables, which must not be accessed concurrently. The no-execute bit must be enabled (see
+/// [`crate::cpu::msr::efer::Flags::NXE`]), otherwise the entries made non-executable are invalid.
+/// The sections must be correct, or the kernel will fault when writing a remapped page or
+/// executing a repaired one.
+pub unsafe fn enforce_wx(
+    pml4: &mut PageTable,
+    translate: &impl Fn(Physical) -> Virtual,
+    policy: &WxPolicy,
+    report: &mut impl FnMut(Virtual, Level),
+) -> WxReport {
+    let mut result = WxReport::default();
+    let sections = [
+        (policy.text, PageEntryFlags::empty()),
+        (policy.rodata, PageEntryFlags::NO_EXECUTE),
+    ];
+    for (range, no_execute) in sections {
+        let Some(range) = range else {
+            continue;
+        };
+
+        for_each_leaf(pml4, range, translate, &mut |start, level, entry| {
+            let last = start.as_u64() + (level.entry_size() - 1);
+            if start < range.start() || last >= range.end().as_u64() {
+                return;
+            }
+
+            let mut flags = entry.flags() - PageEntryFlags::WRITABLE - PageEntryFlags::NO_EXECUTE;
+            flags |= no_execute;
+            if flags != entry.flags() {
+                entry.set_flags(flags);
+                result.remapped += 1;
+            }
+        });
+    }
+
+    let all = VirtualRange::new(Virtual::new(0), Virtual::new(u64::MAX));
+    for_each_leaf(pml4, all, translate, &mut |start, level, entry| {
+        if start.is_kernel() && entry.is_writable() && entry.is_executable() {
+            result.violations += 1;
+            report(start, level);
+
+            let last = start.as_u64() + (level.entry_size() - 1);
+            let code = policy
+                .text
+                .is_some_and(|text| start < text.end() && last >= text.start().as_u64());
+            if policy.repair && !code {
+                entry.add_flags(PageEntryFlags::NO_EXECUTE);
+                result.repaired += 1;
+            }
+        }
+    });
+    result
+}
+
+/// Management of the global pages. The TLB entries of a page marked as global
+/// ([`PageEntryFlags::GLOBAL`]) are not flushed when CR3 is written, so the kernel mappings shared
+/// by all the address spaces stay cached across the context switches. The kernel registers the
+/// ranges that must be global with [`register`] (typically, the kernel image and the direct
+/// mapping of the physical memory), and marks them with [`enable`].
+///
+/// With the page table isolation (see [`crate::pti`]), only the trampoline mapping set may be
+/// global, otherwise the kernel pages would stay in the TLB while running in user mode.
+pub mod global_pages {
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use super::{PageEntryFlags, PageTable};
+    use crate::{
+        address::{Physical, Virtual, VirtualRange},
+        cpu::cr4,
+    };
+
+    /// The maximal number of registered ranges.
+    pub const MAX_RANGES: usize = 16;
+
+    /// The start and the end of the registered ranges.
+    static RANGES: [(AtomicU64, AtomicU64); MAX_RANGES] = {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: (AtomicU64, AtomicU64) = (AtomicU64::new(0), AtomicU64::new(0));
+        [EMPTY; MAX_RANGES]
+    };
+
+    /// The number of registered ranges.
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    /// An error returned by [`register`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Error {
+        /// The range is not in the kernel half of the address space.
+        NotKernel,
+
+        /// [`MAX_RANGES`] ranges are already registered.
+        TableFull,
+    }
+
+    /// Register a range of kernel memory whose pages must be global.
+    ///
+    /// # Errors
+    /// Returns an error if the range is not in the kernel half of the address space, or if too
+    /// many ranges are registered.
+    pub fn register(range: VirtualRange) -> Result<(), Error> {
+        if !range.start().is_kernel() {
+            return Err(Error::NotKernel);
+        }
+
+        let slot = COUNT
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < MAX_RANGES).then_some(count + 1)
+            })
+            .map_err(|_| Error::TableFull)?;
+        RANGES[slot].0.store(range.start().as_u64(), Ordering::Relaxed);
+        RANGES[slot].1.store(range.end().as_u64(), Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns an iterator over the registered ranges.
+    pub fn ranges() -> impl Iterator<Item = VirtualRange> {
+        let count = COUNT.load(Ordering::Acquire);
+        RANGES[..count].iter().filter_map(|(start, end)| {
+            let end = end.load(Ordering::Acquire);
+            let start = start.load(Ordering::Relaxed);
+            (end != 0).then(|| VirtualRange::new(Virtual::new(start), Virtual::new(end)))
+        })
+    }
+
+    /// Mark the pages of the registered ranges as global in the given PML4 (see
+    /// [`super::for_each_leaf`] for the `translate` function), and enable the global pages on the
+    /// current CPU by setting the PGE bit of CR4. Returns the number of leaf entries marked.
+    ///
+    /// The kernel half of the PML4 is usually shared by all the address spaces, so this only
+    /// needs to be done once, but the global pages must be enabled on each CPU: the next calls
+    /// only set the PGE bit if the entries are already marked.
+    ///
+    /// # Safety
+    /// This function is unsafe because `translate` must return the virtual address of valid page
+    /// tables, which must not be accessed concurrently. The registered ranges must be mapped
+    /// identically in all the address spaces, otherwise their stale translations would be used
+    /// after a context switch.
+    pub unsafe fn enable(pml4: &mut PageTable, translate: &impl Fn(Physical) -> Virtual) -> usize {
+        let mut marked = 0;
+        for range in ranges() {
+            super::for_each_leaf(pml4, range, translate, &mut |_, _, entry| {
+                if !entry.flags().contains(PageEntryFlags::GLOBAL) {
+                    entry.add_flags(PageEntryFlags::GLOBAL);
+                    marked += 1;
+                }
+            });
+        }
+        cr4::set(cr4::Flags::PGE);
+        marked
+    }
+
+    /// Returns `true` if the global pages are enabled on the current CPU.
+    #[must_use]
+    pub fn enabled() -> bool {
+        cr4::read_flags().contains(cr4::Flags::PGE)
+    }
+
+    /// Flush all the TLB entries of the current CPU, including the global ones, by toggling the
+    /// PGE bit of CR4 twice. This must be called on each CPU after changing a global mapping
+    /// (which should be rare), since writing CR3 does not flush them.
+    ///
+    /// # Safety
+    /// This function is unsafe because it changes CR4, and must not be interrupted by code that
+    /// changes it too.
+    pub unsafe fn flush_global() {
+        cr4::update(|flags| flags.toggle(cr4::Flags::PGE));
+        cr4::update(|flags| flags.toggle(cr4::Flags::PGE));
+    }
 }
 
 bitflags! {
@@ -255,3 +682,171 @@ bitflags! {
         const SGX = 1 << 15;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Level, PageEntry, PageEntryFlags, PageTable};
+    use crate::address::{Physical, Virtual, VirtualRange};
+
+    /// Translate the address of a page table allocated on the heap, which is used as its
+    /// physical address by the tests.
+    fn identity(address: Physical) -> Virtual {
+        Virtual::new(address.as_u64())
+    }
+
+    /// Link the given table in the entry, with the given flags.
+    fn link(entry: &mut PageEntry, table: &PageTable, flags: PageEntryFlags) {
+        let address = Physical::new(table.as_ptr() as u64);
+        *entry = PageEntry::new(address, flags | PageEntryFlags::PRESENT);
+    }
+
+    #[test]
+    fn walk_leaves() {
+        let mut pml4 = Box::new(PageTable::new());
+        let mut pdpt = Box::new(PageTable::new());
+        let mut pd = Box::new(PageTable::new());
+        let mut pt = Box::new(PageTable::new());
+        let flags = PageEntryFlags::PRESENT | PageEntryFlags::WRITABLE;
+
+        // 0xFFFF_FF80_0000_0000: a 4 KiB page at index 1 of the page table, and a 2 MiB page
+        // at index 1 of the page directory
+        pt[1usize] = PageEntry::new(Physical::new(0x1000), flags);
+        pd[1usize] = PageEntry::new(Physical::new(0x20_0000), flags | PageEntryFlags::HUGE_PAGE);
+        link(&mut pd[0usize], &pt, flags);
+        link(&mut pdpt[0usize], &pd, flags);
+        link(&mut pml4[511usize], &pdpt, flags);
+
+        let base = 0xFFFF_FF80_0000_0000u64;
+        let range = VirtualRange::new(Virtual::new(base), Virtual::new(base + 0x40_0000));
+        let mut leaves = Vec::new();
+        unsafe {
+            super::for_each_leaf(&mut pml4, range, &identity, &mut |address, level, _| {
+                leaves.push((address.as_u64(), level));
+            });
+        }
+        assert_eq!(
+            leaves,
+            [
+                (base + 0x1000, Level::PageTable),
+                (base + 0x20_0000, Level::PageDirectory)
+            ]
+        );
+        assert_eq!(Level::PageDirectory.entry_size(), 0x20_0000);
+    }
+
+    #[test]
+    fn wx_enforcement() {
+        use super::{WxPolicy, WxReport};
+
+        let mut pml4 = Box::new(PageTable::new());
+        let mut pdpt = Box::new(PageTable::new());
+        let mut pd = Box::new(PageTable::new());
+        let mut pt = Box::new(PageTable::new());
+        let rw = PageEntryFlags::PRESENT | PageEntryFlags::WRITABLE;
+
+        // Text at index 0, read-only data at index 1 and writable data at index 2, all mapped
+        // writable and executable
+        for index in 0..3usize {
+            pt[index] = PageEntry::new(Physical::new(0x1000 * (index as u64 + 1)), rw);
+        }
+        link(&mut pd[0usize], &pt, rw);
+        link(&mut pdpt[510usize], &pd, rw);
+        link(&mut pml4[511usize], &pdpt, rw);
+
+        let base = 0xFFFF_FFFF_8000_0000u64;
+        let policy = WxPolicy {
+            repair: true,
+            text: Some(VirtualRange::new(Virtual::new(base), Virtual::new(base + 0x1000))),
+            rodata: Some(VirtualRange::range(Virtual::new(base + 0x1000), 0x1000)),
+        };
+        let mut reported = Vec::new();
+        let report = unsafe {
+            super::enforce_wx(&mut pml4, &identity, &policy, &mut |address, _| {
+                reported.push(address.as_u64());
+            })
+        };
+        assert_eq!(
+            report,
+            WxReport {
+                violations: 1,
+                repaired: 1,
+                remapped: 2
+            }
+        );
+        assert_eq!(reported, [base + 0x2000]);
+        assert_eq!(pt[0usize].flags(), PageEntryFlags::PRESENT);
+        assert_eq!(pt[1usize].flags(), PageEntryFlags::PRESENT | PageEntryFlags::NO_EXECUTE);
+        assert_eq!(pt[2usize].flags(), rw | PageEntryFlags::NO_EXECUTE);
+
+        // A huge page that partially overlaps the text is only reported
+        let huge = rw | PageEntryFlags::HUGE_PAGE;
+        pd[1usize] = PageEntry::new(Physical::new(0x20_0000), huge);
+        let policy = WxPolicy {
+            repair: true,
+            text: Some(VirtualRange::range(Virtual::new(base + 0x20_0000), 0x1000)),
+            rodata: None,
+        };
+        let report = unsafe { super::enforce_wx(&mut pml4, &identity, &policy, &mut |_, _| ()) };
+        assert_eq!((report.violations, report.repaired), (1, 0));
+        assert_eq!(pd[1usize].flags(), huge);
+    }
+
+    #[test]
+    fn guard_pages() {
+        use super::GuardError;
+        use crate::cpu::cr2::PageFaultInfo;
+        use crate::paging::PageFaultErrorCode;
+
+        let mut pml4 = Box::new(PageTable::new());
+        let mut pdpt = Box::new(PageTable::new());
+        let mut pd = Box::new(PageTable::new());
+        let mut pt = Box::new(PageTable::new());
+        let rw = PageEntryFlags::PRESENT | PageEntryFlags::WRITABLE;
+        pt[0usize] = PageEntry::new(Physical::new(0x5000), rw);
+        link(&mut pd[0usize], &pt, rw);
+        link(&mut pdpt[0usize], &pd, rw);
+        link(&mut pml4[256usize], &pdpt, rw);
+
+        // The stack starts at the second page of the page table
+        let base = 0xFFFF_8000_0000_0000u64;
+        let bottom = Virtual::new(base + 0x1000);
+        let previous = unsafe { super::guard_page(&mut pml4, bottom, &identity) };
+        assert_eq!(previous, Ok(Some(Physical::new(0x5000))));
+        assert!(pt[0usize].is_guard() && !pt[0usize].is_present());
+
+        let missing = Virtual::new(base + 0x4000_0000);
+        let error = unsafe { super::guard_page(&mut pml4, missing, &identity) };
+        assert_eq!(error, Err(GuardError::NoPageTable));
+
+        let fault = PageFaultInfo {
+            address: Virtual::new(base + 0xFF8),
+            cause: PageFaultErrorCode::WRITE_ACCESS,
+        };
+        let overflow = unsafe { super::check_stack_overflow(&mut pml4, &fault, 7, &identity) };
+        let overflow = overflow.unwrap();
+        assert_eq!(overflow.task, 7);
+        assert!(overflow.to_string().starts_with("stack overflow on CPU "));
+        assert!(overflow.to_string().ends_with(" task 7 (guard page hit at 0xffff800000000ff8)"));
+
+        let fault = PageFaultInfo {
+            address: Virtual::new(base + 0x2000),
+            ..fault
+        };
+        assert!(unsafe { super::check_stack_overflow(&mut pml4, &fault, 7, &identity) }.is_none());
+    }
+
+    #[test]
+    fn global_ranges() {
+        use super::global_pages::{self, Error};
+
+        let user = VirtualRange::new(Virtual::new(0x1000), Virtual::new(0x2000));
+        assert_eq!(global_pages::register(user), Err(Error::NotKernel));
+
+        let kernel = VirtualRange::new(
+            Virtual::new(0xFFFF_FFFF_8000_0000),
+            Virtual::new(0xFFFF_FFFF_8020_0000),
+        );
+        global_pages::register(kernel).unwrap();
+        assert!(global_pages::ranges().any(|range| range == kernel));
+    }
+}
@@ -0,0 +1,105 @@
+//! Helpers for the panic handler of the kernel. The [`handle`] function does what every panic
+//! handler needs to do: stop the other CPUs, print the panic message, the registers and a
+//! backtrace with the registered writer, and halt the CPU or exit QEMU.
+use core::{
+    fmt,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{
+    backtrace::{self, Frame},
+    cpu::{self, State},
+    irq,
+    lapic::LocalApic,
+    qemu::{self, ExitCode},
+    smp,
+};
+
+/// The function used to print the panic report, or 0 if none is registered.
+static WRITER: AtomicUsize = AtomicUsize::new(0);
+
+/// The local APIC used to freeze the other CPUs, or null if none is registered.
+static LAPIC: AtomicPtr<LocalApic> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Set if QEMU should exit after the panic report instead of halting the CPU.
+static EXIT_QEMU: AtomicBool = AtomicBool::new(false);
+
+/// Set by the first panic, to detect a panic in the panic handler.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Register the function used to print the panic report. The function must not take a lock that
+/// could be held by the panicking code: a serial console should be forcibly unlocked first (see
+/// [`crate::serial::logger::SerialLogger::force_unlock`]).
+pub fn set_writer(writer: fn(fmt::Arguments)) {
+    WRITER.store(writer as usize, Ordering::Release);
+}
+
+/// Register the local APIC used to freeze the other CPUs when the kernel panics (see
+/// [`smp::freeze_others`]). Without a local APIC, the other CPUs keep running.
+pub fn set_lapic(lapic: &'static LocalApic) {
+    LAPIC.store((lapic as *const LocalApic).cast_mut(), Ordering::Release);
+}
+
+/// Select whether QEMU should exit with [`ExitCode::Failed`] after the panic report (see
+/// [`qemu::exit`]), instead of halting the current CPU forever.
+pub fn set_exit_qemu(exit: bool) {
+    EXIT_QEMU.store(exit, Ordering::Relaxed);
+}
+
+/// Handle a kernel panic: disable interrupts, freeze the other CPUs if a local APIC is registered,
+/// print the panic message, the given interrupted state if any and a backtrace with the
+/// registered writer, then exit QEMU or halt the current CPU. The backtrace starts at the given
+/// state if any, or at the caller otherwise.
+///
+/// If the kernel panics again while handling a panic, the current CPU is halted immediately.
+pub fn handle(info: &PanicInfo, state: Option<&State>) -> ! {
+    irq::disable();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        cpu::freeze();
+    }
+
+    // SAFETY: The pointer was registered from a static reference
+    if let Some(lapic) = unsafe { LAPIC.load(Ordering::Acquire).as_ref() } {
+        smp::freeze_others(lapic);
+    }
+
+    print(format_args!("Kernel panic: {info}\n"));
+    if let Some(state) = state {
+        print(format_args!("{state}\n"));
+    }
+
+    print(format_args!("Backtrace:\n"));
+    let mut index = 0;
+    let report = |frame: Frame| {
+        print(format_args!(
+            "  #{index:<2} {:#018x}\n",
+            frame.return_address
+        ));
+        index += 1;
+    };
+
+    // SAFETY: The stack is validated frame by frame by the walker, and the kernel is going to be
+    // halted anyway if the stack is corrupted
+    unsafe {
+        match state {
+            Some(state) => backtrace::walk_state(state, report),
+            None => backtrace::walk_current(report),
+        };
+    }
+
+    if EXIT_QEMU.load(Ordering::Relaxed) {
+        qemu::exit_with(ExitCode::Failed);
+    }
+    cpu::freeze();
+}
+
+/// Print the given formatted arguments with the registered writer, if any.
+fn print(args: fmt::Arguments) {
+    let writer = WRITER.load(Ordering::Acquire);
+    if writer != 0 {
+        // SAFETY: The function pointer was stored by `set_writer`
+        let writer: fn(fmt::Arguments) = unsafe { core::mem::transmute(writer) };
+        writer(args);
+    }
+}
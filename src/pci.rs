@@ -0,0 +1,1008 @@
+//! Access to the PCI configuration space. Each function of each device has 256 bytes of
+//! configuration space (4 KiB with PCI Express), whose first 64 bytes are a standard header
+//! identifying the function and describing its resources. The configuration space is accessed
+//! through a [`ConfigAccess`] backend:
+//! - [`Legacy`], the configuration mechanism #1 through the I/O ports [`CONFIG_ADDRESS`] and
+//!   [`CONFIG_DATA`], which only gives access to the first 256 bytes.
+//! - [`Ecam`], the memory-mapped enhanced configuration access mechanism of PCI Express, whose
+//!   regions are described by the ACPI MCFG table. It gives access to the extended configuration
+//!   space, which contains the extended capabilities (such as MSI-X on some devices).
+use core::ops::RangeInclusive;
+
+use bitflags::bitflags;
+
+use crate::{
+    address::Virtual,
+    io::{inb, ind, inw, outb, outd, outw},
+};
+
+/// The port selecting the register of the configuration space to access.
+pub const CONFIG_ADDRESS: u16 = 0xCF8;
+
+/// The port used to read and write the selected register.
+pub const CONFIG_DATA: u16 = 0xCFC;
+
+/// The common registers of the header.
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const PROG_IF: u16 = 0x09;
+pub const SUBCLASS: u16 = 0x0A;
+pub const CLASS: u16 = 0x0B;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+
+/// The bus registers of the header of a PCI-to-PCI bridge.
+pub const PRIMARY_BUS: u16 = 0x18;
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const SUBORDINATE_BUS: u16 = 0x1A;
+
+/// The vendor identifier read when no function is present.
+pub const NO_VENDOR: u16 = 0xFFFF;
+
+/// The enable bit of [`CONFIG_ADDRESS`].
+const ENABLE: u32 = 1 << 31;
+
+/// The bit of the header type set if the device has several functions.
+const MULTIFUNCTION: u8 = 1 << 7;
+
+/// The number of devices on a bus, and of functions of a device.
+const DEVICES: u8 = 32;
+const FUNCTIONS: u8 = 8;
+
+bitflags! {
+    /// The flags of the command register.
+    pub struct Command: u16 {
+        /// The function responds to the accesses to its I/O space BARs.
+        const IO_SPACE = 1 << 0;
+
+        /// The function responds to the accesses to its memory space BARs.
+        const MEMORY_SPACE = 1 << 1;
+
+        /// The function can initiate memory accesses (DMA) and send MSIs.
+        const BUS_MASTER = 1 << 2;
+
+        /// The function reports parity errors.
+        const PARITY_ERROR_RESPONSE = 1 << 6;
+
+        /// The SERR# driver of the function is enabled.
+        const SERR = 1 << 8;
+
+        /// The function cannot assert its INTx# interrupt.
+        const INTERRUPT_DISABLE = 1 << 10;
+    }
+}
+
+/// The address of a function in the configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConfigAddress {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl ConfigAddress {
+    /// Creates a new configuration address.
+    ///
+    /// # Panics
+    /// Panics if the device is greater than 31 or if the function is greater than 7.
+    #[must_use]
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        assert!(device < DEVICES, "PCI device number is greater than 31");
+        assert!(
+            function < FUNCTIONS,
+            "PCI function number is greater than 7"
+        );
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    #[must_use]
+    pub const fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    #[must_use]
+    pub const fn device(&self) -> u8 {
+        self.device
+    }
+
+    #[must_use]
+    pub const fn function(&self) -> u8 {
+        self.function
+    }
+
+    /// Returns the value written to [`CONFIG_ADDRESS`] to select the 32 bits register containing
+    /// the given offset, which must be lower than 256.
+    #[must_use]
+    pub const fn encode(&self, offset: u16) -> u32 {
+        ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+}
+
+impl core::fmt::Display for ConfigAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A mechanism to access the configuration space.
+///
+/// All the accesses must be naturally aligned: the implementations panic on an unaligned offset
+/// or on an offset out of the configuration space they give access to. Reading the configuration
+/// space of an absent function returns all ones.
+pub trait ConfigAccess {
+    /// Returns the size of the configuration space of each function: 256 bytes, or 4096 bytes
+    /// with the extended configuration space.
+    fn config_size(&self) -> u16;
+
+    /// Returns the range of the buses accessible with this mechanism.
+    fn buses(&self) -> RangeInclusive<u8> {
+        0..=255
+    }
+
+    /// Read the 32 bits register at the given offset.
+    ///
+    /// # Safety
+    /// This function is unsafe because the implementation may need exclusive access to the
+    /// configuration mechanism (as [`Legacy`]): the caller must hold a lock or disable the
+    /// interrupts on a single CPU system.
+    unsafe fn read_u32(&self, address: ConfigAddress, offset: u16) -> u32;
+
+    /// Read the 16 bits register at the given offset.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    unsafe fn read_u16(&self, address: ConfigAddress, offset: u16) -> u16;
+
+    /// Read the 8 bits register at the given offset.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    unsafe fn read_u8(&self, address: ConfigAddress, offset: u16) -> u8;
+
+    /// Write the 32 bits register at the given offset.
+    ///
+    /// # Safety
+    /// This function is unsafe because writing to the configuration space changes the behavior
+    /// of the function, and for the reasons given in [`ConfigAccess::read_u32`].
+    unsafe fn write_u32(&self, address: ConfigAddress, offset: u16, value: u32);
+
+    /// Write the 16 bits register at the given offset.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::write_u32`].
+    unsafe fn write_u16(&self, address: ConfigAddress, offset: u16, value: u16);
+
+    /// Write the 8 bits register at the given offset.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::write_u32`].
+    unsafe fn write_u8(&self, address: ConfigAddress, offset: u16, value: u8);
+}
+
+/// Check that an access of the given size at the given offset is aligned and in a configuration
+/// space of the given size.
+fn check_access(offset: u16, size: u16, config_size: u16) {
+    assert!(
+        offset & (size - 1) == 0,
+        "Unaligned PCI configuration access"
+    );
+    assert!(
+        offset < config_size,
+        "PCI configuration offset {offset:#x} is out of range"
+    );
+}
+
+/// The legacy configuration mechanism #1, through the I/O ports [`CONFIG_ADDRESS`] and
+/// [`CONFIG_DATA`]. Since an access is made of two port accesses, the accesses must not be
+/// concurrent.
+#[derive(Debug)]
+pub struct Legacy {
+    _private: (),
+}
+
+impl Legacy {
+    /// The size of the configuration space accessible with this mechanism.
+    pub const CONFIG_SIZE: u16 = 256;
+
+    /// Creates a new handle to the legacy configuration mechanism.
+    ///
+    /// # Safety
+    /// This function is unsafe because the mechanism must be supported (it is by all PC chipsets),
+    /// and only one handle must exist at a time.
+    #[must_use]
+    pub const unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+}
+
+impl ConfigAccess for Legacy {
+    fn config_size(&self) -> u16 {
+        Self::CONFIG_SIZE
+    }
+
+    unsafe fn read_u32(&self, address: ConfigAddress, offset: u16) -> u32 {
+        check_access(offset, 4, Self::CONFIG_SIZE);
+        outd(CONFIG_ADDRESS, address.encode(offset));
+        ind(CONFIG_DATA)
+    }
+
+    unsafe fn read_u16(&self, address: ConfigAddress, offset: u16) -> u16 {
+        check_access(offset, 2, Self::CONFIG_SIZE);
+        outd(CONFIG_ADDRESS, address.encode(offset));
+        inw(CONFIG_DATA + (offset & 2))
+    }
+
+    unsafe fn read_u8(&self, address: ConfigAddress, offset: u16) -> u8 {
+        check_access(offset, 1, Self::CONFIG_SIZE);
+        outd(CONFIG_ADDRESS, address.encode(offset));
+        inb(CONFIG_DATA + (offset & 3))
+    }
+
+    unsafe fn write_u32(&self, address: ConfigAddress, offset: u16, value: u32) {
+        check_access(offset, 4, Self::CONFIG_SIZE);
+        outd(CONFIG_ADDRESS, address.encode(offset));
+        outd(CONFIG_DATA, value);
+    }
+
+    unsafe fn write_u16(&self, address: ConfigAddress, offset: u16, value: u16) {
+        check_access(offset, 2, Self::CONFIG_SIZE);
+        outd(CONFIG_ADDRESS, address.encode(offset));
+        outw(CONFIG_DATA + (offset & 2), value);
+    }
+
+    unsafe fn write_u8(&self, address: ConfigAddress, offset: u16, value: u8) {
+        check_access(offset, 1, Self::CONFIG_SIZE);
+        outd(CONFIG_ADDRESS, address.encode(offset));
+        outb(CONFIG_DATA + (offset & 3), value);
+    }
+}
+
+/// The enhanced configuration access mechanism (ECAM) of PCI Express, also known as MMCONFIG. The
+/// configuration space of each function of the buses of a segment is mapped in a memory region,
+/// at an offset computed by [`Ecam::offset`]. Each access is a single memory access, so the
+/// accesses can be concurrent.
+#[derive(Debug)]
+pub struct Ecam {
+    base: Virtual,
+    buses: RangeInclusive<u8>,
+}
+
+impl Ecam {
+    /// The size of the configuration space accessible with this mechanism.
+    pub const CONFIG_SIZE: u16 = 4096;
+
+    /// Creates a new handle to the ECAM region mapped at the given virtual address, giving access
+    /// to the given buses. The physical address of the region and the bus range are given by an
+    /// entry of the ACPI MCFG table: the base address of the entry is the address of the bus 0,
+    /// even if the range starts at another bus, so the region to map starts at the base address
+    /// plus the offset of the first bus.
+    ///
+    /// # Safety
+    /// This function is unsafe because the region of the given buses must be mapped at the given
+    /// address as uncacheable memory (1 MiB per bus), and must stay mapped while the handle is
+    /// used. The given address is the address of the first bus of the range.
+    #[must_use]
+    pub const unsafe fn new(base: Virtual, buses: RangeInclusive<u8>) -> Self {
+        Self { base, buses }
+    }
+
+    /// Returns the offset of the given register in the ECAM region, relative to the address of
+    /// the bus 0.
+    #[must_use]
+    pub const fn offset(address: ConfigAddress, offset: u16) -> u64 {
+        (address.bus as u64) << 20
+            | (address.device as u64) << 15
+            | (address.function as u64) << 12
+            | offset as u64
+    }
+
+    /// Returns a pointer to the given register, or `None` if the bus is out of the range of the
+    /// region.
+    fn register<T>(&self, address: ConfigAddress, offset: u16) -> Option<*mut T> {
+        check_access(offset, Self::size_of::<T>(), Self::CONFIG_SIZE);
+        if !self.buses.contains(&address.bus) {
+            return None;
+        }
+        let first = Self::offset(ConfigAddress::new(*self.buses.start(), 0, 0), 0);
+        Some((self.base + (Self::offset(address, offset) - first)).as_mut_ptr::<T>())
+    }
+
+    /// Returns the size of a register type.
+    #[allow(clippy::cast_possible_truncation)]
+    const fn size_of<T>() -> u16 {
+        core::mem::size_of::<T>() as u16
+    }
+}
+
+impl ConfigAccess for Ecam {
+    fn config_size(&self) -> u16 {
+        Self::CONFIG_SIZE
+    }
+
+    fn buses(&self) -> RangeInclusive<u8> {
+        self.buses.clone()
+    }
+
+    unsafe fn read_u32(&self, address: ConfigAddress, offset: u16) -> u32 {
+        self.register::<u32>(address, offset)
+            .map_or(u32::MAX, |register| register.read_volatile())
+    }
+
+    unsafe fn read_u16(&self, address: ConfigAddress, offset: u16) -> u16 {
+        self.register::<u16>(address, offset)
+            .map_or(u16::MAX, |register| register.read_volatile())
+    }
+
+    unsafe fn read_u8(&self, address: ConfigAddress, offset: u16) -> u8 {
+        self.register::<u8>(address, offset)
+            .map_or(u8::MAX, |register| register.read_volatile())
+    }
+
+    unsafe fn write_u32(&self, address: ConfigAddress, offset: u16, value: u32) {
+        if let Some(register) = self.register::<u32>(address, offset) {
+            register.write_volatile(value);
+        }
+    }
+
+    unsafe fn write_u16(&self, address: ConfigAddress, offset: u16, value: u16) {
+        if let Some(register) = self.register::<u16>(address, offset) {
+            register.write_volatile(value);
+        }
+    }
+
+    unsafe fn write_u8(&self, address: ConfigAddress, offset: u16, value: u8) {
+        if let Some(register) = self.register::<u8>(address, offset) {
+            register.write_volatile(value);
+        }
+    }
+}
+
+/// The layout of the header of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    /// A general device, with 6 BARs.
+    General,
+
+    /// A PCI-to-PCI bridge, with 2 BARs.
+    PciBridge,
+
+    /// A PCI-to-CardBus bridge, without BARs.
+    CardBusBridge,
+
+    /// An unknown header layout.
+    Unknown(u8),
+}
+
+impl HeaderType {
+    /// Decode the header type register, without the multi-function bit.
+    #[must_use]
+    pub const fn from_bits(bits: u8) -> Self {
+        match bits & !MULTIFUNCTION {
+            0x00 => HeaderType::General,
+            0x01 => HeaderType::PciBridge,
+            0x02 => HeaderType::CardBusBridge,
+            kind => HeaderType::Unknown(kind),
+        }
+    }
+
+    /// Returns the number of BARs of this header layout.
+    #[must_use]
+    pub const fn bar_count(self) -> u8 {
+        match self {
+            HeaderType::General => 6,
+            HeaderType::PciBridge => 2,
+            HeaderType::CardBusBridge | HeaderType::Unknown(_) => 0,
+        }
+    }
+}
+
+/// The class code of a function, describing its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClassCode {
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl ClassCode {
+    /// The class and subclass of the PCI-to-PCI bridges.
+    pub const PCI_BRIDGE: (u8, u8) = (0x06, 0x04);
+
+    /// Returns `true` if the function has the given class and subclass.
+    #[must_use]
+    pub const fn is(&self, (class, subclass): (u8, u8)) -> bool {
+        self.class == class && self.subclass == subclass
+    }
+}
+
+/// A base address register, describing a range of the I/O or memory space decoded by a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// A range of the I/O space.
+    Io { port: u32 },
+
+    /// A range of the memory space, below 4 GiB.
+    Memory32 { address: u32, prefetchable: bool },
+
+    /// A range of the memory space, using two consecutive BARs.
+    Memory64 { address: u64, prefetchable: bool },
+}
+
+impl Bar {
+    /// Decode the value of a BAR. The value of the next BAR is needed if it is a 64 bits memory
+    /// BAR: in that case, `None` is returned if `high` is `None`, as it is for the last BAR.
+    #[must_use]
+    pub fn decode(low: u32, high: Option<u32>) -> Option<Self> {
+        if low & 1 != 0 {
+            return Some(Bar::Io { port: low & !0x3 });
+        }
+
+        let prefetchable = low & (1 << 3) != 0;
+        match (low >> 1) & 0x3 {
+            0b00 => Some(Bar::Memory32 {
+                address: low & !0xF,
+                prefetchable,
+            }),
+            0b10 => Some(Bar::Memory64 {
+                address: u64::from(high?) << 32 | u64::from(low & !0xF),
+                prefetchable,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if this BAR uses two BAR registers.
+    #[must_use]
+    pub const fn is_64bits(&self) -> bool {
+        matches!(self, Bar::Memory64 { .. })
+    }
+
+    /// Returns the base address of the range.
+    #[must_use]
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Io { port } => u64::from(port),
+            Bar::Memory32 { address, .. } => u64::from(address),
+            Bar::Memory64 { address, .. } => address,
+        }
+    }
+}
+
+/// The kind of the address space range decoded by a BAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// A range of the I/O space.
+    Io,
+
+    /// A range of the memory space below 4 GiB.
+    Memory32 { prefetchable: bool },
+
+    /// A range of the memory space anywhere in the 64 bits address space.
+    Memory64 { prefetchable: bool },
+}
+
+/// An address space range decoded by a function, described by a BAR and sized with
+/// [`Header::resource`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciResource {
+    /// The base address of the range, which is 0 if the firmware did not assign the BAR.
+    pub base: u64,
+
+    /// The size of the range in bytes, which is a power of two.
+    pub len: u64,
+    pub kind: ResourceKind,
+}
+
+impl PciResource {
+    /// Decode a resource from the value of a BAR and the value read back after writing all ones
+    /// to it. For a 64 bits BAR, the values of the upper BAR are given in `high` as `(value,
+    /// mask)`. Returns `None` if the BAR is not implemented (its mask is 0), or if it is an
+    /// invalid or truncated 64 bits BAR.
+    #[must_use]
+    pub fn decode(low: u32, low_mask: u32, high: Option<(u32, u32)>) -> Option<Self> {
+        let bar = Bar::decode(low, high.map(|(value, _)| value))?;
+        let (kind, mask) = match bar {
+            Bar::Io { .. } => {
+                // The upper 16 bits of an I/O BAR may be hardwired to 0
+                let mask = match low_mask & !0x3 {
+                    0 => return None,
+                    mask => mask,
+                };
+                let mask = if mask >> 16 == 0 {
+                    mask | 0xFFFF_0000
+                } else {
+                    mask
+                };
+                (ResourceKind::Io, u64::from(mask) | 0xFFFF_FFFF_0000_0000)
+            }
+            Bar::Memory32 { prefetchable, .. } => (
+                ResourceKind::Memory32 { prefetchable },
+                u64::from(low_mask & !0xF) | 0xFFFF_FFFF_0000_0000,
+            ),
+            Bar::Memory64 { prefetchable, .. } => (
+                ResourceKind::Memory64 { prefetchable },
+                u64::from(high?.1) << 32 | u64::from(low_mask & !0xF),
+            ),
+        };
+
+        let len = (!mask).wrapping_add(1);
+        (mask != 0xFFFF_FFFF_0000_0000 && len != 0).then_some(Self {
+            base: bar.address(),
+            len,
+            kind,
+        })
+    }
+
+    /// Returns `true` if the range is in the memory space and prefetchable: it can then be mapped
+    /// as write-combining memory.
+    #[must_use]
+    pub const fn prefetchable(&self) -> bool {
+        matches!(
+            self.kind,
+            ResourceKind::Memory32 { prefetchable: true }
+                | ResourceKind::Memory64 { prefetchable: true }
+        )
+    }
+}
+
+/// The common part of the header of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub address: ConfigAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision: u8,
+    pub class: ClassCode,
+    pub header_type: HeaderType,
+    pub multifunction: bool,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+}
+
+impl Header {
+    /// Read the header of the function at the given address, or return `None` if there is no
+    /// function at this address.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    #[must_use]
+    pub unsafe fn read(access: &impl ConfigAccess, address: ConfigAddress) -> Option<Self> {
+        let vendor_id = access.read_u16(address, VENDOR_ID);
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+
+        let header_type = access.read_u8(address, HEADER_TYPE);
+        Some(Self {
+            address,
+            vendor_id,
+            device_id: access.read_u16(address, DEVICE_ID),
+            revision: access.read_u8(address, REVISION),
+            class: ClassCode {
+                class: access.read_u8(address, CLASS),
+                subclass: access.read_u8(address, SUBCLASS),
+                prog_if: access.read_u8(address, PROG_IF),
+            },
+            header_type: HeaderType::from_bits(header_type),
+            multifunction: header_type & MULTIFUNCTION != 0,
+            interrupt_line: access.read_u8(address, INTERRUPT_LINE),
+            interrupt_pin: access.read_u8(address, INTERRUPT_PIN),
+        })
+    }
+
+    /// Returns `true` if the function is a PCI-to-PCI bridge.
+    #[must_use]
+    pub const fn is_bridge(&self) -> bool {
+        matches!(self.header_type, HeaderType::PciBridge)
+    }
+
+    /// Read the secondary bus number of a PCI-to-PCI bridge, or return `None` if the function is
+    /// not a bridge.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    #[must_use]
+    pub unsafe fn secondary_bus(&self, access: &impl ConfigAccess) -> Option<u8> {
+        self.is_bridge()
+            .then(|| access.read_u8(self.address, SECONDARY_BUS))
+    }
+
+    /// Read the command register.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    #[must_use]
+    pub unsafe fn command(&self, access: &impl ConfigAccess) -> Command {
+        Command::from_bits_truncate(access.read_u16(self.address, COMMAND))
+    }
+
+    /// Write the command register.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::write_u32`].
+    pub unsafe fn set_command(&self, access: &impl ConfigAccess, command: Command) {
+        access.write_u16(self.address, COMMAND, command.bits());
+    }
+
+    /// Read the BAR with the given index. Returns `None` if the index is out of the BARs of the
+    /// header, if the BAR is not implemented, or if it is the upper half of a 64 bits BAR.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    #[must_use]
+    pub unsafe fn bar(&self, access: &impl ConfigAccess, index: u8) -> Option<Bar> {
+        let count = self.header_type.bar_count();
+        if index >= count || self.is_upper_half(access, index) {
+            return None;
+        }
+
+        let low = access.read_u32(self.address, BAR0 + u16::from(index) * 4);
+        let high = (index + 1 < count)
+            .then(|| access.read_u32(self.address, BAR0 + u16::from(index + 1) * 4));
+        Bar::decode(low, high).filter(|bar| bar.address() != 0)
+    }
+
+    /// Returns `true` if the BAR with the given index is the upper half of a 64 bits BAR. The
+    /// preceding BARs are read from the first one, since only the lower half of a 64 bits BAR
+    /// tells its type.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    unsafe fn is_upper_half(&self, access: &impl ConfigAccess, index: u8) -> bool {
+        let mut current = 0;
+        while current < index {
+            let low = access.read_u32(self.address, BAR0 + u16::from(current) * 4);
+            current += if low & 0b111 == 0b100 { 2 } else { 1 };
+        }
+        current != index
+    }
+
+    /// Returns an iterator over the implemented BARs of the function, with their index.
+    ///
+    /// # Safety
+    /// See [`ConfigAccess::read_u32`].
+    pub unsafe fn bars<'a>(
+        &'a self,
+        access: &'a impl ConfigAccess,
+    ) -> impl Iterator<Item = (u8, Bar)> + 'a {
+        let mut index = 0;
+        core::iter::from_fn(move || {
+            while index < self.header_type.bar_count() {
+                let current = index;
+                let bar = self.bar(access, current);
+                index += if matches!(bar, Some(Bar::Memory64 { .. })) {
+                    2
+                } else {
+                    1
+                };
+                if let Some(bar) = bar {
+                    return Some((current, bar));
+                }
+            }
+            None
+        })
+    }
+
+    /// Read the BAR with the given index and probe the size of its range, by writing all ones to
+    /// it and reading back the bits that can be set. The decoding of the I/O and memory spaces is
+    /// disabled during the probe, and the BAR and the command register are restored afterwards.
+    /// Returns `None` if the index is out of the BARs of the header, if the BAR is not
+    /// implemented, or if it is the upper half of a 64 bits BAR.
+    ///
+    /// # Safety
+    /// This function is unsafe because the function must not be used by a driver during the
+    /// probe, since its BARs are temporarily invalid, and for the reasons given in
+    /// [`ConfigAccess::write_u32`].
+    #[must_use]
+    pub unsafe fn resource(&self, access: &impl ConfigAccess, index: u8) -> Option<PciResource> {
+        let count = self.header_type.bar_count();
+        if index >= count || self.is_upper_half(access, index) {
+            return None;
+        }
+
+        let command = access.read_u16(self.address, COMMAND);
+        let decoding = (Command::IO_SPACE | Command::MEMORY_SPACE).bits();
+        access.write_u16(self.address, COMMAND, command & !decoding);
+
+        let probe = |offset: u16| {
+            let value = access.read_u32(self.address, offset);
+            access.write_u32(self.address, offset, u32::MAX);
+            let mask = access.read_u32(self.address, offset);
+            access.write_u32(self.address, offset, value);
+            (value, mask)
+        };
+
+        let offset = BAR0 + u16::from(index) * 4;
+        let (low, low_mask) = probe(offset);
+        let is_64bits = low & 0b111 == 0b100;
+        let high = (is_64bits && index + 1 < count).then(|| probe(offset + 4));
+
+        access.write_u16(self.address, COMMAND, command);
+        PciResource::decode(low, low_mask, high)
+    }
+
+    /// Returns an iterator over the implemented BARs of the function with their sized resource
+    /// and their index (see [`Header::resource`]).
+    ///
+    /// # Safety
+    /// See [`Header::resource`].
+    pub unsafe fn resources<'a>(
+        &'a self,
+        access: &'a impl ConfigAccess,
+    ) -> impl Iterator<Item = (u8, PciResource)> + 'a {
+        let mut index = 0;
+        core::iter::from_fn(move || {
+            while index < self.header_type.bar_count() {
+                let current = index;
+                let resource = self.resource(access, current);
+                index += if matches!(
+                    resource,
+                    Some(PciResource {
+                        kind: ResourceKind::Memory64 { .. },
+                        ..
+                    })
+                ) {
+                    2
+                } else {
+                    1
+                };
+                if let Some(resource) = resource {
+                    return Some((current, resource));
+                }
+            }
+            None
+        })
+    }
+}
+
+/// An iterator over all the functions of the PCI buses reachable from the host bridges, found by
+/// following the PCI-to-PCI bridges. The functions are returned bus by bus, in increasing order of
+/// bus number.
+#[derive(Debug, Clone)]
+pub struct Enumerator<'a, A: ConfigAccess> {
+    access: &'a A,
+    pending: [u64; 4],
+    visited: [u64; 4],
+    next: Option<ConfigAddress>,
+}
+
+impl<'a, A: ConfigAccess> Enumerator<'a, A> {
+    /// Creates a new enumerator using the given configuration mechanism, starting from the first
+    /// bus accessible by the mechanism. If the host bridge is a multi-function device, each of
+    /// its functions is the root of a separate bus, whose number is the function number.
+    ///
+    /// # Safety
+    /// This function is unsafe because the requirements of [`ConfigAccess::read_u32`] must be
+    /// upheld while the enumerator is used.
+    #[must_use]
+    pub unsafe fn new(access: &'a A) -> Self {
+        let mut enumerator = Self {
+            access,
+            pending: [0; 4],
+            visited: [0; 4],
+            next: None,
+        };
+
+        let root = *access.buses().start();
+        match Header::read(access, ConfigAddress::new(root, 0, 0)) {
+            Some(header) if header.multifunction => {
+                for function in 0..FUNCTIONS {
+                    if Header::read(access, ConfigAddress::new(root, 0, function)).is_some() {
+                        enumerator.schedule(root + function);
+                    }
+                }
+            }
+            _ => enumerator.schedule(root),
+        }
+        enumerator
+    }
+
+    /// Schedule the scan of the given bus, if it was not already scanned or scheduled.
+    fn schedule(&mut self, bus: u8) {
+        if !self.access.buses().contains(&bus) {
+            return;
+        }
+        let (word, bit) = (usize::from(bus / 64), 1 << (bus % 64));
+        if self.visited[word] & bit == 0 {
+            self.visited[word] |= bit;
+            self.pending[word] |= bit;
+        }
+    }
+
+    /// Remove the pending bus with the lowest number, and return it.
+    #[allow(clippy::cast_possible_truncation)]
+    fn next_bus(&mut self) -> Option<u8> {
+        let word = self.pending.iter().position(|&word| word != 0)?;
+        let bit = self.pending[word].trailing_zeros();
+        self.pending[word] &= !(1 << bit);
+        Some((word * 64) as u8 + bit as u8)
+    }
+
+    /// Returns the address following the given one, skipping the other functions of the device if
+    /// it is not a multi-function device.
+    fn following(address: ConfigAddress, multifunction: bool) -> Option<ConfigAddress> {
+        let ConfigAddress {
+            bus,
+            device,
+            function,
+        } = address;
+        if multifunction && function + 1 < FUNCTIONS {
+            Some(ConfigAddress::new(bus, device, function + 1))
+        } else if device + 1 < DEVICES {
+            Some(ConfigAddress::new(bus, device + 1, 0))
+        } else {
+            None
+        }
+    }
+}
+
+impl<A: ConfigAccess> Iterator for Enumerator<'_, A> {
+    type Item = Header;
+
+    fn next(&mut self) -> Option<Header> {
+        loop {
+            let address = match self.next {
+                Some(address) => address,
+                None => ConfigAddress::new(self.next_bus()?, 0, 0),
+            };
+
+            // SAFETY: The configuration ports are not accessed concurrently (see `new`)
+            let header = unsafe { Header::read(self.access, address) };
+            let multifunction = match (address.function, header) {
+                (0, Some(header)) => header.multifunction,
+                (0, None) => false,
+                _ => true,
+            };
+            self.next = Self::following(address, multifunction);
+
+            if let Some(header) = header {
+                // SAFETY: The configuration ports are not accessed concurrently (see `new`)
+                if let Some(bus) = unsafe { header.secondary_bus(self.access) } {
+                    self.schedule(bus);
+                }
+                return Some(header);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Bar, ClassCode, ConfigAccess, ConfigAddress, Ecam, Header, HeaderType, PciResource,
+        ResourceKind, BAR0,
+    };
+
+    /// A function whose configuration space only has BARs, which cannot be written.
+    struct Bars([u32; 6]);
+
+    impl ConfigAccess for Bars {
+        fn config_size(&self) -> u16 {
+            256
+        }
+
+        unsafe fn read_u32(&self, _: ConfigAddress, offset: u16) -> u32 {
+            self.0[usize::from((offset - BAR0) / 4)]
+        }
+
+        unsafe fn read_u16(&self, _: ConfigAddress, _: u16) -> u16 {
+            unimplemented!()
+        }
+
+        unsafe fn read_u8(&self, _: ConfigAddress, _: u16) -> u8 {
+            unimplemented!()
+        }
+
+        unsafe fn write_u32(&self, _: ConfigAddress, _: u16, _: u32) {
+            unimplemented!()
+        }
+
+        unsafe fn write_u16(&self, _: ConfigAddress, _: u16, _: u16) {
+            unimplemented!()
+        }
+
+        unsafe fn write_u8(&self, _: ConfigAddress, _: u16, _: u8) {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn config_address_encoding() {
+        let address = ConfigAddress::new(0x12, 0x1F, 7);
+        assert_eq!(address.encode(0x3E), 0x8012_FF3C);
+        assert_eq!(ConfigAddress::new(0, 3, 0).encode(0x10), 0x8000_1810);
+        assert_eq!(HeaderType::from_bits(0x81), HeaderType::PciBridge);
+        assert_eq!(Ecam::offset(address, 0x104), 0x12F_F104);
+    }
+
+    #[test]
+    fn bar_decoding() {
+        assert_eq!(Bar::decode(0xC001, None), Some(Bar::Io { port: 0xC000 }));
+        assert_eq!(
+            Bar::decode(0xFEB0_0008, None),
+            Some(Bar::Memory32 {
+                address: 0xFEB0_0000,
+                prefetchable: true
+            })
+        );
+        assert_eq!(
+            Bar::decode(0xE000_0004, Some(0x1)),
+            Some(Bar::Memory64 {
+                address: 0x1_E000_0000,
+                prefetchable: false
+            })
+        );
+        assert_eq!(Bar::decode(0xE000_0004, None), None);
+    }
+
+    #[test]
+    fn header_bars() {
+        let header = Header {
+            address: ConfigAddress::new(0, 0, 0),
+            vendor_id: 0x8086,
+            device_id: 0x100E,
+            revision: 0,
+            class: ClassCode {
+                class: 0x02,
+                subclass: 0,
+                prog_if: 0,
+            },
+            header_type: HeaderType::General,
+            multifunction: false,
+            interrupt_line: 0,
+            interrupt_pin: 0,
+        };
+
+        // A 64 bits BAR whose upper half looks like an I/O BAR, and an unassigned 64 bits BAR
+        let access = Bars([0xE000_0004, 0xC001, 0x4, 0, 0xC001, 0]);
+        let bars: Vec<_> = unsafe { header.bars(&access) }.collect();
+        assert_eq!(
+            bars,
+            [
+                (
+                    0,
+                    Bar::Memory64 {
+                        address: 0xC001_E000_0000,
+                        prefetchable: false
+                    }
+                ),
+                (4, Bar::Io { port: 0xC000 })
+            ]
+        );
+        assert_eq!(unsafe { header.bar(&access, 1) }, None);
+        assert_eq!(unsafe { header.bar(&access, 2) }, None);
+        assert_eq!(unsafe { header.bar(&access, 3) }, None);
+    }
+
+    #[test]
+    fn resource_sizing() {
+        let resource = PciResource::decode(0xC001, 0xFFE1, None).unwrap();
+        assert_eq!((resource.base, resource.len), (0xC000, 0x20));
+        assert_eq!(resource.kind, ResourceKind::Io);
+
+        let resource = PciResource::decode(0xFEB8_0008, 0xFFF8_0008, None).unwrap();
+        assert_eq!((resource.base, resource.len), (0xFEB8_0000, 0x8_0000));
+        assert!(resource.prefetchable());
+
+        let high = Some((0x1, 0xFFFF_FFFF));
+        let resource = PciResource::decode(0x0000_000C, 0xF000_000C, high).unwrap();
+        assert_eq!((resource.base, resource.len), (0x1_0000_0000, 0x1000_0000));
+        assert_eq!(resource.kind, ResourceKind::Memory64 { prefetchable: true });
+
+        assert_eq!(PciResource::decode(0, 0, None), None);
+        assert_eq!(PciResource::decode(0x1, 0x1, None), None);
+    }
+}
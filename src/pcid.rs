@@ -0,0 +1,215 @@
+//! Allocation of the process-context identifiers (PCID). When PCIDs are enabled (see
+//! [`crate::cpu::cr4::Flags::PCIDE`]), the TLB entries are tagged with the PCID of the address
+//! space that created them, so a context switch does not need to flush the TLB: the entries of
+//! the other address spaces are simply not used.
+//!
+//! There are only 4096 PCIDs (2048 with the page table isolation, see [`crate::pti::MAX_PCID`]),
+//! so they are recycled by generations. An [`Allocator`] hands out the PCIDs of the current
+//! generation in order, and an address space keeps its [`Tag`] as long as the generation does
+//! not change. When all the PCIDs of the generation are used, a new generation starts: the whole
+//! TLB must be flushed (see [`flush_all`]), and every address space gets a new PCID the next time
+//! it is loaded.
+//!
+//! The TLB is per CPU, so each CPU must have its own allocator, and each address space must keep
+//! one tag per CPU. The allocator of a CPU must only be used by this CPU, with the interrupts
+//! disabled (typically during the context switch).
+use core::arch::asm;
+
+use crate::{
+    address::Physical,
+    cpu::feature::Feature,
+    pti::NOFLUSH_BIT,
+};
+
+/// The maximal PCID. The PCID 0 is reserved for the code running before the allocator is used,
+/// and for the kernel address space.
+pub const MAX_PCID: u16 = 0xFFF;
+
+/// The PCID assigned to an address space on a CPU, valid during a generation of the allocator of
+/// this CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tag {
+    pcid: u16,
+    generation: u64,
+}
+
+impl Tag {
+    /// Returns the PCID.
+    #[must_use]
+    pub const fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    /// Returns the generation in which the PCID was assigned.
+    #[must_use]
+    pub const fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+/// The result of [`Allocator::assign`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    /// The tag to use, which must be kept by the address space for the current CPU.
+    pub tag: Tag,
+
+    /// Set if the tag was still valid: the TLB entries of the PCID can be kept when loading the
+    /// address space.
+    pub reused: bool,
+
+    /// Set if a new generation was started: the whole TLB must be flushed with [`flush_all`]
+    /// before loading the address space.
+    pub flush_all: bool,
+}
+
+impl Assignment {
+    /// Returns the value to write to CR3 to load the PML4 at the given physical address with the
+    /// assigned PCID. The no-flush bit is set if the tag was reused, so that the TLB entries of
+    /// the address space are kept. With the page table isolation, [`crate::pti::kernel_cr3`]
+    /// must be used instead, with the same PCID and no-flush bit.
+    ///
+    /// # Panics
+    /// Panics if the address is not aligned on 4 KiB.
+    #[must_use]
+    pub fn cr3(&self, pml4: Physical) -> u64 {
+        assert!(pml4.is_aligned(4096u64), "PML4 is not aligned on 4 KiB");
+        let noflush = if self.reused { NOFLUSH_BIT } else { 0 };
+        pml4.as_u64() | u64::from(self.tag.pcid) | noflush
+    }
+}
+
+/// A generation-based PCID allocator, for a single CPU (see the module documentation).
+#[derive(Debug)]
+pub struct Allocator {
+    generation: u64,
+    next: u16,
+    max: u16,
+}
+
+impl Allocator {
+    /// Creates a new allocator handing out the PCIDs from 1 to `max`. With the page table
+    /// isolation, `max` must be [`crate::pti::MAX_PCID`], since the bit 11 of the PCID is
+    /// reserved for the user PML4.
+    ///
+    /// # Panics
+    /// Panics if `max` is 0 or greater than [`MAX_PCID`].
+    #[must_use]
+    pub const fn new(max: u16) -> Self {
+        assert!(max != 0 && max <= MAX_PCID, "Invalid maximal PCID");
+        Self {
+            generation: 1,
+            next: 1,
+            max,
+        }
+    }
+
+    /// Returns the current generation. The tags of the previous generations are not valid
+    /// anymore.
+    #[must_use]
+    pub const fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns `true` if the given tag is valid in the current generation.
+    #[must_use]
+    pub const fn is_valid(&self, tag: Tag) -> bool {
+        tag.generation == self.generation
+    }
+
+    /// Returns the PCID to use for an address space, given the tag it kept for this CPU (or
+    /// `None` if it was never loaded on this CPU). The tag is reused if it is still valid,
+    /// otherwise a new PCID is handed out, starting a new generation if all the PCIDs of the
+    /// current one are used.
+    pub fn assign(&mut self, tag: Option<Tag>) -> Assignment {
+        if let Some(tag) = tag.filter(|&tag| self.is_valid(tag)) {
+            return Assignment {
+                tag,
+                reused: true,
+                flush_all: false,
+            };
+        }
+
+        let flush_all = self.next > self.max;
+        if flush_all {
+            self.generation += 1;
+            self.next = 1;
+        }
+
+        let tag = Tag {
+            pcid: self.next,
+            generation: self.generation,
+        };
+        self.next += 1;
+        Assignment {
+            tag,
+            reused: false,
+            flush_all,
+        }
+    }
+}
+
+/// Flush the TLB entries of all the PCIDs on the current CPU, except the global pages. The
+/// `invpcid` instruction is used if it is supported, otherwise all the TLB entries are flushed,
+/// including the global ones (see [`crate::paging::global_pages::flush_global`]).
+///
+/// # Safety
+/// This function is unsafe because PCIDs must be enabled.
+pub unsafe fn flush_all() {
+    if crate::cpu::features().has(Feature::Invpcid) {
+        // All-context invalidation, except the global translations (type 3)
+        let descriptor: [u64; 2] = [0, 0];
+        asm!(
+            "invpcid {}, [{}]",
+            in(reg) 3u64,
+            in(reg) descriptor.as_ptr(),
+            options(nostack, preserves_flags)
+        );
+    } else {
+        crate::paging::global_pages::flush_global();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Allocator;
+    use crate::address::Physical;
+
+    #[test]
+    fn generations() {
+        let mut allocator = Allocator::new(2);
+        let first = allocator.assign(None);
+        assert_eq!(
+            (first.tag.pcid(), first.reused, first.flush_all),
+            (1, false, false)
+        );
+        let second = allocator.assign(None);
+        assert_eq!(second.tag.pcid(), 2);
+
+        let reused = allocator.assign(Some(first.tag));
+        assert_eq!(
+            reused,
+            super::Assignment {
+                reused: true,
+                ..first
+            }
+        );
+
+        // All the PCIDs are used: a new generation starts
+        let third = allocator.assign(None);
+        assert_eq!((third.tag.pcid(), third.flush_all), (1, true));
+        assert_eq!(third.tag.generation(), 2);
+        assert!(!allocator.is_valid(second.tag));
+
+        let renewed = allocator.assign(Some(second.tag));
+        assert_eq!((renewed.tag.pcid(), renewed.reused), (2, false));
+    }
+
+    #[test]
+    fn assignment_cr3() {
+        let mut allocator = Allocator::new(super::MAX_PCID);
+        let first = allocator.assign(None);
+        assert_eq!(first.cr3(Physical::new(0x20_0000)), 0x20_0001);
+        let reused = allocator.assign(Some(first.tag));
+        assert_eq!(reused.cr3(Physical::new(0x20_0000)), 0x8000_0000_0020_0001);
+    }
+}
@@ -0,0 +1,428 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{
+    address::Virtual,
+    cpu::{feature::Feature, msr},
+    segment::GS,
+};
+
+/// Offset in the GS segment of the pointer to the per-CPU area itself.
+pub const SELF_OFFSET: usize = 0;
+
+/// Offset in the GS segment of the identifier of the current CPU.
+pub const CPU_ID_OFFSET: usize = 8;
+
+/// Offset in the GS segment of the kernel stack top used when entering the kernel with the
+/// `syscall` instruction.
+pub const KERNEL_STACK_OFFSET: usize = 16;
+
+/// Offset in the GS segment of a scratch location used to save the user stack pointer when
+/// entering the kernel with the `syscall` instruction.
+pub const USER_STACK_OFFSET: usize = 24;
+
+/// Offset in the GS segment of the interrupt-disable nesting depth of the current CPU (see
+/// [`crate::irq::disable_nested`]).
+pub const IRQ_DEPTH_OFFSET: usize = 32;
+
+/// Offset in the GS segment of the interrupt state saved by the outermost
+/// [`crate::irq::disable_nested`] call.
+pub const IRQ_STATE_OFFSET: usize = 40;
+
+/// Offset in the GS segment of the CR3 bits toggled by the entry and exit code when the page
+/// table isolation is enabled on the current CPU, or 0 if it is disabled (see [`crate::pti`]).
+pub const PTI_CR3_OFFSET: usize = 48;
+
+/// The way [`cpu_id_fast`] reads the identifier of the current CPU, chosen by [`init`].
+static CPU_ID_SOURCE: AtomicU8 = AtomicU8::new(CpuIdSource::Gs as u8);
+
+/// The instruction used by [`cpu_id_fast`] to read the identifier of the current CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuIdSource {
+    /// The identifier is read from the per-CPU area, with a GS-relative load.
+    Gs = 0,
+
+    /// The identifier is read from the TSC auxiliary register with the `rdtscp` instruction,
+    /// which also reads the TSC.
+    Rdtscp = 1,
+
+    /// The identifier is read from the TSC auxiliary register with the `rdpid` instruction.
+    Rdpid = 2,
+}
+
+/// The header of a per-CPU area. The GS base of each CPU points to a per-CPU area starting with
+/// this header, which is used by the entry code of this crate (see [`crate::syscall`]). Kernel
+/// specific per-CPU data can be stored after this header, by embedding it as the first field of a
+/// `#[repr(C)]` structure, and accessed with accessors generated by the [`per_cpu`] macro.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct PerCpu {
+    this: u64,
+    cpu_id: u64,
+    kernel_stack: u64,
+    user_stack: u64,
+    irq_depth: u64,
+    irq_state: u64,
+    pti_cr3: u64,
+}
+
+impl PerCpu {
+    /// Creates a new empty header. The header is filled by [`init`] when the per-CPU area is
+    /// installed on the current CPU.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            this: 0,
+            cpu_id: 0,
+            kernel_stack: 0,
+            user_stack: 0,
+            irq_depth: 0,
+            irq_state: 0,
+            pti_cr3: 0,
+        }
+    }
+}
+
+/// A type that can be read from and written to the GS segment with a single instruction.
+pub trait GsAccess {
+    /// Read a value at the given offset in the GS segment.
+    ///
+    /// # Safety
+    /// This function is unsafe because the GS base must point to a valid per-CPU area, and the
+    /// given offset must be the offset of a value of this type in this area.
+    unsafe fn read(offset: usize) -> Self;
+
+    /// Write a value at the given offset in the GS segment.
+    ///
+    /// # Safety
+    /// This function is unsafe because the GS base must point to a valid per-CPU area, and the
+    /// given offset must be the offset of a value of this type in this area.
+    unsafe fn write(offset: usize, value: Self);
+}
+
+impl GsAccess for u8 {
+    unsafe fn read(offset: usize) -> u8 {
+        let value: u8;
+        asm!("mov {}, gs:[{}]", out(reg_byte) value, in(reg) offset, options(nostack, readonly));
+        value
+    }
+
+    unsafe fn write(offset: usize, value: u8) {
+        asm!("mov gs:[{}], {}", in(reg) offset, in(reg_byte) value, options(nostack));
+    }
+}
+
+impl GsAccess for u16 {
+    unsafe fn read(offset: usize) -> u16 {
+        let value: u16;
+        asm!("mov {:x}, gs:[{}]", out(reg) value, in(reg) offset, options(nostack, readonly));
+        value
+    }
+
+    unsafe fn write(offset: usize, value: u16) {
+        asm!("mov gs:[{}], {:x}", in(reg) offset, in(reg) value, options(nostack));
+    }
+}
+
+impl GsAccess for u32 {
+    unsafe fn read(offset: usize) -> u32 {
+        let value: u32;
+        asm!("mov {:e}, gs:[{}]", out(reg) value, in(reg) offset, options(nostack, readonly));
+        value
+    }
+
+    unsafe fn write(offset: usize, value: u32) {
+        asm!("mov gs:[{}], {:e}", in(reg) offset, in(reg) value, options(nostack));
+    }
+}
+
+impl GsAccess for u64 {
+    unsafe fn read(offset: usize) -> u64 {
+        let value: u64;
+        asm!("mov {}, gs:[{}]", out(reg) value, in(reg) offset, options(nostack, readonly));
+        value
+    }
+
+    unsafe fn write(offset: usize, value: u64) {
+        asm!("mov gs:[{}], {}", in(reg) offset, in(reg) value, options(nostack));
+    }
+}
+
+/// Initialize the per-CPU area of the current CPU. The header at the start of the given area is
+/// filled and the GS base is set to point to it.
+///
+/// If the CPU supports the `rdpid` or the `rdtscp` instruction and the identifier fits in 32
+/// bits, the identifier is also written to the TSC auxiliary register, so that [`cpu_id_fast`]
+/// can read it without accessing the memory. The kernel must not change this register.
+///
+/// # Panics
+/// This function panics if the given area is not aligned on a 8 bytes boundary.
+///
+/// # Safety
+/// This function is unsafe because the given area must be a valid, writable memory region that
+/// lives as long as the current CPU is running, and must not be shared with another CPU. This
+/// function must be called while the kernel GS is active (i.e. not between two `swapgs`).
+pub unsafe fn init(cpu_id: u64, area: Virtual) {
+    assert!(area.is_aligned(8u64), "Per-CPU area is not aligned");
+    area.as_mut_ptr::<PerCpu>().write(PerCpu {
+        this: area.as_u64(),
+        cpu_id,
+        kernel_stack: 0,
+        user_stack: 0,
+        irq_depth: 0,
+        irq_state: 0,
+        pti_cr3: 0,
+    });
+    msr::gs_base::write(area.as_u64());
+
+    let features = crate::cpu::features();
+    let source = match u32::try_from(cpu_id) {
+        Ok(id) if features.has(Feature::Rdpid) || features.has(Feature::Rdtscp) => {
+            msr::tsc_aux::write(id);
+            if features.has(Feature::Rdpid) {
+                CpuIdSource::Rdpid
+            } else {
+                CpuIdSource::Rdtscp
+            }
+        }
+        _ => CpuIdSource::Gs,
+    };
+    CPU_ID_SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// Set the kernel GS base of the current CPU, whatever the current GS state is. If the kernel GS
+/// is active, the base is written to the `GsBase` MSR, otherwise it is written to the
+/// `KernelGsBase` MSR so that it will be loaded by the next `swapgs` instruction.
+///
+/// # Safety
+/// This function is unsafe because the given base must point to a valid per-CPU area located in
+/// the kernel half of the address space (see [`is_kernel_gs_active`]). Since the GS state is
+/// guessed from the current GS base, this function must not be used before [`init`] has been
+/// called on the current CPU.
+pub unsafe fn install_kernel_gs(base: Virtual) {
+    assert!(base.is_kernel(), "Per-CPU area must be in the kernel half");
+    if is_kernel_gs_active() {
+        msr::gs_base::write(base.as_u64());
+    } else {
+        msr::kernel_gs_base::write(base.as_u64());
+    }
+}
+
+/// Returns `true` if the kernel GS seems to be active, i.e. if the `GsBase` MSR contains a kernel
+/// address. This is an heuristic: it assumes that the per-CPU areas are always located in the
+/// kernel half of the address space and that user-controlled GS bases are always located in the
+/// user half. The latter is only guaranteed if the kernel checks the GS bases set by the user.
+///
+/// This is useful for interrupt handlers that can be triggered at any point, including between
+/// the first instruction of an entry stub and its `swapgs` (for example, the NMI or the machine
+/// check handlers), where the code segment of the interrupted state is not enough to know whether
+/// a `swapgs` is needed.
+#[must_use]
+pub fn is_kernel_gs_active() -> bool {
+    Virtual::new_truncate(msr::gs_base::read()).is_kernel()
+}
+
+/// The GS state before a call to [`enter_kernel_gs`], that must be given back to
+/// [`leave_kernel_gs`] to restore it.
+#[must_use]
+#[derive(Debug)]
+pub struct KernelGsState {
+    swapped: bool,
+}
+
+impl KernelGsState {
+    /// Returns `true` if the `swapgs` instruction was executed by [`enter_kernel_gs`].
+    #[must_use]
+    pub const fn swapped(&self) -> bool {
+        self.swapped
+    }
+}
+
+/// Make sure that the kernel GS is active, by executing the `swapgs` instruction only if needed
+/// (see [`is_kernel_gs_active`]). The returned state must be given to [`leave_kernel_gs`] before
+/// returning from the handler. This is intended for NMI and machine check handlers, where the
+/// usual decision based on the interrupted code segment is not reliable.
+///
+/// # Safety
+/// This function is unsafe because it can swap the GS base: the caller must ensure that the
+/// state is correctly restored with [`leave_kernel_gs`].
+pub unsafe fn enter_kernel_gs() -> KernelGsState {
+    let swapped = !is_kernel_gs_active();
+    if swapped {
+        GS::swap();
+    }
+    KernelGsState { swapped }
+}
+
+/// Restore the GS state saved by [`enter_kernel_gs`]: the `swapgs` instruction is executed only
+/// if it was executed by [`enter_kernel_gs`].
+///
+/// # Safety
+/// This function is unsafe because it can swap the GS base: the given state must be the state
+/// returned by the last [`enter_kernel_gs`] call on this CPU.
+#[allow(clippy::needless_pass_by_value)]
+pub unsafe fn leave_kernel_gs(state: KernelGsState) {
+    if state.swapped {
+        GS::swap();
+    }
+}
+
+/// Returns the address of the per-CPU area of the current CPU.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized with [`init`] on
+/// the current CPU, and the kernel GS must be active.
+#[must_use]
+pub unsafe fn area() -> Virtual {
+    Virtual::new(u64::read(SELF_OFFSET))
+}
+
+/// Returns the identifier of the current CPU, as given to [`init`].
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized with [`init`] on
+/// the current CPU, and the kernel GS must be active.
+#[must_use]
+pub unsafe fn current_cpu_id() -> u64 {
+    u64::read(CPU_ID_OFFSET)
+}
+
+/// Returns the identifier of the current CPU, as given to [`init`], with the fastest available
+/// instruction (see [`cpu_id_source`]). With the `rdpid` instruction, the identifier is read
+/// from a register in a single instruction, without accessing the memory and whatever the GS
+/// state is, which makes it suitable for lock-free per-CPU statistics. Otherwise, it falls back
+/// to the `rdtscp` instruction, and then to [`current_cpu_id`].
+///
+/// Like any CPU identifier, the result can be outdated as soon as it is returned if the current
+/// thread can be migrated to another CPU.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized with [`init`] on
+/// the current CPU, and the kernel GS must be active if neither `rdpid` nor `rdtscp` is
+/// supported.
+#[inline]
+#[must_use]
+pub unsafe fn cpu_id_fast() -> u64 {
+    match CPU_ID_SOURCE.load(Ordering::Relaxed) {
+        source if source == CpuIdSource::Rdpid as u8 => {
+            let id: u64;
+            asm!("rdpid {}", out(reg) id, options(nomem, nostack, preserves_flags));
+            id
+        }
+        source if source == CpuIdSource::Rdtscp as u8 => {
+            let mut id = 0;
+            core::arch::x86_64::__rdtscp(core::ptr::addr_of_mut!(id));
+            u64::from(id)
+        }
+        _ => current_cpu_id(),
+    }
+}
+
+/// Returns the instruction used by [`cpu_id_fast`], chosen by [`init`] from the features of the
+/// CPU.
+#[must_use]
+pub fn cpu_id_source() -> CpuIdSource {
+    match CPU_ID_SOURCE.load(Ordering::Relaxed) {
+        source if source == CpuIdSource::Rdpid as u8 => CpuIdSource::Rdpid,
+        source if source == CpuIdSource::Rdtscp as u8 => CpuIdSource::Rdtscp,
+        _ => CpuIdSource::Gs,
+    }
+}
+
+/// Set the kernel stack top used when entering the kernel with the `syscall` instruction on the
+/// current CPU. This should be updated during each context switch, like the RSP0 field of the TSS.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized with [`init`] on
+/// the current CPU, the kernel GS must be active and the given stack must be valid.
+pub unsafe fn set_kernel_stack(top: Virtual) {
+    u64::write(KERNEL_STACK_OFFSET, top.as_u64());
+}
+
+/// Generate an accessor for a per-CPU variable stored at the given offset in the per-CPU area.
+/// The accessor is a type with a `read` and a `write` function, like the segment registers in
+/// the [`crate::segment`] module. The type of the variable must implement [`GsAccess`].
+///
+/// # Example
+/// ```ignore
+/// #[repr(C)]
+/// struct Cpu {
+///     header: PerCpu,
+///     preempt_count: u64,
+/// }
+///
+/// per_cpu!(pub PREEMPT_COUNT: u64 = 56);
+/// let count = unsafe { PREEMPT_COUNT::read() };
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$meta:meta])* $vis:vis $name:ident : $ty:ty = $offset:expr) => {
+        $(#[$meta])*
+        #[allow(non_camel_case_types)]
+        $vis struct $name;
+
+        impl $name {
+            /// Read the value of this variable for the current CPU.
+            ///
+            /// # Safety
+            /// This function is unsafe because the per-CPU area must have been initialized on
+            /// the current CPU, and the kernel GS must be active.
+            #[inline]
+            #[must_use]
+            pub unsafe fn read() -> $ty {
+                <$ty as $crate::percpu::GsAccess>::read($offset)
+            }
+
+            /// Write the value of this variable for the current CPU.
+            ///
+            /// # Safety
+            /// This function is unsafe because the per-CPU area must have been initialized on
+            /// the current CPU, and the kernel GS must be active.
+            #[inline]
+            pub unsafe fn write(value: $ty) {
+                <$ty as $crate::percpu::GsAccess>::write($offset, value);
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use core::mem::size_of;
+
+    #[test]
+    fn struct_size_checks() {
+        assert_eq!(size_of::<super::PerCpu>(), 56);
+    }
+
+    #[test]
+    fn header_offsets() {
+        let header = super::PerCpu::new();
+        let base = core::ptr::addr_of!(header) as usize;
+        assert_eq!(core::ptr::addr_of!(header.this) as usize - base, super::SELF_OFFSET);
+        assert_eq!(core::ptr::addr_of!(header.cpu_id) as usize - base, super::CPU_ID_OFFSET);
+        assert_eq!(
+            core::ptr::addr_of!(header.kernel_stack) as usize - base,
+            super::KERNEL_STACK_OFFSET
+        );
+        assert_eq!(
+            core::ptr::addr_of!(header.user_stack) as usize - base,
+            super::USER_STACK_OFFSET
+        );
+        assert_eq!(
+            core::ptr::addr_of!(header.irq_depth) as usize - base,
+            super::IRQ_DEPTH_OFFSET
+        );
+        assert_eq!(
+            core::ptr::addr_of!(header.irq_state) as usize - base,
+            super::IRQ_STATE_OFFSET
+        );
+        assert_eq!(
+            core::ptr::addr_of!(header.pti_cr3) as usize - base,
+            super::PTI_CR3_OFFSET
+        );
+    }
+}
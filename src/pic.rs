@@ -1,83 +1,414 @@
-use core::sync::atomic::{AtomicU8, Ordering};
-
-use crate::io::Port;
-
-static MASTER_PIC_CMD: Port<u8> = unsafe { Port::new(0x20) };
-static MASTER_PIC_DATA: Port<u8> = unsafe { Port::new(0x21) };
-static SLAVE_PIC_CMD: Port<u8> = unsafe { Port::new(0xA0) };
-static SLAVE_PIC_DATA: Port<u8> = unsafe { Port::new(0xA1) };
-static IRQ_BASE: AtomicU8 = AtomicU8::new(0);
-
-/// Remap the PICs to the given base IRQs. The master PIC will use IRQs [base, base + 7] and the
-/// slave PIC will use IRQs [base + 8, base + 15]. After remapping, all interrupts are unmasked,
-/// but no interrupts will occur until the interrupts are enabled with the `sti` instruction.
-///
-/// # Safety
-/// This function is unsafe because it writes to the PICs with I/O ports, which can cause undefined
-/// behavior if the PICs do not exist or are not in the expected state.
-pub unsafe fn remap(base: u8) {
-    IRQ_BASE.store(base, Ordering::Relaxed);
-
-    // ECW1: Cascade mode, ICW4 needed
-    MASTER_PIC_CMD.write_and_pause(0x11);
-    SLAVE_PIC_CMD.write_and_pause(0x11);
-
-    // ICW2: Write the base IRQs for the PICs
-    MASTER_PIC_DATA.write_and_pause(base);
-    SLAVE_PIC_DATA.write_and_pause(base + 8);
-
-    // ICW3: Connect the PICs to each other
-    MASTER_PIC_DATA.write_and_pause(4); // The slave PIC is connected to IRQ4 on the master PIC
-    SLAVE_PIC_DATA.write_and_pause(2); // The master PIC is connected to IRQ2 on the slave PIC
-
-    // ICW4: Request 8086 mode
-    MASTER_PIC_DATA.write_and_pause(0x01);
-    SLAVE_PIC_DATA.write_and_pause(0x01);
-
-    // OCW1: Enable all interrupts
-    unmask_all();
+use crate::{io::Port, ioapic::TriggerMode};
+
+/// The first IRQ number after the CPU exceptions. [`ChainedPics::disable`] remaps the PICs to
+/// this base if they overlap the exceptions.
+pub const EXCEPTIONS_END: u8 = 32;
+
+/// The result of [`ChainedPics::send_eoi`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eoi {
+    /// The IRQ was a real interrupt, and an EOI was sent to the PICs.
+    Sent,
+
+    /// The IRQ was a spurious interrupt: it must be ignored by the kernel. No EOI was sent to the
+    /// PIC that raised it, but if it was raised by the slave PIC, an EOI was sent to the master
+    /// PIC, since the cascade interrupt was real.
+    Spurious,
+
+    /// The IRQ is not in the range of the PICs, and nothing was done.
+    NotConcerned,
+}
+
+/// The IRQ lines that must always be edge-triggered (the PIT, the keyboard, the cascade, the RTC
+/// and the FPU). Their bits in the ELCR are reserved.
+const EDGE_ONLY: u16 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 8 | 1 << 13;
+
+/// A single 8259 PIC, with its command and data ports, and its edge/level control register
+/// (ELCR).
+struct Pic {
+    command: Port<u8>,
+    data: Port<u8>,
+    elcr: Port<u8>,
 }
 
-/// Check if the given IRQ number is in the range of the PICs. This is useful for checking if an
-/// interrupt handler should send an EOI to the PICs.
-pub fn concerned(irq: u8) -> bool {
-    let base = IRQ_BASE.load(Ordering::Relaxed);
-    irq >= base && irq < base + 16
+/// The two 8259 PICs of the PC architecture, the slave PIC being connected to the IRQ 2 of the
+/// master PIC. The master PIC handles the IRQs [base, base + 7] and the slave PIC the IRQs
+/// [base + 8, base + 15], where base is the IRQ base given to [`ChainedPics::new`] or
+/// [`ChainedPics::remap`]. All IRQ numbers taken by the methods of this type are remapped ones.
+pub struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+    base: u8,
 }
 
-/// Send an end-of-interrupt (EOI) to the PICs. This must be called after an interrupt handler
-/// finishes executing. If the IRQ number is not in the range of the PICs, this function does
-/// nothing.
-///
-/// # Safety
-/// This function is unsafe because it writes to the PICs with I/O ports, which can cause undefined
-/// behavior if the PICs do not exist or are not in the expected state, or if it is used incorrectly.
-pub unsafe fn send_eoi(irq: u8) {
-    if concerned(irq) {
-        if irq - IRQ_BASE.load(Ordering::Relaxed) >= 8 {
-            SLAVE_PIC_CMD.write_and_pause(0x20);
+impl ChainedPics {
+    /// Creates a new handle to the PICs, which will use the given IRQ base. This function does
+    /// not configure the PICs, you must call [`ChainedPics::remap`] to do that.
+    ///
+    /// # Panics
+    /// Panics if the IRQ base is greater than 240, since the 16 IRQs must fit in the IDT.
+    ///
+    /// # Safety
+    /// This function is unsafe because the PICs must exist, and only one handle to the PICs must
+    /// exist at a time, otherwise the handles could configure the PICs concurrently.
+    #[must_use]
+    pub const unsafe fn new(base: u8) -> Self {
+        assert!(base <= 240, "PIC IRQ base is too high");
+        Self {
+            master: Pic {
+                command: Port::new(0x20),
+                data: Port::new(0x21),
+                elcr: Port::new(0x4D0),
+            },
+            slave: Pic {
+                command: Port::new(0xA0),
+                data: Port::new(0xA1),
+                elcr: Port::new(0x4D1),
+            },
+            base,
         }
-        MASTER_PIC_CMD.write_and_pause(0x20);
     }
-}
 
-/// Unmask all interrupts on the PICs. This is the default state after remapping the PICs.
-///
-/// # Safety
-/// This function is unsafe because it writes to the PICs with I/O ports, which can cause undefined
-/// behavior if the PICs do not exist or are not in the expected state.
-pub unsafe fn unmask_all() {
-    MASTER_PIC_DATA.write_and_pause(0x00);
-    SLAVE_PIC_DATA.write_and_pause(0x00);
+    /// Returns the first IRQ number used by the PICs.
+    #[must_use]
+    pub const fn base(&self) -> u8 {
+        self.base
+    }
+
+    /// Remap the PICs to the given base IRQs. The master PIC will use IRQs [base, base + 7] and
+    /// the slave PIC will use IRQs [base + 8, base + 15]. After remapping, all interrupts are
+    /// unmasked, but no interrupts will occur until the interrupts are enabled with the `sti`
+    /// instruction.
+    ///
+    /// # Panics
+    /// Panics if the IRQ base is greater than 240, since the 16 IRQs must fit in the IDT.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    pub unsafe fn remap(&mut self, base: u8) {
+        self.initialize(base);
+
+        // OCW1: Enable all interrupts
+        self.unmask_all();
+    }
+
+    /// Disable the PICs, before switching to the local APIC and the I/O APIC. All interrupts are
+    /// masked, and if the PICs use IRQs overlapping the CPU exceptions (this is the case after
+    /// the boot, with the base 8), they are first remapped to [`EXCEPTIONS_END`], so that a
+    /// spurious interrupt raised by the PICs despite the masks cannot be confused with an
+    /// exception.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state. The spurious IRQs of the
+    /// PICs (IRQ 7 and 15) can still be raised and should be handled by the kernel.
+    pub unsafe fn disable(&mut self) {
+        if self.base < EXCEPTIONS_END {
+            self.initialize(EXCEPTIONS_END);
+        }
+        self.mask_all();
+    }
+
+    /// Check if the given IRQ number is in the range of the PICs. This is useful for checking if
+    /// an interrupt handler should send an EOI to the PICs.
+    #[must_use]
+    pub fn concerned(&self, irq: u8) -> bool {
+        irq >= self.base && irq - self.base < 16
+    }
+
+    /// Send an end-of-interrupt (EOI) to the PICs. This must be called after an interrupt
+    /// handler finishes executing. If the IRQ number is not in the range of the PICs, this
+    /// function does nothing.
+    ///
+    /// The PICs raise a spurious IRQ 7 (or 15 for the slave PIC) when an interrupt request
+    /// disappears before it is acknowledged by the CPU. Such an IRQ is detected because its bit
+    /// is not set in the in-service register, and must not be acknowledged, otherwise the EOI
+    /// would acknowledge another interrupt currently being serviced.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state, or if it is used
+    /// incorrectly.
+    #[must_use]
+    pub unsafe fn send_eoi(&self, irq: u8) -> Eoi {
+        if !self.concerned(irq) {
+            return Eoi::NotConcerned;
+        }
+
+        let line = irq - self.base;
+        if (line == 7 || line == 15) && self.read_isr() & (1 << line) == 0 {
+            if line == 15 {
+                self.master.command.write_and_pause(0x20);
+            }
+            return Eoi::Spurious;
+        }
+
+        if line >= 8 {
+            self.slave.command.write_and_pause(0x20);
+        }
+        self.master.command.write_and_pause(0x20);
+        Eoi::Sent
+    }
+
+    /// Read the interrupt request registers (IRR) of the PICs, which contain the IRQs raised but
+    /// not yet acknowledged by the CPU. The low byte is the register of the master PIC, and the
+    /// high byte the register of the slave PIC.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    #[must_use]
+    pub unsafe fn read_irr(&self) -> u16 {
+        self.read_register(0x0A)
+    }
+
+    /// Read the in-service registers (ISR) of the PICs, which contain the IRQs acknowledged by
+    /// the CPU but not yet ended by an EOI. The low byte is the register of the master PIC, and
+    /// the high byte the register of the slave PIC.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    #[must_use]
+    pub unsafe fn read_isr(&self) -> u16 {
+        self.read_register(0x0B)
+    }
+
+    /// Unmask all interrupts on the PICs. This is the default state after remapping the PICs.
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    pub unsafe fn unmask_all(&self) {
+        self.master.data.write_and_pause(0x00);
+        self.slave.data.write_and_pause(0x00);
+    }
+
+    /// Mask all interrupts on the PICs. An interrupt masked by the PICs will never occur and will
+    /// not be sent to the CPU (lost forever).
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    pub unsafe fn mask_all(&self) {
+        self.master.data.write_and_pause(0xFF);
+        self.slave.data.write_and_pause(0xFF);
+    }
+
+    /// Mask the given IRQ on the PIC handling it. The other IRQs of the PIC keep their current
+    /// mask state.
+    ///
+    /// # Panics
+    /// This function panics if the given IRQ is not in the range of the PICs (see
+    /// [`ChainedPics::concerned`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    pub unsafe fn mask(&self, irq: u8) {
+        let (pic, bit) = self.line(irq);
+        pic.data.update_and_pause(|mask| mask | (1 << bit));
+    }
+
+    /// Unmask the given IRQ on the PIC handling it. If the IRQ is handled by the slave PIC, the
+    /// cascade line (IRQ 2) of the master PIC must also be unmasked for the IRQ to be delivered.
+    ///
+    /// # Panics
+    /// This function panics if the given IRQ is not in the range of the PICs (see
+    /// [`ChainedPics::concerned`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because it writes to the PICs with I/O ports, which can cause
+    /// undefined behavior if the PICs are not in the expected state.
+    pub unsafe fn unmask(&self, irq: u8) {
+        let (pic, bit) = self.line(irq);
+        pic.data.update_and_pause(|mask| mask & !(1 << bit));
+    }
+
+    /// Read the edge/level control registers (ELCR) of the chipset, where a set bit means that
+    /// the corresponding IRQ line is level-triggered. The low byte is the register of the master
+    /// PIC, and the high byte the register of the slave PIC. The bit of an IRQ is its index in
+    /// the PICs, not its remapped number.
+    ///
+    /// # Safety
+    /// This function is unsafe because the ELCR is not part of the 8259 PICs: the chipset must
+    /// provide it (this is the case of all PCI chipsets).
+    #[must_use]
+    pub unsafe fn read_elcr(&self) -> u16 {
+        u16::from(self.slave.elcr.read()) << 8 | u16::from(self.master.elcr.read())
+    }
+
+    /// Returns the trigger mode of the given IRQ, as configured in the ELCR.
+    ///
+    /// # Panics
+    /// This function panics if the given IRQ is not in the range of the PICs (see
+    /// [`ChainedPics::concerned`]).
+    ///
+    /// # Safety
+    /// See [`ChainedPics::read_elcr`].
+    #[must_use]
+    pub unsafe fn trigger(&self, irq: u8) -> TriggerMode {
+        let (pic, bit) = self.line(irq);
+        if pic.elcr.read() & (1 << bit) != 0 {
+            TriggerMode::Level
+        } else {
+            TriggerMode::Edge
+        }
+    }
+
+    /// Set the trigger mode of the given IRQ in the ELCR. This is needed when level-triggered
+    /// legacy PCI interrupts are routed through the PICs. The IRQs 0, 1, 2, 8 and 13 are always
+    /// edge-triggered.
+    ///
+    /// # Panics
+    /// This function panics if the given IRQ is not in the range of the PICs (see
+    /// [`ChainedPics::concerned`]), or if it is one of the IRQs 0, 1, 2, 8 or 13 and the level
+    /// trigger mode is requested.
+    ///
+    /// # Safety
+    /// This function is unsafe because the ELCR is not part of the 8259 PICs: the chipset must
+    /// provide it. Furthermore, the trigger mode must match the device connected to the line,
+    /// otherwise interrupts may be lost or raised continuously.
+    pub unsafe fn set_trigger(&self, irq: u8, trigger: TriggerMode) {
+        let (pic, bit) = self.line(irq);
+        match trigger {
+            TriggerMode::Edge => pic.elcr.update_and_pause(|elcr| elcr & !(1 << bit)),
+            TriggerMode::Level => {
+                assert!(
+                    EDGE_ONLY & (1 << (irq - self.base)) == 0,
+                    "IRQ {} cannot be level-triggered",
+                    irq - self.base
+                );
+                pic.elcr.update_and_pause(|elcr| elcr | (1 << bit));
+            }
+        }
+    }
+
+    /// Send the initialization sequence to the PICs, with the given IRQ base. The masks are
+    /// cleared by the initialization.
+    unsafe fn initialize(&mut self, base: u8) {
+        assert!(base <= 240, "PIC IRQ base is too high");
+        self.base = base;
+
+        // ECW1: Cascade mode, ICW4 needed
+        self.master.command.write_and_pause(0x11);
+        self.slave.command.write_and_pause(0x11);
+
+        // ICW2: Write the base IRQs for the PICs
+        self.master.data.write_and_pause(base);
+        self.slave.data.write_and_pause(base + 8);
+
+        // ICW3: Connect the PICs to each other
+        self.master.data.write_and_pause(4); // The slave PIC is connected to IRQ2 on the master PIC
+        self.slave.data.write_and_pause(2); // The slave PIC has the cascade identity 2
+
+        // ICW4: Request 8086 mode
+        self.master.data.write_and_pause(0x01);
+        self.slave.data.write_and_pause(0x01);
+    }
+
+    /// Select a register of both PICs with the given OCW3 command, and read it from the command
+    /// ports.
+    unsafe fn read_register(&self, ocw3: u8) -> u16 {
+        self.master.command.write_and_pause(ocw3);
+        self.slave.command.write_and_pause(ocw3);
+        u16::from(self.slave.command.read()) << 8 | u16::from(self.master.command.read())
+    }
+
+    /// Returns the PIC handling the given IRQ, and the bit of the IRQ in the interrupt mask
+    /// register of this PIC.
+    fn line(&self, irq: u8) -> (&Pic, u8) {
+        assert!(self.concerned(irq), "IRQ {irq} is not handled by the PICs");
+        match irq - self.base {
+            line @ 0..=7 => (&self.master, line),
+            line => (&self.slave, line - 8),
+        }
+    }
 }
 
-/// Mask all interrupts on the PICs. An interrupt masked by the PICs will never occur and will not
-/// be sent to the CPU (lost forever).
-///
-/// # Safety
-/// This function is unsafe because it writes to the PICs with I/O ports, which can cause undefined
-/// behavior if the PICs do not exist or are not in the expected state.
-pub unsafe fn mask_all() {
-    MASTER_PIC_DATA.write_and_pause(0xFF);
-    SLAVE_PIC_DATA.write_and_pause(0xFF);
+#[cfg(test)]
+mod test {
+    use super::{ChainedPics, Eoi};
+    use crate::{ioapic::TriggerMode, mock};
+
+    #[test]
+    fn mask_unmask() {
+        mock::reset();
+        let pics = unsafe { ChainedPics::new(32) };
+
+        mock::push_read(0x21, 0x01);
+        unsafe { pics.mask(32 + 3) };
+        assert_eq!(mock::port_writes(0x21), [0x09]);
+
+        mock::push_read(0xA1, 0xFF);
+        unsafe { pics.unmask(32 + 12) };
+        assert_eq!(mock::port_writes(0xA1), [0xEF]);
+    }
+
+    #[test]
+    fn spurious_eoi() {
+        mock::reset();
+        let pics = unsafe { ChainedPics::new(32) };
+
+        // Spurious IRQ 7: no EOI at all, only the ISR selection
+        mock::push_read(0x20, 0x00);
+        mock::push_read(0xA0, 0x00);
+        assert_eq!(unsafe { pics.send_eoi(32 + 7) }, Eoi::Spurious);
+        assert_eq!(mock::port_writes(0x20), [0x0B]);
+        assert_eq!(mock::port_writes(0xA0), [0x0B]);
+
+        // Real IRQ 7
+        mock::reset();
+        mock::push_read(0x20, 0x80);
+        mock::push_read(0xA0, 0x00);
+        assert_eq!(unsafe { pics.send_eoi(32 + 7) }, Eoi::Sent);
+        assert_eq!(mock::port_writes(0x20), [0x0B, 0x20]);
+        assert_eq!(mock::port_writes(0xA0), [0x0B]);
+
+        // Spurious IRQ 15: the cascade interrupt of the master PIC is real
+        mock::reset();
+        mock::push_read(0x20, 0x04);
+        mock::push_read(0xA0, 0x00);
+        assert_eq!(unsafe { pics.send_eoi(32 + 15) }, Eoi::Spurious);
+        assert_eq!(mock::port_writes(0x20), [0x0B, 0x20]);
+        assert_eq!(mock::port_writes(0xA0), [0x0B]);
+    }
+
+    #[test]
+    fn elcr() {
+        mock::reset();
+        let pics = unsafe { ChainedPics::new(32) };
+
+        mock::push_read(0x4D1, 0x08);
+        mock::push_read(0x4D1, 0x08);
+        assert_eq!(unsafe { pics.trigger(32 + 11) }, TriggerMode::Level);
+        assert_eq!(unsafe { pics.trigger(32 + 10) }, TriggerMode::Edge);
+
+        mock::push_read(0x4D0, 0x20);
+        unsafe { pics.set_trigger(32 + 3, TriggerMode::Level) };
+        assert_eq!(mock::port_writes(0x4D0), [0x28]);
+
+        mock::push_read(0x4D1, 0x0E);
+        unsafe { pics.set_trigger(32 + 9, TriggerMode::Edge) };
+        assert_eq!(mock::port_writes(0x4D1), [0x0C]);
+
+        // The edge-only lines can still be set to edge-triggered
+        mock::push_read(0x4D0, 0x00);
+        unsafe { pics.set_trigger(32, TriggerMode::Edge) };
+        assert_eq!(mock::port_writes(0x4D0), [0x28, 0x00]);
+    }
+
+    #[test]
+    fn elcr_edge_only() {
+        for irq in [0, 1, 2, 8, 13] {
+            mock::reset();
+            let level = std::panic::catch_unwind(|| unsafe {
+                ChainedPics::new(32).set_trigger(32 + irq, TriggerMode::Level);
+            });
+            assert!(level.is_err(), "IRQ {irq} set to level-triggered");
+            assert!(mock::port_writes(0x4D0).is_empty());
+            assert!(mock::port_writes(0x4D1).is_empty());
+        }
+    }
 }
@@ -4,12 +4,59 @@ static CHANNEL_0: Port<u8> = unsafe { Port::new(0x40) };
 static CHANNEL_1: Port<u8> = unsafe { Port::new(0x41) };
 static CHANNEL_2: Port<u8> = unsafe { Port::new(0x42) };
 static COMMAND: Port<u8> = unsafe { Port::new(0x43) };
+static CHANNEL_2_GATE: Port<u8> = unsafe { Port::new(0x61) };
 
 pub const PIT_TICK_NS: u64 = 1_000_000_000 / 1_193_180;
 pub const PIT_FREQ: u64 = 1_193_180;
 pub const MAX_FREQ: u64 = PIT_FREQ / 2;
 pub const MIN_FREQ: u64 = 1;
 
+/// The maximal count of a PIT channel. A count of 0 is interpreted as 65536 by the PIT.
+pub const MAX_COUNT: u64 = 0x10000;
+
+/// The channels of the PIT. The channel 0 is connected to the IRQ 0, the channel 1 was used for
+/// DRAM refresh and may not exist, and the channel 2 is connected to the PC speaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Channel0 = 0,
+    Channel1 = 1,
+    Channel2 = 2,
+}
+
+impl Channel {
+    /// Returns the data port of the channel.
+    fn port(self) -> &'static Port<u8> {
+        match self {
+            Channel::Channel0 => &CHANNEL_0,
+            Channel::Channel1 => &CHANNEL_1,
+            Channel::Channel2 => &CHANNEL_2,
+        }
+    }
+}
+
+/// The operating modes of a PIT channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Mode 0: the output goes high when the counter reaches 0, and stays high until a new count
+    /// is written. An IRQ is fired once, when the counter reaches 0.
+    InterruptOnTerminalCount = 0,
+
+    /// Mode 1: hardware re-triggerable one-shot. The count starts on a rising edge of the gate,
+    /// which is always high for the channel 0, so this mode only makes sense for the channel 2.
+    HardwareOneShot = 1,
+
+    /// Mode 2: the output goes low for one tick each time the counter reaches 1, then the counter
+    /// is reloaded.
+    RateGenerator = 2,
+
+    /// Mode 3: the output is a square wave, and the counter is reloaded when it reaches 0.
+    SquareWave = 3,
+
+    /// Mode 4: the output goes low for one tick when the counter reaches 0, and the counter is
+    /// not reloaded. An IRQ is fired once, at the end of the pulse.
+    SoftwareStrobe = 4,
+}
+
 /// Represents a Programmable Interval Timer (PIT).
 pub struct Pit {
     frequency: u64,
@@ -38,32 +85,71 @@ impl Pit {
     /// IRQ will be fired every time the counter reaches 0 on IRQ 0: You must set and handle the IRQ
     /// yourself.
     pub fn setup(&self) {
-        let low = (self.latch & 0xFF) as u8;
-        let high = ((self.latch >> 8) & 0xFF) as u8;
-
-        // Set channel 0 to mode 3 (square wave generator), binary format
-        // and set the frequency divisor
-        COMMAND.write(0x36);
-        CHANNEL_0.write(low);
-        CHANNEL_0.write(high);
+        self.setup_mode(Mode::SquareWave);
+    }
+
+    /// Configures the channel 0 of the PIT in the given mode, with the latch of the PIT as the
+    /// count. With the [`Mode::InterruptOnTerminalCount`] and [`Mode::SoftwareStrobe`] modes, a
+    /// single IRQ 0 is fired after one period, and the channel must be reprogrammed to fire again.
+    pub fn setup_mode(&self, mode: Mode) {
+        program(mode, self.latch);
+    }
+
+    /// Configures the channel 0 of the PIT to fire a single IRQ 0 after the given number of
+    /// nanoseconds, in mode 0 (interrupt on terminal count). The delay is rounded to the nearest
+    /// PIT tick, and is at least one tick. This does not change the frequency of the PIT, so
+    /// [`Pit::setup`] must be called again to go back to periodic interrupts. Returns the actual
+    /// delay programmed, in nanoseconds.
+    ///
+    /// # Panics
+    /// Panics if the delay is greater than the maximal count of the PIT (about 54.9 ms).
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn one_shot_ns(&self, ns: u64) -> u64 {
+        let count = (u128::from(ns) * u128::from(PIT_FREQ) + 500_000_000) / 1_000_000_000;
+        assert!(count <= u128::from(MAX_COUNT), "PIT one-shot delay is too long");
+        let count = (count as u64).max(1);
+
+        program(Mode::InterruptOnTerminalCount, count);
+        (u128::from(count) * 1_000_000_000 / u128::from(PIT_FREQ)) as u64
     }
 
     /// Returns the elapsed time since the last IRQ in nanoseconds. In order to do that, it reads the
     /// current value of the counter and calculates the elapsed time since the last IRQ. Since this
     /// function read through the PIT and I/O ports, it is not very fast, and should not be called
     /// often.
+    ///
+    /// If the counter has not yet been reloaded since the PIT was programmed, the offset is 0.
     pub fn nano_offset(&self) -> u64 {
-        // Read the current value of the counter (channel 0)
-        COMMAND.write(0);
-        let low = CHANNEL_0.read() as u64;
-        let high = CHANNEL_0.read() as u64;
-        let counter = (high << 8) | low;
-
-        // Calculate the elapsed time since the last IRQ
-        let elapsed = self.latch - counter;
+        let Some(counter) = read_counter(Channel::Channel0) else {
+            return 0;
+        };
+
+        // Calculate the elapsed time since the last IRQ. The counter should never be greater than
+        // the latch, but saturate in case the PIT was reprogrammed behind our back
+        let elapsed = self.latch.saturating_sub(u64::from(counter));
         elapsed * PIT_TICK_NS
     }
 
+    /// Stops the channel 0 of the PIT: the counter is stopped until a new count is written, so no
+    /// IRQ 0 is fired until the PIT is reprogrammed.
+    pub fn stop(&self) {
+        // Writing the control word of mode 0 stops the counter until the count is written
+        COMMAND.write(0x30);
+    }
+
+    /// Busy-wait for one period of the PIT (1 / frequency seconds), using the channel 2 in one-shot
+    /// mode. The channel 0 is not used, so this can be used to calibrate other timers while the
+    /// PIT is generating interrupts. The PC speaker is disabled while waiting.
+    ///
+    /// # Panics
+    /// Panics if the latch does not fit in the 16 bits counter of the PIT (i.e. if the frequency
+    /// is lower than 19 Hz).
+    pub fn wait_period(&self) {
+        assert!(self.latch <= 0xFFFF, "PIT period is too long for the channel 2 counter");
+        wait_channel_2(self.latch);
+    }
+
     /// Returns the latch value of the PIT, which is the value that the counter is reset to when it
     /// reaches 0.
     pub fn get_latch(&self) -> u64 {
@@ -75,3 +161,135 @@ impl Pit {
         self.frequency
     }
 }
+
+/// Program the channel 0 of the PIT in the given mode and binary format, with the given count.
+/// A count of [`MAX_COUNT`] is written as 0, as expected by the PIT.
+fn program(mode: Mode, count: u64) {
+    let low = (count & 0xFF) as u8;
+    let high = ((count >> 8) & 0xFF) as u8;
+
+    // Select channel 0, access mode lobyte/hibyte, binary format
+    COMMAND.write(0x30 | (mode as u8) << 1);
+    CHANNEL_0.write(low);
+    CHANNEL_0.write(high);
+}
+
+/// Busy-wait for at least the given number of microseconds, without using interrupts. The
+/// channel 0 is programmed in mode 0 (interrupt on terminal count), and its output is polled
+/// with the read-back command. This is intended for early boot delays, before the timer
+/// interrupts are set up: the channel 0 must be reprogrammed after, and an IRQ 0 is fired at the
+/// end of each chunk of 65535 ticks (about 54.9 ms) if it is not masked.
+pub fn delay_us(us: u64) {
+    let mut ticks = us_to_ticks(us);
+    while ticks > 0 {
+        let count = ticks.min(0xFFFF);
+        program(Mode::InterruptOnTerminalCount, count);
+
+        // Wait until the new count is loaded into the counter, and the output goes high
+        loop {
+            let status = read_back_status_0();
+            if status & 0x40 == 0 && status & 0x80 != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        ticks -= count;
+    }
+}
+
+/// Busy-wait for at least the given number of microseconds, without using interrupts, using the
+/// channel 2 in one-shot mode. Unlike [`delay_us`], the channel 0 is not used, so this can be
+/// used while the PIT is generating timer interrupts. The PC speaker is disabled while waiting.
+pub fn delay_us_gated(us: u64) {
+    let mut ticks = us_to_ticks(us);
+    while ticks > 0 {
+        let count = ticks.min(0xFFFF);
+        wait_channel_2(count);
+        ticks -= count;
+    }
+}
+
+/// Convert the given number of microseconds into PIT ticks, rounded up.
+#[allow(clippy::cast_possible_truncation)]
+fn us_to_ticks(us: u64) -> u64 {
+    (u128::from(us) * u128::from(PIT_FREQ)).div_ceil(1_000_000) as u64
+}
+
+/// Read the current count of the given channel with the read-back command, which latches the
+/// status and the count of the channel at the same time, so the count cannot change between the
+/// reads of its two bytes. Returns `None` if the count written to the channel has not yet been
+/// loaded into the counter (null count), in which case the count read is meaningless.
+///
+/// A count of 0 means 65536 in modes 0, 1 and 4, and is never observed in the periodic modes.
+#[must_use]
+pub fn read_counter(channel: Channel) -> Option<u16> {
+    // Read-back command: latch the status and the count of the channel
+    COMMAND.write(0xC0 | 1 << (channel as u8 + 1));
+    let status = channel.port().read();
+    let low = channel.port().read();
+    let high = channel.port().read();
+
+    if status & 0x40 != 0 {
+        None
+    } else {
+        Some(u16::from(high) << 8 | u16::from(low))
+    }
+}
+
+/// Read the current count of the given channel with the counter latch command. This does not
+/// detect a null count (see [`read_counter`]), but is supported by the old 8253 PIT, which does
+/// not implement the read-back command.
+#[must_use]
+pub fn latch_counter(channel: Channel) -> u16 {
+    // Counter latch command: the access mode bits are 0, the channel is in the bits 6-7
+    COMMAND.write((channel as u8) << 6);
+    let low = channel.port().read();
+    let high = channel.port().read();
+    u16::from(high) << 8 | u16::from(low)
+}
+
+/// Read the status byte of the channel 0 with the read-back command. The bit 7 is the state of
+/// the output, and the bit 6 is set while the count written has not yet been loaded into the
+/// counter (null count).
+fn read_back_status_0() -> u8 {
+    // Read-back command: latch the status (not the count) of the channel 0
+    COMMAND.write(0xE2);
+    CHANNEL_0.read()
+}
+
+/// Busy-wait until the channel 2 counts the given number of ticks, in mode 0. The count must be
+/// between 1 and 65535.
+fn wait_channel_2(count: u64) {
+    let low = (count & 0xFF) as u8;
+    let high = ((count >> 8) & 0xFF) as u8;
+
+    // Enable the gate of the channel 2 and disable the speaker output
+    let control = CHANNEL_2_GATE.read();
+    CHANNEL_2_GATE.write((control & !0x02) | 0x01);
+
+    // Set channel 2 to mode 0 (interrupt on terminal count), binary format. The count starts
+    // as soon as the divisor is written
+    COMMAND.write(0xB0);
+    CHANNEL_2.write(low);
+    CHANNEL_2.write(high);
+
+    // Wait until the output of the channel 2 goes high
+    while CHANNEL_2_GATE.read() & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+    CHANNEL_2_GATE.write(control);
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pit;
+    use crate::mock;
+
+    #[test]
+    fn channel_0_programming() {
+        mock::reset();
+        Pit::new(1000).setup();
+        assert_eq!(mock::port_writes(0x43), [0x36]);
+        assert_eq!(mock::port_writes(0x40), [0xA9, 0x04]);
+    }
+}
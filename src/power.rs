@@ -0,0 +1,115 @@
+//! System reset and power-off. The reset methods are tried from the cleanest to the most brutal,
+//! since none of them works on every computer:
+//! - the reset line pulsed by the PS/2 controller, which exists on most PC and is emulated by
+//!   most chipsets without PS/2 ports.
+//! - the reset control register of the chipset (port [`RESET_CONTROL_PORT`]).
+//! - a triple fault, by raising an exception with an empty IDT, which always resets the CPU.
+//!
+//! The ACPI reset register and the ACPI sleep state S5 (soft-off), the proper ways to reset and
+//! power off a computer, require parsing the ACPI tables and are left to the kernel. Only the
+//! power-off of the common virtual machines is supported here, with their fixed ACPI ports.
+use crate::{
+    cpu, idt,
+    io::{inb, outb, outw},
+    ps2,
+};
+
+/// The reset control register of the chipset.
+pub const RESET_CONTROL_PORT: u16 = 0xCF9;
+
+/// The command of the PS/2 controller pulsing the reset line of the CPU.
+const PS2_PULSE_RESET: u8 = 0xFE;
+
+/// The bits of the reset control register: a reset is triggered when the bit [`RESET_CPU`]
+/// switches from 0 to 1, and is a full reset (with power cycle) if [`FULL_RESET`] is set.
+const SYSTEM_RESET: u8 = 1 << 1;
+const RESET_CPU: u8 = 1 << 2;
+const FULL_RESET: u8 = 1 << 3;
+
+/// The number of reads of the status of the PS/2 controller before giving up on it.
+const PS2_TIMEOUT_SPINS: usize = 100_000;
+
+/// The number of status reads made to wait for a reset method to take effect, before trying the
+/// next one.
+const RESET_DELAY_SPINS: usize = 1_000_000;
+
+/// The `PM1a` control ports of the common virtual machines, and the values entering the sleep state
+/// S5 (soft-off) with them.
+const VM_SHUTDOWN_PORTS: [(u16, u16); 3] = [
+    // QEMU, with the PIIX4 or ICH9 chipset
+    (0x604, 0x2000),
+    // Bochs and old versions of QEMU
+    (0xB004, 0x2000),
+    // VirtualBox
+    (0x4004, 0x3400),
+];
+
+/// Reset the computer. The interrupts are disabled, and the reset methods are tried one after the
+/// other (see the module documentation). This function never returns: the last method, a triple
+/// fault, resets the CPU even if it is the only one to be reset.
+///
+/// The computer is reset without stopping the other CPUs or the devices, and without flushing any
+/// data: the kernel should do it before, if it still can.
+pub fn reboot() -> ! {
+    cpu::cli();
+    // SAFETY: The computer is reset, so the side effects of the reset methods do not matter
+    unsafe {
+        ps2_reset();
+        wait();
+        chipset_reset();
+        wait();
+        triple_fault();
+    }
+}
+
+/// Power off the computer if it is one of the common virtual machines (QEMU, Bochs and
+/// `VirtualBox`), by entering the ACPI sleep state S5 through their fixed ACPI ports. If this does
+/// not work (on real hardware, for example), the current CPU is frozen.
+///
+/// Like [`reboot`], the computer is stopped without stopping the devices and without flushing any
+/// data.
+pub fn qemu_shutdown() -> ! {
+    cpu::cli();
+    for (port, value) in VM_SHUTDOWN_PORTS {
+        // SAFETY: These ports are not used on the virtual machines, except by the PM1a control
+        // register, and the current CPU is frozen anyway if the computer is not powered off
+        unsafe {
+            outw(port, value);
+        }
+    }
+    cpu::freeze();
+}
+
+/// Pulse the reset line of the CPU with the PS/2 controller, if it accepts commands.
+unsafe fn ps2_reset() {
+    for _ in 0..PS2_TIMEOUT_SPINS {
+        let status = ps2::Status::from_bits_truncate(inb(ps2::COMMAND_PORT));
+        if !status.contains(ps2::Status::INPUT_FULL) {
+            outb(ps2::COMMAND_PORT, PS2_PULSE_RESET);
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset the computer with the reset control register of the chipset.
+unsafe fn chipset_reset() {
+    outb(RESET_CONTROL_PORT, SYSTEM_RESET | FULL_RESET);
+    outb(RESET_CONTROL_PORT, SYSTEM_RESET | RESET_CPU | FULL_RESET);
+}
+
+/// Reset the CPU with a triple fault: without IDT, the breakpoint exception cannot be delivered,
+/// which raises a double fault, which cannot be delivered either.
+unsafe fn triple_fault() -> ! {
+    idt::Register::null().load();
+    loop {
+        core::arch::asm!("int3");
+    }
+}
+
+/// Wait a little for the previous reset method to take effect.
+fn wait() {
+    for _ in 0..RESET_DELAY_SPINS {
+        core::hint::spin_loop();
+    }
+}
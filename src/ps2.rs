@@ -0,0 +1,1382 @@
+//! Driver for the legacy 8042 PS/2 controller, which connects the keyboard (first port, IRQ 1)
+//! and the mouse (second port, IRQ 12) on PC. Most computers without real PS/2 ports still
+//! emulate the controller for USB keyboards, which makes it useful as an early input device.
+use bitflags::bitflags;
+
+use crate::io::{inb, outb};
+
+/// The data port, used to read the bytes sent by the devices and the responses of the
+/// controller, and to send bytes to the devices.
+pub const DATA_PORT: u16 = 0x60;
+
+/// The status register (when read) and the command register (when written).
+pub const COMMAND_PORT: u16 = 0x64;
+
+/// The number of status reads before a read or write of the data port times out.
+const TIMEOUT_SPINS: usize = 100_000;
+
+/// Commands of the controller.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_PORT2: u8 = 0xA7;
+const ENABLE_PORT2: u8 = 0xA8;
+const TEST_PORT2: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_PORT1: u8 = 0xAB;
+const DISABLE_PORT1: u8 = 0xAD;
+const ENABLE_PORT1: u8 = 0xAE;
+const WRITE_PORT2: u8 = 0xD4;
+
+/// The response of the controller to a successful self-test.
+const SELF_TEST_PASSED: u8 = 0x55;
+
+/// The responses of the devices to a command.
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+
+/// The number of times a byte is sent to a device that asks for it to be resent.
+const MAX_RESENDS: usize = 3;
+
+bitflags! {
+    /// The flags of the status register.
+    pub struct Status: u8 {
+        /// The output buffer contains a byte, which can be read from the data port.
+        const OUTPUT_FULL = 1 << 0;
+
+        /// The input buffer is full: the controller has not processed the last byte written.
+        const INPUT_FULL = 1 << 1;
+
+        /// Set by the firmware once the system has passed its power-on self-test.
+        const SYSTEM = 1 << 2;
+
+        /// The last byte written was a command (to the command port) instead of data.
+        const COMMAND = 1 << 3;
+
+        /// The byte in the output buffer comes from the second port (the mouse).
+        const AUX_OUTPUT = 1 << 5;
+
+        /// A timeout error occurred with a device.
+        const TIMEOUT = 1 << 6;
+
+        /// A parity error occurred with a device.
+        const PARITY = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// The flags of the configuration byte of the controller.
+    pub struct Config: u8 {
+        /// Enable the interrupt of the first port (IRQ 1).
+        const PORT1_INTERRUPT = 1 << 0;
+
+        /// Enable the interrupt of the second port (IRQ 12).
+        const PORT2_INTERRUPT = 1 << 1;
+
+        /// Set by the firmware once the system has passed its power-on self-test.
+        const SYSTEM = 1 << 2;
+
+        /// The clock of the first port is disabled.
+        const PORT1_CLOCK_DISABLED = 1 << 4;
+
+        /// The clock of the second port is disabled.
+        const PORT2_CLOCK_DISABLED = 1 << 5;
+
+        /// Translate the scancodes of the first port to the scancode set 1.
+        const TRANSLATION = 1 << 6;
+    }
+}
+
+/// An error of the PS/2 controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The controller did not accept or provide a byte in time.
+    Timeout,
+
+    /// The self-test of the controller failed, with the given response.
+    SelfTestFailed(u8),
+
+    /// The interface test of a port failed, with the given response (1 to 4 for clock and data
+    /// lines stuck low or high).
+    PortTestFailed(u8),
+
+    /// A device answered a command with the given unexpected byte.
+    UnexpectedResponse(u8),
+}
+
+/// The ports of the controller found working by [`Controller::initialize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub port1: bool,
+    pub port2: bool,
+}
+
+/// The 8042 PS/2 controller.
+#[derive(Debug)]
+pub struct Controller {
+    _private: (),
+}
+
+impl Controller {
+    /// Creates a new handle to the PS/2 controller.
+    ///
+    /// # Safety
+    /// This function is unsafe because the controller must exist (its presence is indicated by
+    /// the ACPI FADT on recent computers), and only one handle must exist at a time.
+    #[must_use]
+    pub const unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Initialize the controller and its ports: the ports are disabled, the output buffer is
+    /// flushed, the controller and the ports are tested, and the working ports are enabled with
+    /// their interrupts. The scancode translation is disabled, so the keyboard sends the
+    /// scancodes of its current set (usually the set 2).
+    ///
+    /// # Errors
+    /// Returns an error if the controller does not respond or if its self-test fails. A failing
+    /// port is not an error: it is reported as not working in the returned [`Ports`].
+    pub fn initialize(&self) -> Result<Ports, Error> {
+        self.disable_port1()?;
+        self.disable_port2()?;
+        self.flush();
+
+        // Disable the interrupts and the translation during the initialization
+        let mut config = self.config()?;
+        config.remove(Config::PORT1_INTERRUPT | Config::PORT2_INTERRUPT | Config::TRANSLATION);
+        self.set_config(config)?;
+
+        // The self-test may reset the controller, so the configuration is written again
+        self.self_test()?;
+        self.set_config(config)?;
+
+        // The clock of the second port is enabled by the enable command only if the port exists
+        self.enable_port2()?;
+        let dual = !self.config()?.contains(Config::PORT2_CLOCK_DISABLED);
+        self.disable_port2()?;
+
+        let ports = Ports {
+            port1: self.test_port1().is_ok(),
+            port2: dual && self.test_port2().is_ok(),
+        };
+
+        if ports.port1 {
+            self.enable_port1()?;
+            config.insert(Config::PORT1_INTERRUPT);
+            config.remove(Config::PORT1_CLOCK_DISABLED);
+        }
+        if ports.port2 {
+            self.enable_port2()?;
+            config.insert(Config::PORT2_INTERRUPT);
+            config.remove(Config::PORT2_CLOCK_DISABLED);
+        }
+        self.set_config(config)?;
+        Ok(ports)
+    }
+
+    /// Read the status register.
+    #[must_use]
+    pub fn status(&self) -> Status {
+        // SAFETY: The controller exists (see `Controller::new`)
+        Status::from_bits_truncate(unsafe { inb(COMMAND_PORT) })
+    }
+
+    /// Run the self-test of the controller.
+    ///
+    /// # Errors
+    /// Returns [`Error::SelfTestFailed`] if the test failed, or [`Error::Timeout`] if the
+    /// controller did not respond.
+    pub fn self_test(&self) -> Result<(), Error> {
+        match self.command_with_response(SELF_TEST)? {
+            SELF_TEST_PASSED => Ok(()),
+            response => Err(Error::SelfTestFailed(response)),
+        }
+    }
+
+    /// Run the interface test of the first port.
+    ///
+    /// # Errors
+    /// Returns [`Error::PortTestFailed`] if the test failed, or [`Error::Timeout`] if the
+    /// controller did not respond.
+    pub fn test_port1(&self) -> Result<(), Error> {
+        match self.command_with_response(TEST_PORT1)? {
+            0 => Ok(()),
+            response => Err(Error::PortTestFailed(response)),
+        }
+    }
+
+    /// Run the interface test of the second port.
+    ///
+    /// # Errors
+    /// Returns [`Error::PortTestFailed`] if the test failed, or [`Error::Timeout`] if the
+    /// controller did not respond.
+    pub fn test_port2(&self) -> Result<(), Error> {
+        match self.command_with_response(TEST_PORT2)? {
+            0 => Ok(()),
+            response => Err(Error::PortTestFailed(response)),
+        }
+    }
+
+    /// Read the configuration byte of the controller.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not respond.
+    pub fn config(&self) -> Result<Config, Error> {
+        self.command_with_response(READ_CONFIG)
+            .map(Config::from_bits_truncate)
+    }
+
+    /// Write the configuration byte of the controller.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not accept the byte.
+    pub fn set_config(&self, config: Config) -> Result<(), Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write_data(config.bits())
+    }
+
+    /// Enable the first port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not accept the command.
+    pub fn enable_port1(&self) -> Result<(), Error> {
+        self.command(ENABLE_PORT1)
+    }
+
+    /// Disable the first port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not accept the command.
+    pub fn disable_port1(&self) -> Result<(), Error> {
+        self.command(DISABLE_PORT1)
+    }
+
+    /// Enable the second port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not accept the command.
+    pub fn enable_port2(&self) -> Result<(), Error> {
+        self.command(ENABLE_PORT2)
+    }
+
+    /// Disable the second port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not accept the command.
+    pub fn disable_port2(&self) -> Result<(), Error> {
+        self.command(DISABLE_PORT2)
+    }
+
+    /// Discard all the bytes in the output buffer.
+    pub fn flush(&self) {
+        for _ in 0..TIMEOUT_SPINS {
+            if !self.status().contains(Status::OUTPUT_FULL) {
+                break;
+            }
+            // SAFETY: The controller exists (see `Controller::new`)
+            let _ = unsafe { inb(DATA_PORT) };
+        }
+    }
+
+    /// Read the byte sent by the first port (the keyboard), if any, without waiting. This is
+    /// meant to be called by the handler of the IRQ 1, which is fired when a byte is available:
+    /// each call reads at most one byte. Returns `None` if the output buffer is empty or if the
+    /// byte comes from the second port.
+    #[must_use]
+    pub fn read_scancode(&self) -> Option<u8> {
+        let status = self.status();
+        if !status.contains(Status::OUTPUT_FULL) || status.contains(Status::AUX_OUTPUT) {
+            return None;
+        }
+        // SAFETY: The controller exists (see `Controller::new`)
+        Some(unsafe { inb(DATA_PORT) })
+    }
+
+    /// Read the byte sent by the second port (the mouse), if any, without waiting. This is meant to
+    /// be called by the handler of the IRQ 12, like [`Controller::read_scancode`] for the IRQ 1.
+    #[must_use]
+    pub fn read_aux(&self) -> Option<u8> {
+        let status = self.status();
+        if !status.contains(Status::OUTPUT_FULL | Status::AUX_OUTPUT) {
+            return None;
+        }
+        // SAFETY: The controller exists (see `Controller::new`)
+        Some(unsafe { inb(DATA_PORT) })
+    }
+
+    /// Wait for a byte in the output buffer and read it.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if no byte is available in time.
+    pub fn read_data(&self) -> Result<u8, Error> {
+        self.wait(|status| status.contains(Status::OUTPUT_FULL))?;
+        // SAFETY: The controller exists (see `Controller::new`)
+        Ok(unsafe { inb(DATA_PORT) })
+    }
+
+    /// Wait for the input buffer to be empty and write the given byte to the data port. If no
+    /// command expecting a parameter was sent before, the byte is sent to the first port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the input buffer does not become empty in time.
+    pub fn write_data(&self, byte: u8) -> Result<(), Error> {
+        self.wait(|status| !status.contains(Status::INPUT_FULL))?;
+        // SAFETY: The controller exists (see `Controller::new`)
+        unsafe { outb(DATA_PORT, byte) };
+        Ok(())
+    }
+
+    /// Write the given byte to the device of the second port.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller did not accept the byte in time.
+    pub fn write_port2(&self, byte: u8) -> Result<(), Error> {
+        self.command(WRITE_PORT2)?;
+        self.write_data(byte)
+    }
+
+    /// Send a command byte to the device of the second port, and wait for its acknowledgement.
+    /// The byte is sent again if the device asks for it.
+    ///
+    /// # Errors
+    /// Returns [`Error::Timeout`] if the controller or the device did not respond in time, or
+    /// [`Error::UnexpectedResponse`] if the device did not acknowledge the byte.
+    pub fn send_port2(&self, byte: u8) -> Result<(), Error> {
+        for _ in 0..MAX_RESENDS {
+            self.write_port2(byte)?;
+            match self.read_data()? {
+                ACK => return Ok(()),
+                RESEND => {}
+                response => return Err(Error::UnexpectedResponse(response)),
+            }
+        }
+        Err(Error::UnexpectedResponse(RESEND))
+    }
+
+    /// Send a command to the controller.
+    fn command(&self, command: u8) -> Result<(), Error> {
+        self.wait(|status| !status.contains(Status::INPUT_FULL))?;
+        // SAFETY: The controller exists (see `Controller::new`)
+        unsafe { outb(COMMAND_PORT, command) };
+        Ok(())
+    }
+
+    /// Send a command to the controller and read its response.
+    fn command_with_response(&self, command: u8) -> Result<u8, Error> {
+        self.command(command)?;
+        self.read_data()
+    }
+
+    /// Wait until the status register satisfies the given condition.
+    fn wait(&self, condition: impl Fn(Status) -> bool) -> Result<(), Error> {
+        for _ in 0..TIMEOUT_SPINS {
+            if condition(self.status()) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Error::Timeout)
+    }
+}
+
+pub mod mouse {
+    //! Support of the PS/2 mouse, connected to the second port of the controller. The mouse sends
+    //! a packet of 3 bytes for each movement or button change, or of 4 bytes if it is an
+    //! `IntelliMouse` with a scroll wheel. The bytes are received one by one through the IRQ 12
+    //! and decoded by a [`PacketDecoder`].
+    use bitflags::bitflags;
+
+    use super::{Controller, Error};
+
+    /// The commands of the mouse.
+    const SET_RESOLUTION: u8 = 0xE8;
+    const GET_ID: u8 = 0xF2;
+    const SET_SAMPLE_RATE: u8 = 0xF3;
+    const ENABLE_REPORTING: u8 = 0xF4;
+    const DISABLE_REPORTING: u8 = 0xF5;
+    const SET_DEFAULTS: u8 = 0xF6;
+    const RESET: u8 = 0xFF;
+
+    /// The byte sent by the mouse after a successful reset.
+    const RESET_PASSED: u8 = 0xAA;
+
+    /// The number of reads of the data port while waiting for the end of the self-test of the
+    /// mouse after a reset, which may take several hundred milliseconds.
+    const RESET_ATTEMPTS: usize = 10;
+
+    /// The identifiers of the mouse, sent after a reset or on [`GET_ID`].
+    const STANDARD_ID: u8 = 0x00;
+    const WHEEL_ID: u8 = 0x03;
+
+    /// The sample rates accepted by the mouse, in samples per second.
+    pub const SAMPLE_RATES: [u8; 7] = [10, 20, 40, 60, 80, 100, 200];
+
+    /// The bit of the first byte of a packet that is always set, used to find the start of a
+    /// packet.
+    const ALWAYS_ONE: u8 = 1 << 3;
+
+    /// The sign and overflow bits of the first byte of a packet.
+    const X_SIGN: u8 = 1 << 4;
+    const Y_SIGN: u8 = 1 << 5;
+    const X_OVERFLOW: u8 = 1 << 6;
+    const Y_OVERFLOW: u8 = 1 << 7;
+
+    bitflags! {
+        /// The buttons of the mouse, as reported in the first byte of a packet.
+        pub struct Buttons: u8 {
+            const LEFT = 1 << 0;
+            const RIGHT = 1 << 1;
+            const MIDDLE = 1 << 2;
+        }
+    }
+
+    /// The resolution of the mouse, in counts per millimeter.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Resolution {
+        One = 0,
+        Two = 1,
+        Four = 2,
+        Eight = 3,
+    }
+
+    /// The kind of a mouse, which determines the size of its packets.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Kind {
+        /// A standard mouse, with 3 buttons and 3 bytes packets.
+        Standard,
+
+        /// An `IntelliMouse`, with a scroll wheel and 4 bytes packets.
+        Wheel,
+    }
+
+    impl Kind {
+        /// Returns the size of the packets sent by this kind of mouse.
+        #[must_use]
+        pub const fn packet_size(self) -> usize {
+            match self {
+                Kind::Standard => 3,
+                Kind::Wheel => 4,
+            }
+        }
+    }
+
+    /// An event decoded from a packet of the mouse. The movement is relative to the previous
+    /// packet, with the Y axis pointing up. It is 0 on an axis whose counter overflowed, since the
+    /// value reported by the mouse is meaningless in that case.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Event {
+        pub dx: i16,
+        pub dy: i16,
+        pub wheel: i8,
+        pub buttons: Buttons,
+    }
+
+    /// A decoder assembling the bytes of the mouse into packets. If a byte is lost, the decoder
+    /// resynchronizes on the next byte that looks like the first byte of a packet.
+    #[derive(Debug, Clone)]
+    pub struct PacketDecoder {
+        kind: Kind,
+        packet: [u8; 4],
+        index: usize,
+    }
+
+    impl PacketDecoder {
+        /// Creates a new decoder for the given kind of mouse.
+        #[must_use]
+        pub const fn new(kind: Kind) -> Self {
+            Self {
+                kind,
+                packet: [0; 4],
+                index: 0,
+            }
+        }
+
+        /// Returns the kind of mouse decoded.
+        #[must_use]
+        pub const fn kind(&self) -> Kind {
+            self.kind
+        }
+
+        /// Discard the bytes of the current packet.
+        pub fn reset(&mut self) {
+            self.index = 0;
+        }
+
+        /// Add a byte received from the mouse, and returns the decoded event if it completes a
+        /// packet.
+        pub fn push(&mut self, byte: u8) -> Option<Event> {
+            if self.index == 0 && byte & ALWAYS_ONE == 0 {
+                return None;
+            }
+
+            self.packet[self.index] = byte;
+            self.index += 1;
+            if self.index < self.kind.packet_size() {
+                return None;
+            }
+            self.index = 0;
+            Some(self.decode())
+        }
+
+        /// Decode the complete packet.
+        fn decode(&self) -> Event {
+            let [flags, x, y, z] = self.packet;
+            let axis = |value: u8, sign: u8, overflow: u8| -> i16 {
+                if flags & overflow != 0 {
+                    0
+                } else if flags & sign != 0 {
+                    i16::from(value) - 0x100
+                } else {
+                    i16::from(value)
+                }
+            };
+
+            // The wheel movement is a 4 bits signed value
+            #[allow(clippy::cast_possible_wrap)]
+            let wheel = match self.kind {
+                Kind::Standard => 0,
+                Kind::Wheel => ((z << 4) as i8) >> 4,
+            };
+
+            Event {
+                dx: axis(x, X_SIGN, X_OVERFLOW),
+                dy: axis(y, Y_SIGN, Y_OVERFLOW),
+                wheel,
+                buttons: Buttons::from_bits_truncate(flags),
+            }
+        }
+    }
+
+    /// A PS/2 mouse connected to the second port of the controller.
+    #[derive(Debug)]
+    pub struct Mouse<'a> {
+        controller: &'a Controller,
+        decoder: PacketDecoder,
+    }
+
+    impl<'a> Mouse<'a> {
+        /// Reset and initialize the mouse: the scroll wheel is enabled if the mouse has one, the
+        /// default settings are restored and the reporting of the movements is enabled. The second
+        /// port must have been enabled (see [`Controller::initialize`]).
+        ///
+        /// # Errors
+        /// Returns an error if the mouse does not respond, or fails its self-test.
+        pub fn initialize(controller: &'a Controller) -> Result<Self, Error> {
+            controller.send_port2(RESET)?;
+            let mut response = controller.read_data();
+            for _ in 0..RESET_ATTEMPTS {
+                if response != Err(Error::Timeout) {
+                    break;
+                }
+                response = controller.read_data();
+            }
+            match response? {
+                RESET_PASSED => {}
+                response => return Err(Error::UnexpectedResponse(response)),
+            }
+            // The identifier of the mouse follows the result of the self-test
+            let _ = controller.read_data();
+
+            let mut mouse = Self {
+                controller,
+                decoder: PacketDecoder::new(Kind::Standard),
+            };
+            mouse.controller.send_port2(SET_DEFAULTS)?;
+
+            // The magic sequence of sample rates enabling the scroll wheel of an IntelliMouse
+            for rate in [200, 100, 80] {
+                mouse.set_sample_rate(rate)?;
+            }
+            let kind = match mouse.id()? {
+                WHEEL_ID => Kind::Wheel,
+                STANDARD_ID => Kind::Standard,
+                id => return Err(Error::UnexpectedResponse(id)),
+            };
+            mouse.decoder = PacketDecoder::new(kind);
+            mouse.set_sample_rate(100)?;
+            mouse.enable_reporting()?;
+            Ok(mouse)
+        }
+
+        /// Returns the kind of the mouse.
+        #[must_use]
+        pub const fn kind(&self) -> Kind {
+            self.decoder.kind()
+        }
+
+        /// Read the identifier of the mouse. Reporting must be disabled.
+        ///
+        /// # Errors
+        /// Returns an error if the mouse does not respond.
+        pub fn id(&self) -> Result<u8, Error> {
+            self.controller.send_port2(GET_ID)?;
+            self.controller.read_data()
+        }
+
+        /// Set the number of packets sent per second by the mouse.
+        ///
+        /// # Panics
+        /// Panics if the rate is not one of the [`SAMPLE_RATES`].
+        ///
+        /// # Errors
+        /// Returns an error if the mouse does not acknowledge the command.
+        pub fn set_sample_rate(&self, rate: u8) -> Result<(), Error> {
+            assert!(
+                SAMPLE_RATES.contains(&rate),
+                "Invalid mouse sample rate {rate}"
+            );
+            self.controller.send_port2(SET_SAMPLE_RATE)?;
+            self.controller.send_port2(rate)
+        }
+
+        /// Set the resolution of the mouse.
+        ///
+        /// # Errors
+        /// Returns an error if the mouse does not acknowledge the command.
+        pub fn set_resolution(&self, resolution: Resolution) -> Result<(), Error> {
+            self.controller.send_port2(SET_RESOLUTION)?;
+            self.controller.send_port2(resolution as u8)
+        }
+
+        /// Enable the reporting of the movements: the mouse starts sending packets.
+        ///
+        /// # Errors
+        /// Returns an error if the mouse does not acknowledge the command.
+        pub fn enable_reporting(&mut self) -> Result<(), Error> {
+            self.decoder.reset();
+            self.controller.send_port2(ENABLE_REPORTING)
+        }
+
+        /// Disable the reporting of the movements.
+        ///
+        /// # Errors
+        /// Returns an error if the mouse does not acknowledge the command.
+        pub fn disable_reporting(&mut self) -> Result<(), Error> {
+            self.controller.send_port2(DISABLE_REPORTING)?;
+            self.decoder.reset();
+            Ok(())
+        }
+
+        /// Read the byte received from the mouse, and returns the decoded event if it completes a
+        /// packet. This must be called by the handler of the IRQ 12.
+        pub fn on_interrupt(&mut self) -> Option<Event> {
+            let byte = self.controller.read_aux()?;
+            self.decoder.push(byte)
+        }
+    }
+}
+
+pub mod keyboard {
+    //! Decoding of the scancodes of the PS/2 keyboard, received one by one through the IRQ 1,
+    //! into [`KeyEvent`]s. Both the scancode set 1 (the default when the controller translates
+    //! the scancodes) and the set 2 (the default of the keyboards) are supported, and the decoder
+    //! tracks the state of the modifier keys.
+    use bitflags::bitflags;
+
+    /// The prefix of the extended scancodes.
+    const EXTENDED: u8 = 0xE0;
+
+    /// The prefix of the scancodes of the pause key.
+    const PAUSE: u8 = 0xE1;
+
+    /// The prefix of the break codes of the scancode set 2.
+    const SET2_BREAK: u8 = 0xF0;
+
+    /// The bit set in the break codes of the scancode set 1.
+    const SET1_BREAK: u8 = 1 << 7;
+
+    /// The number of bytes following the prefix of the pause key, without the break prefixes of
+    /// the set 2. The pause key sends its make and break codes at once when pressed.
+    const PAUSE_LENGTH: u8 = 2;
+
+    /// A scancode set.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ScancodeSet {
+        Set1,
+        Set2,
+    }
+
+    /// The keys of a US QWERTY keyboard, identified by their position. The layout is only used by
+    /// [`KeyEvent::to_char`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum KeyCode {
+        Escape,
+        F1,
+        F2,
+        F3,
+        F4,
+        F5,
+        F6,
+        F7,
+        F8,
+        F9,
+        F10,
+        F11,
+        F12,
+        PrintScreen,
+        ScrollLock,
+        Pause,
+        Backquote,
+        Digit1,
+        Digit2,
+        Digit3,
+        Digit4,
+        Digit5,
+        Digit6,
+        Digit7,
+        Digit8,
+        Digit9,
+        Digit0,
+        Minus,
+        Equal,
+        Backspace,
+        Tab,
+        Q,
+        W,
+        E,
+        R,
+        T,
+        Y,
+        U,
+        I,
+        O,
+        P,
+        LeftBracket,
+        RightBracket,
+        Backslash,
+        CapsLock,
+        A,
+        S,
+        D,
+        F,
+        G,
+        H,
+        J,
+        K,
+        L,
+        Semicolon,
+        Quote,
+        Enter,
+        LeftShift,
+        NonUsBackslash,
+        Z,
+        X,
+        C,
+        V,
+        B,
+        N,
+        M,
+        Comma,
+        Period,
+        Slash,
+        RightShift,
+        LeftControl,
+        LeftMeta,
+        LeftAlt,
+        Space,
+        RightAlt,
+        RightMeta,
+        Menu,
+        RightControl,
+        Insert,
+        Delete,
+        Home,
+        End,
+        PageUp,
+        PageDown,
+        Up,
+        Down,
+        Left,
+        Right,
+        NumLock,
+        KeypadDivide,
+        KeypadMultiply,
+        KeypadMinus,
+        KeypadPlus,
+        KeypadEnter,
+        KeypadPeriod,
+        Keypad0,
+        Keypad1,
+        Keypad2,
+        Keypad3,
+        Keypad4,
+        Keypad5,
+        Keypad6,
+        Keypad7,
+        Keypad8,
+        Keypad9,
+    }
+
+    /// Whether a key was pressed or released.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum KeyState {
+        Pressed,
+        Released,
+    }
+
+    bitflags! {
+        /// The state of the modifier keys, and of the lock keys which are toggled each time they
+        /// are pressed.
+        pub struct Modifiers: u16 {
+            const LEFT_SHIFT = 1 << 0;
+            const RIGHT_SHIFT = 1 << 1;
+            const LEFT_CONTROL = 1 << 2;
+            const RIGHT_CONTROL = 1 << 3;
+            const LEFT_ALT = 1 << 4;
+            const RIGHT_ALT = 1 << 5;
+            const LEFT_META = 1 << 6;
+            const RIGHT_META = 1 << 7;
+            const CAPS_LOCK = 1 << 8;
+            const NUM_LOCK = 1 << 9;
+            const SCROLL_LOCK = 1 << 10;
+        }
+    }
+
+    impl Modifiers {
+        /// Returns `true` if one of the shift keys is pressed.
+        #[must_use]
+        pub const fn shift(self) -> bool {
+            self.intersects(Self::LEFT_SHIFT.union(Self::RIGHT_SHIFT))
+        }
+
+        /// Returns `true` if one of the control keys is pressed.
+        #[must_use]
+        pub const fn control(self) -> bool {
+            self.intersects(Self::LEFT_CONTROL.union(Self::RIGHT_CONTROL))
+        }
+
+        /// Returns `true` if one of the alt keys is pressed.
+        #[must_use]
+        pub const fn alt(self) -> bool {
+            self.intersects(Self::LEFT_ALT.union(Self::RIGHT_ALT))
+        }
+    }
+
+    /// A key pressed or released, with the state of the modifiers after the event.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KeyEvent {
+        pub code: KeyCode,
+        pub state: KeyState,
+        pub modifiers: Modifiers,
+    }
+
+    impl KeyEvent {
+        /// Returns the character typed by this event with a US QWERTY layout, or `None` if the
+        /// key was released or does not type a character.
+        #[must_use]
+        pub fn to_char(&self) -> Option<char> {
+            if self.state == KeyState::Released {
+                return None;
+            }
+
+            let shift = self.modifiers.shift();
+            let num_lock = self.modifiers.contains(Modifiers::NUM_LOCK);
+            if let Some(letter) = letter(self.code) {
+                let upper = shift != self.modifiers.contains(Modifiers::CAPS_LOCK);
+                return Some(if upper {
+                    letter.to_ascii_uppercase()
+                } else {
+                    letter
+                });
+            }
+
+            let (normal, shifted) = match self.code {
+                KeyCode::Backquote => ('`', '~'),
+                KeyCode::Digit1 => ('1', '!'),
+                KeyCode::Digit2 => ('2', '@'),
+                KeyCode::Digit3 => ('3', '#'),
+                KeyCode::Digit4 => ('4', '$'),
+                KeyCode::Digit5 => ('5', '%'),
+                KeyCode::Digit6 => ('6', '^'),
+                KeyCode::Digit7 => ('7', '&'),
+                KeyCode::Digit8 => ('8', '*'),
+                KeyCode::Digit9 => ('9', '('),
+                KeyCode::Digit0 => ('0', ')'),
+                KeyCode::Minus => ('-', '_'),
+                KeyCode::Equal => ('=', '+'),
+                KeyCode::LeftBracket => ('[', '{'),
+                KeyCode::RightBracket => (']', '}'),
+                KeyCode::Backslash | KeyCode::NonUsBackslash => ('\\', '|'),
+                KeyCode::Semicolon => (';', ':'),
+                KeyCode::Quote => ('\'', '"'),
+                KeyCode::Comma => (',', '<'),
+                KeyCode::Period => ('.', '>'),
+                KeyCode::Slash => ('/', '?'),
+                KeyCode::Space => (' ', ' '),
+                KeyCode::Tab => ('\t', '\t'),
+                KeyCode::Enter | KeyCode::KeypadEnter => ('\n', '\n'),
+                KeyCode::Backspace => ('\x08', '\x08'),
+                KeyCode::KeypadDivide => ('/', '/'),
+                KeyCode::KeypadMultiply => ('*', '*'),
+                KeyCode::KeypadMinus => ('-', '-'),
+                KeyCode::KeypadPlus => ('+', '+'),
+                _ => return keypad_digit(self.code).filter(|_| num_lock),
+            };
+            Some(if shift { shifted } else { normal })
+        }
+    }
+
+    /// A decoder of the scancodes sent by the keyboard.
+    #[derive(Debug, Clone)]
+    pub struct Decoder {
+        set: ScancodeSet,
+        modifiers: Modifiers,
+        extended: bool,
+        release: bool,
+        pause: u8,
+    }
+
+    impl Decoder {
+        /// Creates a new decoder for the given scancode set. The scancode set 1 must be used if
+        /// the translation of the controller is enabled (see [`super::Config::TRANSLATION`]).
+        #[must_use]
+        pub const fn new(set: ScancodeSet) -> Self {
+            Self {
+                set,
+                modifiers: Modifiers::empty(),
+                extended: false,
+                release: false,
+                pause: 0,
+            }
+        }
+
+        /// Returns the current state of the modifiers.
+        #[must_use]
+        pub const fn modifiers(&self) -> Modifiers {
+            self.modifiers
+        }
+
+        /// Add a byte received from the keyboard, and returns the decoded event if it completes a
+        /// scancode. The unknown scancodes and the responses of the keyboard to the commands are
+        /// ignored.
+        pub fn push(&mut self, byte: u8) -> Option<KeyEvent> {
+            match byte {
+                EXTENDED => {
+                    self.extended = true;
+                    return None;
+                }
+                PAUSE => {
+                    self.pause = PAUSE_LENGTH;
+                    return None;
+                }
+                SET2_BREAK if self.set == ScancodeSet::Set2 => {
+                    self.release = true;
+                    return None;
+                }
+                _ => {}
+            }
+
+            let (code, release) = match self.set {
+                ScancodeSet::Set1 => (byte & !SET1_BREAK, byte & SET1_BREAK != 0),
+                ScancodeSet::Set2 => (byte, self.release),
+            };
+            let extended = core::mem::take(&mut self.extended);
+            self.release = false;
+
+            let key = if self.pause > 0 {
+                self.pause -= 1;
+                if self.pause > 0 {
+                    return None;
+                }
+                Some(KeyCode::Pause)
+            } else {
+                match (self.set, extended) {
+                    (ScancodeSet::Set1, false) => set1(code),
+                    (ScancodeSet::Set1, true) => set1_extended(code),
+                    (ScancodeSet::Set2, false) => set2(code),
+                    (ScancodeSet::Set2, true) => set2_extended(code),
+                }
+            }?;
+
+            let state = if release {
+                KeyState::Released
+            } else {
+                KeyState::Pressed
+            };
+            self.update_modifiers(key, state);
+            Some(KeyEvent {
+                code: key,
+                state,
+                modifiers: self.modifiers,
+            })
+        }
+
+        /// Update the state of the modifiers after a key event.
+        fn update_modifiers(&mut self, code: KeyCode, state: KeyState) {
+            let modifier = match code {
+                KeyCode::LeftShift => Modifiers::LEFT_SHIFT,
+                KeyCode::RightShift => Modifiers::RIGHT_SHIFT,
+                KeyCode::LeftControl => Modifiers::LEFT_CONTROL,
+                KeyCode::RightControl => Modifiers::RIGHT_CONTROL,
+                KeyCode::LeftAlt => Modifiers::LEFT_ALT,
+                KeyCode::RightAlt => Modifiers::RIGHT_ALT,
+                KeyCode::LeftMeta => Modifiers::LEFT_META,
+                KeyCode::RightMeta => Modifiers::RIGHT_META,
+                KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock => {
+                    if state == KeyState::Pressed {
+                        self.modifiers.toggle(match code {
+                            KeyCode::CapsLock => Modifiers::CAPS_LOCK,
+                            KeyCode::NumLock => Modifiers::NUM_LOCK,
+                            _ => Modifiers::SCROLL_LOCK,
+                        });
+                    }
+                    return;
+                }
+                _ => return,
+            };
+            self.modifiers.set(modifier, state == KeyState::Pressed);
+        }
+    }
+
+    /// Returns the lowercase letter typed by the given key, if any.
+    fn letter(code: KeyCode) -> Option<char> {
+        Some(match code {
+            KeyCode::A => 'a',
+            KeyCode::B => 'b',
+            KeyCode::C => 'c',
+            KeyCode::D => 'd',
+            KeyCode::E => 'e',
+            KeyCode::F => 'f',
+            KeyCode::G => 'g',
+            KeyCode::H => 'h',
+            KeyCode::I => 'i',
+            KeyCode::J => 'j',
+            KeyCode::K => 'k',
+            KeyCode::L => 'l',
+            KeyCode::M => 'm',
+            KeyCode::N => 'n',
+            KeyCode::O => 'o',
+            KeyCode::P => 'p',
+            KeyCode::Q => 'q',
+            KeyCode::R => 'r',
+            KeyCode::S => 's',
+            KeyCode::T => 't',
+            KeyCode::U => 'u',
+            KeyCode::V => 'v',
+            KeyCode::W => 'w',
+            KeyCode::X => 'x',
+            KeyCode::Y => 'y',
+            KeyCode::Z => 'z',
+            _ => return None,
+        })
+    }
+
+    /// Returns the digit typed by the given keypad key when the num lock is on, if any.
+    fn keypad_digit(code: KeyCode) -> Option<char> {
+        Some(match code {
+            KeyCode::Keypad0 => '0',
+            KeyCode::Keypad1 => '1',
+            KeyCode::Keypad2 => '2',
+            KeyCode::Keypad3 => '3',
+            KeyCode::Keypad4 => '4',
+            KeyCode::Keypad5 => '5',
+            KeyCode::Keypad6 => '6',
+            KeyCode::Keypad7 => '7',
+            KeyCode::Keypad8 => '8',
+            KeyCode::Keypad9 => '9',
+            KeyCode::KeypadPeriod => '.',
+            _ => return None,
+        })
+    }
+
+    /// Returns the key of the given scancode of the set 1, without the break bit.
+    const fn set1(code: u8) -> Option<KeyCode> {
+        Some(match code {
+            0x01 => KeyCode::Escape,
+            0x02 => KeyCode::Digit1,
+            0x03 => KeyCode::Digit2,
+            0x04 => KeyCode::Digit3,
+            0x05 => KeyCode::Digit4,
+            0x06 => KeyCode::Digit5,
+            0x07 => KeyCode::Digit6,
+            0x08 => KeyCode::Digit7,
+            0x09 => KeyCode::Digit8,
+            0x0A => KeyCode::Digit9,
+            0x0B => KeyCode::Digit0,
+            0x0C => KeyCode::Minus,
+            0x0D => KeyCode::Equal,
+            0x0E => KeyCode::Backspace,
+            0x0F => KeyCode::Tab,
+            0x10 => KeyCode::Q,
+            0x11 => KeyCode::W,
+            0x12 => KeyCode::E,
+            0x13 => KeyCode::R,
+            0x14 => KeyCode::T,
+            0x15 => KeyCode::Y,
+            0x16 => KeyCode::U,
+            0x17 => KeyCode::I,
+            0x18 => KeyCode::O,
+            0x19 => KeyCode::P,
+            0x1A => KeyCode::LeftBracket,
+            0x1B => KeyCode::RightBracket,
+            0x1C => KeyCode::Enter,
+            0x1D => KeyCode::LeftControl,
+            0x1E => KeyCode::A,
+            0x1F => KeyCode::S,
+            0x20 => KeyCode::D,
+            0x21 => KeyCode::F,
+            0x22 => KeyCode::G,
+            0x23 => KeyCode::H,
+            0x24 => KeyCode::J,
+            0x25 => KeyCode::K,
+            0x26 => KeyCode::L,
+            0x27 => KeyCode::Semicolon,
+            0x28 => KeyCode::Quote,
+            0x29 => KeyCode::Backquote,
+            0x2A => KeyCode::LeftShift,
+            0x2B => KeyCode::Backslash,
+            0x2C => KeyCode::Z,
+            0x2D => KeyCode::X,
+            0x2E => KeyCode::C,
+            0x2F => KeyCode::V,
+            0x30 => KeyCode::B,
+            0x31 => KeyCode::N,
+            0x32 => KeyCode::M,
+            0x33 => KeyCode::Comma,
+            0x34 => KeyCode::Period,
+            0x35 => KeyCode::Slash,
+            0x36 => KeyCode::RightShift,
+            0x37 => KeyCode::KeypadMultiply,
+            0x38 => KeyCode::LeftAlt,
+            0x39 => KeyCode::Space,
+            0x3A => KeyCode::CapsLock,
+            0x3B => KeyCode::F1,
+            0x3C => KeyCode::F2,
+            0x3D => KeyCode::F3,
+            0x3E => KeyCode::F4,
+            0x3F => KeyCode::F5,
+            0x40 => KeyCode::F6,
+            0x41 => KeyCode::F7,
+            0x42 => KeyCode::F8,
+            0x43 => KeyCode::F9,
+            0x44 => KeyCode::F10,
+            0x45 => KeyCode::NumLock,
+            0x46 => KeyCode::ScrollLock,
+            0x47 => KeyCode::Keypad7,
+            0x48 => KeyCode::Keypad8,
+            0x49 => KeyCode::Keypad9,
+            0x4A => KeyCode::KeypadMinus,
+            0x4B => KeyCode::Keypad4,
+            0x4C => KeyCode::Keypad5,
+            0x4D => KeyCode::Keypad6,
+            0x4E => KeyCode::KeypadPlus,
+            0x4F => KeyCode::Keypad1,
+            0x50 => KeyCode::Keypad2,
+            0x51 => KeyCode::Keypad3,
+            0x52 => KeyCode::Keypad0,
+            0x53 => KeyCode::KeypadPeriod,
+            0x56 => KeyCode::NonUsBackslash,
+            0x57 => KeyCode::F11,
+            0x58 => KeyCode::F12,
+            _ => return None,
+        })
+    }
+
+    /// Returns the key of the given extended scancode of the set 1, without the break bit. The
+    /// fake shifts sent around the print screen key are ignored.
+    const fn set1_extended(code: u8) -> Option<KeyCode> {
+        Some(match code {
+            0x1C => KeyCode::KeypadEnter,
+            0x1D => KeyCode::RightControl,
+            0x35 => KeyCode::KeypadDivide,
+            0x37 => KeyCode::PrintScreen,
+            0x38 => KeyCode::RightAlt,
+            0x47 => KeyCode::Home,
+            0x48 => KeyCode::Up,
+            0x49 => KeyCode::PageUp,
+            0x4B => KeyCode::Left,
+            0x4D => KeyCode::Right,
+            0x4F => KeyCode::End,
+            0x50 => KeyCode::Down,
+            0x51 => KeyCode::PageDown,
+            0x52 => KeyCode::Insert,
+            0x53 => KeyCode::Delete,
+            0x5B => KeyCode::LeftMeta,
+            0x5C => KeyCode::RightMeta,
+            0x5D => KeyCode::Menu,
+            _ => return None,
+        })
+    }
+
+    /// Returns the key of the given scancode of the set 2.
+    const fn set2(code: u8) -> Option<KeyCode> {
+        Some(match code {
+            0x01 => KeyCode::F9,
+            0x03 => KeyCode::F5,
+            0x04 => KeyCode::F3,
+            0x05 => KeyCode::F1,
+            0x06 => KeyCode::F2,
+            0x07 => KeyCode::F12,
+            0x09 => KeyCode::F10,
+            0x0A => KeyCode::F8,
+            0x0B => KeyCode::F6,
+            0x0C => KeyCode::F4,
+            0x0D => KeyCode::Tab,
+            0x0E => KeyCode::Backquote,
+            0x11 => KeyCode::LeftAlt,
+            0x12 => KeyCode::LeftShift,
+            0x14 => KeyCode::LeftControl,
+            0x15 => KeyCode::Q,
+            0x16 => KeyCode::Digit1,
+            0x1A => KeyCode::Z,
+            0x1B => KeyCode::S,
+            0x1C => KeyCode::A,
+            0x1D => KeyCode::W,
+            0x1E => KeyCode::Digit2,
+            0x21 => KeyCode::C,
+            0x22 => KeyCode::X,
+            0x23 => KeyCode::D,
+            0x24 => KeyCode::E,
+            0x25 => KeyCode::Digit4,
+            0x26 => KeyCode::Digit3,
+            0x29 => KeyCode::Space,
+            0x2A => KeyCode::V,
+            0x2B => KeyCode::F,
+            0x2C => KeyCode::T,
+            0x2D => KeyCode::R,
+            0x2E => KeyCode::Digit5,
+            0x31 => KeyCode::N,
+            0x32 => KeyCode::B,
+            0x33 => KeyCode::H,
+            0x34 => KeyCode::G,
+            0x35 => KeyCode::Y,
+            0x36 => KeyCode::Digit6,
+            0x3A => KeyCode::M,
+            0x3B => KeyCode::J,
+            0x3C => KeyCode::U,
+            0x3D => KeyCode::Digit7,
+            0x3E => KeyCode::Digit8,
+            0x41 => KeyCode::Comma,
+            0x42 => KeyCode::K,
+            0x43 => KeyCode::I,
+            0x44 => KeyCode::O,
+            0x45 => KeyCode::Digit0,
+            0x46 => KeyCode::Digit9,
+            0x49 => KeyCode::Period,
+            0x4A => KeyCode::Slash,
+            0x4B => KeyCode::L,
+            0x4C => KeyCode::Semicolon,
+            0x4D => KeyCode::P,
+            0x4E => KeyCode::Minus,
+            0x52 => KeyCode::Quote,
+            0x54 => KeyCode::LeftBracket,
+            0x55 => KeyCode::Equal,
+            0x58 => KeyCode::CapsLock,
+            0x59 => KeyCode::RightShift,
+            0x5A => KeyCode::Enter,
+            0x5B => KeyCode::RightBracket,
+            0x5D => KeyCode::Backslash,
+            0x61 => KeyCode::NonUsBackslash,
+            0x66 => KeyCode::Backspace,
+            0x69 => KeyCode::Keypad1,
+            0x6B => KeyCode::Keypad4,
+            0x6C => KeyCode::Keypad7,
+            0x70 => KeyCode::Keypad0,
+            0x71 => KeyCode::KeypadPeriod,
+            0x72 => KeyCode::Keypad2,
+            0x73 => KeyCode::Keypad5,
+            0x74 => KeyCode::Keypad6,
+            0x75 => KeyCode::Keypad8,
+            0x76 => KeyCode::Escape,
+            0x77 => KeyCode::NumLock,
+            0x78 => KeyCode::F11,
+            0x79 => KeyCode::KeypadPlus,
+            0x7A => KeyCode::Keypad3,
+            0x7B => KeyCode::KeypadMinus,
+            0x7C => KeyCode::KeypadMultiply,
+            0x7D => KeyCode::Keypad9,
+            0x7E => KeyCode::ScrollLock,
+            0x83 => KeyCode::F7,
+            _ => return None,
+        })
+    }
+
+    /// Returns the key of the given extended scancode of the set 2. The fake shifts sent around
+    /// the print screen key are ignored.
+    const fn set2_extended(code: u8) -> Option<KeyCode> {
+        Some(match code {
+            0x11 => KeyCode::RightAlt,
+            0x14 => KeyCode::RightControl,
+            0x1F => KeyCode::LeftMeta,
+            0x27 => KeyCode::RightMeta,
+            0x2F => KeyCode::Menu,
+            0x4A => KeyCode::KeypadDivide,
+            0x5A => KeyCode::KeypadEnter,
+            0x69 => KeyCode::End,
+            0x6B => KeyCode::Left,
+            0x6C => KeyCode::Home,
+            0x70 => KeyCode::Insert,
+            0x71 => KeyCode::Delete,
+            0x72 => KeyCode::Down,
+            0x74 => KeyCode::Right,
+            0x75 => KeyCode::Up,
+            0x7A => KeyCode::PageDown,
+            0x7C => KeyCode::PrintScreen,
+            0x7D => KeyCode::PageUp,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        keyboard::{Decoder, KeyCode, KeyState, Modifiers, ScancodeSet},
+        mouse::{Buttons, Event, Kind, PacketDecoder},
+    };
+
+    #[test]
+    fn scancode_decoding() {
+        // Shift + A, then the right control key, in the scancode set 2
+        let mut decoder = Decoder::new(ScancodeSet::Set2);
+        let events = [0x12, 0x1C, 0xF0, 0x1C, 0xF0, 0x12, 0xE0, 0x14]
+            .into_iter()
+            .filter_map(|byte| decoder.push(byte))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[1].code, KeyCode::A);
+        assert_eq!(events[1].to_char(), Some('A'));
+        assert_eq!(events[2].state, KeyState::Released);
+        assert_eq!(events[2].to_char(), None);
+        assert_eq!(events[4].code, KeyCode::RightControl);
+        assert_eq!(decoder.modifiers(), Modifiers::RIGHT_CONTROL);
+
+        // Caps lock, then the pause key, in the scancode set 1
+        let mut decoder = Decoder::new(ScancodeSet::Set1);
+        let events = [0x3A, 0xBA, 0x10, 0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5]
+            .into_iter()
+            .filter_map(|byte| decoder.push(byte))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[2].to_char(), Some('Q'));
+        assert_eq!(events[3].code, KeyCode::Pause);
+        assert_eq!(events[3].state, KeyState::Pressed);
+        assert_eq!(events[4].state, KeyState::Released);
+    }
+
+    #[test]
+    fn mouse_packet_decoding() {
+        let mut decoder = PacketDecoder::new(Kind::Standard);
+        assert_eq!(decoder.push(0x00), None);
+        assert_eq!(decoder.push(0x29), None);
+        assert_eq!(decoder.push(0x05), None);
+        let event = Event {
+            dx: 5,
+            dy: -2,
+            wheel: 0,
+            buttons: Buttons::LEFT,
+        };
+        assert_eq!(decoder.push(0xFE), Some(event));
+
+        let mut decoder = PacketDecoder::new(Kind::Wheel);
+        for byte in [0x4A, 0x80, 0x01] {
+            assert_eq!(decoder.push(byte), None);
+        }
+        let event = Event {
+            dx: 0,
+            dy: 1,
+            wheel: -1,
+            buttons: Buttons::RIGHT,
+        };
+        assert_eq!(decoder.push(0x0F), Some(event));
+    }
+}
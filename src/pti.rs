@@ -0,0 +1,267 @@
+//! Kernel page table isolation (KPTI), the mitigation of the rogue data cache load vulnerability
+//! (Meltdown). Each address space has two PML4 tables, allocated together in an 8 KiB aligned
+//! [`Pml4Pair`]: the kernel PML4, used while running in kernel mode, maps the whole kernel, and
+//! the user PML4, used while running in user mode, only maps the user space and a minimal set of
+//! kernel pages needed to enter and leave the kernel (the trampoline mapping set).
+//!
+//! Since the user PML4 directly follows the kernel PML4, the entry and exit code of this crate
+//! ([`crate::idt::interrupt_enter`], [`crate::idt::interrupt_exit`], [`crate::syscall::entry`],
+//! [`crate::cpu::enter_usermode`] and [`crate::cpu::restore_state`]) switches between them by
+//! toggling the bit 12 of CR3, when the isolation is enabled on the current CPU with [`enable`].
+//! The paranoid entry code of the IST vectors (see [`crate::idt::paranoid_interrupt_enter`])
+//! tests this bit instead of the saved CS register, and restores the previous CR3 on exit.
+//! If PCIDs are used, the user PML4 uses the PCID of the kernel PML4 with the bit 11 set, and the
+//! TLB is not flushed on the switches.
+//!
+//! The trampoline mapping set must contain, in kernel-only pages:
+//! - the code of the entry and exit stubs,
+//! - the IDT, the GDT and the TSS of each CPU,
+//! - the per-CPU area of each CPU (see [`crate::percpu`]),
+//! - the interrupt stacks of each CPU (the RSP0 and IST stacks of the TSS), since the CPU pushes
+//!   the interrupt frame before the entry code can switch to the kernel PML4. The stack used by
+//!   [`crate::cpu::enter_usermode`] and the state given to [`crate::cpu::restore_state`] must
+//!   also be mapped when returning to user mode.
+//! - the `silicium_clear_cpu_buffers` and `silicium_verw_selector` statics, read by the exit code
+//!   after the switch to the user PML4 to clear the CPU buffers (see
+//!   [`crate::cpu::mitigations::set_clear_on_user_return`]), and the `silicium_swapgs_fence`
+//!   static, read by the entry code before the switch to the kernel PML4 (see
+//!   [`crate::cpu::mitigations::set_swapgs_fence`]).
+//!
+//! The kernel pages should not be global when the isolation is enabled, except the trampoline
+//! set, otherwise they would stay in the TLB while running in user mode.
+use core::arch::asm;
+
+use crate::{
+    address::Physical,
+    cpu::{cr4, feature::Feature, msr::arch_capabilities, quirks::Vendor},
+    paging::{PageEntry, PageEntryFlags, PageTable},
+    percpu::{GsAccess, PTI_CR3_OFFSET},
+};
+
+/// The bit of CR3 selecting the user PML4 of a [`Pml4Pair`].
+pub const USER_PML4_BIT: u64 = 1 << 12;
+
+/// The bit of the PCID set for the user PML4, when PCIDs are used.
+pub const USER_PCID_BIT: u64 = 1 << 11;
+
+/// The bit of CR3 that prevents the TLB entries of the new PCID from being flushed when CR3 is
+/// written. It is only valid when PCIDs are enabled.
+pub const NOFLUSH_BIT: u64 = 1 << 63;
+
+/// The maximal PCID that can be used by an address space, since the bit 11 is reserved for the
+/// user PML4.
+pub const MAX_PCID: u16 = 0x7FF;
+
+/// The number of entries of a PML4 mapping the user space (the lower half).
+const USER_ENTRIES: usize = PageTable::COUNT / 2;
+
+/// The kernel and the user PML4 tables of an address space. The user PML4 must directly follow
+/// the kernel PML4, so the pair is aligned on 8 KiB.
+#[derive(Debug)]
+#[repr(C, align(8192))]
+pub struct Pml4Pair {
+    kernel: PageTable,
+    user: PageTable,
+}
+
+impl Pml4Pair {
+    /// Creates a new pair of empty PML4 tables.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            kernel: PageTable::new(),
+            user: PageTable::new(),
+        }
+    }
+
+    /// Returns the kernel PML4.
+    #[must_use]
+    pub const fn kernel(&self) -> &PageTable {
+        &self.kernel
+    }
+
+    /// Returns the user PML4.
+    #[must_use]
+    pub const fn user(&self) -> &PageTable {
+        &self.user
+    }
+
+    /// Set an entry of the user space half of both PML4 tables, so that the user space is the
+    /// same in kernel and user mode.
+    ///
+    /// # Panics
+    /// Panics if the index is not in the user space half (greater than 255).
+    pub fn set_user_entry(&mut self, index: usize, address: Physical, flags: PageEntryFlags) {
+        assert!(index < USER_ENTRIES, "PML4 index is not in the user space half");
+        self.kernel[index] = PageEntry::new(address, flags);
+        self.user[index] = PageEntry::new(address, flags);
+    }
+
+    /// Set an entry of the kernel half of the kernel PML4 only. The kernel pages mapped by this
+    /// entry will not be visible while running in user mode.
+    ///
+    /// # Panics
+    /// Panics if the index is not in the kernel half (lower than 256 or greater than 511).
+    pub fn set_kernel_entry(&mut self, index: usize, address: Physical, flags: PageEntryFlags) {
+        assert!(
+            (USER_ENTRIES..PageTable::COUNT).contains(&index),
+            "PML4 index is not in the kernel half"
+        );
+        self.kernel[index] = PageEntry::new(address, flags);
+    }
+
+    /// Set an entry of the kernel half of both PML4 tables. This must only be used for the
+    /// entries mapping the trampoline mapping set (see the module documentation).
+    ///
+    /// # Panics
+    /// Panics if the index is not in the kernel half (lower than 256 or greater than 511).
+    pub fn set_trampoline_entry(&mut self, index: usize, address: Physical, flags: PageEntryFlags) {
+        self.set_kernel_entry(index, address, flags);
+        self.user[index] = PageEntry::new(address, flags);
+    }
+
+    /// Clear the given entry in both PML4 tables.
+    ///
+    /// # Panics
+    /// Panics if the index is greater than 511.
+    pub fn clear_entry(&mut self, index: usize) {
+        self.kernel[index].clear();
+        self.user[index].clear();
+    }
+}
+
+impl Default for Pml4Pair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the value to write to CR3 to switch to the kernel PML4 of the pair at the given
+/// physical address, with the given PCID. The PCID must be 0 if PCIDs are not enabled.
+///
+/// # Panics
+/// Panics if the address is not aligned on 8 KiB, or if the PCID is greater than [`MAX_PCID`].
+#[must_use]
+pub fn kernel_cr3(pair: Physical, pcid: u16) -> u64 {
+    assert!(pair.is_aligned(8192u64), "PML4 pair is not aligned on 8 KiB");
+    assert!(pcid <= MAX_PCID, "PCID is reserved for the user PML4");
+    pair.as_u64() | u64::from(pcid)
+}
+
+/// Returns the value of CR3 used in user mode for the given kernel CR3 value (see
+/// [`kernel_cr3`]), without the no-flush bit.
+#[must_use]
+pub fn user_cr3(kernel_cr3: u64, pcid: bool) -> u64 {
+    if pcid {
+        kernel_cr3 | USER_PML4_BIT | USER_PCID_BIT
+    } else {
+        kernel_cr3 | USER_PML4_BIT
+    }
+}
+
+/// Returns `true` if the current CPU is vulnerable to rogue data cache load (Meltdown), and
+/// therefore needs the page table isolation. Only Intel processors are affected.
+#[must_use]
+pub fn needed() -> bool {
+    let intel = crate::cpu::vendor() == Vendor::Intel;
+    intel && !arch_capabilities::read().contains(arch_capabilities::Flags::RDCL_NO)
+}
+
+/// Returns `true` if PCIDs are supported by the current CPU.
+#[must_use]
+pub fn pcid_supported() -> bool {
+    crate::cpu::features().has(Feature::Pcid)
+}
+
+/// Returns `true` if the `invpcid` instruction is supported by the current CPU.
+#[must_use]
+pub fn invpcid_supported() -> bool {
+    crate::cpu::features().has(Feature::Invpcid)
+}
+
+/// Enable the page table isolation on the current CPU. If `pcid` is `true`, PCIDs are enabled
+/// and the TLB is not flushed when switching between the kernel and the user PML4.
+///
+/// # Panics
+/// Panics if `pcid` is `true` but PCIDs are not supported by the current CPU.
+///
+/// # Safety
+/// This function is unsafe because the current CR3 must point to the kernel PML4 of a
+/// [`Pml4Pair`] whose user PML4 maps the trampoline mapping set, and so must all the address
+/// spaces used afterwards. If `pcid` is `true`, the current PCID (the bits 0 to 11 of CR3) must
+/// be 0. The per-CPU area must have been initialized on the current CPU, and the kernel GS must
+/// be active.
+pub unsafe fn enable(pcid: bool) {
+    let bits = if pcid {
+        assert!(pcid_supported(), "PCIDs are not supported by this CPU");
+        cr4::set(cr4::Flags::PCIDE);
+        USER_PML4_BIT | USER_PCID_BIT | NOFLUSH_BIT
+    } else {
+        USER_PML4_BIT
+    };
+    u64::write(PTI_CR3_OFFSET, bits);
+}
+
+/// Disable the page table isolation on the current CPU. PCIDs stay enabled if they were.
+///
+/// # Safety
+/// This function is unsafe because it must be called in kernel mode, with the kernel PML4 active.
+/// The per-CPU area must have been initialized on the current CPU, and the kernel GS must be
+/// active.
+pub unsafe fn disable() {
+    u64::write(PTI_CR3_OFFSET, 0);
+}
+
+/// Returns `true` if the page table isolation is enabled on the current CPU.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized on the current
+/// CPU, and the kernel GS must be active.
+#[must_use]
+pub unsafe fn enabled() -> bool {
+    u64::read(PTI_CR3_OFFSET) != 0
+}
+
+/// Flush all the TLB entries of the user PML4 associated with the given kernel PCID. Since the
+/// TLB is not flushed when returning to user mode with PCIDs, this must be called after changing
+/// the user space mappings of an address space, in addition to the usual invalidation (which
+/// only affects the kernel PCID).
+///
+/// # Panics
+/// Panics if the `invpcid` instruction is not supported, or if the PCID is greater than
+/// [`MAX_PCID`].
+///
+/// # Safety
+/// This function is unsafe because PCIDs must be enabled.
+pub unsafe fn flush_user_pcid(pcid: u16) {
+    assert!(invpcid_supported(), "INVPCID is not supported by this CPU");
+    assert!(pcid <= MAX_PCID, "PCID is reserved for the user PML4");
+
+    // Single-context invalidation (type 1) of the user PCID
+    let descriptor: [u64; 2] = [u64::from(pcid) | USER_PCID_BIT, 0];
+    asm!(
+        "invpcid {}, [{}]",
+        in(reg) 1u64,
+        in(reg) descriptor.as_ptr(),
+        options(nostack, preserves_flags)
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use crate::address::Physical;
+
+    #[test]
+    fn pml4_pair_layout() {
+        assert_eq!(core::mem::align_of::<super::Pml4Pair>(), 8192);
+        assert_eq!(core::mem::size_of::<super::Pml4Pair>(), 8192);
+    }
+
+    #[test]
+    fn cr3_values() {
+        let cr3 = super::kernel_cr3(Physical::new(0x20_0000), 5);
+        assert_eq!(cr3, 0x20_0005);
+        assert_eq!(super::user_cr3(cr3, true), 0x20_1805);
+        assert_eq!(super::user_cr3(cr3, false), 0x20_1005);
+    }
+}
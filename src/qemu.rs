@@ -0,0 +1,68 @@
+//! Helpers for running the kernel in QEMU. The `isa-debug-exit` device makes QEMU exit with a
+//! status computed from the value written to its I/O port, so that tests running in QEMU can
+//! report their result through the exit status of QEMU:
+//! ```text
+//! qemu-system-x86_64 -device isa-debug-exit,iobase=0xf4,iosize=0x04 ...
+//! ```
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::{cpu, io};
+
+/// The default I/O port of the `isa-debug-exit` device.
+pub const DEFAULT_EXIT_PORT: u16 = 0xF4;
+
+/// The I/O port of the QEMU debug console (`-debugcon`), which prints every byte written to it.
+pub const DEBUGCON_PORT: u16 = 0xE9;
+
+/// The I/O port of the `isa-debug-exit` device.
+static EXIT_PORT: AtomicU16 = AtomicU16::new(DEFAULT_EXIT_PORT);
+
+/// Conventional exit codes for tests. Since QEMU exits with the status `(code << 1) | 1`, these
+/// codes give the statuses 33 and 35, which cannot be confused with the status 1 returned by QEMU
+/// itself on error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Set the I/O port of the `isa-debug-exit` device, which is given by the `iobase` option of
+/// the device. The default port is [`DEFAULT_EXIT_PORT`].
+pub fn set_exit_port(port: u16) {
+    EXIT_PORT.store(port, Ordering::Relaxed);
+}
+
+/// Returns the I/O port of the `isa-debug-exit` device.
+#[must_use]
+pub fn exit_port() -> u16 {
+    EXIT_PORT.load(Ordering::Relaxed)
+}
+
+/// Write the given bytes to the QEMU debug console. Unlike a serial port, the debug console never
+/// needs to be initialized and never blocks. The bytes are lost if the device does not exist.
+pub fn debugcon_write(bytes: &[u8]) {
+    for &byte in bytes {
+        // SAFETY: Writing to the debug console port has no effect if the device is not present
+        unsafe {
+            io::outb(DEBUGCON_PORT, byte);
+        }
+    }
+}
+
+/// Exit QEMU with the status `(code << 1) | 1`, by writing the given code to the
+/// `isa-debug-exit` device. If the device does not exist (for example on real hardware), the
+/// current CPU is frozen instead.
+pub fn exit(code: u32) -> ! {
+    // SAFETY: Writing to the debug exit port has no effect if the device is not present, except
+    // on the unlikely hardware using the same port
+    unsafe {
+        io::outd(exit_port(), code);
+    }
+    cpu::freeze();
+}
+
+/// Exit QEMU with the status corresponding to the given conventional exit code (see [`exit`]).
+pub fn exit_with(code: ExitCode) -> ! {
+    exit(code as u32);
+}
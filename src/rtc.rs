@@ -0,0 +1,278 @@
+//! Driver for the real-time clock (RTC) of the CMOS. The CMOS registers are accessed through the
+//! index and data ports shared with the NMI mask (see [`crate::irq::nmi`]), so that selecting a
+//! register never changes the NMI mask.
+use bitflags::bitflags;
+
+use crate::{
+    io::{inb, outb},
+    irq::nmi::{self, DATA_PORT, INDEX_PORT},
+};
+
+/// The RTC registers of the CMOS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Seconds = 0x00,
+    Minutes = 0x02,
+    Hours = 0x04,
+    Day = 0x07,
+    Month = 0x08,
+    Year = 0x09,
+    StatusA = 0x0A,
+    StatusB = 0x0B,
+    StatusC = 0x0C,
+}
+
+/// The century register used by most chipsets, if the ACPI FADT does not provide one.
+pub const DEFAULT_CENTURY_REGISTER: u8 = 0x32;
+
+/// The update in progress bit of the status register A.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+/// The 24-hour format bit of the status register B.
+const HOUR_24: u8 = 1 << 1;
+
+/// The binary format bit of the status register B (BCD format if clear).
+const BINARY: u8 = 1 << 2;
+
+/// The PM bit of the hours register, in the 12-hour format.
+const HOUR_PM: u8 = 1 << 7;
+
+/// The maximal number of reads of the date and time before [`Rtc::read`] gives up waiting for
+/// two consecutive reads to match.
+const MAX_READ_ATTEMPTS: usize = 16;
+
+bitflags! {
+    /// The interrupts of the RTC, delivered on the IRQ 8. The same bits are used to enable the
+    /// interrupts in the status register B, and to report them in the status register C.
+    pub struct Interrupts: u8 {
+        /// The update-ended interrupt, fired once per second after the clock is updated.
+        const UPDATE = 1 << 4;
+
+        /// The alarm interrupt.
+        const ALARM = 1 << 5;
+
+        /// The periodic interrupt, fired at the rate set with [`super::Rtc::enable_periodic`].
+        const PERIODIC = 1 << 6;
+    }
+}
+
+/// A date and time read from the RTC. The RTC does not know the time zone, it is usually the UTC
+/// time but may be the local time on computers that also run Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// The raw values of the date and time registers, in the format of the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+impl RawDateTime {
+    /// Decode the raw values, given the value of the status register B which indicates whether
+    /// the values are in BCD or binary, and whether the hours are in the 12 or 24-hour format.
+    /// Without a century register, the year is assumed to be in the 21st century.
+    fn decode(self, status_b: u8) -> DateTime {
+        let convert = |value: u8| {
+            if status_b & BINARY != 0 {
+                value
+            } else {
+                (value >> 4) * 10 + (value & 0x0F)
+            }
+        };
+
+        let mut hour = convert(self.hour & !HOUR_PM);
+        if status_b & HOUR_24 == 0 {
+            hour %= 12;
+            if self.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+
+        let century = self.century.map_or(20, convert);
+        DateTime {
+            year: u16::from(century) * 100 + u16::from(convert(self.year)),
+            month: convert(self.month),
+            day: convert(self.day),
+            hour,
+            minute: convert(self.minute),
+            second: convert(self.second),
+        }
+    }
+}
+
+/// The real-time clock of the CMOS.
+#[derive(Debug, Clone)]
+pub struct Rtc {
+    century: Option<u8>,
+}
+
+impl Rtc {
+    /// Creates a new handle to the RTC. The century register is given by the ACPI FADT, and is
+    /// usually [`DEFAULT_CENTURY_REGISTER`]. If there is no century register, the years read are
+    /// assumed to be in the 21st century.
+    ///
+    /// # Safety
+    /// This function is unsafe because the CMOS must exist, and only one handle to the RTC must
+    /// exist at a time, since the CMOS ports must not be accessed concurrently.
+    #[must_use]
+    pub const unsafe fn new(century: Option<u8>) -> Self {
+        Self { century }
+    }
+
+    /// Read the current date and time. The RTC is read only when no update is in progress, and
+    /// read twice until two consecutive reads match, so that the values are not taken in the
+    /// middle of an update. Returns `None` if the values keep changing, which should never happen
+    /// with a working RTC.
+    ///
+    /// # Safety
+    /// This function is unsafe because it accesses the CMOS ports: interrupts should be disabled
+    /// so that an interrupt handler cannot select another register between the index and the
+    /// data accesses.
+    #[must_use]
+    pub unsafe fn read(&self) -> Option<DateTime> {
+        let mut last = self.read_raw();
+        for _ in 0..MAX_READ_ATTEMPTS {
+            let current = self.read_raw();
+            if current == last {
+                return Some(current.decode(read(Register::StatusB as u8)));
+            }
+            last = current;
+        }
+        None
+    }
+
+    /// Enable the periodic interrupt with the given rate. The frequency of the interrupt is
+    /// `32768 >> (rate - 1)` Hz, from 8192 Hz (rate 3) to 2 Hz (rate 15).
+    ///
+    /// # Panics
+    /// Panics if the rate is not between 3 and 15.
+    ///
+    /// # Safety
+    /// This function is unsafe because the IRQ 8 will be fired periodically, and its handler
+    /// must call [`Rtc::acknowledge`], otherwise no other interrupt will be fired.
+    pub unsafe fn enable_periodic(&self, rate: u8) {
+        assert!((3..=15).contains(&rate), "Invalid RTC periodic rate {rate}");
+        let status_a = read(Register::StatusA as u8);
+        write(Register::StatusA as u8, (status_a & 0xF0) | rate);
+        self.enable(Interrupts::PERIODIC);
+    }
+
+    /// Enable the given interrupts.
+    ///
+    /// # Safety
+    /// This function is unsafe because the IRQ 8 will be fired, and its handler must call
+    /// [`Rtc::acknowledge`], otherwise no other interrupt will be fired.
+    pub unsafe fn enable(&self, interrupts: Interrupts) {
+        let status_b = read(Register::StatusB as u8);
+        write(Register::StatusB as u8, status_b | interrupts.bits());
+    }
+
+    /// Disable the given interrupts.
+    ///
+    /// # Safety
+    /// This function is unsafe because it accesses the CMOS ports (see [`Rtc::read`]).
+    pub unsafe fn disable(&self, interrupts: Interrupts) {
+        let status_b = read(Register::StatusB as u8);
+        write(Register::StatusB as u8, status_b & !interrupts.bits());
+    }
+
+    /// Acknowledge the pending interrupts of the RTC, and return them. This must be called by the
+    /// handler of the IRQ 8, otherwise the RTC will not fire any other interrupt.
+    ///
+    /// # Safety
+    /// This function is unsafe because it accesses the CMOS ports (see [`Rtc::read`]).
+    #[must_use]
+    pub unsafe fn acknowledge(&self) -> Interrupts {
+        Interrupts::from_bits_truncate(read(Register::StatusC as u8))
+    }
+
+    /// Wait until no update is in progress, and read the raw values of the date and time.
+    unsafe fn read_raw(&self) -> RawDateTime {
+        while read(Register::StatusA as u8) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+
+        RawDateTime {
+            second: read(Register::Seconds as u8),
+            minute: read(Register::Minutes as u8),
+            hour: read(Register::Hours as u8),
+            day: read(Register::Day as u8),
+            month: read(Register::Month as u8),
+            year: read(Register::Year as u8),
+            century: self.century.map(|register| read(register)),
+        }
+    }
+}
+
+/// Read the given CMOS register, preserving the NMI mask.
+///
+/// # Safety
+/// This function is unsafe because it accesses the CMOS ports (see [`Rtc::read`]).
+#[must_use]
+pub unsafe fn read(register: u8) -> u8 {
+    outb(INDEX_PORT, nmi::index(register));
+    inb(DATA_PORT)
+}
+
+/// Write the given value to the given CMOS register, preserving the NMI mask.
+///
+/// # Safety
+/// This function is unsafe because it accesses the CMOS ports (see [`Rtc::read`]), and writing
+/// to some CMOS registers can change the configuration of the firmware.
+pub unsafe fn write(register: u8, value: u8) {
+    outb(INDEX_PORT, nmi::index(register));
+    outb(DATA_PORT, value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DateTime, RawDateTime, BINARY, HOUR_24};
+
+    #[test]
+    fn date_time_decoding() {
+        let raw = RawDateTime {
+            second: 0x59,
+            minute: 0x30,
+            hour: 0x80 | 0x12,
+            day: 0x31,
+            month: 0x12,
+            year: 0x23,
+            century: None,
+        };
+        let expected = DateTime {
+            year: 2023,
+            month: 12,
+            day: 31,
+            hour: 12,
+            minute: 30,
+            second: 59,
+        };
+        assert_eq!(raw.decode(0), expected);
+
+        let raw = RawDateTime {
+            hour: 12,
+            century: Some(19),
+            year: 99,
+            ..raw
+        };
+        let decoded = raw.decode(BINARY | HOUR_24);
+        assert_eq!(decoded.year, 1999);
+        assert_eq!(decoded.hour, 12);
+
+        let raw = RawDateTime { hour: 0x12, ..raw };
+        assert_eq!(raw.decode(0).hour, 0);
+    }
+}
@@ -1,4 +1,4 @@
-use core::arch::asm;
+use core::{arch::asm, fmt};
 
 use crate::cpu::Privilege;
 
@@ -25,6 +25,83 @@ impl Selector {
     }
 }
 
+/// A descriptor table referenced by a [`SelectorErrorCode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl fmt::Display for DescriptorTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Gdt => "GDT",
+            Self::Idt => "IDT",
+            Self::Ldt => "LDT",
+        })
+    }
+}
+
+/// The error code pushed by the CPU for the exceptions caused by a segment selector or an IDT
+/// entry: invalid TSS (#TS), segment not present (#NP), stack-segment fault (#SS) and general
+/// protection fault (#GP). An error code of 0 means that the fault is not related to a specific
+/// descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    /// Creates a new selector error code from the raw error code pushed by the CPU.
+    #[must_use]
+    pub const fn new(code: u64) -> Self {
+        Self(code)
+    }
+
+    /// Returns the raw error code.
+    #[must_use]
+    pub const fn value(self) -> u64 {
+        self.0
+    }
+
+    /// Returns `true` if the exception was caused by an event external to the program, such as
+    /// a hardware interrupt, and not by the executed instruction.
+    #[must_use]
+    pub const fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    /// Returns the descriptor table containing the descriptor at fault.
+    #[must_use]
+    pub const fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0 => DescriptorTable::Gdt,
+            2 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Returns the index of the descriptor at fault in its table. For the IDT, this is the
+    /// interrupt vector.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1FFF) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "index={:#x}, table={}, external={}",
+            self.index(),
+            self.table(),
+            self.external()
+        )
+    }
+}
+
 pub struct CS;
 impl CS {
     /// Read the current code segment selector.
@@ -161,3 +238,24 @@ pub unsafe fn reload(code: &Selector, data: &Selector) {
     SS::write(data.0);
     CS::write(code.0);
 }
+
+#[cfg(test)]
+mod test {
+    use super::{DescriptorTable, SelectorErrorCode};
+
+    #[test]
+    fn selector_error_code() {
+        // A #GP raised by a hardware interrupt using the IDT entry 0x21
+        let code = SelectorErrorCode::new((0x21 << 3) | 0b011);
+        assert!(code.external());
+        assert_eq!(code.table(), DescriptorTable::Idt);
+        assert_eq!(code.index(), 0x21);
+        assert_eq!(code.to_string(), "index=0x21, table=IDT, external=true");
+
+        let code = SelectorErrorCode::new((5 << 3) | 0b100);
+        assert!(!code.external());
+        assert_eq!(code.table(), DescriptorTable::Ldt);
+        assert_eq!(code.index(), 5);
+        assert_eq!(SelectorErrorCode::new(0x18).table(), DescriptorTable::Gdt);
+    }
+}
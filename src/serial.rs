@@ -1,6 +1,24 @@
-use crate::io;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 
-#[derive(Copy, Clone, Debug)]
+use bitflags::bitflags;
+
+use crate::{address::Virtual, io};
+
+/// The frequency of the UART clock divided by 16, which is the maximal baud rate.
+pub const MAX_BAUD: u32 = 115_200;
+
+/// The number of line status reads before [`Serial::self_test`] gives up waiting for a byte sent
+/// in loopback mode.
+const LOOPBACK_ATTEMPTS: usize = 1000;
+
+/// The divisor latch access bit of the line control register. When set, the data and interrupt
+/// enable registers give access to the low and high bytes of the baud rate divisor.
+const DLAB: u8 = 1 << 7;
+
+/// The physical address of the COM port base addresses in the BIOS data area.
+pub const BDA_COM_PORTS: u64 = 0x400;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Port {
     COM1 = 0x3F8,
     COM2 = 0x2F8,
@@ -8,7 +26,456 @@ pub enum Port {
     COM4 = 0x2E8,
 }
 
+impl Port {
+    /// Returns the port with the given base I/O port, or `None` if it is not a standard COM
+    /// port base.
+    #[must_use]
+    pub const fn from_base(base: u16) -> Option<Port> {
+        match base {
+            0x3F8 => Some(Port::COM1),
+            0x2F8 => Some(Port::COM2),
+            0x3E8 => Some(Port::COM3),
+            0x2E8 => Some(Port::COM4),
+            _ => None,
+        }
+    }
+
+    /// Returns the ISA IRQ line used by the port: the IRQ 4 for COM1 and COM3, and the IRQ 3 for
+    /// COM2 and COM4. COM1 and COM3 (and COM2 and COM4) share the same line.
+    #[must_use]
+    pub const fn irq(&self) -> u8 {
+        match self {
+            Port::COM1 | Port::COM3 => 4,
+            Port::COM2 | Port::COM4 => 3,
+        }
+    }
+
+    /// Discover the COM ports detected by the BIOS, from the four base addresses stored in the
+    /// BIOS data area. The entries set to 0 by the BIOS, or containing a non-standard base, are
+    /// `None`. The BIOS data area does not exist on UEFI-only systems, where [`Serial::probe`]
+    /// should be used on each standard port instead.
+    ///
+    /// # Safety
+    /// This function is unsafe because the given address must be the virtual address where
+    /// [`BDA_COM_PORTS`] is mapped, and the BIOS data area must not have been overwritten.
+    #[must_use]
+    pub unsafe fn discover(bda: Virtual) -> [Option<Port>; 4] {
+        let bases = bda.as_ptr::<u16>();
+        let mut ports = [None; 4];
+        for (i, port) in ports.iter_mut().enumerate() {
+            *port = Port::from_base(core::ptr::read_volatile(bases.add(i)));
+        }
+        ports
+    }
+}
+
+/// The model of a UART, identified by [`Serial::probe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    /// The original UART, without FIFO and without scratch register.
+    Uart8250,
+
+    /// A UART without FIFO, but with a scratch register.
+    Uart16450,
+
+    /// A UART with a FIFO that does not work reliably: it must not be enabled.
+    Uart16550,
+
+    /// A UART with a working 16 bytes FIFO. Most emulated and modern UARTs are 16550A
+    /// compatible.
+    Uart16550A,
+
+    /// A 16550A compatible UART with a 64 bytes FIFO and automatic hardware flow control. The
+    /// FIFO is only used in its 16 bytes mode by this driver.
+    Uart16750,
+}
+
+impl Model {
+    /// Returns `true` if the UART has a FIFO that can safely be used.
+    #[must_use]
+    pub const fn has_fifo(&self) -> bool {
+        matches!(self, Model::Uart16550A | Model::Uart16750)
+    }
+
+    /// Returns `true` if the UART supports automatic hardware flow control (see
+    /// [`Serial::set_auto_flow_control`]).
+    #[must_use]
+    pub const fn has_auto_flow_control(&self) -> bool {
+        matches!(self, Model::Uart16750)
+    }
+}
+
+bitflags! {
+    /// The flags of the modem control register.
+    pub struct ModemControl: u8 {
+        /// Data terminal ready output.
+        const DTR = 1 << 0;
+
+        /// Request to send output. With the automatic flow control, the RTS output is also
+        /// driven by the receive FIFO level while this bit is set.
+        const RTS = 1 << 1;
+
+        /// Auxiliary output 1, unused on PC.
+        const OUT1 = 1 << 2;
+
+        /// Auxiliary output 2, which enables the IRQ line of the UART on PC.
+        const OUT2 = 1 << 3;
+
+        /// Loopback mode: the transmitted bytes are received by the UART itself, and the modem
+        /// outputs are connected to the modem inputs.
+        const LOOPBACK = 1 << 4;
+
+        /// Automatic flow control, on UARTs supporting it (see
+        /// [`super::Model::has_auto_flow_control`]): the transmission is paused while CTS is
+        /// inactive.
+        const AUTO_FLOW = 1 << 5;
+    }
+}
+
+bitflags! {
+    /// The flags of the modem status register. The delta flags are set when the corresponding
+    /// input changed since the last read of the register, and are cleared by reading it.
+    pub struct ModemStatus: u8 {
+        const DELTA_CTS = 1 << 0;
+        const DELTA_DSR = 1 << 1;
+
+        /// The ring indicator input went from active to inactive.
+        const TRAILING_EDGE_RI = 1 << 2;
+        const DELTA_DCD = 1 << 3;
+
+        /// Clear to send input.
+        const CTS = 1 << 4;
+
+        /// Data set ready input.
+        const DSR = 1 << 5;
+
+        /// Ring indicator input.
+        const RI = 1 << 6;
+
+        /// Data carrier detect input.
+        const DCD = 1 << 7;
+    }
+}
+
+/// The depth of the transmit FIFO of a 16550A.
+pub const FIFO_DEPTH: usize = 16;
+
+/// The number of bytes that can be stored in a [`RingBuffer`].
+pub const BUFFER_SIZE: usize = 256;
+
+/// The received data available interrupt bit of the interrupt enable register.
+const RX_INTERRUPT: u8 = 1 << 0;
+
+/// The transmitter holding register empty interrupt bit of the interrupt enable register.
+const TX_INTERRUPT: u8 = 1 << 1;
+
+/// The data ready bit of the line status register.
+const DATA_READY: u8 = 1 << 0;
+
+/// The transmitter holding register empty bit of the line status register.
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+bitflags! {
+    /// The reception errors reported by the line status register. Reading the line status
+    /// register clears these bits.
+    pub struct LineErrors: u8 {
+        /// A byte was received while the receive buffer (or the FIFO) was full: at least one
+        /// byte was lost.
+        const OVERRUN = 1 << 1;
+
+        /// The parity bit of the received byte is wrong.
+        const PARITY = 1 << 2;
+
+        /// The received byte does not have a valid stop bit.
+        const FRAMING = 1 << 3;
+
+        /// The line has been held at 0 for longer than a whole word.
+        const BREAK = 1 << 4;
+    }
+}
+
+/// The operation cannot be performed now without blocking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldBlock;
+
+/// An error returned by [`Serial::try_read`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// No byte has been received.
+    WouldBlock,
+
+    /// Reception errors were detected on the line.
+    Line(LineErrors),
+}
+
+/// An error returned by [`Serial::self_test`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// The line status register reads as `0xFF`, which is the value of a floating bus: there is
+    /// no UART behind the port.
+    NotPresent,
+
+    /// The byte sent in loopback mode was not received: there is probably no UART behind the
+    /// port.
+    Timeout,
+
+    /// A different byte than the one sent was received.
+    Mismatch { sent: u8, received: u8 },
+
+    /// The modem inputs do not follow the modem outputs in loopback mode.
+    ModemLines,
+}
+
+/// The parity bit sent after each word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+
+    /// The parity bit is always set.
+    Mark = 0b101,
+
+    /// The parity bit is always clear.
+    Space = 0b111,
+}
+
+/// The number of stop bits sent after each word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One = 0,
+
+    /// Two stop bits, or one and a half stop bits if the word length is 5 bits.
+    Two = 1,
+}
+
+/// The number of data bits in each word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordLength {
+    Five = 0b00,
+    Six = 0b01,
+    Seven = 0b10,
+    Eight = 0b11,
+}
+
+/// The baud rate is not supported by the UART: it must be a divisor of [`MAX_BAUD`], and the
+/// divisor must fit in the 16-bit divisor latch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidBaudRate;
+
+/// The configuration of the line of a serial port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    baud: u32,
+    parity: Parity,
+    stop_bits: StopBits,
+    word_length: WordLength,
+}
+
+impl Config {
+    /// The configuration used by [`Serial::init_com`]: 38400 bauds, 8 data bits, no parity and
+    /// one stop bit.
+    pub const DEFAULT: Config = Config::new(38400, Parity::None, StopBits::One, WordLength::Eight);
+
+    /// Creates a new line configuration.
+    ///
+    /// # Panics
+    /// This function panics if the baud rate is not supported (see [`InvalidBaudRate`]).
+    #[must_use]
+    pub const fn new(
+        baud: u32,
+        parity: Parity,
+        stop_bits: StopBits,
+        word_length: WordLength,
+    ) -> Self {
+        match Self::try_new(baud, parity, stop_bits, word_length) {
+            Ok(config) => config,
+            Err(InvalidBaudRate) => panic!("Unsupported serial baud rate"),
+        }
+    }
+
+    /// Tries to create a new line configuration.
+    ///
+    /// # Errors
+    /// This function returns an [`InvalidBaudRate`] error if the baud rate is not a divisor of
+    /// [`MAX_BAUD`], since the UART cannot generate it exactly, or if it is lower than 2 bauds.
+    pub const fn try_new(
+        baud: u32,
+        parity: Parity,
+        stop_bits: StopBits,
+        word_length: WordLength,
+    ) -> Result<Self, InvalidBaudRate> {
+        if baud < 2 || baud > MAX_BAUD || MAX_BAUD % baud != 0 {
+            return Err(InvalidBaudRate);
+        }
+        Ok(Self {
+            baud,
+            parity,
+            stop_bits,
+            word_length,
+        })
+    }
+
+    /// Returns the baud rate.
+    #[must_use]
+    pub const fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Returns the parity.
+    #[must_use]
+    pub const fn parity(&self) -> Parity {
+        self.parity
+    }
+
+    /// Returns the number of stop bits.
+    #[must_use]
+    pub const fn stop_bits(&self) -> StopBits {
+        self.stop_bits
+    }
+
+    /// Returns the word length.
+    #[must_use]
+    pub const fn word_length(&self) -> WordLength {
+        self.word_length
+    }
+
+    /// Returns the value of the baud rate divisor latch for this configuration.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn divisor(&self) -> u16 {
+        // The baud rate is at least 2 bauds, so the divisor is at most 57600
+        (MAX_BAUD / self.baud) as u16
+    }
+
+    /// Returns the value of the line control register for this configuration, with the divisor
+    /// latch access bit clear.
+    const fn line_control(self) -> u8 {
+        self.word_length as u8 | (self.stop_bits as u8) << 2 | (self.parity as u8) << 3
+    }
+
+    /// Decode a configuration from the values of the line control register and of the divisor
+    /// latch. Returns `None` if the divisor is 0.
+    const fn from_registers(line_control: u8, divisor: u16) -> Option<Self> {
+        if divisor == 0 {
+            return None;
+        }
+
+        let word_length = match line_control & 0b11 {
+            0b00 => WordLength::Five,
+            0b01 => WordLength::Six,
+            0b10 => WordLength::Seven,
+            _ => WordLength::Eight,
+        };
+        let stop_bits = if line_control & (1 << 2) != 0 {
+            StopBits::Two
+        } else {
+            StopBits::One
+        };
+        let parity = match (line_control >> 3) & 0b111 {
+            0b001 => Parity::Odd,
+            0b011 => Parity::Even,
+            0b101 => Parity::Mark,
+            0b111 => Parity::Space,
+            _ => Parity::None,
+        };
+
+        Some(Self {
+            baud: MAX_BAUD / divisor as u32,
+            parity,
+            stop_bits,
+            word_length,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// A fixed-size lock-free ring buffer of bytes, with a single producer and a single consumer,
+/// used to exchange bytes between the interrupt handler of a serial port and the rest of the
+/// kernel. The producer and the consumer may run concurrently, but there must be only one of
+/// each at a time.
+#[derive(Debug)]
+pub struct RingBuffer {
+    data: [AtomicU8; BUFFER_SIZE],
+
+    /// The number of bytes pushed since the creation of the buffer, wrapping around.
+    head: AtomicUsize,
+
+    /// The number of bytes popped since the creation of the buffer, wrapping around.
+    tail: AtomicUsize,
+}
+
+impl RingBuffer {
+    /// Creates a new empty ring buffer.
+    #[must_use]
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicU8 = AtomicU8::new(0);
+        Self {
+            data: [EMPTY; BUFFER_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Push a byte at the end of the buffer. Returns `false` if the buffer is full, in which case
+    /// the byte is not pushed. This must only be called by the producer.
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == BUFFER_SIZE {
+            return false;
+        }
+        self.data[head % BUFFER_SIZE].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pop the byte at the start of the buffer, or returns `None` if the buffer is empty. This
+    /// must only be called by the consumer.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.data[tail % BUFFER_SIZE].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Returns the number of bytes in the buffer.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    /// Returns `true` if the buffer is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for RingBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Serial {
+    rx: RingBuffer,
+    tx: RingBuffer,
+
+    /// Set by [`Serial::probe`] if the UART has a working FIFO, which has been enabled.
+    fifo: AtomicBool,
+
+    /// Set by [`Serial::probe`] if the UART supports automatic hardware flow control.
+    auto_flow: AtomicBool,
     data: io::Port<u8>,
     interrupt_enable: io::Port<u8>,
     fifo_control: io::Port<u8>,
@@ -24,6 +491,10 @@ impl Serial {
     pub const fn new(com: Port) -> Serial {
         unsafe {
             Serial {
+                rx: RingBuffer::new(),
+                tx: RingBuffer::new(),
+                fifo: AtomicBool::new(false),
+                auto_flow: AtomicBool::new(false),
                 data: io::Port::new(com as u16),
                 interrupt_enable: io::Port::new(com as u16 + 1),
                 fifo_control: io::Port::new(com as u16 + 2),
@@ -41,25 +512,377 @@ impl Serial {
     /// function probably doesn't work on real hardware.
     pub fn init_com(&self) {
         self.interrupt_enable.write(0x00);
-        self.line_control.write(0x80);
-        self.data.write(0x03);
-        self.interrupt_enable.write(0x00);
-        self.line_control.write(0x03);
+        self.configure(&Config::DEFAULT);
         self.fifo_control.write(0xC7);
         self.modem_control.write(0x0B);
         // We don't test if the line is ready to be written to here (I'm lazy)
     }
 
+    /// Configure the line of the serial port: the baud rate divisor and the line control
+    /// register. Bytes being transmitted or received while the line is reconfigured may be
+    /// corrupted.
+    pub fn configure(&self, config: &Config) {
+        let [low, high] = config.divisor().to_le_bytes();
+        self.line_control.write(DLAB);
+        self.data.write(low);
+        self.interrupt_enable.write(high);
+        self.line_control.write(config.line_control());
+    }
+
+    /// Read the current line configuration of the serial port from its registers. Returns `None`
+    /// if the baud rate divisor is 0, which happens if the port has never been configured.
+    #[must_use]
+    pub fn config(&self) -> Option<Config> {
+        let line_control = self.line_control.read();
+        self.line_control.write(line_control | DLAB);
+        let divisor = u16::from_le_bytes([self.data.read(), self.interrupt_enable.read()]);
+        self.line_control.write(line_control & !DLAB);
+        Config::from_registers(line_control, divisor)
+    }
+
+    /// Returns the current baud rate of the serial port, or `None` if it has never been
+    /// configured (see [`Serial::config`]).
+    #[must_use]
+    pub fn baud(&self) -> Option<u32> {
+        self.config().map(|config| config.baud())
+    }
+
+    /// Detect whether a UART is present behind this port and identify its model. The presence
+    /// is tested with [`Serial::self_test`], and the model is identified
+    /// with the FIFO bits of the interrupt identification register and with the scratch register.
+    /// When this function returns, the FIFOs are enabled and cleared if the UART is a 16550A, and
+    /// disabled otherwise. The modem control register is restored, but a byte being received
+    /// may be lost. Returns `None` if no UART is present.
+    #[must_use]
+    pub fn probe(&self) -> Option<Model> {
+        self.self_test().ok()?;
+
+        // Enable the FIFOs and read back their state from the interrupt identification register,
+        // which is at the same I/O port as the FIFO control register
+        self.fifo_control.write(0xC7);
+        let model = match self.fifo_control.read() >> 6 {
+            0b11 if self.has_64_bytes_fifo() => Model::Uart16750,
+            0b11 => Model::Uart16550A,
+            0b10 => Model::Uart16550,
+            _ if self.scratch_works() => Model::Uart16450,
+            _ => Model::Uart8250,
+        };
+
+        if !model.has_fifo() {
+            self.fifo_control.write(0x00);
+        }
+        self.fifo.store(model.has_fifo(), Ordering::Relaxed);
+        self.auto_flow
+            .store(model.has_auto_flow_control(), Ordering::Relaxed);
+        Some(model)
+    }
+
+    /// Test whether the UART has a 64 bytes FIFO, which identifies a 16750. The 64 bytes mode
+    /// can only be enabled while the divisor latch access bit is set, and is reported by the bit
+    /// 5 of the interrupt identification register. The FIFO is left in its 16 bytes mode.
+    fn has_64_bytes_fifo(&self) -> bool {
+        let line_control = self.line_control.read();
+        self.line_control.write(line_control | DLAB);
+        self.fifo_control.write(0xE7);
+        let identification = self.fifo_control.read();
+        self.line_control.write(line_control);
+        self.fifo_control.write(0xC7);
+        identification & (1 << 5) != 0
+    }
+
+    /// Test the UART in loopback mode, without sending anything on the line: a pattern of bytes is
+    /// sent and must be received back, and the modem inputs must follow the modem outputs. The
+    /// previous modem control register is restored afterwards, and the bytes received before
+    /// the test are discarded.
+    ///
+    /// If this test succeeds but nothing is received from the other end of the line, the
+    /// problem is the cable or the configuration of the line, not the UART.
+    ///
+    /// # Errors
+    /// Returns a [`SelfTestError`] describing the first check that failed.
+    pub fn self_test(&self) -> Result<(), SelfTestError> {
+        if self.line_status.read() == 0xFF {
+            return Err(SelfTestError::NotPresent);
+        }
+
+        let control = self.modem_control();
+        self.set_modem_control(ModemControl::LOOPBACK);
+        for _ in 0..=FIFO_DEPTH {
+            if !self.data_pending() {
+                break;
+            }
+            let _ = self.data.read();
+        }
+
+        let result = self.loopback_bytes().and_then(|()| {
+            // In loopback mode, DTR, RTS, OUT1 and OUT2 are connected to DSR, CTS, RI and DCD
+            let outputs =
+                ModemControl::DTR | ModemControl::RTS | ModemControl::OUT1 | ModemControl::OUT2;
+            self.set_modem_control(ModemControl::LOOPBACK | outputs);
+            let inputs = ModemStatus::CTS | ModemStatus::DSR | ModemStatus::RI | ModemStatus::DCD;
+            if self.modem_status() & inputs == inputs {
+                Ok(())
+            } else {
+                Err(SelfTestError::ModemLines)
+            }
+        });
+
+        self.set_modem_control(control);
+        result
+    }
+
+    /// Send the self-test pattern in loopback mode and check that it is received back.
+    fn loopback_bytes(&self) -> Result<(), SelfTestError> {
+        for sent in [0xAE, 0x55, 0xAA, 0x00, 0xFF] {
+            self.write(sent);
+            let mut attempts = 0;
+            while !self.data_pending() {
+                if attempts == LOOPBACK_ATTEMPTS {
+                    return Err(SelfTestError::Timeout);
+                }
+                core::hint::spin_loop();
+                attempts += 1;
+            }
+
+            let received = self.data.read();
+            if received != sent {
+                return Err(SelfTestError::Mismatch { sent, received });
+            }
+        }
+        Ok(())
+    }
+
+    /// Test whether the scratch register of the UART keeps the values written to it.
+    fn scratch_works(&self) -> bool {
+        [0x55, 0xAA].iter().all(|&value| {
+            self.scratch.write(value);
+            self.scratch.read() == value
+        })
+    }
+
+    /// Read the modem control register.
+    #[must_use]
+    pub fn modem_control(&self) -> ModemControl {
+        ModemControl::from_bits_truncate(self.modem_control.read())
+    }
+
+    /// Write the modem control register. Clearing [`ModemControl::OUT2`] disables the interrupts
+    /// of the port on PC.
+    pub fn set_modem_control(&self, control: ModemControl) {
+        self.modem_control.write(control.bits());
+    }
+
+    /// Read the modem status register. This clears the delta flags of the register.
+    #[must_use]
+    pub fn modem_status(&self) -> ModemStatus {
+        ModemStatus::from_bits_truncate(self.modem_status.read())
+    }
+
+    /// Set or clear the request to send output.
+    pub fn set_rts(&self, active: bool) {
+        self.update_modem_control(ModemControl::RTS, active);
+    }
+
+    /// Set or clear the data terminal ready output.
+    pub fn set_dtr(&self, active: bool) {
+        self.update_modem_control(ModemControl::DTR, active);
+    }
+
+    /// Enable or disable the loopback mode, in which the transmitted bytes are received by the
+    /// UART itself instead of being sent on the line.
+    pub fn set_loopback(&self, enabled: bool) {
+        self.update_modem_control(ModemControl::LOOPBACK, enabled);
+    }
+
+    /// Returns `true` if the clear to send input is active.
+    #[must_use]
+    pub fn cts(&self) -> bool {
+        self.modem_status().contains(ModemStatus::CTS)
+    }
+
+    /// Returns `true` if the data set ready input is active.
+    #[must_use]
+    pub fn dsr(&self) -> bool {
+        self.modem_status().contains(ModemStatus::DSR)
+    }
+
+    /// Returns `true` if the data carrier detect input is active.
+    #[must_use]
+    pub fn dcd(&self) -> bool {
+        self.modem_status().contains(ModemStatus::DCD)
+    }
+
+    /// Enable or disable the automatic hardware flow control: when enabled, the UART pauses the
+    /// transmission while CTS is inactive, and deactivates RTS when its receive FIFO is almost
+    /// full. Returns `false` if [`Serial::probe`] did not detect a UART supporting it, in which
+    /// case nothing is changed.
+    pub fn set_auto_flow_control(&self, enabled: bool) -> bool {
+        if !self.auto_flow.load(Ordering::Relaxed) {
+            return false;
+        }
+        if enabled {
+            self.update_modem_control(ModemControl::AUTO_FLOW | ModemControl::RTS, true);
+        } else {
+            self.update_modem_control(ModemControl::AUTO_FLOW, false);
+        }
+        true
+    }
+
+    /// Set or clear the given flags of the modem control register.
+    fn update_modem_control(&self, flags: ModemControl, set: bool) {
+        let mut control = self.modem_control();
+        control.set(flags, set);
+        self.set_modem_control(control);
+    }
+
+    /// Enable the received data interrupt, so that received bytes are stored in the receive
+    /// buffer by [`Serial::on_interrupt`] and read with [`Serial::read_buffered`] instead of
+    /// polling the port. The interrupt is delivered on the IRQ line of the port (see
+    /// [`Port::irq`]), if the OUT2 bit of the modem control register is set (which is done by
+    /// [`Serial::init_com`]).
+    ///
+    /// # Safety
+    /// This function is unsafe because the handler of the IRQ line must be installed and must
+    /// call [`Serial::on_interrupt`], otherwise the interrupt will be fired again and again.
+    pub unsafe fn enable_rx_interrupts(&self) {
+        self.interrupt_enable.set_bits(RX_INTERRUPT);
+    }
+
+    /// Disable the received data interrupt. The bytes already in the receive buffer can still be
+    /// read with [`Serial::read_buffered`].
+    pub fn disable_rx_interrupts(&self) {
+        self.interrupt_enable.clear_bits(RX_INTERRUPT);
+    }
+
+    /// Handle an interrupt of the serial port: drain the received bytes into the receive buffer,
+    /// and send the bytes of the transmit buffer while the transmitter is ready. The bytes
+    /// received with errors are discarded (see [`Serial::try_read`]), and the bytes received
+    /// while the buffer is full are lost. This must be called by the handler of the IRQ line of
+    /// the port, and must not be called concurrently on the same port.
+    pub fn on_interrupt(&self) {
+        loop {
+            match self.try_read() {
+                Ok(byte) => {
+                    let _ = self.rx.push(byte);
+                }
+                // The corrupted byte has been discarded: try the next one
+                Err(SerialError::Line(_)) => {}
+                Err(SerialError::WouldBlock) => break,
+            }
+        }
+        self.drain_tx();
+    }
+
+    /// Queue the given bytes in the transmit buffer, and enable the transmitter holding register
+    /// empty interrupt so that they are sent by [`Serial::on_interrupt`] without waiting for the
+    /// port. Returns the number of bytes queued, which is lower than the number of given bytes if
+    /// the buffer is full.
+    ///
+    /// # Safety
+    /// This function is unsafe because the handler of the IRQ line of the port (see
+    /// [`Port::irq`]) must be installed and must call [`Serial::on_interrupt`], otherwise the
+    /// bytes will never be sent. This function must not be called concurrently on the same port,
+    /// and must not interrupt [`Serial::on_interrupt`] on the same port.
+    pub unsafe fn write_buffered(&self, bytes: &[u8]) -> usize {
+        let queued = bytes.iter().take_while(|&&byte| self.tx.push(byte)).count();
+        if queued > 0 {
+            // The UART fires an interrupt as soon as this interrupt is enabled if the
+            // transmitter holding register is empty, which starts the transmission
+            self.interrupt_enable.set_bits(TX_INTERRUPT);
+        }
+        queued
+    }
+
+    /// Send all the bytes of the transmit buffer by polling the port, and wait until they have
+    /// been transmitted. This is meant for the panic path, where the interrupts cannot be relied
+    /// upon anymore: the transmitter holding register empty interrupt is disabled.
+    ///
+    /// If [`Serial::on_interrupt`] runs concurrently on another CPU, some bytes may be sent
+    /// twice or out of order.
+    pub fn flush(&self) {
+        self.interrupt_enable.clear_bits(TX_INTERRUPT);
+        while let Some(byte) = self.tx.pop() {
+            self.write(byte);
+        }
+        while !self.is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Send the bytes of the transmit buffer while the transmitter is ready. When the buffer is
+    /// empty, the transmitter holding register empty interrupt is disabled, otherwise the UART
+    /// would keep asking for more bytes.
+    fn drain_tx(&self) {
+        while !self.tx.is_empty() && self.is_transmit_empty() {
+            if let Some(byte) = self.tx.pop() {
+                self.data.write(byte);
+            }
+        }
+
+        if self.tx.is_empty() {
+            self.interrupt_enable.clear_bits(TX_INTERRUPT);
+
+            // A byte may have been queued just before the interrupt was disabled: enable it
+            // again so that it is not stuck in the buffer
+            if !self.tx.is_empty() {
+                self.interrupt_enable.set_bits(TX_INTERRUPT);
+            }
+        }
+    }
+
+    /// Read a byte from the receive buffer filled by [`Serial::on_interrupt`], or returns `None`
+    /// if it is empty. This must not be called concurrently on the same port.
+    #[must_use]
+    pub fn read_buffered(&self) -> Option<u8> {
+        self.rx.pop()
+    }
+
     /// Check if the serial port is ready to be written to.
     #[must_use]
     pub fn is_transmit_empty(&self) -> bool {
-        self.line_status.read() & 0x20 != 0
+        self.line_status.read() & TRANSMIT_EMPTY != 0
     }
 
     /// Check if the serial port has data to be read.
     #[must_use]
     pub fn data_pending(&self) -> bool {
-        self.line_status.read() & 0x01 != 0
+        self.line_status.read() & DATA_READY != 0
+    }
+
+    /// Write a byte to the serial port if the transmitter is ready, without waiting.
+    ///
+    /// # Errors
+    /// Returns [`WouldBlock`] if the transmitter holding register is not empty.
+    pub fn try_write(&self, byte: u8) -> Result<(), WouldBlock> {
+        if !self.is_transmit_empty() {
+            return Err(WouldBlock);
+        }
+        self.data.write(byte);
+        Ok(())
+    }
+
+    /// Read a byte from the serial port if one has been received, without waiting.
+    ///
+    /// # Errors
+    /// Returns [`SerialError::WouldBlock`] if no byte has been received, and
+    /// [`SerialError::Line`] if reception errors were detected. If the errors concern the
+    /// received byte (parity, framing or break), the corrupted byte is discarded. After an
+    /// overrun, the received byte is valid and is returned by the next call.
+    pub fn try_read(&self) -> Result<u8, SerialError> {
+        let status = self.line_status.read();
+        let errors = LineErrors::from_bits_truncate(status);
+        if !errors.is_empty() {
+            let corrupted = LineErrors::PARITY | LineErrors::FRAMING | LineErrors::BREAK;
+            if errors.intersects(corrupted) && status & DATA_READY != 0 {
+                let _ = self.data.read();
+            }
+            return Err(SerialError::Line(errors));
+        }
+
+        if status & DATA_READY == 0 {
+            return Err(SerialError::WouldBlock);
+        }
+        Ok(self.data.read())
     }
 
     /// Write a byte to the serial port.
@@ -70,6 +893,27 @@ impl Serial {
         self.data.write(byte);
     }
 
+    /// Write the given bytes to the serial port. If [`Serial::probe`] has detected a working
+    /// FIFO, up to [`FIFO_DEPTH`] bytes are written each time the transmitter is ready, since the
+    /// transmitter holding register empty bit means that the whole FIFO is empty. Otherwise, the
+    /// bytes are written one by one like with [`Serial::write`].
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let burst = if self.fifo.load(Ordering::Relaxed) {
+            FIFO_DEPTH
+        } else {
+            1
+        };
+
+        for chunk in bytes.chunks(burst) {
+            while !self.is_transmit_empty() {
+                core::hint::spin_loop();
+            }
+            for &byte in chunk {
+                self.data.write(byte);
+            }
+        }
+    }
+
     /// Read a byte from the serial port.
     #[must_use]
     pub fn read(&self) -> u8 {
@@ -82,9 +926,281 @@ impl Serial {
 
 impl core::fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for byte in s.bytes() {
-            self.write(byte);
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// A global serial console protected by an interrupt-safe spin lock, enabled with the
+/// `serial_logger` feature. The [`crate::print`], [`crate::println`] and [`crate::serial_dbg`]
+/// macros write to the global [`logger::CONSOLE`], on the COM1 port. With the `log` feature, the
+/// console is also a backend for the `log` crate (see [`logger::init_log`]).
+#[cfg(feature = "serial_logger")]
+pub mod logger {
+    use core::{
+        fmt::{self, Write},
+        ops::Deref,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use super::{Port, Serial};
+    use crate::irq;
+
+    /// The global serial console used by the printing macros. It must be initialized with
+    /// [`Serial::init_com`] (through [`SerialLogger::lock`]) before printing anything.
+    pub static CONSOLE: SerialLogger = SerialLogger::new(Port::COM1);
+
+    /// A serial port protected by a spin lock. Interrupts are disabled on the current CPU while
+    /// the lock is held, so that an interrupt handler printing something cannot deadlock with
+    /// the code it interrupted.
+    pub struct SerialLogger {
+        serial: Serial,
+        locked: AtomicBool,
+    }
+
+    /// A guard giving access to the serial port of a [`SerialLogger`]. The lock is released and
+    /// the previous interrupt state is restored when the guard is dropped.
+    pub struct SerialGuard<'a> {
+        logger: &'a SerialLogger,
+        interrupts: bool,
+    }
+
+    impl SerialLogger {
+        /// Creates a new unlocked logger on the given port. The port is not initialized.
+        #[must_use]
+        pub const fn new(port: Port) -> Self {
+            Self {
+                serial: Serial::new(port),
+                locked: AtomicBool::new(false),
+            }
+        }
+
+        /// Disable interrupts on the current CPU and lock the serial port, spinning until it is
+        /// available.
+        pub fn lock(&self) -> SerialGuard<'_> {
+            let interrupts = irq::enabled();
+            irq::disable();
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            SerialGuard {
+                logger: self,
+                interrupts,
+            }
+        }
+
+        /// Try to lock the serial port without spinning. Returns `None` if it is already locked,
+        /// and interrupts are left untouched in this case.
+        pub fn try_lock(&self) -> Option<SerialGuard<'_>> {
+            let interrupts = irq::enabled();
+            irq::disable();
+            if self
+                .locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                irq::restore(interrupts);
+                return None;
+            }
+            Some(SerialGuard {
+                logger: self,
+                interrupts,
+            })
+        }
+
+        /// Forcibly unlock the serial port, even if it is held. This is meant for the panic
+        /// handler: if the panic happened while the port was locked (or if another CPU holding
+        /// the lock was stopped), printing the panic message would deadlock.
+        ///
+        /// # Safety
+        /// This function is unsafe because the current holder of the lock, if any, must never
+        /// use the port again: its output would be interleaved with the new one.
+        pub unsafe fn force_unlock(&self) {
+            self.locked.store(false, Ordering::Release);
+        }
+
+        /// Write the given formatted arguments to the serial port, locking it for the whole
+        /// message.
+        pub fn write_fmt(&self, args: fmt::Arguments) {
+            let _ = self.lock().write_fmt(args);
+        }
+    }
+
+    impl Deref for SerialGuard<'_> {
+        type Target = Serial;
+
+        fn deref(&self) -> &Serial {
+            &self.logger.serial
+        }
+    }
+
+    impl Write for SerialGuard<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.logger.serial.write_bytes(s.as_bytes());
+            Ok(())
         }
+    }
+
+    impl Drop for SerialGuard<'_> {
+        fn drop(&mut self) {
+            self.logger.locked.store(false, Ordering::Release);
+            irq::restore(self.interrupts);
+        }
+    }
+
+    /// Install the global console as the logger of the `log` crate, with the given maximal
+    /// level. Each record is written on its own line, with the time elapsed since the
+    /// initialization of [`crate::clock`] (if it is initialized), the level and the module
+    /// that emitted it.
+    ///
+    /// # Errors
+    /// Returns an error if a logger has already been installed.
+    #[cfg(feature = "log")]
+    pub fn init_log(level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_logger(&CONSOLE)?;
+        log::set_max_level(level);
         Ok(())
     }
+
+    #[cfg(feature = "log")]
+    impl log::Log for SerialLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::max_level()
+        }
+
+        fn log(&self, record: &log::Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+
+            let now = crate::clock::now();
+            let mut serial = self.lock();
+            if now != 0 {
+                let _ = write!(
+                    serial,
+                    "[{:5}.{:06}] ",
+                    now / 1_000_000_000,
+                    now % 1_000_000_000 / 1000
+                );
+            }
+            let _ = writeln!(
+                serial,
+                "{:5} {}: {}",
+                record.level(),
+                record.module_path().unwrap_or_else(|| record.target()),
+                record.args()
+            );
+        }
+
+        fn flush(&self) {
+            self.lock().flush();
+        }
+    }
+
+    /// Implementation of the printing macros.
+    #[doc(hidden)]
+    pub fn _print(args: fmt::Arguments) {
+        CONSOLE.write_fmt(args);
+    }
+
+    /// Print to the global serial console.
+    #[macro_export]
+    macro_rules! print {
+        ($($arg:tt)*) => {
+            $crate::serial::logger::_print(format_args!($($arg)*))
+        };
+    }
+
+    /// Print to the global serial console, with a newline.
+    #[macro_export]
+    macro_rules! println {
+        () => {
+            $crate::print!("\n")
+        };
+        ($($arg:tt)*) => {
+            $crate::serial::logger::_print(format_args!("{}\n", format_args!($($arg)*)))
+        };
+    }
+
+    /// Print the value of the given expression with its source location to the global serial
+    /// console, and return it, like the `dbg!` macro of the standard library.
+    #[macro_export]
+    macro_rules! serial_dbg {
+        () => {
+            $crate::println!("[{}:{}]", file!(), line!())
+        };
+        ($value:expr $(,)?) => {
+            match $value {
+                value => {
+                    $crate::println!(
+                        "[{}:{}] {} = {:#?}",
+                        file!(),
+                        line!(),
+                        stringify!($value),
+                        &value
+                    );
+                    value
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Config, InvalidBaudRate, Parity, Port, RingBuffer, StopBits, WordLength, BUFFER_SIZE,
+    };
+
+    #[test]
+    fn config_encoding() {
+        assert_eq!(Config::DEFAULT.divisor(), 3);
+        assert_eq!(Config::DEFAULT.line_control(), 0x03);
+
+        let config = Config::new(9600, Parity::Even, StopBits::Two, WordLength::Seven);
+        assert_eq!(config.divisor(), 12);
+        assert_eq!(config.line_control(), 0b0001_1110);
+        assert_eq!(Config::from_registers(0b0001_1110, 12), Some(config));
+        assert_eq!(Config::from_registers(0x03, 0), None);
+    }
+
+    #[test]
+    fn port_from_base() {
+        assert_eq!(Port::from_base(0x2F8), Some(Port::COM2));
+        assert_eq!(Port::from_base(0), None);
+        assert_eq!(Port::from_base(0x3F9), None);
+    }
+
+    #[test]
+    fn invalid_baud_rates() {
+        let config = |baud| Config::try_new(baud, Parity::None, StopBits::One, WordLength::Eight);
+        assert_eq!(config(0), Err(InvalidBaudRate));
+        assert_eq!(config(1), Err(InvalidBaudRate));
+        assert_eq!(config(230_400), Err(InvalidBaudRate));
+        assert_eq!(config(7000), Err(InvalidBaudRate));
+        assert_eq!(config(50).map(|config| config.divisor()), Ok(2304));
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn ring_buffer() {
+        let buffer = RingBuffer::new();
+        assert_eq!(buffer.pop(), None);
+
+        for round in 0..3u8 {
+            for i in 0..BUFFER_SIZE {
+                assert!(buffer.push(round ^ i as u8));
+            }
+            assert!(!buffer.push(0));
+            assert_eq!(buffer.len(), BUFFER_SIZE);
+            for i in 0..BUFFER_SIZE {
+                assert_eq!(buffer.pop(), Some(round ^ i as u8));
+            }
+            assert!(buffer.is_empty());
+        }
+    }
 }
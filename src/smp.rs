@@ -0,0 +1,958 @@
+use core::{
+    arch::{asm, global_asm},
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{
+    address::{Physical, Virtual},
+    cpu::{cr0, cr3, cr4, msr},
+    lapic::{Ipi, IpiDestination, IpiError, IpiPriority, LocalApic, LvtEntry},
+    once::InitOnce,
+};
+
+// The trampoline executed by the application processors (APs) when they receive a startup IPI.
+// The APs start in real mode at the physical address where the trampoline was copied, and the
+// trampoline switches them directly to long mode, using the control registers of the bootstrap
+// processor (BSP) and the page table given to `Trampoline::set_cr3`. Finally, the trampoline loads
+// the stack pointer, signals the BSP that the AP has started and calls the entry point.
+//
+// The trampoline is relocatable: the 16 bits code only uses addresses relative to the CS segment,
+// the 64 bits code only uses RIP-relative addresses, and the absolute addresses needed to switch
+// to long mode (the GDT base and the far jump target) are patched when the trampoline is copied.
+global_asm!(
+    "
+    .section .rodata.silicium_trampoline, \"a\"
+    .code16
+    .set TRAMPOLINE_GDTR, silicium_trampoline_gdtr - silicium_trampoline_start
+    .set TRAMPOLINE_FAR_JUMP, silicium_trampoline_far_jump - silicium_trampoline_start
+    .set TRAMPOLINE_CR0, silicium_trampoline_cr0 - silicium_trampoline_start
+    .set TRAMPOLINE_CR3, silicium_trampoline_cr3 - silicium_trampoline_start
+    .set TRAMPOLINE_CR4, silicium_trampoline_cr4 - silicium_trampoline_start
+    .set TRAMPOLINE_EFER, silicium_trampoline_efer - silicium_trampoline_start
+    .global silicium_trampoline_start
+silicium_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    # Load a temporary GDT with a 64 bits code segment
+    lgdt [TRAMPOLINE_GDTR]
+
+    # Enable PAE, and the other CR4 features used by the BSP
+    mov eax, [TRAMPOLINE_CR4]
+    mov cr4, eax
+
+    # Load the page table
+    mov eax, [TRAMPOLINE_CR3]
+    mov cr3, eax
+
+    # Enable long mode in the EFER register
+    mov ecx, 0xC0000080
+    mov eax, [TRAMPOLINE_EFER]
+    xor edx, edx
+    wrmsr
+
+    # Enable protected mode and paging at the same time
+    mov eax, [TRAMPOLINE_CR0]
+    mov cr0, eax
+
+    # Jump to the 64 bits code
+    jmp fword ptr [TRAMPOLINE_FAR_JUMP]
+
+    .code64
+    .global silicium_trampoline_long_mode
+silicium_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    # Load the stack and the argument of the entry point
+    mov rsp, [rip + silicium_trampoline_stack]
+    mov rdi, [rip + silicium_trampoline_argument]
+    mov rax, [rip + silicium_trampoline_entry]
+
+    # Signal the BSP that we have started. After this point, the BSP can modify the trampoline
+    # to start another AP.
+    mov dword ptr [rip + silicium_trampoline_started], 1
+
+    # Call the entry point. It should never return
+    call rax
+2:
+    cli
+    hlt
+    jmp 2b
+
+    .align 8
+    .global silicium_trampoline_gdt
+silicium_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+    .global silicium_trampoline_gdtr
+silicium_trampoline_gdtr:
+    .word silicium_trampoline_gdtr - silicium_trampoline_gdt - 1
+    .long 0
+    .global silicium_trampoline_far_jump
+silicium_trampoline_far_jump:
+    .long 0
+    .word 0x08
+    .align 8
+    .global silicium_trampoline_cr0
+silicium_trampoline_cr0:
+    .long 0
+    .global silicium_trampoline_cr3
+silicium_trampoline_cr3:
+    .long 0
+    .global silicium_trampoline_cr4
+silicium_trampoline_cr4:
+    .long 0
+    .global silicium_trampoline_efer
+silicium_trampoline_efer:
+    .long 0
+    .global silicium_trampoline_stack
+silicium_trampoline_stack:
+    .quad 0
+    .global silicium_trampoline_argument
+silicium_trampoline_argument:
+    .quad 0
+    .global silicium_trampoline_entry
+silicium_trampoline_entry:
+    .quad 0
+    .global silicium_trampoline_started
+silicium_trampoline_started:
+    .long 0
+    .global silicium_trampoline_end
+silicium_trampoline_end:
+    .previous
+    "
+);
+
+extern "C" {
+    static silicium_trampoline_start: u8;
+    static silicium_trampoline_end: u8;
+    static silicium_trampoline_cr0: u8;
+    static silicium_trampoline_cr3: u8;
+    static silicium_trampoline_cr4: u8;
+    static silicium_trampoline_efer: u8;
+    static silicium_trampoline_stack: u8;
+    static silicium_trampoline_argument: u8;
+    static silicium_trampoline_entry: u8;
+    static silicium_trampoline_started: u8;
+    static silicium_trampoline_gdt: u8;
+    static silicium_trampoline_gdtr: u8;
+    static silicium_trampoline_far_jump: u8;
+    static silicium_trampoline_long_mode: u8;
+}
+
+/// Delay to wait after sending the INIT IPI, in microseconds.
+pub const INIT_DELAY_US: u64 = 10_000;
+
+/// Delay to wait after sending a startup IPI, in microseconds.
+pub const STARTUP_DELAY_US: u64 = 200;
+
+/// Maximum time to wait for an AP to check in after the startup IPIs, in microseconds.
+pub const CHECK_IN_TIMEOUT_US: u64 = 100_000;
+
+/// The interrupt vector used by [`call_on`] and [`call_on_all_others`]. The kernel must install an
+/// interrupt handler for this vector on each CPU, which calls [`handle_call_function`] and then
+/// sends an end-of-interrupt to the local APIC.
+pub const CALL_FUNCTION_VECTOR: u8 = 0xFD;
+
+/// The maximal number of CPUs supported by this module. The CPUs are identified by a dense index
+/// below this limit (see [`register_cpu`]), whatever their local APIC ID is.
+pub const MAX_CPUS: usize = 256;
+
+/// The value of the unused entries of [`APIC_IDS`]. This is the x2APIC broadcast ID, which is
+/// never the ID of a CPU.
+const NO_APIC_ID: u32 = u32::MAX;
+
+/// The local APIC ID of each CPU, indexed by CPU index (see [`register_cpu`]).
+static APIC_IDS: [AtomicU32; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU32 = AtomicU32::new(NO_APIC_ID);
+    [NONE; MAX_CPUS]
+};
+
+/// The set of CPUs that are ready to receive cross-CPU function calls (see [`set_online`]).
+static ONLINE: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Set by [`freeze_others`] to signal the other CPUs that they must park themselves when they
+/// receive a NMI.
+static FREEZING: AtomicBool = AtomicBool::new(false);
+
+/// The number of CPUs parked by [`nmi_park`] or [`park_if_freezing`].
+static PARKED: AtomicU32 = AtomicU32::new(0);
+
+/// The mailboxes used to send a function to each CPU, indexed by CPU index.
+static MAILBOXES: [Mailbox; MAX_CPUS] = [Mailbox::EMPTY; MAX_CPUS];
+
+/// The park states of the CPUs (see [`park_cpu`]), indexed by CPU index. A parked CPU woken up by
+/// [`unpark_cpu`] moves from `WAKING` to `RESUMING` itself, so that [`unpark_cpu`] can put it
+/// back in the `PARKED_STATE` state if it does not respond.
+const RUNNING: u8 = 0;
+const PARKING: u8 = 1;
+const PARKED_STATE: u8 = 2;
+const WAKING: u8 = 3;
+const RESUMING: u8 = 4;
+static PARK_STATES: [AtomicU8; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU8 = AtomicU8::new(RUNNING);
+    [ZERO; MAX_CPUS]
+};
+
+/// The number of spins [`unpark_cpu`] waits for a parked CPU to respond before sending another
+/// NMI, and the number of NMIs sent before giving up.
+const UNPARK_RETRY_SPINS: u32 = 100_000;
+const UNPARK_RETRIES: u32 = 100;
+
+/// The local APIC handle used by the parked CPUs to mask their timer. All the CPUs can use the
+/// same handle (see [`LocalApic`]).
+static PARK_LAPIC: InitOnce<LocalApic> = InitOnce::new();
+
+/// An error reported by the cross-CPU operations of this module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The current CPU cannot park itself.
+    CurrentCpu,
+
+    /// The CPU is not online (see [`set_online`]).
+    NotOnline,
+
+    /// The CPU is already parked, or being parked.
+    AlreadyParked,
+
+    /// The CPU is not parked.
+    NotParked,
+
+    /// The CPU is not registered with [`register_cpu`], or the identifier of the current CPU
+    /// given to [`crate::percpu::init`] is not a valid CPU index.
+    UnknownCpu,
+
+    /// The IPI could not be sent to the CPU.
+    Ipi(IpiError),
+
+    /// The CPU did not respond in time.
+    Timeout,
+}
+
+/// The entry point of an application processor. The argument is the value given to
+/// [`Trampoline::start`] for this AP.
+pub type Entry = extern "C" fn(u64) -> !;
+
+/// A set of CPUs, identified by a number below 256: their CPU index for this module (see
+/// [`register_cpu`]), or their local APIC ID for the users limited to 8 bits destinations (see
+/// [`crate::acpi::Madt::cpus`] and [`crate::irq_routing`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuSet([u64; 4]);
+
+impl CpuSet {
+    /// Creates a new empty set.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self([0; 4])
+    }
+
+    /// Add the given CPU to the set.
+    pub fn insert(&mut self, cpu: u8) {
+        self.0[usize::from(cpu / 64)] |= 1 << (cpu % 64);
+    }
+
+    /// Remove the given CPU from the set.
+    pub fn remove(&mut self, cpu: u8) {
+        self.0[usize::from(cpu / 64)] &= !(1 << (cpu % 64));
+    }
+
+    /// Returns `true` if the given CPU is in the set.
+    #[must_use]
+    pub const fn contains(&self, cpu: u8) -> bool {
+        self.0[(cpu / 64) as usize] & (1 << (cpu % 64)) != 0
+    }
+
+    /// Returns the number of CPUs in the set.
+    #[must_use]
+    pub const fn count(&self) -> u32 {
+        self.0[0].count_ones()
+            + self.0[1].count_ones()
+            + self.0[2].count_ones()
+            + self.0[3].count_ones()
+    }
+
+    /// Returns `true` if the set is empty.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Returns an iterator over the CPUs in the set.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(|&id| self.contains(id))
+    }
+}
+
+/// A per-CPU mailbox, used to send a function to execute to a CPU.
+struct Mailbox {
+    /// Set while a CPU is using this mailbox to send a function.
+    lock: AtomicBool,
+
+    /// Set when a function has been posted, and cleared by the target CPU once it is done.
+    pending: AtomicBool,
+
+    /// The monomorphized function that calls the closure, and a pointer to the closure.
+    function: AtomicUsize,
+    data: AtomicUsize,
+}
+
+impl Mailbox {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = Self {
+        lock: AtomicBool::new(false),
+        pending: AtomicBool::new(false),
+        function: AtomicUsize::new(0),
+        data: AtomicUsize::new(0),
+    };
+
+    /// Lock the mailbox and post the given closure. The closure must stay alive until the mailbox
+    /// is no longer pending.
+    fn post<F: Fn() + Sync>(&self, f: &F) {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        self.function.store(call_closure::<F> as unsafe fn(usize) as usize, Ordering::Relaxed);
+        self.data.store(addr_of!(*f) as usize, Ordering::Relaxed);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// Wait until the posted function has been executed by the target CPU, and unlock the mailbox.
+    fn wait(&self) {
+        while self.pending.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        self.lock.store(false, Ordering::Release);
+    }
+
+    /// Withdraw the posted function and unlock the mailbox, when the IPI notifying the target CPU
+    /// could not be sent.
+    fn cancel(&self) {
+        self.pending.store(false, Ordering::Release);
+        self.lock.store(false, Ordering::Release);
+    }
+}
+
+/// Register the CPU with the given local APIC ID under the given index. Each CPU must be
+/// registered before being marked as online with [`set_online`], and the same index must be
+/// given as the CPU identifier to [`crate::percpu::init`] on this CPU: it is used to find the
+/// current CPU without reading its local APIC ID, which is slow under virtualization.
+pub fn register_cpu(cpu: u8, apic_id: u32) {
+    APIC_IDS[usize::from(cpu)].store(apic_id, Ordering::Release);
+}
+
+/// Returns the local APIC ID of the CPU with the given index, or `None` if it is not registered
+/// (see [`register_cpu`]).
+#[must_use]
+pub fn apic_id(cpu: u8) -> Option<u32> {
+    Some(APIC_IDS[usize::from(cpu)].load(Ordering::Acquire)).filter(|&id| id != NO_APIC_ID)
+}
+
+/// Returns the index of the CPU with the given local APIC ID, or `None` if it is not registered
+/// (see [`register_cpu`]).
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn cpu_index(apic_id: u32) -> Option<u8> {
+    if apic_id == NO_APIC_ID {
+        return None;
+    }
+    APIC_IDS
+        .iter()
+        .position(|id| id.load(Ordering::Acquire) == apic_id)
+        .map(|cpu| cpu as u8)
+}
+
+/// Returns the index of the current CPU, given to [`crate::percpu::init`].
+///
+/// # Errors
+/// Returns [`Error::UnknownCpu`] if the identifier of the current CPU is not a valid CPU index.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized on the current
+/// CPU (see [`crate::percpu::cpu_id_fast`]).
+unsafe fn current_cpu() -> Result<u8, Error> {
+    u8::try_from(crate::percpu::cpu_id_fast()).map_err(|_| Error::UnknownCpu)
+}
+
+/// Post the given function to the mailbox of the given CPU and send it the function call IPI.
+///
+/// # Safety
+/// See [`call_on`].
+unsafe fn post_to<F: Fn() + Sync>(lapic: &LocalApic, cpu: u8, f: &F) -> Result<(), Error> {
+    let apic_id = apic_id(cpu).ok_or(Error::UnknownCpu)?;
+    let mailbox = &MAILBOXES[usize::from(cpu)];
+    mailbox.post(f);
+    lapic
+        .send_ipi(
+            IpiDestination::Core(apic_id),
+            IpiPriority::Normal,
+            CALL_FUNCTION_VECTOR,
+        )
+        .map_err(|error| {
+            mailbox.cancel();
+            Error::Ipi(error)
+        })
+}
+
+/// Call the closure of type `F` pointed to by `data`.
+unsafe fn call_closure<F: Fn()>(data: usize) {
+    (*(data as *const F))();
+}
+
+/// Mark the given CPU as ready or not to receive cross-CPU function calls. A CPU should be marked
+/// as online once it is registered (see [`register_cpu`]), its IDT is loaded with a handler for
+/// [`CALL_FUNCTION_VECTOR`], its local APIC is enabled and interrupts are enabled.
+pub fn set_online(cpu: u8, online: bool) {
+    let word = &ONLINE[usize::from(cpu / 64)];
+    if online {
+        word.fetch_or(1 << (cpu % 64), Ordering::Release);
+    } else {
+        word.fetch_and(!(1 << (cpu % 64)), Ordering::Release);
+    }
+}
+
+/// Returns the set of CPUs that are ready to receive cross-CPU function calls.
+#[must_use]
+pub fn online() -> CpuSet {
+    CpuSet([
+        ONLINE[0].load(Ordering::Acquire),
+        ONLINE[1].load(Ordering::Acquire),
+        ONLINE[2].load(Ordering::Acquire),
+        ONLINE[3].load(Ordering::Acquire),
+    ])
+}
+
+/// Execute the given function on the CPU with the given index, and wait until it has finished.
+/// If the given CPU is the current CPU, the function is called directly.
+///
+/// # Errors
+/// Returns [`Error::UnknownCpu`] if the current or the target CPU is not registered, or
+/// [`Error::Ipi`] if the IPI cannot be sent to the target CPU. The function is not executed in
+/// both cases.
+///
+/// # Safety
+/// This function is unsafe because the target CPU must be online (see [`set_online`]), and the
+/// per-CPU area must have been initialized on the current CPU. Interrupts must be enabled on the
+/// current CPU while waiting, otherwise two CPUs calling a function on each other will deadlock.
+pub unsafe fn call_on<F: Fn() + Sync>(lapic: &LocalApic, cpu: u8, f: F) -> Result<(), Error> {
+    if cpu == current_cpu()? {
+        f();
+        return Ok(());
+    }
+
+    post_to(lapic, cpu, &f)?;
+    MAILBOXES[usize::from(cpu)].wait();
+    Ok(())
+}
+
+/// Execute the given function on all online CPUs except the current one, and wait until all of
+/// them have finished. The function is executed concurrently on all target CPUs.
+///
+/// # Errors
+/// Returns the first error encountered while posting the function to the target CPUs (see
+/// [`call_on`]). The function is still executed on the other CPUs, and this function waits for
+/// them before returning.
+///
+/// # Safety
+/// See [`call_on`].
+pub unsafe fn call_on_all_others<F: Fn() + Sync>(lapic: &LocalApic, f: F) -> Result<(), Error> {
+    let mut targets = online();
+    targets.remove(current_cpu()?);
+
+    let mut result = Ok(());
+    let mut posted = CpuSet::new();
+    for cpu in targets.iter() {
+        match post_to(lapic, cpu, &f) {
+            Ok(()) => posted.insert(cpu),
+            Err(error) => result = result.and(Err(error)),
+        }
+    }
+
+    for cpu in posted.iter() {
+        MAILBOXES[usize::from(cpu)].wait();
+    }
+    result
+}
+
+/// Execute the function posted in the mailbox of the current CPU, if any. This function must be
+/// called by the interrupt handler of [`CALL_FUNCTION_VECTOR`]. It does nothing if the current
+/// CPU has no valid CPU index, since no function can be posted to it.
+///
+/// # Safety
+/// This function is unsafe because it must only be called from the interrupt handler of
+/// [`CALL_FUNCTION_VECTOR`], once the per-CPU area has been initialized on the current CPU.
+pub unsafe fn handle_call_function() {
+    let Ok(cpu) = current_cpu() else {
+        return;
+    };
+
+    let mailbox = &MAILBOXES[usize::from(cpu)];
+    if mailbox.pending.load(Ordering::Acquire) {
+        let function = mailbox.function.load(Ordering::Relaxed);
+        let data = mailbox.data.load(Ordering::Relaxed);
+        let function: unsafe fn(usize) = core::mem::transmute(function);
+        function(data);
+        mailbox.pending.store(false, Ordering::Release);
+    }
+}
+
+/// Stop all the other CPUs by sending them a NMI. This is intended to be used when the kernel
+/// panics, so that the panic output is not interleaved with the output of other CPUs and the
+/// state of the system is not modified while being dumped. The NMI handler of the other CPUs must
+/// either be [`nmi_park`], or call [`park_if_freezing`].
+///
+/// This function does not wait for the other CPUs to be parked: [`parked`] can be used to know
+/// how many CPUs have been parked.
+pub fn freeze_others(lapic: &LocalApic) {
+    FREEZING.store(true, Ordering::SeqCst);
+    unsafe {
+        // There is nothing better to do if the IPI cannot be sent
+        let _ = lapic.send_ipi(IpiDestination::OtherCores, IpiPriority::Nmi, 0);
+    }
+}
+
+/// Returns `true` if [`freeze_others`] has been called.
+#[must_use]
+pub fn freezing() -> bool {
+    FREEZING.load(Ordering::SeqCst)
+}
+
+/// Returns the number of CPUs parked after a call to [`freeze_others`].
+#[must_use]
+pub fn parked() -> u32 {
+    PARKED.load(Ordering::SeqCst)
+}
+
+/// Park the current CPU forever if [`freeze_others`] has been called, otherwise do nothing. This
+/// should be called at the start of the NMI handler of the kernel.
+pub fn park_if_freezing() {
+    if freezing() {
+        PARKED.fetch_add(1, Ordering::SeqCst);
+        crate::cpu::freeze();
+    }
+}
+
+/// A minimal NMI handler that parks the current CPU forever, with interrupts disabled. Since NMIs
+/// are blocked until the next `iretq`, the CPU will never wake up again. The address of this
+/// function can be directly installed in the IDT for the NMI vector when the kernel panics.
+///
+/// # Safety
+/// This function must never be called directly: it must only be used as an interrupt handler.
+#[naked]
+pub unsafe extern "C" fn nmi_park() -> ! {
+    asm!(
+        "
+        cli
+        lock inc dword ptr [rip + {parked}]
+       2:
+        hlt
+        jmp 2b
+        ",
+        parked = sym PARKED,
+        options(noreturn)
+    );
+}
+
+/// Park the CPU with the given index: the CPU masks its local APIC timer, is marked as
+/// offline (see [`set_online`]) and stays in a loop with interrupts disabled until it is woken up
+/// by [`unpark_cpu`]. This waits until the CPU is parked. The interrupts routed to the CPU should
+/// be moved to the other CPUs first (see [`crate::irq_routing::Router::rebalance`]), since they
+/// will not be handled while the CPU is parked.
+///
+/// The parked CPU waits with `monitor` and `mwait` on its park state if they are supported (see
+/// [`crate::irq::mwait_supported`]), so that [`unpark_cpu`] only has to write to it. Otherwise,
+/// it waits with `hlt` and [`unpark_cpu`] wakes it up with a NMI: the NMI handler of the kernel
+/// must then return normally when [`is_parked`] is `true` for the current CPU. Since the NMI may
+/// be sent several times, the last one can also be received just after the CPU is running again,
+/// and must be ignored.
+///
+/// # Errors
+/// Returns an error if the CPU is the current CPU, if it is not online, if it is already parked,
+/// or if the IPI cannot be sent to it (see [`call_on`]).
+///
+/// # Safety
+/// See [`call_on`]. The CPU is parked from the interrupt handler of [`CALL_FUNCTION_VECTOR`], so
+/// it must not hold any lock needed by the other CPUs.
+pub unsafe fn park_cpu(lapic: &LocalApic, cpu: u8) -> Result<(), Error> {
+    if cpu == current_cpu()? {
+        return Err(Error::CurrentCpu);
+    }
+    if !online().contains(cpu) {
+        return Err(Error::NotOnline);
+    }
+    let state = &PARK_STATES[usize::from(cpu)];
+    state
+        .compare_exchange(RUNNING, PARKING, Ordering::Acquire, Ordering::Relaxed)
+        .map_err(|_| Error::AlreadyParked)?;
+
+    // The handle must be published before the park request. If another CPU is parking a CPU at
+    // the same time, it may be the one initializing it: wait until it is done
+    let _ = PARK_LAPIC.set(lapic.clone());
+    while !PARK_LAPIC.is_initialized() {
+        core::hint::spin_loop();
+    }
+    set_online(cpu, false);
+
+    // The mailbox stays locked while the CPU is parked, and is unlocked by `unpark_cpu`. The
+    // posted function does not capture anything, so it stays valid after this function returns
+    if let Err(error) = post_to(lapic, cpu, &park_current) {
+        set_online(cpu, true);
+        state.store(RUNNING, Ordering::Release);
+        return Err(error);
+    }
+
+    while state.load(Ordering::Acquire) != PARKED_STATE {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Wake up the CPU with the given index, parked by [`park_cpu`]. This waits until the CPU has
+/// restored its local APIC timer and is online again.
+///
+/// When the CPU waits with `hlt`, the NMI waking it up can be delivered just before the `hlt`
+/// instruction, after the CPU checked its park state. The NMI is therefore sent again until the
+/// CPU responds, every [`UNPARK_RETRY_SPINS`] spins.
+///
+/// # Errors
+/// Returns [`Error::NotParked`] if the CPU is not parked, [`Error::UnknownCpu`] if it is not
+/// registered, [`Error::Ipi`] if the NMI cannot be sent to it, when it is needed, or
+/// [`Error::Timeout`] if it did not respond to [`UNPARK_RETRIES`] NMIs. The CPU stays parked in
+/// the two latter cases.
+pub fn unpark_cpu(lapic: &LocalApic, cpu: u8) -> Result<(), Error> {
+    let mwait = crate::irq::mwait_supported();
+    let apic_id = apic_id(cpu).ok_or(Error::UnknownCpu)?;
+    let state = &PARK_STATES[usize::from(cpu)];
+    state
+        .compare_exchange(PARKED_STATE, WAKING, Ordering::Release, Ordering::Relaxed)
+        .map_err(|_| Error::NotParked)?;
+
+    // The CPU has responded once it left the `WAKING` state. Otherwise, it is put back in the
+    // parked state, unless it responded in the meantime
+    let responded = || state.load(Ordering::Acquire) != WAKING;
+    let give_up = |error| {
+        state
+            .compare_exchange(WAKING, PARKED_STATE, Ordering::Acquire, Ordering::Acquire)
+            .map_or(Ok(()), |_| Err(error))
+    };
+
+    if !mwait {
+        'wake: {
+            for _ in 0..UNPARK_RETRIES {
+                // SAFETY: The NMI handler must return normally for the parked CPUs (see
+                // `park_cpu`)
+                let sent =
+                    unsafe { lapic.send_ipi(IpiDestination::Core(apic_id), IpiPriority::Nmi, 0) };
+                if let Err(error) = sent {
+                    give_up(Error::Ipi(error))?;
+                    break 'wake;
+                }
+                for _ in 0..UNPARK_RETRY_SPINS {
+                    if responded() {
+                        break 'wake;
+                    }
+                    core::hint::spin_loop();
+                }
+            }
+            give_up(Error::Timeout)?;
+        }
+    }
+    MAILBOXES[usize::from(cpu)].wait();
+    Ok(())
+}
+
+/// Returns `true` if the CPU with the given index is parked, or being parked or woken up.
+#[must_use]
+pub fn is_parked(cpu: u8) -> bool {
+    PARK_STATES[usize::from(cpu)].load(Ordering::Acquire) != RUNNING
+}
+
+/// Park the current CPU until it is woken up by [`unpark_cpu`]. This is posted by [`park_cpu`]
+/// in the mailbox of the CPU, and executed by the interrupt handler of [`CALL_FUNCTION_VECTOR`].
+fn park_current() {
+    // SAFETY: The function call IPIs are handled once the per-CPU area is initialized
+    let Ok(cpu) = (unsafe { current_cpu() }) else {
+        return;
+    };
+    let state = &PARK_STATES[usize::from(cpu)];
+    let mwait = crate::irq::mwait_supported();
+
+    // The handle is always initialized by `park_cpu` before posting the request, but the CPU
+    // is still parked without masking its timer if it is not
+    let lapic = PARK_LAPIC.get();
+    crate::cpu::cli();
+    let timer = lapic.map(|lapic| {
+        let timer = lapic.lvt_timer();
+        // SAFETY: Masking the timer does not trigger any interrupt
+        unsafe { lapic.set_lvt_timer(LvtEntry::from_bits(timer.bits()).set_masked(true).build()) };
+        timer
+    });
+    state.store(PARKED_STATE, Ordering::Release);
+
+    // The wakeup is claimed by moving from `WAKING` to `RESUMING`: if `unpark_cpu` gave up in the
+    // meantime, the state is back to `PARKED_STATE` and the CPU keeps waiting
+    while state
+        .compare_exchange(WAKING, RESUMING, Ordering::Acquire, Ordering::Acquire)
+        .is_err()
+    {
+        // SAFETY: The interrupts are disabled, so the CPU is only woken up by a write to the park
+        // state (with `mwait`), a NMI or a machine check
+        unsafe {
+            if mwait {
+                asm!(
+                    "monitor",
+                    in("rax") addr_of!(*state),
+                    in("ecx") 0,
+                    in("edx") 0,
+                    options(nostack, preserves_flags)
+                );
+                if state.load(Ordering::Acquire) == PARKED_STATE {
+                    asm!("mwait", in("eax") 0, in("ecx") 0, options(nomem, nostack));
+                }
+            } else {
+                asm!("hlt", options(nomem, nostack));
+            }
+        }
+    }
+
+    if let (Some(lapic), Some(timer)) = (lapic, timer) {
+        // SAFETY: The timer is restored in the state it was before parking
+        unsafe { lapic.set_lvt_timer(timer) };
+    }
+    state.store(RUNNING, Ordering::Release);
+    set_online(cpu, true);
+}
+
+/// The AP startup trampoline, copied in a page below 1 MiB.
+pub struct Trampoline {
+    virt: Virtual,
+    phys: Physical,
+}
+
+impl Trampoline {
+    /// Copy the trampoline to the given page, located below 1 MiB. The control registers and the
+    /// EFER register of the current CPU are copied into the trampoline and will be used by the
+    /// application processors. The page table loaded by the APs is the current one by default, and
+    /// can be changed with [`Trampoline::set_cr3`].
+    ///
+    /// # Panics
+    /// This function panics if the given physical address is not page aligned or is not below
+    /// 1 MiB, or if the current page table is not below 4 GiB.
+    ///
+    /// # Safety
+    /// This function is unsafe because the given virtual address must be a writable mapping of the
+    /// given physical page, which must not be used for anything else while APs are started. The
+    /// page must also be identity mapped in the page table used by the APs.
+    #[must_use]
+    pub unsafe fn install(virt: Virtual, phys: Physical) -> Self {
+        assert!(phys.is_page_aligned(), "Trampoline must be page aligned");
+        assert!(phys.as_u64() < 0x10_0000, "Trampoline must be below 1 MiB");
+
+        let start = addr_of!(silicium_trampoline_start);
+        let size = Self::size();
+        assert!(size <= 4096, "Trampoline does not fit in a page");
+        core::ptr::copy_nonoverlapping(start, virt.as_mut_ptr::<u8>(), size);
+
+        let trampoline = Self { virt, phys };
+        let gdt = phys.as_u64() + Self::offset(addr_of!(silicium_trampoline_gdt));
+        let long_mode = phys.as_u64() + Self::offset(addr_of!(silicium_trampoline_long_mode));
+        trampoline.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_gdtr)) + 2, gdt);
+        trampoline.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_far_jump)), long_mode);
+
+        // The PCID feature cannot be enabled outside of long mode
+        let cr4 = cr4::read() & !cr4::Flags::PCIDE.bits();
+        trampoline.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_cr0)), cr0::read());
+        trampoline.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_cr4)), cr4);
+        let efer = msr::efer::read_raw();
+        trampoline.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_efer)), efer);
+        trampoline.set_cr3(Physical::new(cr3::read() & !0xFFF));
+        trampoline
+    }
+
+    /// Returns the size of the trampoline, in bytes.
+    #[must_use]
+    pub fn size() -> usize {
+        #[allow(clippy::cast_possible_truncation)]
+        unsafe {
+            Self::offset(addr_of!(silicium_trampoline_end)) as usize
+        }
+    }
+
+    /// Returns the physical address where the trampoline was copied.
+    #[must_use]
+    pub const fn physical(&self) -> Physical {
+        self.phys
+    }
+
+    /// Set the page table loaded by the APs. The trampoline page must be identity mapped in this
+    /// page table.
+    ///
+    /// # Panics
+    /// This function panics if the given physical address is not page aligned or not below 4 GiB,
+    /// because the trampoline loads it from 16 bits code.
+    pub fn set_cr3(&self, pml4: Physical) {
+        assert!(pml4.is_page_aligned(), "Page table must be page aligned");
+        assert!(pml4.as_u64() < 0x1_0000_0000, "Page table must be below 4 GiB");
+        unsafe {
+            self.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_cr3)), pml4.as_u64());
+        }
+    }
+
+    /// Start the application processor with the given local APIC ID with the INIT-SIPI-SIPI
+    /// sequence. The AP will call the given entry point with the given argument, on the given
+    /// stack. The `delay` function must wait for the given number of microseconds.
+    ///
+    /// Returns `true` if the AP has checked in before the timeout, `false` otherwise.
+    ///
+    /// # Safety
+    /// This function is unsafe because the given stack must be a valid stack top for the AP,
+    /// aligned on a 16 bytes boundary, and the entry point must be mapped in the page table used
+    /// by the APs.
+    pub unsafe fn start(
+        &self,
+        lapic: &LocalApic,
+        apic_id: u32,
+        entry: Entry,
+        argument: u64,
+        stack: Virtual,
+        delay: fn(u64),
+    ) -> bool {
+        assert!(stack.is_aligned(16u64), "AP stack must be aligned on 16 bytes");
+        self.patch::<u64>(Self::offset(addr_of!(silicium_trampoline_stack)), stack.as_u64());
+        self.patch::<u64>(Self::offset(addr_of!(silicium_trampoline_argument)), argument);
+        self.patch::<u64>(Self::offset(addr_of!(silicium_trampoline_entry)), entry as usize as u64);
+        self.patch::<u32>(Self::offset(addr_of!(silicium_trampoline_started)), 0);
+
+        // The startup IPI vector is the page number of the trampoline
+        #[allow(clippy::cast_possible_truncation)]
+        let vector = (self.phys.as_u64() >> 12) as u8;
+
+        let destination = IpiDestination::Core(apic_id);
+        if lapic.send(Ipi::init(destination)).is_err() {
+            return false;
+        }
+        delay(INIT_DELAY_US);
+
+        for _ in 0..2 {
+            if lapic.send(Ipi::startup(destination, vector)).is_err() {
+                return false;
+            }
+            delay(STARTUP_DELAY_US);
+            if self.checked_in() {
+                return true;
+            }
+        }
+
+        let mut waited = 0;
+        while waited < CHECK_IN_TIMEOUT_US {
+            if self.checked_in() {
+                return true;
+            }
+            delay(100);
+            waited += 100;
+        }
+        false
+    }
+
+    /// Start all the given application processors, identified by their CPU index, one after the
+    /// other (see [`Trampoline::start`]). The APs must have been registered with
+    /// [`register_cpu`], the unregistered ones are skipped. The argument given to the entry point
+    /// of each AP is its CPU index, to be given to [`crate::percpu::init`], and its stack is given
+    /// by the `stack` function, called with the same index.
+    ///
+    /// Returns the set of APs that have checked in.
+    ///
+    /// # Safety
+    /// See [`Trampoline::start`].
+    pub unsafe fn start_all(
+        &self,
+        lapic: &LocalApic,
+        cpus: &CpuSet,
+        entry: Entry,
+        stack: impl Fn(u8) -> Virtual,
+        delay: fn(u64),
+    ) -> CpuSet {
+        let mut started = CpuSet::new();
+        for cpu in cpus.iter() {
+            let Some(apic_id) = apic_id(cpu) else {
+                continue;
+            };
+            if self.start(lapic, apic_id, entry, u64::from(cpu), stack(cpu), delay) {
+                started.insert(cpu);
+            }
+        }
+        started
+    }
+
+    /// Returns `true` if the last started AP has checked in.
+    fn checked_in(&self) -> bool {
+        unsafe {
+            let offset = Self::offset(addr_of!(silicium_trampoline_started));
+            (self.virt + offset).as_ptr::<u32>().read_volatile() != 0
+        }
+    }
+
+    /// Write the given value at the given offset in the trampoline, truncated to the size of `T`.
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe fn patch<T>(&self, offset: u64, value: u64) {
+        let ptr = (self.virt + offset).as_mut_ptr::<u8>();
+        match core::mem::size_of::<T>() {
+            4 => ptr.cast::<u32>().write_unaligned(value as u32),
+            8 => ptr.cast::<u64>().write_unaligned(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the offset of the given trampoline symbol from the start of the trampoline.
+    unsafe fn offset(symbol: *const u8) -> u64 {
+        (symbol as usize - addr_of!(silicium_trampoline_start) as usize) as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn cpu_set() {
+        let mut set = super::CpuSet::new();
+        assert!(set.is_empty());
+        set.insert(0);
+        set.insert(65);
+        set.insert(255);
+        assert_eq!(set.count(), 3);
+        assert!(set.contains(65));
+        assert!(!set.contains(64));
+        set.remove(65);
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 255]);
+    }
+
+    #[test]
+    fn cpu_registration() {
+        super::register_cpu(7, 0x1_0000);
+        assert_eq!(super::apic_id(7), Some(0x1_0000));
+        assert_eq!(super::cpu_index(0x1_0000), Some(7));
+        assert_eq!(super::apic_id(8), None);
+        assert_eq!(super::cpu_index(u32::MAX), None);
+    }
+}
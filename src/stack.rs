@@ -0,0 +1,277 @@
+//! Accounting of the kernel stacks of the CPUs. The bounds of the kernel stack of each CPU are
+//! recorded with [`register`], which writes a canary at the lowest end of the stack (where an
+//! overflow hits first) and fills the unused part of the stack with a known pattern. The timer
+//! interrupt handler can then call [`check_current`] to detect an overflow before it corrupts
+//! the data below the stack, and [`usage_high_water`] reports the deepest usage of the stack.
+//!
+//! The canary only detects overflows that write to it: a function with a large frame can skip
+//! it, so a guard page below the stack is still needed to catch every overflow.
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{address::Virtual, smp::MAX_CPUS};
+
+/// The pattern written at the lowest end of the stacks.
+pub const CANARY: u64 = 0x57AC_C0DE_DEAD_BEEF;
+
+/// The number of canary words at the lowest end of the stacks.
+pub const CANARY_WORDS: usize = 8;
+
+/// The pattern filling the unused part of the stacks, to measure their usage.
+const PAINT: u64 = 0xCCCC_CCCC_CCCC_CCCC;
+
+/// The bounds of the kernel stack of each CPU, indexed by CPU index (see
+/// [`crate::smp::register_cpu`]). A bottom of 0 means that no stack is registered.
+static BOTTOMS: [AtomicU64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+static TOPS: [AtomicU64; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+};
+
+/// An error reported by [`register`] and [`check_current`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The identifier of the current CPU, given to [`crate::percpu::init`], is not a valid CPU
+    /// index.
+    UnknownCpu,
+
+    /// No kernel stack is registered for the current CPU.
+    NotRegistered,
+
+    /// The canary at the lowest end of the stack has been overwritten: the stack overflowed.
+    CanaryCorrupted,
+
+    /// The stack pointer is outside of the registered stack.
+    OutOfBounds(Virtual),
+}
+
+/// The bounds of a kernel stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    bottom: Virtual,
+    top: Virtual,
+}
+
+impl KernelStack {
+    /// Creates a new kernel stack from its lowest address and the address following its highest
+    /// byte.
+    ///
+    /// # Panics
+    /// Panics if the addresses are not aligned on 8 bytes, or if the stack is too small to hold
+    /// the canary.
+    ///
+    /// # Safety
+    /// This function is unsafe because the stack must be mapped and writable, and must only be
+    /// used as a stack, since [`KernelStack::prepare`] writes to its unused part.
+    #[must_use]
+    pub unsafe fn new(bottom: Virtual, top: Virtual) -> Self {
+        assert!(
+            bottom.is_aligned(8u64) && top.is_aligned(8u64),
+            "Stack is not aligned"
+        );
+        assert!(
+            top.as_u64() >= bottom.as_u64() + 8 * CANARY_WORDS as u64,
+            "Stack is too small"
+        );
+        Self { bottom, top }
+    }
+
+    /// Returns the lowest address of the stack.
+    #[must_use]
+    pub const fn bottom(&self) -> Virtual {
+        self.bottom
+    }
+
+    /// Returns the address following the highest byte of the stack.
+    #[must_use]
+    pub const fn top(&self) -> Virtual {
+        self.top
+    }
+
+    /// Returns the size of the stack, in bytes.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn size(&self) -> usize {
+        (self.top.as_u64() - self.bottom.as_u64()) as usize
+    }
+
+    /// Returns `true` if the given address belongs to the stack.
+    #[must_use]
+    pub const fn contains(&self, address: Virtual) -> bool {
+        address.as_u64() >= self.bottom.as_u64() && address.as_u64() < self.top.as_u64()
+    }
+
+    /// Write the canary, and fill the unused part of the stack with the pattern measuring its
+    /// usage. If the current stack pointer is inside the stack, only the part below it is filled.
+    pub fn prepare(&self) {
+        let rsp = current_rsp();
+        let end = if self.contains(rsp) { rsp } else { self.top };
+        let unused = self.words().min(self.offset_words(end));
+
+        for index in 0..unused {
+            let value = if index < CANARY_WORDS { CANARY } else { PAINT };
+            // SAFETY: The word is in the unused part of the stack, which is mapped and writable
+            // (see `KernelStack::new`)
+            unsafe { self.word(index).write_volatile(value) };
+        }
+    }
+
+    /// Returns `true` if the canary is intact.
+    #[must_use]
+    pub fn canary_intact(&self) -> bool {
+        // SAFETY: The stack is mapped (see `KernelStack::new`)
+        (0..CANARY_WORDS).all(|index| unsafe { self.word(index).read_volatile() } == CANARY)
+    }
+
+    /// Returns the maximal number of bytes used on the stack since [`KernelStack::prepare`] was
+    /// called, found by looking for the lowest word that does not contain the pattern anymore.
+    /// This reads the whole unused part of the stack, so it should not be called too often.
+    #[must_use]
+    pub fn high_water(&self) -> usize {
+        let words = self.words();
+        (CANARY_WORDS..words)
+            // SAFETY: The stack is mapped (see `KernelStack::new`)
+            .find(|&index| unsafe { self.word(index).read_volatile() } != PAINT)
+            .map_or(0, |index| (words - index) * 8)
+    }
+
+    /// Returns the number of words of the stack.
+    const fn words(&self) -> usize {
+        self.offset_words(self.top)
+    }
+
+    /// Returns the number of words between the bottom of the stack and the given address.
+    #[allow(clippy::cast_possible_truncation)]
+    const fn offset_words(&self, address: Virtual) -> usize {
+        ((address.as_u64() - self.bottom.as_u64()) / 8) as usize
+    }
+
+    /// Returns a pointer to the word at the given index, starting from the bottom of the stack.
+    /// The words are always accessed with volatile operations, since the used part of the stack
+    /// can be modified at any time.
+    fn word(&self, index: usize) -> *mut u64 {
+        self.bottom.as_mut_ptr::<u64>().wrapping_add(index)
+    }
+}
+
+/// Register the given kernel stack for the current CPU, and prepare it (see
+/// [`KernelStack::prepare`]). This does not change the stack used when entering the kernel,
+/// which must also be set with [`crate::percpu::set_kernel_stack`].
+///
+/// # Errors
+/// Returns [`Error::UnknownCpu`] if the current CPU has no valid CPU index.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area must have been initialized on the current
+/// CPU, with its CPU index as identifier (see [`crate::percpu::cpu_id_fast`]).
+pub unsafe fn register(stack: &KernelStack) -> Result<(), Error> {
+    let cpu = current_cpu().ok_or(Error::UnknownCpu)?;
+    stack.prepare();
+    TOPS[cpu].store(stack.top.as_u64(), Ordering::Relaxed);
+    BOTTOMS[cpu].store(stack.bottom.as_u64(), Ordering::Release);
+    Ok(())
+}
+
+/// Returns the kernel stack registered for the current CPU, if any.
+///
+/// # Safety
+/// See [`register`].
+#[must_use]
+pub unsafe fn current() -> Option<KernelStack> {
+    let cpu = current_cpu()?;
+    match BOTTOMS[cpu].load(Ordering::Acquire) {
+        0 => None,
+        bottom => Some(KernelStack {
+            bottom: Virtual::new(bottom),
+            top: Virtual::new(TOPS[cpu].load(Ordering::Relaxed)),
+        }),
+    }
+}
+
+/// Check the kernel stack of the current CPU: the stack pointer must be inside the registered
+/// stack, and its canary must be intact. This is intended to be called from the timer interrupt
+/// handler, before switching to another stack.
+///
+/// # Errors
+/// Returns an error if the current CPU has no valid CPU index, if no stack is registered, if the
+/// stack pointer is outside of the stack, or if the canary has been overwritten.
+///
+/// # Safety
+/// See [`register`].
+pub unsafe fn check_current() -> Result<(), Error> {
+    current_cpu().ok_or(Error::UnknownCpu)?;
+    let stack = current().ok_or(Error::NotRegistered)?;
+    let rsp = current_rsp();
+    if !stack.contains(rsp) {
+        return Err(Error::OutOfBounds(rsp));
+    }
+    if !stack.canary_intact() {
+        return Err(Error::CanaryCorrupted);
+    }
+    Ok(())
+}
+
+/// Returns the maximal usage of the kernel stack of the current CPU, in bytes (see
+/// [`KernelStack::high_water`]), or `None` if no stack is registered.
+///
+/// # Safety
+/// See [`register`].
+#[must_use]
+pub unsafe fn usage_high_water() -> Option<usize> {
+    current().map(|stack| stack.high_water())
+}
+
+/// Returns the index of the current CPU in the stack tables, read from the per-CPU identifier
+/// without accessing the memory if possible, or `None` if it is not a valid CPU index.
+unsafe fn current_cpu() -> Option<usize> {
+    usize::try_from(crate::percpu::cpu_id_fast())
+        .ok()
+        .filter(|&cpu| cpu < MAX_CPUS)
+}
+
+/// Returns the current stack pointer.
+#[inline]
+fn current_rsp() -> Virtual {
+    let rsp: u64;
+    // SAFETY: Reading the stack pointer has no side effect
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    Virtual::new(rsp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KernelStack, CANARY_WORDS};
+    use crate::address::Virtual;
+
+    #[test]
+    fn canary_and_high_water() {
+        let mut buffer = vec![0u64; 64];
+        let bottom = Virtual::from_ptr(buffer.as_mut_ptr());
+        let stack = unsafe { KernelStack::new(bottom, bottom + 64 * 8u64) };
+        assert_eq!(stack.size(), 512);
+        assert!(!stack.canary_intact());
+
+        stack.prepare();
+        assert!(stack.canary_intact());
+        assert_eq!(stack.high_water(), 0);
+
+        // Simulate the use of the highest 10 words of the stack. The buffer is only accessed
+        // through the pointer of the stack, which would be invalidated by a direct access
+        for index in 54..64 {
+            unsafe { stack.word(index).write_volatile(0) };
+        }
+        assert_eq!(stack.high_water(), 80);
+
+        // Simulate an overflow
+        unsafe { stack.word(CANARY_WORDS - 1).write_volatile(0) };
+        assert!(!stack.canary_intact());
+        assert_eq!(stack.high_water(), 80);
+    }
+}
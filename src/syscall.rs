@@ -0,0 +1,364 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    cpu::{msr, State},
+    percpu::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET},
+    segment::Selector,
+};
+
+/// Flags cleared in the RFLAGS register when entering the kernel with the `syscall` instruction:
+/// the trap flag (TF), the interrupt flag (IF), the direction flag (DF), the nested task flag (NT)
+/// and the alignment check flag (AC).
+pub const FLAGS_MASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 14 | 1 << 18;
+
+/// Handler registered with [`setup`], called by [`entry`] for each system call.
+static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// User code and stack segment selectors pushed by [`entry`] into the saved state. They are
+/// computed from the STAR value given to [`setup`].
+static USER_CS: AtomicU64 = AtomicU64::new(0);
+static USER_SS: AtomicU64 = AtomicU64::new(0);
+
+/// Set if [`entry`] must check that the return address is canonical before using `sysretq` (see
+/// [`crate::cpu::quirks::Quirks::sysret_gp_in_kernel`]). It is set by [`setup`], and by default
+/// until then.
+static SYSRET_CHECK: AtomicBool = AtomicBool::new(true);
+
+/// An invalid layout of segment selectors given to [`Star::try_new`].
+///
+/// The `syscall` and `sysret` instructions compute the segment selectors from a base selector,
+/// and therefore expect a specific layout of the GDT: the kernel data segment must immediately
+/// follow the kernel code segment, and the user code segment must immediately follow the user
+/// data segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidStarLayout;
+
+/// The value of the STAR MSR, containing the segment selectors used by the `syscall` and
+/// `sysret` instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Star(u64);
+
+impl Star {
+    /// Creates a new STAR value from the given segment selectors.
+    ///
+    /// # Panics
+    /// This function panics if the segment selectors do not follow the layout expected by the
+    /// `syscall` and `sysret` instructions (see [`InvalidStarLayout`]).
+    #[must_use]
+    pub const fn new(
+        kernel_code: Selector,
+        kernel_data: Selector,
+        user_code: Selector,
+        user_data: Selector,
+    ) -> Self {
+        match Self::try_new(kernel_code, kernel_data, user_code, user_data) {
+            Ok(star) => star,
+            Err(InvalidStarLayout) => panic!("Invalid segment layout for syscall/sysret"),
+        }
+    }
+
+    /// Tries to create a new STAR value from the given segment selectors.
+    ///
+    /// # Errors
+    /// This function returns an [`InvalidStarLayout`] error if the kernel data segment does not
+    /// immediately follow the kernel code segment, or if the user code segment does not
+    /// immediately follow the user data segment in the GDT.
+    pub const fn try_new(
+        kernel_code: Selector,
+        kernel_data: Selector,
+        user_code: Selector,
+        user_data: Selector,
+    ) -> Result<Self, InvalidStarLayout> {
+        let kernel_code = kernel_code.value() & !7;
+        let kernel_data = kernel_data.value() & !7;
+        let user_code = user_code.value() & !7;
+        let user_data = user_data.value() & !7;
+
+        if kernel_data != kernel_code + 8 || user_code != user_data + 8 || user_data < 8 {
+            return Err(InvalidStarLayout);
+        }
+
+        // The sysret base selector is 8 bytes before the user data segment (this entry was used
+        // by the 32 bits user code segment in compatibility mode), with a requested privilege
+        // level of 3.
+        let sysret_base = (user_data - 8) | 3;
+        Ok(Self((sysret_base as u64) << 48 | (kernel_code as u64) << 32))
+    }
+
+    /// Creates a STAR value from the raw value of the STAR MSR, without checking the layout of the
+    /// segment selectors.
+    #[must_use]
+    pub const fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
+    /// Returns the user code segment selector loaded by the `sysret` instruction.
+    #[must_use]
+    pub const fn user_code(&self) -> u16 {
+        (self.0 >> 48) as u16 + 16
+    }
+
+    /// Returns the user stack segment selector loaded by the `sysret` instruction.
+    #[must_use]
+    pub const fn user_data(&self) -> u16 {
+        (self.0 >> 48) as u16 + 8
+    }
+
+    /// Returns the raw value of the STAR MSR.
+    #[must_use]
+    pub const fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Setup the `syscall` and `sysret` instructions on the current CPU: the SCE bit is set in the
+/// EFER register, the segment selectors are set in the STAR register, the entry point is set to
+/// [`entry`] in the LSTAR register and the [`FLAGS_MASK`] is set in the FMASK register. The given
+/// handler will be called for each system call with the saved user state, and the value of the
+/// RAX register in this state will be returned to the user.
+///
+/// If the `syscall` instruction is available in compatibility mode (see
+/// [`crate::cpu::quirks::Quirks::compat_syscall`]), the CSTAR register is set to [`compat_entry`],
+/// which rejects the system calls of 32 bits code. The return address is only checked by
+/// [`entry`] on the processors where `sysretq` faults in kernel mode (see
+/// [`crate::cpu::quirks::Quirks::sysret_gp_in_kernel`]).
+///
+/// This function must be called on each CPU of the system.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area of each CPU must have been initialized with
+/// [`crate::percpu::init`] and its kernel stack set with [`crate::percpu::set_kernel_stack`],
+/// otherwise the first system call will crash the kernel. The
+/// segment selectors in the STAR value must also be valid descriptors in the GDT.
+pub unsafe fn setup(star: Star, handler: fn(&mut State)) {
+    HANDLER.store(handler as usize, Ordering::Release);
+    USER_CS.store(u64::from(star.user_code()), Ordering::Relaxed);
+    USER_SS.store(u64::from(star.user_data()), Ordering::Relaxed);
+    SYSRET_CHECK.store(crate::cpu::quirks().sysret_gp_in_kernel, Ordering::Relaxed);
+
+    msr::efer::set(msr::efer::Flags::SCE);
+    msr::star::write(star);
+    msr::lstar::write(entry as unsafe extern "C" fn() as usize as u64);
+    if crate::cpu::quirks().compat_syscall {
+        msr::cstar::write(compat_entry as unsafe extern "C" fn() as usize as u64);
+    }
+    msr::fmask::write(FLAGS_MASK);
+}
+
+/// Called by [`entry`] with a pointer to the saved user state. It simply calls the handler
+/// registered with [`setup`].
+extern "C" fn dispatch(state: &mut State) {
+    let handler = HANDLER.load(Ordering::Acquire);
+    assert!(handler != 0, "No system call handler set");
+
+    // SAFETY: The handler was stored from a valid `fn(&mut State)` pointer in `setup`
+    let handler: fn(&mut State) = unsafe { core::mem::transmute(handler) };
+    handler(state);
+}
+
+/// The entry point of the `syscall` instruction. It performs the following actions:
+/// - Swap the GS register to the kernel GS, save the user stack pointer and load the kernel stack
+///   pointer from the per-CPU area (see [`crate::percpu::PerCpu`]).
+///
+/// - Build a [`State`] on the kernel stack with the same layout as the one built by the interrupt
+///   handlers. The instruction pointer and the flags are taken from the RCX and R11 registers,
+///   where the `syscall` instruction saved them.
+///
+/// - Call the handler registered with [`setup`] with a pointer to this state.
+///
+/// - Restore the state and return to user mode with the `sysretq` instruction. If the instruction
+///   pointer in the state is not canonical, the `iretq` instruction is used instead on the
+///   processors where `sysretq` would raise a general protection fault in kernel mode but with
+///   the user stack pointer (see [`setup`]). The `iretq` instruction raises it with the kernel
+///   stack and the kernel GS, which can be handled safely.
+///
+/// The interrupts are disabled by the `syscall` instruction, but a NMI, a debug exception or a
+/// machine check can still be triggered between the `swapgs` and the switch of the page tables,
+/// in kernel mode but with the user page tables or the user GS. Their handlers must therefore be
+/// generated with the `paranoid_interrupt_handler` or `paranoid_light_interrupt_handler` macros,
+/// which do not trust the saved CS register.
+#[naked]
+#[allow(clippy::too_many_lines)]
+pub unsafe extern "C" fn entry() {
+    asm!(
+        "
+        # Switch to the kernel GS and the kernel stack
+        swapgs
+        mov gs:[{user_stack}], rsp
+
+        # Switch to the kernel page tables if the page table isolation is enabled. The user
+        # stack pointer is saved, so RSP can be used as a scratch register
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 4f
+        mov rsp, cr3
+        or rsp, gs:[{pti_cr3}]
+        btr rsp, 12
+        btr rsp, 11
+        mov cr3, rsp
+       4:
+        mov rsp, gs:[{kernel_stack}]
+
+        # Push an interrupt frame, like the CPU does during an interrupt
+        push QWORD PTR [rip + {user_ss}]
+        push QWORD PTR gs:[{user_stack}]
+        push r11
+        push QWORD PTR [rip + {user_cs}]
+        push rcx
+
+        # Push fake error code, interrupt number and return address
+        push 0
+        push 0
+        push 0
+
+        # Save scratch registers
+        push r11
+        push r10
+        push r9
+        push r8
+        push rdi
+        push rsi
+        push rdx
+        push rcx
+        push rax
+
+        # Save preserved registers
+        push r15
+        push r14
+        push r13
+        push r12
+        push rbx
+        push rbp
+
+        # Call the handler with a pointer to the state. We pushed 23 registers on a 16 bytes
+        # aligned stack, so it must be realigned before the call
+        mov rdi, rsp
+        sub rsp, 8
+        call {dispatch}
+        add rsp, 8
+
+        # The handler may have enabled interrupts: disable them to avoid a race condition
+        # with swapgs
+        cli
+
+        # Restore preserved registers
+        pop rbp
+        pop rbx
+        pop r12
+        pop r13
+        pop r14
+        pop r15
+
+        # Restore scratch registers
+        pop rax
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+
+        # Skip error code, interrupt number and return address
+        add rsp, 8 * 3
+
+        # Check that the return address is canonical, if sysret would fault in kernel mode
+        # otherwise. The RCX and R11 registers are clobbered by the syscall instruction, so we
+        # can freely use them
+        mov rcx, [rsp]
+        cmp BYTE PTR [rip + {sysret_check}], 0
+        je 6f
+        mov r11, rcx
+        shl r11, 16
+        sar r11, 16
+        cmp r11, rcx
+        jne 2f
+       6:
+
+        # Return to user mode with sysret
+        mov r11, [rsp + 8 * 2]
+        mov rsp, [rsp + 8 * 3]
+
+        # Switch to the user page tables if the page table isolation is enabled. The user stack
+        # pointer slot of the per-CPU area is used to preserve RAX, which holds the return value
+        cmp QWORD PTR gs:[{pti_cr3}], 0
+        je 5f
+        mov gs:[{user_stack}], rax
+        mov rax, cr3
+        or rax, gs:[{pti_cr3}]
+        mov cr3, rax
+        mov rax, gs:[{user_stack}]
+       5:
+
+        # Clear the CPU buffers if the MDS mitigation is enabled. This must be done after the
+        # last access to the kernel data, otherwise it would be left in the buffers
+        cmp BYTE PTR [rip + {clear_cpu_buffers}], 0
+        je 3f
+        verw WORD PTR [rip + {verw_selector}]
+       3:
+        swapgs
+        sysretq
+
+        # The return address is not canonical: return with iretq and the kernel GS, so that the
+        # general protection fault is raised on the kernel stack
+       2:
+        iretq
+        ",
+        user_stack = const USER_STACK_OFFSET,
+        kernel_stack = const KERNEL_STACK_OFFSET,
+        user_cs = sym USER_CS,
+        user_ss = sym USER_SS,
+        dispatch = sym dispatch,
+        sysret_check = sym SYSRET_CHECK,
+        pti_cr3 = const crate::percpu::PTI_CR3_OFFSET,
+        clear_cpu_buffers = sym crate::cpu::mitigations::CLEAR_CPU_BUFFERS,
+        verw_selector = sym crate::cpu::mitigations::VERW_SELECTOR,
+        options(noreturn)
+    );
+}
+
+/// The entry point of the `syscall` instruction in compatibility mode, on the processors where it
+/// is available (see [`setup`]). 32 bits system calls are not supported: it immediately returns
+/// to the user code with -1 in EAX, without switching to the kernel GS, stack or page tables. With
+/// the page table isolation, this function must therefore be mapped in the trampoline set.
+#[naked]
+pub unsafe extern "C" fn compat_entry() {
+    asm!(
+        "
+        mov eax, -1
+        sysret
+        ",
+        options(noreturn)
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{cpu::Privilege, segment::Selector};
+
+    #[test]
+    fn star_layout() {
+        let star = super::Star::new(
+            Selector::new(1, Privilege::KERNEL),
+            Selector::new(2, Privilege::KERNEL),
+            Selector::new(4, Privilege::USER),
+            Selector::new(3, Privilege::USER),
+        );
+        assert_eq!(star.value(), 0x0013_0008_0000_0000);
+        assert_eq!(star.user_code(), 0x23);
+        assert_eq!(star.user_data(), 0x1B);
+    }
+
+    #[test]
+    fn star_invalid_layout() {
+        let star = super::Star::try_new(
+            Selector::KERNEL_CODE64,
+            Selector::KERNEL_DATA,
+            Selector::USER_CODE64,
+            Selector::USER_DATA,
+        );
+        assert_eq!(star, Err(super::InvalidStarLayout));
+    }
+}
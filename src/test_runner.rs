@@ -0,0 +1,122 @@
+//! A runner for the `custom_test_frameworks` feature, enabled with the `test_runner` feature, to
+//! run tests inside QEMU on the real IDT, GDT and interrupt paths. The results are printed on the
+//! global serial console (see [`crate::serial::logger`]) or on the QEMU debug console, and QEMU
+//! exits with [`ExitCode::Success`] if all tests passed, or [`ExitCode::Failed`] otherwise (see
+//! [`crate::qemu`]).
+//!
+//! A kernel using this runner must set `#![test_runner(silicium_x86_64::test_runner::runner)]`,
+//! call the generated test harness, and call [`panic`] from its panic handler. A watchdog on the
+//! PIT can be started with [`start_watchdog`] to fail the tests that never return, if the IRQ 0
+//! handler calls [`tick`].
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    pit::Pit,
+    qemu::{self, ExitCode},
+    serial::logger::CONSOLE,
+};
+
+/// Set if the results are printed on the QEMU debug console instead of the serial console.
+static DEBUGCON: AtomicBool = AtomicBool::new(false);
+
+/// The number of PIT interrupts since the start of the current test.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of PIT interrupts after which the current test fails, or 0 if the watchdog is not
+/// started.
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A test that can be run by [`runner`]. It is implemented for all functions without arguments,
+/// which are the items annotated with `#[test_case]`.
+pub trait Testable {
+    /// Run the test, printing its name and its result.
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        output(format_args!("{}...\t", core::any::type_name::<T>()));
+        TICKS.store(0, Ordering::Relaxed);
+        self();
+        output(format_args!("[ok]\n"));
+    }
+}
+
+/// Select whether the results are printed on the QEMU debug console (see
+/// [`qemu::debugcon_write`]) instead of the serial console.
+pub fn use_debugcon(enabled: bool) {
+    DEBUGCON.store(enabled, Ordering::Relaxed);
+}
+
+/// Run all the given tests and exit QEMU with [`ExitCode::Success`]. A failing test panics, and
+/// [`panic`] exits QEMU with [`ExitCode::Failed`].
+pub fn runner(tests: &[&dyn Testable]) -> ! {
+    output(format_args!("Running {} tests\n", tests.len()));
+    for test in tests {
+        test.run();
+    }
+    qemu::exit_with(ExitCode::Success);
+}
+
+/// Report the failure of the current test and exit QEMU with [`ExitCode::Failed`]. This must be
+/// called by the panic handler of the kernel.
+pub fn panic(info: &PanicInfo) -> ! {
+    // SAFETY: The tests are stopped, nobody will continue printing with the lock held
+    unsafe {
+        CONSOLE.force_unlock();
+    }
+    output(format_args!("[failed]\n\n{info}\n"));
+    qemu::exit_with(ExitCode::Failed);
+}
+
+/// Start a watchdog failing any test that runs for longer than the given number of milliseconds.
+/// The PIT is configured in periodic mode (see [`Pit::setup`]), and [`tick`] must be called by
+/// the handler of the IRQ 0.
+///
+/// # Safety
+/// This function is unsafe because it reprograms the PIT, and the IRQ 0 must be routed to a
+/// handler calling [`tick`]: interrupts must be enabled during the tests.
+pub unsafe fn start_watchdog(pit: &Pit, timeout_ms: u64) {
+    let ticks = (timeout_ms * pit.get_frequency()).div_ceil(1000).max(1);
+    TICKS.store(0, Ordering::Relaxed);
+    TIMEOUT_TICKS.store(ticks, Ordering::Relaxed);
+    pit.setup();
+}
+
+/// Signal a PIT interrupt to the watchdog. If the current test has timed out, its failure is
+/// reported and QEMU exits with [`ExitCode::Failed`]. Does nothing if the watchdog is not
+/// started.
+pub fn tick() {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout != 0 && TICKS.fetch_add(1, Ordering::Relaxed) + 1 >= timeout {
+        // SAFETY: The timed out test will never print anything again
+        unsafe {
+            CONSOLE.force_unlock();
+        }
+        output(format_args!("[timeout]\n"));
+        qemu::exit_with(ExitCode::Failed);
+    }
+}
+
+/// Print the given formatted arguments on the selected output.
+fn output(args: fmt::Arguments) {
+    if DEBUGCON.load(Ordering::Relaxed) {
+        let _ = Debugcon.write_fmt(args);
+    } else {
+        CONSOLE.write_fmt(args);
+    }
+}
+
+/// A writer to the QEMU debug console.
+struct Debugcon;
+
+impl Write for Debugcon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        qemu::debugcon_write(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Thermal monitoring of Intel processors. The thermal status registers report whether the core
+//! (or the package) is currently throttled, and log the throttling events since they were last
+//! cleared, so that the kernel can report them. The digital thermal sensor gives the temperature
+//! as a number of degrees below the maximal junction temperature (`TjMax`).
+use bitflags::bitflags;
+
+use crate::{
+    cpu::msr,
+    lapic::{LocalApic, LvtEntry},
+};
+
+/// The thermal status register of the current core.
+const IA32_THERM_STATUS: u32 = 0x19C;
+
+/// The thermal interrupt control register of the current core.
+const IA32_THERM_INTERRUPT: u32 = 0x19B;
+
+/// The thermal status register of the current package.
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+
+/// The thermal interrupt control register of the current package.
+const IA32_PACKAGE_THERM_INTERRUPT: u32 = 0x1B2;
+
+/// The temperature target register, which contains `TjMax`.
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+
+/// The bit of the thermal status registers set if the digital readout is valid.
+const READING_VALID: u64 = 1 << 31;
+
+bitflags! {
+    /// The flags of a thermal status register. The log flags are sticky: they are set by the
+    /// processor when the corresponding status flag is set, and stay set until cleared with
+    /// [`super::clear_logs`].
+    pub struct ThermalFlags: u64 {
+        /// The processor is currently throttled because of its temperature.
+        const THROTTLING = 1 << 0;
+        const THROTTLING_LOG = 1 << 1;
+
+        /// The PROCHOT# signal (or FORCEPR#) is currently asserted.
+        const PROCHOT = 1 << 2;
+        const PROCHOT_LOG = 1 << 3;
+
+        /// The critical temperature has been reached: the processor may shut down.
+        const CRITICAL = 1 << 4;
+        const CRITICAL_LOG = 1 << 5;
+
+        /// The temperature is above the first programmable threshold.
+        const THRESHOLD_1 = 1 << 6;
+        const THRESHOLD_1_LOG = 1 << 7;
+
+        /// The temperature is above the second programmable threshold.
+        const THRESHOLD_2 = 1 << 8;
+        const THRESHOLD_2_LOG = 1 << 9;
+
+        /// The processor is currently limited by its power limit.
+        const POWER_LIMIT = 1 << 10;
+        const POWER_LIMIT_LOG = 1 << 11;
+    }
+}
+
+/// The decoded value of a thermal status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermalStatus {
+    flags: ThermalFlags,
+    readout: Option<u8>,
+}
+
+impl ThermalStatus {
+    /// Decode the given value of a thermal status register.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self {
+            flags: ThermalFlags::from_bits_truncate(bits),
+            readout: if bits & READING_VALID != 0 {
+                Some(((bits >> 16) & 0x7F) as u8)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the flags of the status register.
+    #[must_use]
+    pub const fn flags(&self) -> ThermalFlags {
+        self.flags
+    }
+
+    /// Returns `true` if the processor is throttling now or has throttled since the logs were
+    /// last cleared, because of its temperature, of the PROCHOT# signal or of its power limit.
+    #[must_use]
+    pub const fn throttled(&self) -> bool {
+        self.flags.intersects(
+            ThermalFlags::THROTTLING_LOG
+                .union(ThermalFlags::PROCHOT_LOG)
+                .union(ThermalFlags::POWER_LIMIT_LOG),
+        )
+    }
+
+    /// Returns the digital readout of the thermal sensor, i.e. the number of degrees Celsius
+    /// below `TjMax`, or `None` if it is not valid.
+    #[must_use]
+    pub const fn readout(&self) -> Option<u8> {
+        self.readout
+    }
+
+    /// Returns the temperature in degrees Celsius, given `TjMax` (see [`tj_max`]), or `None` if the
+    /// readout is not valid.
+    #[must_use]
+    pub const fn temperature(&self, tj_max: u8) -> Option<u8> {
+        match self.readout {
+            Some(readout) => Some(tj_max.saturating_sub(readout)),
+            None => None,
+        }
+    }
+}
+
+/// Returns `true` if the digital thermal sensor and the core thermal status register are
+/// supported by the current CPU.
+#[must_use]
+pub fn supported() -> bool {
+    thermal_leaf() & (1 << 0) != 0
+}
+
+/// Returns `true` if the package thermal status register is supported by the current CPU.
+#[must_use]
+pub fn package_supported() -> bool {
+    thermal_leaf() & (1 << 6) != 0
+}
+
+/// Read the thermal status of the current core.
+///
+/// # Safety
+/// This function is unsafe because the register is only supported if [`supported`] returns
+/// `true`.
+#[must_use]
+pub unsafe fn core_status() -> ThermalStatus {
+    ThermalStatus::from_bits(msr::read(IA32_THERM_STATUS))
+}
+
+/// Read the thermal status of the current package.
+///
+/// # Safety
+/// This function is unsafe because the register is only supported if [`package_supported`]
+/// returns `true`.
+#[must_use]
+pub unsafe fn package_status() -> ThermalStatus {
+    ThermalStatus::from_bits(msr::read(IA32_PACKAGE_THERM_STATUS))
+}
+
+/// Clear the log flags of the thermal status registers of the current core and, if supported, of
+/// the current package.
+///
+/// # Safety
+/// This function is unsafe because the register is only supported if [`supported`] returns
+/// `true`.
+pub unsafe fn clear_logs() {
+    msr::write(IA32_THERM_STATUS, 0);
+    if package_supported() {
+        msr::write(IA32_PACKAGE_THERM_STATUS, 0);
+    }
+}
+
+/// Returns `TjMax`, the maximal junction temperature of the current CPU, in degrees Celsius. This
+/// register is not architectural but is implemented by all Intel processors supporting the
+/// digital thermal sensor since the Core microarchitecture. Returns `None` if the register
+/// reports 0.
+///
+/// # Safety
+/// This function is unsafe because the register may not exist on old or non-Intel processors.
+#[must_use]
+pub unsafe fn tj_max() -> Option<u8> {
+    match msr::read(MSR_TEMPERATURE_TARGET).to_le_bytes()[2] {
+        0 => None,
+        tj_max => Some(tj_max),
+    }
+}
+
+/// Route the thermal interrupts of the current core (and package, if supported) to the given
+/// vector, through the thermal LVT entry of the local APIC. An interrupt is fired when the
+/// throttling starts or stops, when the PROCHOT# signal is asserted, and when the critical
+/// temperature is reached. The handler should read the status registers and clear the logs.
+///
+/// # Safety
+/// This function is unsafe because the registers are only supported if [`supported`] returns
+/// `true`, and the interrupt handler must be installed.
+pub unsafe fn route_interrupts(lapic: &LocalApic, vector: u8) {
+    // High and low temperature, PROCHOT# and critical temperature interrupts
+    let enable = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 4;
+
+    msr::write(IA32_THERM_INTERRUPT, msr::read(IA32_THERM_INTERRUPT) | enable);
+    if package_supported() {
+        let value = msr::read(IA32_PACKAGE_THERM_INTERRUPT);
+        msr::write(IA32_PACKAGE_THERM_INTERRUPT, value | enable);
+    }
+    lapic.set_lvt_thermal(LvtEntry::new(vector));
+}
+
+/// Returns the register EAX of the thermal and power management leaf of `cpuid`, or 0 if the leaf
+/// is not supported.
+fn thermal_leaf() -> u32 {
+    unsafe {
+        if core::arch::x86_64::__cpuid(0).eax < 6 {
+            return 0;
+        }
+        core::arch::x86_64::__cpuid(6).eax
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ThermalFlags, ThermalStatus};
+
+    #[test]
+    fn thermal_status_decoding() {
+        let status = ThermalStatus::from_bits(1 << 31 | 0x1E << 16 | 0b1010);
+        assert_eq!(status.flags(), ThermalFlags::THROTTLING_LOG | ThermalFlags::PROCHOT_LOG);
+        assert!(status.throttled());
+        assert_eq!(status.readout(), Some(30));
+        assert_eq!(status.temperature(100), Some(70));
+
+        let status = ThermalStatus::from_bits(0x1E << 16);
+        assert!(!status.throttled());
+        assert_eq!(status.temperature(100), None);
+    }
+}
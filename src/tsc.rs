@@ -1,9 +1,18 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    clock::Counter,
+    cpu::{feature::Feature, quirks::Vendor},
+    pit::Pit,
+};
 
 /// Returns true if the time stamp counter is supported.
 pub fn is_supported() -> bool {
-    unsafe {
-        core::arch::x86_64::__cpuid(0x00000001).edx & (1 << 4) != 0
-    }
+    crate::cpu::features().has(Feature::Tsc)
 }
 
 /// Returns true if the time stamp counter is invariant. Invariant means that it is not affected by
@@ -12,9 +21,7 @@ pub fn is_supported() -> bool {
 /// Please note that this function cannot distinguish between invariant TSCs, and constant TSCs (
 /// which can vary in frequency when the CPU is in a low power state).
 pub fn is_invariant() -> bool {
-    unsafe {
-        core::arch::x86_64::__cpuid(0x80000007).edx & (1 << 8) != 0
-    }
+    crate::cpu::features().has(Feature::InvariantTsc)
 }
 
 /// Reads the time stamp counter. 
@@ -29,3 +36,377 @@ pub fn read() -> u64 {
         core::arch::x86_64::_rdtsc()
     }
 }
+
+/// The number of PIT periods (or counter periods) measured by [`calibrate`] when the frequency of
+/// the TSC is not enumerated by the CPU. The shortest measure is kept, to filter out the measures
+/// disturbed by system management interrupts.
+const CALIBRATION_ROUNDS: usize = 3;
+
+/// The number of calibration periods per second when the TSC is measured against an external
+/// counter (a 10 ms period).
+const COUNTER_PERIODS_PER_SECOND: u64 = 100;
+
+/// The maximal number of `pause` instructions executed by [`delay`] between two reads of the TSC.
+const MAX_BACKOFF: u32 = 16;
+
+/// The maximal number of spins while waiting for the other CPU in [`SyncProbe`], before giving up.
+const SYNC_TIMEOUT_SPINS: u64 = 100_000_000;
+
+/// The frequency of the TSC recorded by [`calibrate`] or [`set_clock`], in Hz, or 0 if unknown.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The frequency of the time stamp counter, used to convert between cycles and nanoseconds. The
+/// conversions use 128 bits intermediate values, so they cannot overflow before the result does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscClock {
+    hz: u64,
+}
+
+impl TscClock {
+    /// Creates a new TSC clock with the given frequency, in Hz.
+    ///
+    /// # Panics
+    /// Panics if the frequency is 0.
+    #[must_use]
+    pub const fn new(hz: u64) -> Self {
+        assert!(hz != 0, "TSC frequency cannot be 0");
+        Self { hz }
+    }
+
+    /// Returns the frequency of the TSC, in Hz.
+    #[must_use]
+    pub const fn hz(&self) -> u64 {
+        self.hz
+    }
+
+    /// Converts the given number of TSC cycles into nanoseconds, rounded down. Saturates to
+    /// `u64::MAX` if the result does not fit in 64 bits.
+    #[must_use]
+    pub fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        let ns = u128::from(cycles) * 1_000_000_000 / u128::from(self.hz);
+        u64::try_from(ns).unwrap_or(u64::MAX)
+    }
+
+    /// Converts the given number of nanoseconds into TSC cycles, rounded up. Saturates to
+    /// `u64::MAX` if the result does not fit in 64 bits.
+    #[must_use]
+    pub fn ns_to_cycles(&self, ns: u64) -> u64 {
+        let cycles = (u128::from(ns) * u128::from(self.hz)).div_ceil(1_000_000_000);
+        u64::try_from(cycles).unwrap_or(u64::MAX)
+    }
+}
+
+/// Determines the frequency of the time stamp counter. If the CPU enumerates it with the `cpuid`
+/// instruction (leaf 0x15, or the base frequency of leaf 0x16 on Intel processors), it is computed
+/// from it. Otherwise, the TSC is measured during several 10 ms periods of the given external
+/// counter if any (such as the HPET main counter, see [`Counter`]), or of the given PIT (see
+/// [`Pit::wait_period`]): a PIT with a frequency of 100 Hz (a 10 ms period) gives a good
+/// trade-off between accuracy and boot time.
+///
+/// The clock is also recorded with [`set_clock`], so that [`delay`] can be used after.
+///
+/// # Safety
+/// This function is unsafe because it reprograms the channel 2 of the PIT when no counter is
+/// given. Interrupts should be disabled during the calibration to keep it accurate.
+#[must_use]
+pub unsafe fn calibrate(pit: &Pit, counter: Option<&Counter>) -> TscClock {
+    let clock = if let Some(hz) = enumerated_frequency() {
+        TscClock::new(hz)
+    } else if let Some(counter) = counter {
+        TscClock::new(measure(counter))
+    } else {
+        let mut cycles = u64::MAX;
+        for _ in 0..CALIBRATION_ROUNDS {
+            let start = read();
+            pit.wait_period();
+            cycles = cycles.min(read() - start);
+        }
+        TscClock::new((cycles * pit.get_frequency()).max(1))
+    };
+
+    set_clock(clock);
+    clock
+}
+
+/// Measure the frequency of the TSC against the given counter, during several periods of
+/// 1 / [`COUNTER_PERIODS_PER_SECOND`] second. The measure starts on a tick of the counter, and
+/// the lowest frequency is kept, since a system management interrupt between the last read of
+/// the counter and the read of the TSC can only add cycles.
+fn measure(counter: &Counter) -> u64 {
+    let period = (counter.hz / COUNTER_PERIODS_PER_SECOND).max(1);
+    let mut hz = u64::MAX;
+    for _ in 0..CALIBRATION_ROUNDS {
+        let base = (counter.read)();
+        let mut origin = base;
+        while origin == base {
+            core::hint::spin_loop();
+            origin = (counter.read)();
+        }
+        let start = read();
+
+        let mut ticks = 0;
+        while ticks < period {
+            core::hint::spin_loop();
+            ticks = (counter.read)().wrapping_sub(origin);
+        }
+        let cycles = read() - start;
+        let round = u128::from(cycles) * u128::from(counter.hz) / u128::from(ticks);
+        hz = hz.min(u64::try_from(round).unwrap_or(u64::MAX));
+    }
+    hz.max(1)
+}
+
+/// Record the given clock as the frequency of the TSC, used by [`delay`]. This is done by
+/// [`calibrate`], but can also be used if the frequency is known by other means (for example,
+/// given by an hypervisor).
+pub fn set_clock(clock: TscClock) {
+    FREQUENCY.store(clock.hz(), Ordering::Relaxed);
+}
+
+/// Returns the clock recorded by [`calibrate`] or [`set_clock`], if any.
+#[must_use]
+pub fn clock() -> Option<TscClock> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(TscClock::new(hz)),
+    }
+}
+
+/// Busy-wait for at least the given duration, using the TSC. The TSC is read less and less often
+/// as the wait goes on, with up to [`MAX_BACKOFF`] `pause` instructions between two reads, to
+/// reduce the power consumption and leave resources to the sibling hyperthread, while keeping a
+/// sub-microsecond precision. The TSC must be invariant for the delay to be accurate (see
+/// [`is_invariant`]).
+///
+/// # Panics
+/// Panics if the frequency of the TSC is unknown (see [`calibrate`] and [`set_clock`]).
+pub fn delay(duration: Duration) {
+    let clock = clock().expect("TSC is not calibrated");
+    let nanoseconds = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    let cycles = clock.ns_to_cycles(nanoseconds);
+
+    let start = read();
+    let mut backoff = 1;
+    while read().wrapping_sub(start) < cycles {
+        for _ in 0..backoff {
+            core::hint::spin_loop();
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Returns the frequency of the TSC enumerated by the `cpuid` instruction, if any. The leaf 0x15
+/// gives the ratio between the TSC and the core crystal clock, and the frequency of the crystal
+/// if it is known. Otherwise, the processor base frequency of the leaf 0x16 is used, which is
+/// equal to the TSC frequency on Intel processors. Both leaves are only trusted on Intel
+/// processors, like in [`crate::lapic`].
+fn enumerated_frequency() -> Option<u64> {
+    if crate::cpu::vendor() != Vendor::Intel {
+        return None;
+    }
+    let max = unsafe { core::arch::x86_64::__cpuid(0).eax };
+    if max >= 0x15 {
+        let leaf = unsafe { core::arch::x86_64::__cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax));
+        }
+    }
+    if max >= 0x16 {
+        let leaf = unsafe { core::arch::x86_64::__cpuid(0x16) };
+        if leaf.eax & 0xFFFF != 0 {
+            return Some(u64::from(leaf.eax & 0xFFFF) * 1_000_000);
+        }
+    }
+    None
+}
+
+/// Reads the time stamp counter, ordered with the surrounding memory accesses and instructions.
+/// Unlike [`read`], the counter cannot be read before the previous loads and stores are
+/// globally visible, nor after the next instructions begin execution.
+#[must_use]
+pub fn read_ordered() -> u64 {
+    unsafe {
+        core::arch::x86_64::_mm_mfence();
+        core::arch::x86_64::_mm_lfence();
+        let tsc = core::arch::x86_64::_rdtsc();
+        core::arch::x86_64::_mm_lfence();
+        tsc
+    }
+}
+
+/// The offset between the TSC of two CPUs, measured with a [`SyncProbe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Skew {
+    offset: i64,
+    uncertainty: u64,
+}
+
+impl Skew {
+    /// Computes the skew from one round trip: the local CPU read `start`, then the remote CPU
+    /// read `remote`, then the local CPU read `end`. The remote read happened between the two
+    /// local reads, so the offset is estimated from the middle of the round trip, with an
+    /// uncertainty of half the round trip.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn from_round_trip(start: u64, remote: u64, end: u64) -> Self {
+        let half = end.saturating_sub(start) / 2;
+        let offset = i128::from(remote) - i128::from(start) - i128::from(half);
+        Self {
+            offset: offset.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64,
+            uncertainty: half,
+        }
+    }
+
+    /// Returns the estimated offset of the remote TSC from the local one, in cycles. A positive
+    /// offset means that the remote TSC is ahead of the local one.
+    #[must_use]
+    pub const fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    /// Returns the uncertainty of the offset, in cycles.
+    #[must_use]
+    pub const fn uncertainty(&self) -> u64 {
+        self.uncertainty
+    }
+
+    /// Returns `true` if the TSCs can be considered as synchronized, i.e. if the offset may be
+    /// smaller than the given tolerance, in cycles, given the uncertainty of the measure.
+    #[must_use]
+    pub const fn is_synchronized(&self, tolerance: u64) -> bool {
+        self.offset.unsigned_abs() <= self.uncertainty.saturating_add(tolerance)
+    }
+}
+
+impl fmt::Display for Skew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TSC skew of {} cycles (+/- {})", self.offset, self.uncertainty)
+    }
+}
+
+/// A probe used to measure the offset between the TSC of the BSP and the TSC of an AP during the
+/// SMP bring-up. The BSP calls [`SyncProbe::measure`] while the AP calls [`SyncProbe::respond`]
+/// with the same number of rounds. In each round, the BSP reads its TSC and signals the AP, which
+/// reads its own TSC and signals back, and the BSP reads its TSC again. The round with the
+/// shortest round trip gives the most precise estimate of the offset.
+///
+/// The probe can be reused for the next AP once [`SyncProbe::measure`] returned.
+#[derive(Debug)]
+pub struct SyncProbe {
+    turn: AtomicU64,
+    remote: AtomicU64,
+}
+
+impl SyncProbe {
+    /// Creates a new probe.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            turn: AtomicU64::new(0),
+            remote: AtomicU64::new(0),
+        }
+    }
+
+    /// Measure the offset between the TSC of the current CPU and the TSC of the CPU calling
+    /// [`SyncProbe::respond`], with the given number of rounds. Returns `None` if the other CPU
+    /// does not respond in time, in which case the probe should not be reused.
+    ///
+    /// # Panics
+    /// Panics if the number of rounds is 0.
+    #[must_use]
+    pub fn measure(&self, rounds: u32) -> Option<Skew> {
+        assert!(rounds > 0, "TSC synchronization check needs at least one round");
+        let mut best: Option<Skew> = None;
+
+        for round in 0..u64::from(rounds) {
+            let start = read_ordered();
+            self.turn.store(round * 2 + 1, Ordering::Release);
+            if !wait_turn(&self.turn, round * 2 + 2) {
+                return None;
+            }
+            let end = read_ordered();
+
+            let skew = Skew::from_round_trip(start, self.remote.load(Ordering::Relaxed), end);
+            best = match best {
+                Some(best) if best.uncertainty <= skew.uncertainty => Some(best),
+                _ => Some(skew),
+            };
+        }
+
+        self.turn.store(0, Ordering::Release);
+        best
+    }
+
+    /// Respond to the rounds of [`SyncProbe::measure`] called by another CPU. Returns `false` if
+    /// the other CPU does not start a round in time.
+    #[must_use]
+    pub fn respond(&self, rounds: u32) -> bool {
+        for round in 0..u64::from(rounds) {
+            if !wait_turn(&self.turn, round * 2 + 1) {
+                return false;
+            }
+            self.remote.store(read_ordered(), Ordering::Relaxed);
+            self.turn.store(round * 2 + 2, Ordering::Release);
+        }
+        true
+    }
+}
+
+impl Default for SyncProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spin until the given turn counter reaches the given value. Returns `false` on timeout.
+fn wait_turn(turn: &AtomicU64, expected: u64) -> bool {
+    for _ in 0..SYNC_TIMEOUT_SPINS {
+        if turn.load(Ordering::Acquire) == expected {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Skew, TscClock};
+    use crate::clock::Counter;
+
+    #[test]
+    fn tsc_clock_conversions() {
+        let clock = TscClock::new(3_000_000_000);
+        assert_eq!(clock.cycles_to_ns(3_000), 1_000);
+        assert_eq!(clock.ns_to_cycles(1_000), 3_000);
+        assert_eq!(clock.ns_to_cycles(1), 3);
+        assert_eq!(clock.cycles_to_ns(u64::MAX), 6_148_914_691_236_517_205);
+
+        let clock = TscClock::new(1);
+        assert_eq!(clock.ns_to_cycles(1), 1);
+        assert_eq!(clock.cycles_to_ns(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn skew_estimation() {
+        let skew = Skew::from_round_trip(1_000, 1_600, 1_200);
+        assert_eq!(skew.offset(), 500);
+        assert_eq!(skew.uncertainty(), 100);
+        assert!(!skew.is_synchronized(300));
+        assert!(skew.is_synchronized(400));
+
+        let skew = Skew::from_round_trip(1_000, 1_050, 1_100);
+        assert_eq!(skew.offset(), 0);
+    }
+
+    #[test]
+    fn counter_calibration() {
+        // The TSC measured against itself, announced with a 1 MHz frequency
+        let counter = Counter {
+            read: super::read,
+            hz: 1_000_000,
+        };
+        let hz = super::measure(&counter);
+        assert!((900_000..1_100_000).contains(&hz), "{hz}");
+    }
+}
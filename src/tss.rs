@@ -7,6 +7,11 @@ pub struct TaskStateSegment {
     pub interrupt_stack_table: [u64; 7],
     reserved_3: u64,
     reserved_4: u16,
+
+    /// The offset of the I/O permission bitmap from the base of the TSS. It is set to the size of
+    /// the TSS by [`TaskStateSegment::new`], and the limit of the TSS descriptor ends with the
+    /// TSS (see [`crate::gdt::Descriptor::tss`]): there is no bitmap, and the user code can only
+    /// access the I/O ports if its I/O privilege level allows it (see [`crate::cpu::set_iopl`]).
     pub iomap_base: u16,
 }
 
@@ -0,0 +1,119 @@
+//! Copies between the kernel and the user space. The user pointers given by system calls cannot be
+//! trusted: the range is checked to be entirely in the user half of the address space, and the
+//! copy tolerates unmapped user pages thanks to the exception table (see [`crate::extable`]), so
+//! that a bad pointer returns an error to the caller instead of crashing the kernel.
+//!
+//! The page fault handler of the kernel must first try to resolve the fault as usual (for example
+//! for a demand-paged user page), and only call [`crate::extable::fixup`] if the fault cannot be
+//! resolved. If SMAP is enabled, the user pages are only accessible during the copy, with the
+//! `stac` and `clac` instructions.
+use core::arch::asm;
+
+use crate::{address::Virtual, cpu::cr4};
+
+/// The end of the user half of the address space.
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// A user page could not be accessed during the copy, or the user range is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+/// Copy the bytes at the given user address into the given kernel buffer.
+///
+/// # Errors
+/// Returns [`Fault`] if the user range is not entirely in the user half of the address space, or
+/// if a page of the range is not mapped or not readable. The content of the buffer is then
+/// unspecified.
+///
+/// # Safety
+/// This function is unsafe because the page fault handler must call [`crate::extable::fixup`]
+/// for the kernel page faults it cannot resolve, and the user pages must not be MMIO mappings
+/// with side effects on read.
+pub unsafe fn copy_from_user(dst: &mut [u8], src: Virtual) -> Result<(), Fault> {
+    check_user_range(src.as_u64(), dst.len())?;
+    copy(dst.as_mut_ptr(), src.as_ptr(), dst.len())
+}
+
+/// Copy the given kernel buffer to the given user address.
+///
+/// # Errors
+/// Returns [`Fault`] if the user range is not entirely in the user half of the address space, or
+/// if a page of the range is not mapped or not writable. Some bytes may have been written to the
+/// user range in this case.
+///
+/// # Safety
+/// This function is unsafe because the page fault handler must call [`crate::extable::fixup`]
+/// for the kernel page faults it cannot resolve, and the user range must not overlap with
+/// memory used by the kernel (for example a page shared with the kernel).
+pub unsafe fn copy_to_user(dst: Virtual, src: &[u8]) -> Result<(), Fault> {
+    check_user_range(dst.as_u64(), src.len())?;
+    copy(dst.as_mut_ptr(), src.as_ptr(), src.len())
+}
+
+/// Check that the range of the given length at the given address is entirely in the user half
+/// of the address space.
+fn check_user_range(start: u64, len: usize) -> Result<(), Fault> {
+    match start.checked_add(len as u64) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(Fault),
+    }
+}
+
+/// Copy the given number of bytes with `rep movsb`, with the user pages accessible if SMAP is
+/// enabled. A page fault during the copy resumes at the fixup code, which reports the fault.
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Fault> {
+    let smap = u64::from(cr4::read_flags().contains(cr4::Flags::SMAP));
+    let fault: u64;
+    asm!(
+        "test {smap}, {smap}",
+        "jz 2f",
+        "stac",
+        "2:",
+        "xor {fault:e}, {fault:e}",
+        "3: rep movsb",
+        "4:",
+        crate::extable!("3b", "4b", "5f"),
+        "jmp 6f",
+        "5: mov {fault:e}, 1",
+        "6:",
+        "test {smap}, {smap}",
+        "jz 7f",
+        "clac",
+        "7:",
+        smap = in(reg) smap,
+        fault = out(reg) fault,
+        inout("rdi") dst => _,
+        inout("rsi") src => _,
+        inout("rcx") len => _,
+        options(nostack)
+    );
+
+    if fault == 0 {
+        Ok(())
+    } else {
+        Err(Fault)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Fault;
+
+    #[test]
+    fn user_range_checks() {
+        assert_eq!(super::check_user_range(0x1000, 0x1000), Ok(()));
+        assert_eq!(
+            super::check_user_range(0x0000_7FFF_FFFF_F000, 0x1000),
+            Ok(())
+        );
+        assert_eq!(
+            super::check_user_range(0x0000_7FFF_FFFF_F000, 0x1001),
+            Err(Fault)
+        );
+        assert_eq!(
+            super::check_user_range(0xFFFF_8000_0000_0000, 1),
+            Err(Fault)
+        );
+        assert_eq!(super::check_user_range(u64::MAX, 2), Err(Fault));
+    }
+}
@@ -0,0 +1,313 @@
+//! A hard lockup detector, built on the performance monitoring counters of Intel and AMD
+//! processors (see [`crate::cpu::quirks::Pmu`]). A counter is programmed to overflow after a
+//! given number of unhalted core cycles, and the overflow is delivered as a NMI through the
+//! performance counter LVT entry of the local APIC. Since NMIs cannot be masked, the watchdog
+//! fires even if a CPU spins with interrupts disabled.
+//!
+//! The kernel must call [`touch`] regularly on each CPU (for example, in the timer interrupt
+//! handler), and [`check`] at the start of its NMI handler. If a CPU has not called [`touch`]
+//! during several consecutive watchdog periods, [`check`] reports a lockup. Halted CPUs do not
+//! count unhalted cycles, so an idle CPU never triggers the watchdog.
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
+
+use crate::{
+    cpu::{msr, quirks::Pmu, State},
+    lapic::{LocalApic, LvtDelivery, LvtEntry},
+};
+
+/// The global control register of the performance counters (version 2 and later).
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+
+/// The overflow control register of the performance counters (version 2 and later).
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// The flags of the event counted by the watchdog (the unhalted core cycles): counted in user and
+/// kernel mode, with an interrupt on overflow and the counter enabled.
+const EVENT_FLAGS: u64 = 1 << 16 | 1 << 17 | 1 << 20 | 1 << 22;
+
+/// The width of the performance counters of AMD processors.
+const AMD_COUNTER_WIDTH: u8 = 48;
+
+/// The maximal period of the watchdog, in cycles. On Intel processors, the counter is written
+/// with the legacy MSR, which only writes the low 32 bits and sign extends them.
+pub const MAX_PERIOD: u64 = 0x7FFF_FFFF;
+
+/// The number of consecutive watchdog periods without a call to [`touch`] before a lockup is
+/// reported.
+pub const LOCKUP_THRESHOLD: u32 = 3;
+
+/// The number of cycles between two watchdog NMIs.
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// The width in bits of the general purpose counters, read by [`start`] so that the NMI handler
+/// does not need to execute `cpuid`.
+static COUNTER_WIDTH: AtomicU8 = AtomicU8::new(0);
+
+/// Whether the counters must also be enabled in the global control register, which only exists
+/// in the architectural performance monitoring version 2 and later. Set by [`start`].
+static GLOBAL_CONTROL: AtomicBool = AtomicBool::new(false);
+
+/// The number of calls to [`touch`] of each CPU, indexed by CPU identifier (see
+/// [`crate::percpu::cpu_id_fast`]).
+static TOUCHES: [AtomicU64; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+/// The value of [`TOUCHES`] seen by the last watchdog NMI of each CPU.
+static LAST_TOUCHES: [AtomicU64; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+/// The number of consecutive watchdog periods without a call to [`touch`] of each CPU.
+static STALLS: [AtomicU32; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU32 = AtomicU32::new(0);
+    [ZERO; 256]
+};
+
+/// The result of [`check`].
+#[derive(Debug)]
+pub enum Status<'a> {
+    /// The NMI was not triggered by the watchdog: it must be handled by the kernel.
+    Other,
+
+    /// The NMI was triggered by the watchdog, and the current CPU made progress since the last
+    /// one (or not for long enough to be considered as locked up).
+    Alive,
+
+    /// The NMI was triggered by the watchdog, and the current CPU seems to be locked up.
+    Lockup(Lockup<'a>),
+}
+
+/// A hard lockup detected by [`check`]. It can be displayed to print a report with a dump of the
+/// registers of the locked up CPU.
+#[derive(Debug)]
+pub struct Lockup<'a> {
+    apic_id: u32,
+    stalls: u32,
+    state: &'a State,
+}
+
+impl Lockup<'_> {
+    /// Returns the local APIC ID of the locked up CPU.
+    #[must_use]
+    pub const fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Returns the number of consecutive watchdog periods without progress.
+    #[must_use]
+    pub const fn stalls(&self) -> u32 {
+        self.stalls
+    }
+
+    /// Returns the state of the CPU when the watchdog NMI was triggered.
+    #[must_use]
+    pub const fn state(&self) -> &State {
+        self.state
+    }
+}
+
+impl fmt::Display for Lockup<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Hard lockup detected on CPU {} ({} watchdog periods without progress)",
+            self.apic_id, self.stalls
+        )?;
+        write!(f, "{}", self.state)
+    }
+}
+
+/// Returns `true` if the watchdog is supported by the current CPU, i.e. if the CPU has the AMD
+/// performance counters, or the architectural performance monitoring with at least one general
+/// purpose counter.
+#[must_use]
+pub fn supported() -> bool {
+    match crate::cpu::quirks().pmu {
+        Pmu::Architectural => {
+            let (version, counters, _) = perfmon();
+            version >= 1 && counters >= 1
+        }
+        Pmu::Amd => true,
+        Pmu::None => false,
+    }
+}
+
+/// Start the watchdog on the current CPU: a NMI will be triggered each time the CPU executed the
+/// given number of unhalted cycles. The first general purpose performance counter is used by the
+/// watchdog, and must not be used by the kernel.
+///
+/// # Panics
+/// This function panics if the watchdog is not supported (see [`supported`]), if the period is 0
+/// or greater than [`MAX_PERIOD`], or if the identifier of the current CPU is greater than 255.
+///
+/// # Safety
+/// This function is unsafe because the NMI handler of the kernel must be installed and must call
+/// [`check`], otherwise the watchdog NMI will not be handled correctly. The per-CPU area of the
+/// current CPU must also have been initialized (see [`crate::percpu::cpu_id_fast`]).
+pub unsafe fn start(lapic: &LocalApic, period: u64) {
+    assert!(supported(), "Watchdog is not supported by this CPU");
+    assert!(period != 0 && period <= MAX_PERIOD, "Invalid watchdog period");
+    let cpu = cpu_index().expect("CPU identifier too large for the watchdog");
+
+    let pmu = crate::cpu::quirks().pmu;
+    let (version, _, width) = perfmon();
+    let width = match pmu {
+        Pmu::Amd => AMD_COUNTER_WIDTH,
+        _ => width,
+    };
+    COUNTER_WIDTH.store(width, Ordering::Relaxed);
+    GLOBAL_CONTROL.store(pmu == Pmu::Architectural && version >= 2, Ordering::Relaxed);
+    PERIOD.store(period, Ordering::Relaxed);
+    TOUCHES[cpu].store(0, Ordering::Relaxed);
+    LAST_TOUCHES[cpu].store(0, Ordering::Relaxed);
+    STALLS[cpu].store(0, Ordering::Relaxed);
+
+    let selector = event_select(pmu);
+    let event = pmu.unhalted_cycles().expect("No performance counters") | EVENT_FLAGS;
+    msr::write(selector, 0);
+    reload(pmu, period);
+    lapic.set_lvt_performance(LvtEntry::new(0).set_delivery(LvtDelivery::Nmi).build());
+    msr::write(selector, event);
+    if global_control() {
+        msr::write(IA32_PERF_GLOBAL_CTRL, msr::read(IA32_PERF_GLOBAL_CTRL) | 1);
+    }
+}
+
+/// Stop the watchdog on the current CPU.
+///
+/// # Safety
+/// This function is unsafe because it reprograms the first general purpose performance counter.
+pub unsafe fn stop(lapic: &LocalApic) {
+    msr::write(event_select(crate::cpu::quirks().pmu), 0);
+    lapic.set_lvt_performance(LvtEntry::MASKED);
+}
+
+/// Signal that the current CPU is making progress. This must be called regularly on each CPU
+/// where the watchdog is started, more often than the watchdog period.
+///
+/// # Safety
+/// This function is unsafe because the per-CPU area of the current CPU must have been
+/// initialized (see [`crate::percpu::cpu_id_fast`]).
+pub unsafe fn touch() {
+    if let Some(cpu) = cpu_index() {
+        TOUCHES[cpu].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Check if the current NMI was triggered by the watchdog, and if the current CPU seems to be
+/// locked up. This must be called at the start of the NMI handler of the kernel with the state of
+/// the interrupted code. If the NMI was triggered by the watchdog, the counter is reloaded and the
+/// performance counter LVT entry, masked by the local APIC when the NMI was delivered, is
+/// unmasked.
+///
+/// # Safety
+/// This function is unsafe because it must only be called from the NMI handler, on a CPU where
+/// the watchdog has been started with [`start`].
+pub unsafe fn check<'a>(lapic: &LocalApic, state: &'a State) -> Status<'a> {
+    let period = PERIOD.load(Ordering::Relaxed);
+    let Some(cpu) = cpu_index() else {
+        return Status::Other;
+    };
+    let pmu = crate::cpu::quirks().pmu;
+    if period == 0 || !overflowed(pmu) {
+        return Status::Other;
+    }
+
+    reload(pmu, period);
+    if global_control() {
+        msr::write(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+    }
+    lapic.set_lvt_performance(LvtEntry::new(0).set_delivery(LvtDelivery::Nmi).build());
+
+    let touches = TOUCHES[cpu].load(Ordering::Relaxed);
+    if LAST_TOUCHES[cpu].swap(touches, Ordering::Relaxed) != touches {
+        STALLS[cpu].store(0, Ordering::Relaxed);
+        return Status::Alive;
+    }
+
+    let stalls = STALLS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    if stalls < LOCKUP_THRESHOLD {
+        return Status::Alive;
+    }
+
+    Status::Lockup(Lockup {
+        apic_id: lapic.id(),
+        stalls,
+        state,
+    })
+}
+
+/// Returns the version of the architectural performance monitoring, the number of general
+/// purpose counters and their width in bits.
+fn perfmon() -> (u8, u8, u8) {
+    let max = unsafe { core::arch::x86_64::__cpuid(0).eax };
+    if max < 0x0A {
+        return (0, 0, 0);
+    }
+    let eax = unsafe { core::arch::x86_64::__cpuid(0x0A).eax };
+    let [version, counters, width, _] = eax.to_le_bytes();
+    (version, counters, width)
+}
+
+/// Returns the event selection register of the first general purpose counter.
+///
+/// # Panics
+/// Panics if the CPU has no known performance counters.
+fn event_select(pmu: Pmu) -> u32 {
+    pmu.event_select().expect("No performance counters")
+}
+
+/// Returns the register of the first general purpose counter, and its width in bits.
+///
+/// # Panics
+/// Panics if the CPU has no known performance counters.
+fn counter(pmu: Pmu) -> (u32, u8) {
+    let width = COUNTER_WIDTH.load(Ordering::Relaxed);
+    (pmu.counter().expect("No performance counters"), width)
+}
+
+/// Returns `true` if the counters must also be enabled in the global control register (see
+/// [`GLOBAL_CONTROL`]).
+fn global_control() -> bool {
+    GLOBAL_CONTROL.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the first general purpose counter has overflowed. The counter is loaded with
+/// the opposite of the period, so its most significant bit is cleared when it overflows.
+unsafe fn overflowed(pmu: Pmu) -> bool {
+    let (counter, width) = counter(pmu);
+    msr::read(counter) & (1 << (width - 1)) == 0
+}
+
+/// Load the first general purpose counter so that it overflows after the given number of cycles.
+/// Only the low 32 bits are written on Intel processors, since the legacy MSR sign extends them.
+unsafe fn reload(pmu: Pmu, period: u64) {
+    let (counter, width) = counter(pmu);
+    let mask = if pmu == Pmu::Amd {
+        (1 << width) - 1
+    } else {
+        0xFFFF_FFFF
+    };
+    msr::write(counter, period.wrapping_neg() & mask);
+}
+
+/// Returns the index of the current CPU in the watchdog tables, if its identifier is small
+/// enough.
+///
+/// # Safety
+/// The per-CPU area of the current CPU must have been initialized.
+unsafe fn cpu_index() -> Option<usize> {
+    usize::try_from(crate::percpu::cpu_id_fast())
+        .ok()
+        .filter(|&cpu| cpu < TOUCHES.len())
+}
+